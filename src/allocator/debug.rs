@@ -0,0 +1,556 @@
+// src/allocator/debug.rs
+//
+// LibertyOS' debug allocator, a wrapper around the heap (or the slab-allocator) that catches misuses of the memory
+// it hands out. Every allocation gets a header and is surrounded by redzones, which are checked when it is freed.
+// New memory is poisoned, and freed memory is poisoned and held back in a quarantine for a while, so that double
+// frees and writes after free are caught. The allocations that are still outstanding are linked together, with the
+// call sites (return addresses) that made them, and can be dumped over serial to find leaks. The return addresses
+// can be resolved with addr2line against the kernel's binary.
+
+/*
+	IMPORTS
+*/
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{arch::asm, mem, ptr::{self, null_mut}};
+use x86_64::{instructions::interrupts, structures::paging::PageTableFlags, VirtAddr};
+
+use crate::{allocator::{alignup, Locked}, serprintln};
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of each redzone
+const REDZONE: usize = 32;
+
+// Byte of the redzones
+const REDZONE_BYTE: u8 = 0xBB;
+
+// Byte that new memory is filled with
+const ALLOC_POISON: u8 = 0x5A;
+
+// Byte that freed memory is filled with
+const FREE_POISON: u8 = 0x6B;
+
+// Magic values of the header, for allocations that are live and freed
+const MAGIC_LIVE: u64 = 0x4C49_5645_4845_4150;
+const MAGIC_FREED: u64 = 0x4652_4545_4845_4150;
+
+// Number of return addresses recorded per allocation or free
+const SITES: usize = 6;
+
+// Number of freed allocations held back before they are really freed
+const QUARANTINE: usize = 256;
+
+// Largest distance between two frames of the stack
+const MAX_FRAME: usize = 1 << 20;
+
+
+// Header struct (before the front redzone of every allocation)
+#[repr(C)]
+struct Header
+{
+	magic: u64,
+
+	// Size requested
+	size: usize,
+
+	// Alignment requested
+	align: usize,
+
+	// Sequence number of the allocation
+	seq: u64,
+
+	// Outstanding allocations
+	next: *mut Header,
+	prev: *mut Header,
+
+	// Call site of the allocation (and then of the free)
+	sites: [usize; SITES],
+}
+
+
+// DebugStats struct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DebugStats
+{
+	pub allocs: usize,
+	pub frees: usize,
+
+	// Outstanding allocations, and the bytes they requested
+	pub live: usize,
+	pub live_bytes: usize,
+
+	// Allocations in the quarantine
+	pub quarantined: usize,
+
+	// Misuses detected (redzone overruns, double and invalid frees, writes after free)
+	pub errors: usize,
+}
+
+
+// DebugAlloc struct
+pub struct DebugAlloc
+{
+	// Most recent outstanding allocation
+	head: *mut Header,
+
+	// Freed allocations (a ring, of which `qlen` entries from `qpos` onwards are in use)
+	quarantine: [*mut Header; QUARANTINE],
+	qpos: usize,
+	qlen: usize,
+
+	seq: u64,
+	stats: DebugStats,
+}
+
+
+// The allocator only hands out pointers to the memory it manages
+unsafe impl Send for DebugAlloc {}
+
+
+// Implementation of the DebugAlloc struct
+impl DebugAlloc
+{
+	// New
+	pub const fn new() -> Self
+	{
+		Self
+		{
+			head: null_mut(),
+			quarantine: [null_mut(); QUARANTINE],
+			qpos: 0,
+			qlen: 0,
+			seq: 0,
+			stats: DebugStats
+			{
+				allocs: 0,
+				frees: 0,
+				live: 0,
+				live_bytes: 0,
+				quarantined: 0,
+				errors: 0,
+			},
+		}
+	}
+
+
+	// Link an allocation
+	unsafe fn link(&mut self, header: *mut Header)
+	{
+		self.seq += 1;
+		(*header).seq = self.seq;
+		(*header).prev = null_mut();
+		(*header).next = self.head;
+		if !self.head.is_null()
+		{
+			(*self.head).prev = header;
+		}
+		self.head = header;
+
+		self.stats.allocs += 1;
+		self.stats.live += 1;
+		self.stats.live_bytes += (*header).size;
+	}
+
+
+	// Unlink an allocation
+	unsafe fn unlink(&mut self, header: *mut Header)
+	{
+		if (*header).prev.is_null()
+		{
+			self.head = (*header).next;
+		}
+		else
+		{
+			(*(*header).prev).next = (*header).next;
+		}
+
+		if !(*header).next.is_null()
+		{
+			(*(*header).next).prev = (*header).prev;
+		}
+
+		self.stats.frees += 1;
+		self.stats.live -= 1;
+		self.stats.live_bytes -= (*header).size;
+	}
+
+
+	// Put a freed allocation into the quarantine, returning the one that it pushes out
+	fn quarantine(&mut self, header: *mut Header) -> Option<*mut Header>
+	{
+		if self.qlen < QUARANTINE
+		{
+			self.quarantine[(self.qpos + self.qlen) % QUARANTINE] = header;
+			self.qlen += 1;
+			self.stats.quarantined = self.qlen;
+			return None;
+		}
+
+		let evicted = self.quarantine[self.qpos];
+		self.quarantine[self.qpos] = header;
+		self.qpos = (self.qpos + 1) % QUARANTINE;
+		Some(evicted)
+	}
+}
+
+
+// The allocator that does the allocations
+fn inner() -> &'static dyn GlobalAlloc
+{
+	#[cfg(feature = "slab")]
+	{
+		&super::SLAB
+	}
+
+	#[cfg(not(feature = "slab"))]
+	{
+		&super::ALLOCATOR
+	}
+}
+
+
+// Space before the allocation (the header and the front redzone, rounded up to the alignment)
+fn front(align: usize) -> usize
+{
+	alignup(mem::size_of::<Header>() + REDZONE, align)
+}
+
+
+// Layout of the allocation as made by the inner allocator
+fn outer(size: usize, align: usize) -> Layout
+{
+	let align = align.max(mem::align_of::<Header>());
+	unsafe
+	{
+		Layout::from_size_align_unchecked(front(align) + size + REDZONE, align)
+	}
+}
+
+
+// Header of an allocation
+fn header(ptr: *mut u8) -> *mut Header
+{
+	(ptr as usize - REDZONE - mem::size_of::<Header>()) as *mut Header
+}
+
+
+// Whether or not an address is mapped for the kernel only (the pages of processes are out of reach with SMAP)
+fn kernel(address: usize) -> bool
+{
+	crate::mem::pte(VirtAddr::new_truncate(address as u64)).map_or(false, |entry|
+	{
+		let flags = entry.flags();
+		flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+	})
+}
+
+
+// Return addresses of the callers of the function that calls this one
+//
+// Follows the frame pointers (which the target keeps), as long as each frame is mapped and lies above the previous
+// one on the stack.
+#[inline(always)]
+fn callsites() -> [usize; SITES]
+{
+	let mut sites = [0; SITES];
+	let mut rbp: usize;
+	unsafe
+	{
+		asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+	}
+
+	// The return address into the caller (the shim that calls the global allocator) is skipped
+	for i in 0..=SITES
+	{
+		if rbp == 0 || rbp % 8 != 0 || !kernel(rbp) || !kernel(rbp + 8)
+		{
+			break;
+		}
+
+		let (next, ret) = unsafe
+		{
+			(*(rbp as *const usize), *((rbp + 8) as *const usize))
+		};
+
+		if i > 0
+		{
+			sites[i - 1] = ret;
+		}
+
+		if next <= rbp || next - rbp > MAX_FRAME
+		{
+			break;
+		}
+		rbp = next;
+	}
+	sites
+}
+
+
+// Check that a region is filled with a byte, returning the offset of the first byte that differs
+unsafe fn check(start: *const u8, len: usize, byte: u8) -> Option<usize>
+{
+	core::slice::from_raw_parts(start, len).iter().position(|&b| b != byte)
+}
+
+
+// Report a misuse of an allocation
+fn report(what: &str, ptr: *mut u8, header: Option<&Header>, sites: &[usize; SITES])
+{
+	serprintln!("[ERR] HEAPDEBUG: {} OF {:#X}", what, ptr as usize);
+	if let Some(header) = header
+	{
+		serprintln!("[ERR] HEAPDEBUG: ALLOCATION #{} OF {} BYTES, {} AT {:#X?}", header.seq, header.size, if header.magic == MAGIC_LIVE
+		{
+			"ALLOCATED"
+		}
+		else
+		{
+			"FREED"
+		}, trim(&header.sites));
+	}
+	serprintln!("[ERR] HEAPDEBUG: AT {:#X?}", trim(sites));
+}
+
+
+// Call sites that were recorded
+fn trim(sites: &[usize; SITES]) -> &[usize]
+{
+	let len = sites.iter().position(|&site| site == 0).unwrap_or(SITES);
+	&sites[..len]
+}
+
+
+// Check a freed allocation before it is really freed (it must still be poisoned), and free it
+unsafe fn release(header: *mut Header, errors: &mut usize)
+{
+	let size = (*header).size;
+	let align = (*header).align;
+	let ptr = (header as *mut u8).add(mem::size_of::<Header>() + REDZONE);
+
+	if let Some(offset) = check(ptr, size, FREE_POISON)
+	{
+		report("WRITE AFTER FREE", ptr.add(offset), Some(&*header), &(*header).sites);
+		*errors += 1;
+	}
+
+	let layout = outer(size, align);
+	inner().dealloc(ptr.sub(front(layout.align())), layout);
+}
+
+
+// Implementation of the GlobalAlloc trait for the Locked<DebugAlloc> struct
+unsafe impl GlobalAlloc for Locked<DebugAlloc>
+{
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8
+	{
+		let sites = callsites();
+
+		// This one is not locked while the inner allocator runs, so that the two locks are never nested
+		let outer = outer(layout.size(), layout.align());
+		let base = inner().alloc(outer);
+		if base.is_null()
+		{
+			return null_mut();
+		}
+
+		let ptr = base.add(front(outer.align()));
+		let header = header(ptr);
+		header.write(Header
+		{
+			magic: MAGIC_LIVE,
+			size: layout.size(),
+			align: layout.align(),
+			seq: 0,
+			next: null_mut(),
+			prev: null_mut(),
+			sites,
+		});
+		ptr::write_bytes(ptr.sub(REDZONE), REDZONE_BYTE, REDZONE);
+		ptr::write_bytes(ptr, ALLOC_POISON, layout.size());
+		ptr::write_bytes(ptr.add(layout.size()), REDZONE_BYTE, REDZONE);
+
+		interrupts::without_interrupts(||
+		{
+			self.lock().link(header);
+		});
+		ptr
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+	{
+		if ptr.is_null()
+		{
+			return;
+		}
+
+		let sites = callsites();
+		let header = header(ptr);
+		let mut errors = 0;
+
+		match (*header).magic
+		{
+			MAGIC_LIVE => {},
+			MAGIC_FREED =>
+			{
+				report("DOUBLE FREE", ptr, Some(&*header), &sites);
+				self.error(1);
+				return;
+			},
+			_ =>
+			{
+				// Not something that this allocator handed out (or its header was overwritten), so it is leaked
+				report("INVALID FREE", ptr, None, &sites);
+				self.error(1);
+				return;
+			},
+		}
+
+		if (*header).size != layout.size() || (*header).align != layout.align()
+		{
+			serprintln!("[ERR] HEAPDEBUG: FREED WITH {:?}, ALLOCATED WITH SIZE {} ALIGN {}", layout, (*header).size, (*header).align);
+			report("MISMATCHED FREE", ptr, Some(&*header), &sites);
+			errors += 1;
+		}
+
+		let size = (*header).size;
+		if let Some(offset) = check(ptr.sub(REDZONE), REDZONE, REDZONE_BYTE)
+		{
+			report("UNDERRUN", ptr.sub(REDZONE - offset), Some(&*header), &sites);
+			errors += 1;
+		}
+		if let Some(offset) = check(ptr.add(size), REDZONE, REDZONE_BYTE)
+		{
+			report("OVERRUN", ptr.add(size + offset), Some(&*header), &sites);
+			errors += 1;
+		}
+
+		ptr::write_bytes(ptr, FREE_POISON, size);
+
+		let evicted = interrupts::without_interrupts(||
+		{
+			let mut allocator = self.lock();
+			allocator.unlink(header);
+			(*header).magic = MAGIC_FREED;
+			(*header).sites = sites;
+			allocator.quarantine(header)
+		});
+
+		if let Some(evicted) = evicted
+		{
+			release(evicted, &mut errors);
+		}
+
+		self.error(errors);
+	}
+}
+
+
+// Implementation of the Locked<DebugAlloc> struct
+impl Locked<DebugAlloc>
+{
+	// Count errors
+	fn error(&self, count: usize)
+	{
+		if count > 0
+		{
+			interrupts::without_interrupts(||
+			{
+				self.lock().stats.errors += count;
+			});
+		}
+	}
+}
+
+
+// Statistics (of the debug allocator of the kernel)
+pub fn stats() -> DebugStats
+{
+	interrupts::without_interrupts(||
+	{
+		super::DEBUG.lock().stats
+	})
+}
+
+
+// Mark (the sequence number of the last allocation, to dump the allocations made after it)
+pub fn mark() -> u64
+{
+	interrupts::without_interrupts(||
+	{
+		super::DEBUG.lock().seq
+	})
+}
+
+
+// Dump the outstanding allocations made after a mark (all of them with a mark of zero) over serial
+pub fn dump(since: u64)
+{
+	interrupts::without_interrupts(||
+	{
+		let allocator = super::DEBUG.lock();
+		let mut count = 0;
+		let mut bytes = 0;
+
+		let mut header = allocator.head;
+		while !header.is_null()
+		{
+			let entry = unsafe
+			{
+				&*header
+			};
+
+			// The most recent allocations come first
+			if entry.seq <= since
+			{
+				break;
+			}
+
+			serprintln!("[INFO] HEAPDEBUG: #{} {:#X} {} BYTES AT {:#X?}", entry.seq, header as usize + mem::size_of::<Header>() + REDZONE, entry.size, trim(&entry.sites));
+			count += 1;
+			bytes += entry.size;
+			header = entry.next;
+		}
+
+		serprintln!("[INFO] HEAPDEBUG: {} OUTSTANDING ALLOCATIONS, {} BYTES", count, bytes);
+	});
+}
+
+
+// Check the allocations in the quarantine, and free them
+pub fn drain()
+{
+	let mut errors = 0;
+	loop
+	{
+		let header = interrupts::without_interrupts(||
+		{
+			let mut allocator = super::DEBUG.lock();
+			if allocator.qlen == 0
+			{
+				return None;
+			}
+
+			let header = allocator.quarantine[allocator.qpos];
+			allocator.qpos = (allocator.qpos + 1) % QUARANTINE;
+			allocator.qlen -= 1;
+			allocator.stats.quarantined = allocator.qlen;
+			Some(header)
+		});
+
+		match header
+		{
+			Some(header) => unsafe
+			{
+				release(header, &mut errors);
+			},
+			None => break,
+		}
+	}
+
+	super::DEBUG.error(errors);
+}
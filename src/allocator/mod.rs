@@ -6,40 +6,77 @@
 	IMPORTS
 */
 
-use alloc::{alloc::{GlobalAlloc, Layout}, slice::SliceIndex, sync::Arc, vec::Vec, vec};
-use core::{cmp, ops::{Index, IndexMut}, ptr::null_mut};
-use linked_list_allocator::LockedHeap;
-use spin::Mutex;
-use x86_64::{structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, page::PageRangeInclusive, PageTableFlags, Size4KiB}, VirtAddr};
+use alloc::{alloc::{GlobalAlloc, Layout}, slice::SliceIndex, sync::Arc};
+use core::{cmp, ops::{Index, IndexMut}, ptr::null_mut, sync::atomic::{AtomicUsize, Ordering}};
+use linked_list_allocator::Heap;
+use x86_64::{structures::paging::{mapper::{MapToError, UnmapError}, FrameAllocator, Mapper, Page, page::PageRangeInclusive, PageTableFlags, PhysFrame, Size4KiB}, VirtAddr};
 
-use crate::print;
+use crate::{mem::frame::{self, GlobalFrameAllocator, Zone}, print};
 
 // Bump allocation
 pub mod bump;
 
+// Debug allocation (a wrapper that checks how allocations are used)
+pub mod debug;
+
 // Fixed-size allocation
 pub mod fixedsize;
 
 // Linked-list allocation
 pub mod lnls;
 
+// Slab allocation
+pub mod slab;
+
+
+// Initial size of the heap (it grows on demand, up to its maximum size)
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MB
+
+// Lowest start of the heap (it is moved up by a random amount, see mem::kaslr)
+pub const HEAP_BASE: usize = 0x_4444_4444_0000;
+
+// Virtual address space reserved for the heap (its maximum size cannot exceed this)
+pub const HEAP_REGION: usize = 64 << 30; // 64 GB
+
+// Smallest amount that the heap grows by
+const HEAP_GROWTH: usize = 64 * 1024;
+
+// Page size
+const PAGE_SIZE: usize = 4096;
+
+// Virtual address space for the large allocations of the slab-allocator (that are not physically contiguous), right
+// after the region of the heap
+pub const SLAB_REGION: usize = 64 << 30; // 64 GB
+
+// Start of the heap
+static HEAP_START: AtomicUsize = AtomicUsize::new(HEAP_BASE);
+
+// Maximum size of the heap (zero until the heap has been initialized, unless it was set beforehand)
+static HEAP_MAX: AtomicUsize = AtomicUsize::new(0);
 
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KB
-pub const HEAP_START: usize = 0x_4444_4444_0000;
 
+// The heap is shared by every CPU; interrupts are disabled while it is locked, so that an interrupt
+// handler that allocates cannot deadlock against the code it interrupted.
+#[cfg_attr(not(any(feature = "slab", feature = "heapdebug")), global_allocator)]
+pub static ALLOCATOR: Locked<Heap> = Locked::new(Heap::empty());
 
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+// The slab-allocator (used instead of the heap with the "slab" feature)
+#[cfg_attr(all(feature = "slab", not(feature = "heapdebug")), global_allocator)]
+pub static SLAB: Locked<slab::SlabAlloc> = Locked::new(slab::SlabAlloc::new());
+
+// The debug allocator (wraps whichever of the above is selected, with the "heapdebug" feature)
+#[cfg_attr(feature = "heapdebug", global_allocator)]
+pub static DEBUG: Locked<debug::DebugAlloc> = Locked::new(debug::DebugAlloc::new());
 
 pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), MapToError<Size4KiB>>
 {
-	// Allocate half of available memory to the heap.
-	// NOTE: Memory allocated to the heap cannot exceed 16MB.
-	//let heap_size = cmp::min(crate::mem::memsize() / 2, 16 << 20);
+	// By default, the heap may grow to half of the usable memory
+	let usable = frame::stats().total * frame::FRAME_SIZE as usize;
+	let _ = HEAP_MAX.compare_exchange(0, cmp::min(usable / 2, HEAP_REGION), Ordering::SeqCst, Ordering::SeqCst);
 
 	let page_range =
 	{
-		let heap_start = VirtAddr::new(HEAP_START as u64);
+		let heap_start = VirtAddr::new(heap_start() as u64);
 		let heap_end = heap_start + HEAP_SIZE - 1u64;
 		let heap_startpage = Page::containing_address(heap_start);
 		let heap_endpage = Page::containing_address(heap_end);
@@ -61,7 +98,7 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
 
 	unsafe
 	{
-		ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE as usize);
+		ALLOCATOR.lock().init(heap_start(), HEAP_SIZE);
 	}
 
 	Ok(())
@@ -77,10 +114,7 @@ pub fn palloc(address: u64, size: u64)
 		crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET))
 	};
 
-	let mut framealloc = unsafe
-	{
-		crate::mem::BootInfoFrameAllocator::init(crate::mem::MEMMAP.unwrap())
-	};
+	let mut framealloc = GlobalFrameAllocator;
 
 	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
@@ -93,7 +127,15 @@ pub fn palloc(address: u64, size: u64)
 
 	for page in pages
 	{
-		let frame = framealloc.allocate_frame().unwrap();
+		let frame = match framealloc.allocate_frame()
+		{
+			Some(frame) => frame,
+			None =>
+			{
+				print!("[ERR] OUT OF MEMORY, UNABLE TO MAP {:?}", page);
+				return;
+			}
+		};
 
 		unsafe
 		{
@@ -103,6 +145,7 @@ pub fn palloc(address: u64, size: u64)
 			}
 			else
 			{
+				crate::mem::frame::free(frame);
 				print!("[ERR] UNABLE TO MAP {:?}", page);
 			}
 		}
@@ -128,13 +171,18 @@ pub fn pdealloc(address: u64, size: u64)
 
 	for page in pages
 	{
-		if let Ok((_frame, mapping)) = mapper.unmap(page)
-		{
-			mapping.flush();
-		}
-		else
+		match mapper.unmap(page)
 		{
-			print!("[ERR] COULD NOT DEALLOCATE {:?}", page);
+			Ok((frame, mapping)) =>
+			{
+				mapping.flush();
+				crate::mem::frame::free(frame);
+			},
+
+			// Pages that are mapped on demand may never have been touched
+			Err(UnmapError::PageNotMapped) => {},
+
+			Err(_) => print!("[ERR] COULD NOT DEALLOCATE {:?}", page),
 		}
 	}
 }
@@ -172,9 +220,98 @@ impl<A> Locked<A>
 		}
 	}
 
+	// Lock (servicing TLB shootdowns while it waits, since the heap is locked with interrupts disabled)
 	pub fn lock(&self) -> spin::MutexGuard<A>
 	{
-		self.inner.lock()
+		crate::sys::smp::lock(&self.inner)
+	}
+}
+
+
+// Grow the heap
+//
+// Maps enough new pages at the top of the heap for the given allocation to fit, if its maximum size allows it.
+// Returns false if the heap could not grow.
+fn grow(heap: &mut Heap, layout: &Layout) -> bool
+{
+	let needed = layout.size() + layout.align();
+	let by = alignup(cmp::max(needed, HEAP_GROWTH), PAGE_SIZE);
+	if heap.size() + by > heapmax()
+	{
+		return false;
+	}
+
+	let mut mapper = unsafe
+	{
+		crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET))
+	};
+
+	let top = heap.top();
+	let mut mapped = 0;
+	while mapped < by
+	{
+		let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new((top + mapped) as u64));
+		let frame = match frame::alloc(Zone::Normal)
+		{
+			Some(frame) => frame,
+			None => break,
+		};
+
+		match unsafe
+		{
+			mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, &mut GlobalFrameAllocator)
+		}
+		{
+			Ok(flush) => flush.flush(),
+			Err(_) =>
+			{
+				frame::free(frame);
+				break;
+			}
+		}
+		mapped += PAGE_SIZE;
+	}
+
+	if mapped > 0
+	{
+		unsafe
+		{
+			heap.extend(mapped);
+		}
+	}
+	mapped > 0
+}
+
+
+// Implementation of the GlobalAlloc trait for the Locked<Heap> struct
+unsafe impl GlobalAlloc for Locked<Heap>
+{
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8
+	{
+		x86_64::instructions::interrupts::without_interrupts(||
+		{
+			let mut heap = self.lock();
+			loop
+			{
+				if let Ok(ptr) = heap.allocate_first_fit(layout)
+				{
+					return ptr.as_ptr();
+				}
+
+				if !grow(&mut heap, &layout)
+				{
+					return null_mut();
+				}
+			}
+		})
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+	{
+		x86_64::instructions::interrupts::without_interrupts(||
+		{
+			self.lock().deallocate(core::ptr::NonNull::new_unchecked(ptr), layout)
+		})
 	}
 }
 
@@ -185,32 +322,148 @@ fn alignup(address: usize, align: usize) -> usize
 }
 
 
+// Start of the heap
+pub fn heap_start() -> usize
+{
+	HEAP_START.load(Ordering::SeqCst)
+}
+
+
+// Set the start of the heap (only before the heap has been initialized)
+pub fn set_heap_start(address: usize)
+{
+	HEAP_START.store(address, Ordering::SeqCst);
+}
+
+
+// Start of the region of the slab-allocator
+pub fn slab_start() -> usize
+{
+	heap_start() + HEAP_REGION
+}
+
+
+// Maximum size of the heap
+pub fn heapmax() -> usize
+{
+	HEAP_MAX.load(Ordering::SeqCst)
+}
+
+
+// Set the maximum size of the heap (the heap does not shrink, so this fails below its current size)
+pub fn set_heapmax(size: usize) -> Result<(), ()>
+{
+	let current = x86_64::instructions::interrupts::without_interrupts(||
+	{
+		ALLOCATOR.lock().size()
+	});
+
+	if size < current
+	{
+		return Err(());
+	}
+
+	HEAP_MAX.store(cmp::min(size, HEAP_REGION), Ordering::SeqCst);
+	Ok(())
+}
+
+
 // Free memory
+//
+// This is the free part of the heap, and what it can still grow by (as far as its maximum size and the free
+// physical memory allow).
+#[cfg(not(feature = "slab"))]
 pub fn memfree() -> usize
 {
-	ALLOCATOR.lock().free()
+	let (size, free) = x86_64::instructions::interrupts::without_interrupts(||
+	{
+		let heap = ALLOCATOR.lock();
+		(heap.size(), heap.free())
+	});
+
+	let growth = cmp::min(heapmax().saturating_sub(size), crate::mem::memfree() as usize);
+	free + growth
 }
 
 
-// Memory size
+// Memory size (of the heap, as currently mapped)
+#[cfg(not(feature = "slab"))]
 pub fn memsize() -> usize
 {
-	ALLOCATOR.lock().size()
+	x86_64::instructions::interrupts::without_interrupts(||
+	{
+		ALLOCATOR.lock().size()
+	})
 }
 
 
 // Used memory
+#[cfg(not(feature = "slab"))]
 pub fn memused() -> usize
 {
-	ALLOCATOR.lock().free()
+	x86_64::instructions::interrupts::without_interrupts(||
+	{
+		ALLOCATOR.lock().used()
+	})
+}
+
+
+// Free memory (the free objects of the slabs, and the free physical memory)
+#[cfg(feature = "slab")]
+pub fn memfree() -> usize
+{
+	let stats = slab::stats();
+	let objects: usize = stats.caches.iter().map(|cache| (cache.capacity - cache.objects) * cache.size).sum();
+	objects + crate::mem::memfree() as usize
+}
+
+
+// Memory size (of the pages held by the slab-allocator)
+#[cfg(feature = "slab")]
+pub fn memsize() -> usize
+{
+	slab::stats().held()
+}
+
+
+// Used memory
+#[cfg(feature = "slab")]
+pub fn memused() -> usize
+{
+	slab::stats().requested()
+}
+
+
+// DmaRegion struct (physically contiguous frames below 4 GB, which are freed along with the last buffer using them)
+#[derive(Debug)]
+struct DmaRegion
+{
+	frame: PhysFrame,
+	count: usize,
+}
+
+
+// Implementation of the Drop trait for the DmaRegion struct
+impl Drop for DmaRegion
+{
+	fn drop(&mut self)
+	{
+		frame::free_contiguous(self.frame, self.count);
+	}
 }
 
 
 // PhysicalBuffer struct
-#[derive(Clone)]
+//
+// A buffer that devices can reach with DMA. It is made of physically contiguous frames, which the kernel reaches
+// through the physical memory map.
+#[derive(Clone, Debug)]
 pub struct PhysicalBuffer
 {
-	buffer: Arc<Mutex<Vec<u8>>>,
+	region: Arc<DmaRegion>,
+
+	// Length of the buffer
+	len: usize,
 }
 
 
@@ -220,35 +473,88 @@ impl PhysicalBuffer
 	// Address
 	pub fn address(&self) -> u64
 	{
-		physaddr(&self.buffer.lock()[0])
+		self.region.frame.start_address().as_u64()
 	}
 
 
-	// From
-	pub fn from(vec: Vec<u8>) -> Self
+	// Aligned
+	//
+	// Creates a buffer whose physical address is a multiple of `align` (which must be a power of
+	// two), as required by most DMA-capable devices.
+	pub fn aligned(len: usize, align: usize) -> Self
 	{
-		let bufferlen = vec.len() - 1;
-		let memlen = physaddr(&vec[bufferlen]) - physaddr(&vec[0]);
+		let count = cmp::max(alignup(len, PAGE_SIZE) / PAGE_SIZE, 1);
+		let frame = frame::alloc_contiguous(count, cmp::max(align / PAGE_SIZE, 1), Zone::Dma32)
+			.expect("[ERR] OUT OF MEMORY FOR DMA");
 
-		if bufferlen == memlen as usize
+		let mut buffer = Self
 		{
-			Self
+			region: Arc::new(DmaRegion
 			{
-				buffer: Arc::new(Mutex::new(vec))
-			}
+				frame,
+				count,
+			}),
+			len,
+		};
+		buffer.fill(0);
+		buffer
+	}
+
+
+	// New
+	pub fn new(len: usize) -> Self
+	{
+		Self::aligned(len, 1)
+	}
+}
+
+
+// Implementation of the Deref trait for the PhysicalBuffer struct
+impl core::ops::Deref for PhysicalBuffer
+{
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8]
+	{
+		unsafe
+		{
+			alloc::slice::from_raw_parts(crate::mem::ptov(self.region.frame.start_address()).as_ptr(), self.len)
 		}
-		else
+	}
+}
+
+
+// Implementation of the DerefMut trait for the PhysicalBuffer struct
+impl core::ops::DerefMut for PhysicalBuffer
+{
+	fn deref_mut(&mut self) -> &mut [u8]
+	{
+		unsafe
 		{
-			// If there is an error, clone the vector and retry
-			Self::from(vec.clone())
+			alloc::slice::from_raw_parts_mut(crate::mem::ptov(self.region.frame.start_address()).as_mut_ptr(), self.len)
 		}
 	}
+}
 
 
-	// New
-	pub fn new(len: usize) -> Self
+// Implementation of the Index trait for the PhysicalBuffer struct
+impl<I: SliceIndex<[u8]>> Index<I> for PhysicalBuffer
+{
+	type Output = I::Output;
+
+	fn index(&self, idx: I) -> &Self::Output
+	{
+		Index::index(&**self, idx)
+	}
+}
+
+
+// Implementation of the IndexMut trait for the PhysicalBuffer struct
+impl<I: SliceIndex<[u8]>> IndexMut<I> for PhysicalBuffer
+{
+	fn index_mut(&mut self, idx: I) -> &mut Self::Output
 	{
-		Self::from(vec![0; len])
+		IndexMut::index_mut(&mut **self, idx)
 	}
 }
 
@@ -0,0 +1,585 @@
+// src/allocator/slab.rs
+//
+// LibertyOS' slab-allocator. Small allocations are served from per-size object caches, whose slabs are whole
+// (physically contiguous) pages taken from the frame allocator, and reached through the physical memory map.
+// Large allocations get pages of their own: a contiguous run if one is free, or scattered frames mapped into a
+// region of virtual memory otherwise.
+
+/*
+	IMPORTS
+*/
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use arrayvec::ArrayVec;
+use core::{mem, ptr::null_mut};
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB}};
+
+use crate::{allocator::{alignup, slab_start, Locked, SLAB_REGION}, mem::frame::{self, GlobalFrameAllocator, Zone}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Object sizes of the caches (every object is aligned to its size)
+const SIZES: [usize; CACHES] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+// Pages per slab, for each cache
+const SLAB_PAGES: [usize; CACHES] = [1, 1, 1, 1, 1, 1, 1, 2, 4];
+
+// Number of caches
+pub const CACHES: usize = 9;
+
+// Page size
+const PAGE_SIZE: usize = 4096;
+
+// Number of free ranges of the virtual region that are remembered (ranges beyond this are not reused)
+const MAX_VRANGES: usize = 32;
+
+
+// Slab struct (the header at the start of every slab)
+#[repr(C)]
+struct Slab
+{
+	next: *mut Slab,
+	prev: *mut Slab,
+
+	// First free object
+	free: *mut FreeObj,
+
+	// Objects in use
+	inuse: usize,
+}
+
+
+// FreeObj struct (a free object, linked to the next one)
+struct FreeObj
+{
+	next: *mut FreeObj,
+}
+
+
+// Cache struct
+struct Cache
+{
+	// Object size
+	size: usize,
+
+	// Pages per slab
+	pages: usize,
+
+	// Slabs with free objects (full slabs are not kept track of, until one of their objects is freed)
+	partial: *mut Slab,
+
+	// An empty slab, kept to avoid returning pages to the frame allocator only to take them back
+	spare: *mut Slab,
+
+	slabs: usize,
+	objects: usize,
+
+	// Bytes requested by the allocations that are served by the cache
+	requested: usize,
+}
+
+
+// CacheStats struct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats
+{
+	// Object size
+	pub size: usize,
+
+	pub slabs: usize,
+
+	// Objects in use
+	pub objects: usize,
+
+	// Objects that fit in the slabs
+	pub capacity: usize,
+
+	// Bytes requested by the allocations
+	pub requested: usize,
+}
+
+
+// SlabStats struct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabStats
+{
+	pub caches: [CacheStats; CACHES],
+
+	// Pages of the large allocations
+	pub large_pages: usize,
+
+	// Bytes requested by the large allocations
+	pub large_requested: usize,
+}
+
+
+// SlabAlloc struct
+pub struct SlabAlloc
+{
+	caches: [Cache; CACHES],
+
+	large_pages: usize,
+	large_requested: usize,
+
+	// Offset of the virtual region that has never been used (the region moves with the heap), and the ranges that
+	// were freed
+	vnext: usize,
+	vfree: ArrayVec<(usize, usize), MAX_VRANGES>,
+}
+
+
+// The slabs are only reached through the lock of the allocator
+unsafe impl Send for SlabAlloc {}
+
+
+// Implementation of the Cache struct
+impl Cache
+{
+	// New
+	const fn new(idx: usize) -> Self
+	{
+		Cache
+		{
+			size: SIZES[idx],
+			pages: SLAB_PAGES[idx],
+			partial: null_mut(),
+			spare: null_mut(),
+			slabs: 0,
+			objects: 0,
+			requested: 0,
+		}
+	}
+
+
+	// Offset of the first object of a slab
+	fn offset(&self) -> usize
+	{
+		alignup(mem::size_of::<Slab>(), self.size)
+	}
+
+
+	// Objects per slab
+	fn capacity(&self) -> usize
+	{
+		(self.pages * PAGE_SIZE - self.offset()) / self.size
+	}
+
+
+	// Create a slab
+	unsafe fn grow(&mut self) -> *mut Slab
+	{
+		let frame = match frame::alloc_contiguous(self.pages, self.pages, Zone::Normal)
+		{
+			Some(frame) => frame,
+			None => return null_mut(),
+		};
+
+		let base = crate::mem::ptov(frame.start_address()).as_u64() as usize;
+		let slab = base as *mut Slab;
+
+		// Link every object into the free list, the lowest first
+		let mut free = null_mut();
+		for i in (0..self.capacity()).rev()
+		{
+			let obj = (base + self.offset() + i * self.size) as *mut FreeObj;
+			obj.write(FreeObj
+			{
+				next: free,
+			});
+			free = obj;
+		}
+
+		slab.write(Slab
+		{
+			next: null_mut(),
+			prev: null_mut(),
+			free,
+			inuse: 0,
+		});
+
+		self.slabs += 1;
+		slab
+	}
+
+
+	// Release a slab (returns its pages to the frame allocator)
+	unsafe fn release(&mut self, slab: *mut Slab)
+	{
+		let address = PhysAddr::new(slab as u64 - crate::mem::PMEM_OFFSET);
+		frame::free_contiguous(PhysFrame::containing_address(address), self.pages);
+		self.slabs -= 1;
+	}
+
+
+	// Push a slab onto the partial list
+	unsafe fn push(&mut self, slab: *mut Slab)
+	{
+		(*slab).prev = null_mut();
+		(*slab).next = self.partial;
+		if !self.partial.is_null()
+		{
+			(*self.partial).prev = slab;
+		}
+		self.partial = slab;
+	}
+
+
+	// Remove a slab from the partial list
+	unsafe fn unlink(&mut self, slab: *mut Slab)
+	{
+		if (*slab).prev.is_null()
+		{
+			self.partial = (*slab).next;
+		}
+		else
+		{
+			(*(*slab).prev).next = (*slab).next;
+		}
+
+		if !(*slab).next.is_null()
+		{
+			(*(*slab).next).prev = (*slab).prev;
+		}
+	}
+
+
+	// Allocate
+	unsafe fn alloc(&mut self, size: usize) -> *mut u8
+	{
+		if self.partial.is_null()
+		{
+			let slab = if self.spare.is_null()
+			{
+				self.grow()
+			}
+			else
+			{
+				mem::replace(&mut self.spare, null_mut())
+			};
+
+			if slab.is_null()
+			{
+				return null_mut();
+			}
+			self.push(slab);
+		}
+
+		let slab = self.partial;
+		let obj = (*slab).free;
+		(*slab).free = (*obj).next;
+		(*slab).inuse += 1;
+
+		if (*slab).free.is_null()
+		{
+			self.unlink(slab);
+		}
+
+		self.objects += 1;
+		self.requested += size;
+		obj as *mut u8
+	}
+
+
+	// Deallocate
+	unsafe fn dealloc(&mut self, ptr: *mut u8, size: usize)
+	{
+		let slab = (ptr as usize & !(self.pages * PAGE_SIZE - 1)) as *mut Slab;
+		let full = (*slab).free.is_null();
+
+		let obj = ptr as *mut FreeObj;
+		obj.write(FreeObj
+		{
+			next: (*slab).free,
+		});
+		(*slab).free = obj;
+		(*slab).inuse -= 1;
+
+		self.objects -= 1;
+		self.requested -= size;
+
+		if full
+		{
+			self.push(slab);
+		}
+
+		if (*slab).inuse == 0
+		{
+			self.unlink(slab);
+			if self.spare.is_null()
+			{
+				self.spare = slab;
+			}
+			else
+			{
+				self.release(slab);
+			}
+		}
+	}
+
+
+	// Statistics
+	fn stats(&self) -> CacheStats
+	{
+		CacheStats
+		{
+			size: self.size,
+			slabs: self.slabs,
+			objects: self.objects,
+			capacity: self.slabs * self.capacity(),
+			requested: self.requested,
+		}
+	}
+}
+
+
+// Implementation of the SlabAlloc struct
+impl SlabAlloc
+{
+	// Create a new slab-allocator
+	pub const fn new() -> Self
+	{
+		SlabAlloc
+		{
+			caches: [Cache::new(0), Cache::new(1), Cache::new(2), Cache::new(3), Cache::new(4), Cache::new(5), Cache::new(6), Cache::new(7), Cache::new(8)],
+			large_pages: 0,
+			large_requested: 0,
+			vnext: 0,
+			vfree: ArrayVec::new_const(),
+		}
+	}
+
+
+	// Cache for a layout (None for large allocations)
+	fn cache(layout: &Layout) -> Option<usize>
+	{
+		let size = layout.size().max(layout.align());
+		SIZES.iter().position(|&s| s >= size)
+	}
+
+
+	// Take a range of the virtual region
+	fn vtake(&mut self, pages: usize) -> Option<usize>
+	{
+		let len = pages * PAGE_SIZE;
+		if let Some(idx) = self.vfree.iter().position(|&(_, free)| free >= len)
+		{
+			let (start, free) = self.vfree[idx];
+			if free == len
+			{
+				self.vfree.swap_remove(idx);
+			}
+			else
+			{
+				self.vfree[idx] = (start + len, free - len);
+			}
+			return Some(start);
+		}
+
+		if self.vnext + len > SLAB_REGION
+		{
+			return None;
+		}
+		self.vnext += len;
+		Some(slab_start() + self.vnext - len)
+	}
+
+
+	// Allocate a large object
+	unsafe fn alloc_large(&mut self, layout: Layout) -> *mut u8
+	{
+		let pages = alignup(layout.size(), PAGE_SIZE) / PAGE_SIZE;
+		let align = (layout.align() / PAGE_SIZE).max(1);
+
+		if let Some(frame) = frame::alloc_contiguous(pages, align, Zone::Normal)
+		{
+			self.large_pages += pages;
+			self.large_requested += layout.size();
+			return crate::mem::ptov(frame.start_address()).as_mut_ptr();
+		}
+
+		// Otherwise, scattered frames are mapped into the virtual region
+		if align > 1
+		{
+			return null_mut();
+		}
+
+		let start = match self.vtake(pages)
+		{
+			Some(start) => start,
+			None => return null_mut(),
+		};
+
+		let mut mapper = crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET));
+		for i in 0..pages
+		{
+			let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new((start + i * PAGE_SIZE) as u64));
+			let mapped = frame::alloc(Zone::Normal).map_or(false, |frame|
+			{
+				match mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, &mut GlobalFrameAllocator)
+				{
+					Ok(flush) =>
+					{
+						flush.flush();
+						true
+					}
+					Err(_) =>
+					{
+						frame::free(frame);
+						false
+					}
+				}
+			});
+
+			if !mapped
+			{
+				self.unmap(start, i);
+				return null_mut();
+			}
+		}
+
+		self.large_pages += pages;
+		self.large_requested += layout.size();
+		start as *mut u8
+	}
+
+
+	// Unmap pages of the virtual region (and give the range back)
+	unsafe fn unmap(&mut self, start: usize, pages: usize)
+	{
+		let mut mapper = crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET));
+		for i in 0..pages
+		{
+			let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new((start + i * PAGE_SIZE) as u64));
+			if let Ok((frame, flush)) = mapper.unmap(page)
+			{
+				flush.flush();
+				frame::free(frame);
+			}
+		}
+
+		if pages > 0
+		{
+			let _ = self.vfree.try_push((start, pages * PAGE_SIZE));
+		}
+	}
+
+
+	// Deallocate a large object
+	unsafe fn dealloc_large(&mut self, ptr: *mut u8, layout: Layout)
+	{
+		let pages = alignup(layout.size(), PAGE_SIZE) / PAGE_SIZE;
+		let address = ptr as usize;
+
+		if (slab_start()..slab_start() + SLAB_REGION).contains(&address)
+		{
+			self.unmap(address, pages);
+		}
+		else
+		{
+			let frame = PhysFrame::containing_address(PhysAddr::new(address as u64 - crate::mem::PMEM_OFFSET));
+			frame::free_contiguous(frame, pages);
+		}
+
+		self.large_pages -= pages;
+		self.large_requested -= layout.size();
+	}
+
+
+	// Statistics
+	pub fn stats(&self) -> SlabStats
+	{
+		let mut stats = SlabStats
+		{
+			large_pages: self.large_pages,
+			large_requested: self.large_requested,
+			..SlabStats::default()
+		};
+
+		for (stat, cache) in stats.caches.iter_mut().zip(self.caches.iter())
+		{
+			*stat = cache.stats();
+		}
+		stats
+	}
+}
+
+
+// Implementation of the SlabStats struct
+impl SlabStats
+{
+	// Bytes of the pages held by the allocator
+	pub fn held(&self) -> usize
+	{
+		let slabs: usize = self.caches.iter().zip(SLAB_PAGES.iter()).map(|(cache, pages)| cache.slabs * pages).sum();
+		(slabs + self.large_pages) * PAGE_SIZE
+	}
+
+
+	// Bytes requested by the allocations
+	pub fn requested(&self) -> usize
+	{
+		self.caches.iter().map(|cache| cache.requested).sum::<usize>() + self.large_requested
+	}
+
+
+	// Fragmentation (the part of the memory held that is not used by the allocations, from 0 to 1)
+	pub fn fragmentation(&self) -> f64
+	{
+		let held = self.held();
+		if held == 0
+		{
+			return 0.0;
+		}
+		1.0 - self.requested() as f64 / held as f64
+	}
+}
+
+
+// Implementation of the GlobalAlloc trait for the Locked<SlabAlloc> struct
+unsafe impl GlobalAlloc for Locked<SlabAlloc>
+{
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8
+	{
+		interrupts::without_interrupts(||
+		{
+			let mut allocator = self.lock();
+			match SlabAlloc::cache(&layout)
+			{
+				Some(idx) => allocator.caches[idx].alloc(layout.size()),
+				None => allocator.alloc_large(layout),
+			}
+		})
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+	{
+		if ptr.is_null()
+		{
+			return;
+		}
+
+		interrupts::without_interrupts(||
+		{
+			let mut allocator = self.lock();
+			match SlabAlloc::cache(&layout)
+			{
+				Some(idx) => allocator.caches[idx].dealloc(ptr, layout.size()),
+				None => allocator.dealloc_large(ptr, layout),
+			}
+		})
+	}
+}
+
+
+// Statistics (of the slab-allocator of the kernel)
+pub fn stats() -> SlabStats
+{
+	interrupts::without_interrupts(||
+	{
+		super::SLAB.lock().stats()
+	})
+}
+
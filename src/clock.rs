@@ -1,17 +1,339 @@
 // clock.rs
 //
-// Basic implementation of time-keeping for the LibertyOS kernel.
+// Basic implementation of time-keeping for the LibertyOS kernel. The RTC keeps UTC, and local time is derived
+// from it with a configurable UTC offset.
 
 /*
 	IMPORTS
 */
 
-use crate::cmos::CMOS;
+use alloc::vec::Vec;
+use core::{fmt, sync::atomic::{AtomicI32, Ordering}};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::cmos::{CMOS, RTC};
 use crate::time;
 
 
+/*
+	CONSTANTS
+*/
+
 const D_BEFORE_MON: [u64; 13] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334, 365];
 
+// Largest UTC offset allowed (in seconds)
+const MAX_OFFSET: i32 = 24 * 3600 - 1;
+
+// Offset of local time from UTC (in seconds)
+static UTC_OFFSET: AtomicI32 = AtomicI32::new(0);
+
+
+// Alarm (UTC timestamp, in seconds, and callback)
+type Alarm = (u64, fn());
+
+
+lazy_static!
+{
+	// Pending alarms, soonest first
+	static ref ALARMS: Mutex<Vec<Alarm>> = Mutex::new(Vec::new());
+}
+
+
+// DateTime struct (a broken-down date and time, in the time zone given by its offset)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime
+{
+	pub year: u16,
+	pub month: u8,
+	pub day: u8,
+	pub hour: u8,
+	pub minute: u8,
+	pub second: u8,
+	pub nanosecond: u32,
+
+	// Offset from UTC (in seconds)
+	pub offset: i32,
+}
+
+
+// Implementation of the DateTime struct
+impl DateTime
+{
+	// From timestamp (seconds since the UNIX epoch, which are clamped to the epoch if negative)
+	pub fn from_timestamp(timestamp: f64, offset: i32) -> Self
+	{
+		let local = (timestamp + offset as f64).max(0.0);
+		let secs = local as u64;
+		let nanosecond = ((local - secs as f64) * 1e9) as u32;
+
+		let mut days = secs / 86400;
+		let mut year = 1970;
+		loop
+		{
+			let len = if leapyr(year)
+			{
+				366
+			}
+			else
+			{
+				365
+			};
+
+			if days < len
+			{
+				break;
+			}
+			days -= len;
+			year += 1;
+		}
+
+		let mut month = 1;
+		while month < 12 && d_before_mon(year, month + 1) <= days
+		{
+			month += 1;
+		}
+
+		let rem = secs % 86400;
+		DateTime
+		{
+			year: year as u16,
+			month: month as u8,
+			day: (days - d_before_mon(year, month) + 1) as u8,
+			hour: (rem / 3600) as u8,
+			minute: (rem / 60 % 60) as u8,
+			second: (rem % 60) as u8,
+			nanosecond,
+			offset,
+		}
+	}
+
+
+	// Timestamp (seconds since the UNIX epoch)
+	pub fn timestamp(&self) -> f64
+	{
+		let local = 86400 * d_before_yr(self.year as u64)
+				+ 86400 * d_before_mon(self.year as u64, self.month as u64)
+				+ 86400 * (self.day - 1) as u64
+				+ 3600 * self.hour as u64
+				+ 60 * self.minute as u64
+				+ self.second as u64;
+		(local as i64 - self.offset as i64) as f64 + self.nanosecond as f64 / 1e9
+	}
+
+
+	// Now (in local time)
+	pub fn now() -> Self
+	{
+		DateTime::from_timestamp(realtime(), utc_offset())
+	}
+
+
+	// The same instant, with another offset
+	pub fn with_offset(&self, offset: i32) -> Self
+	{
+		DateTime::from_timestamp(self.timestamp(), offset)
+	}
+
+
+	// Parse (ISO-8601)
+	//
+	// Accepts "YYYY-MM-DD", optionally followed by "THH:MM", seconds, a fraction of a second and an offset ("Z"
+	// or "+HH:MM"). Without an offset, the time is taken to be local time.
+	pub fn parse(s: &str) -> Result<Self, ()>
+	{
+		let s = s.trim();
+		if !s.is_ascii() || s.len() < 10
+		{
+			return Err(());
+		}
+
+		let (date, rest) = s.split_at(10);
+		let bytes = date.as_bytes();
+		if bytes[4] != b'-' || bytes[7] != b'-'
+		{
+			return Err(());
+		}
+
+		let year = digits(&date[0..4])?;
+		let month = digits(&date[5..7])?;
+		let day = digits(&date[8..10])?;
+		if year < 1970 || !(1..=12).contains(&month) || day == 0 || day > days_in_mon(year as u64, month as u64) as u32
+		{
+			return Err(());
+		}
+
+		let mut dt = DateTime
+		{
+			year: year as u16,
+			month: month as u8,
+			day: day as u8,
+			hour: 0,
+			minute: 0,
+			second: 0,
+			nanosecond: 0,
+			offset: utc_offset(),
+		};
+
+		let rest = match rest.as_bytes().first()
+		{
+			None => return Ok(dt),
+			Some(b'T' | b't' | b' ') => &rest[1..],
+			Some(_) => return Err(()),
+		};
+
+		let (clock, offset) = match rest.find(|c| c == 'Z' || c == 'z' || c == '+' || c == '-')
+		{
+			Some(idx) => (&rest[..idx], Some(&rest[idx..])),
+			None => (rest, None),
+		};
+
+		let (clock, fraction) = match clock.split_once('.')
+		{
+			Some((clock, fraction)) => (clock, Some(fraction)),
+			None => (clock, None),
+		};
+
+		let mut fields = clock.split(':');
+		let hour = digits(fields.next().ok_or(())?)?;
+		let minute = digits(fields.next().ok_or(())?)?;
+		let second = match fields.next()
+		{
+			Some(field) => digits(field)?,
+			None if fraction.is_none() => 0,
+			None => return Err(()),
+		};
+
+		if fields.next().is_some() || hour > 23 || minute > 59 || second > 59
+		{
+			return Err(());
+		}
+
+		if let Some(fraction) = fraction
+		{
+			if fraction.is_empty() || fraction.len() > 9
+			{
+				return Err(());
+			}
+			dt.nanosecond = digits(fraction)? * 10u32.pow(9 - fraction.len() as u32);
+		}
+
+		if let Some(offset) = offset
+		{
+			dt.offset = parse_offset(offset)?;
+		}
+
+		dt.hour = hour as u8;
+		dt.minute = minute as u8;
+		dt.second = second as u8;
+		Ok(dt)
+	}
+}
+
+
+// Implementation of the Display trait for the DateTime struct (ISO-8601)
+impl fmt::Display for DateTime
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)?;
+		write!(f, "{}", Offset(self.offset))
+	}
+}
+
+
+// Offset struct (formats a UTC offset as "Z" or "+HH:MM")
+pub struct Offset(pub i32);
+
+
+// Implementation of the Display trait for the Offset struct
+impl fmt::Display for Offset
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		if self.0 == 0
+		{
+			return write!(f, "Z");
+		}
+
+		let sign = if self.0 < 0
+		{
+			'-'
+		}
+		else
+		{
+			'+'
+		};
+		let abs = self.0.unsigned_abs();
+		write!(f, "{}{:02}:{:02}", sign, abs / 3600, abs / 60 % 60)
+	}
+}
+
+
+// Parse a UTC offset ("Z", "UTC", "+HH", "+HHMM" or "+HH:MM")
+pub fn parse_offset(s: &str) -> Result<i32, ()>
+{
+	if s.eq_ignore_ascii_case("z") || s.eq_ignore_ascii_case("utc")
+	{
+		return Ok(0);
+	}
+
+	let (sign, rest) = match s.as_bytes().first()
+	{
+		Some(b'+') => (1, &s[1..]),
+		Some(b'-') => (-1, &s[1..]),
+		_ => return Err(()),
+	};
+
+	let (hours, minutes) = match rest.len()
+	{
+		2 => (rest, "00"),
+		4 => rest.split_at(2),
+		5 if rest.as_bytes()[2] == b':' => (&rest[..2], &rest[3..]),
+		_ => return Err(()),
+	};
+
+	let hours = digits(hours)?;
+	let minutes = digits(minutes)?;
+	if hours > 23 || minutes > 59
+	{
+		return Err(());
+	}
+
+	Ok(sign * (hours * 3600 + minutes * 60) as i32)
+}
+
+
+// Parse a field of decimal digits
+fn digits(s: &str) -> Result<u32, ()>
+{
+	if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit())
+	{
+		return Err(());
+	}
+	s.parse().map_err(|_| ())
+}
+
+
+// Offset of local time from UTC (in seconds)
+pub fn utc_offset() -> i32
+{
+	UTC_OFFSET.load(Ordering::Relaxed)
+}
+
+
+// Set the offset of local time from UTC (in seconds)
+pub fn set_utc_offset(offset: i32) -> Result<(), ()>
+{
+	if offset.abs() > MAX_OFFSET
+	{
+		return Err(());
+	}
+	UTC_OFFSET.store(offset, Ordering::Relaxed);
+	Ok(())
+}
+
 
 // This is a public function that is responsible for defining "real-time". This function uses the CMOS of the
 // system to calculate the time.
@@ -29,11 +351,88 @@ pub fn realtime() -> f64
 }
 
 
+// Set real-time (writes a UTC timestamp to the RTC, which only stores whole seconds, and the years 2000 to 2099)
+pub fn set_realtime(timestamp: f64) -> Result<(), ()>
+{
+	let dt = DateTime::from_timestamp(timestamp, 0);
+	CMOS::new().set_rtc(&RTC
+	{
+		day: dt.day,
+		hour: dt.hour,
+		minute: dt.minute,
+		month: dt.month,
+		second: dt.second,
+		year: dt.year,
+	})
+}
+
+
+// Schedule an alarm
+//
+// The callback runs in interrupt context, once the given UTC timestamp (in seconds) has been reached. The RTC
+// alarm only matches the time of day, so alarms more than a day away are re-armed until their day comes.
+pub fn alarm(timestamp: u64, callback: fn()) -> Result<(), ()>
+{
+	if timestamp <= realtime() as u64
+	{
+		return Err(());
+	}
+
+	interrupts::without_interrupts(||
+	{
+		let mut alarms = ALARMS.lock();
+		let idx = alarms.partition_point(|&(ts, _)| ts <= timestamp);
+		alarms.insert(idx, (timestamp, callback));
+		program_alarm(&alarms);
+	});
+	Ok(())
+}
+
+
+// Program the RTC alarm for the soonest pending alarm
+fn program_alarm(alarms: &[Alarm])
+{
+	let mut cmos = CMOS::new();
+	match alarms.first()
+	{
+		Some(&(timestamp, _)) =>
+		{
+			let dt = DateTime::from_timestamp(timestamp as f64, 0);
+			cmos.set_alarm(dt.hour, dt.minute, dt.second);
+		}
+		None => cmos.disable_alarmintr(),
+	}
+}
+
+
+// Alarm interrupt handler (called by the RTC interrupt handler)
+pub fn alarm_intrh()
+{
+	let now = realtime() as u64;
+	let due: Vec<Alarm> = match ALARMS.try_lock()
+	{
+		Some(mut alarms) =>
+		{
+			let count = alarms.partition_point(|&(ts, _)| ts <= now);
+			let due = alarms.drain(..count).collect();
+			program_alarm(&alarms);
+			due
+		}
+		None => return,
+	};
+
+	for (_, callback) in due
+	{
+		callback();
+	}
+}
+
+
 // This is a public function that provides a method for returning the time that the system has been
-// active.
+// active (in seconds, as counted by the clock source).
 pub fn uptime() -> f64
 {
-	time::time_between_ticks() * time::tick() as f64
+	time::monotonic() as f64 / 1e9
 }
 
 
@@ -69,6 +468,13 @@ fn d_before_mon(year: u64, month: u64) -> u64
 }
 
 
+// This function provides the number of days in a month.
+fn days_in_mon(year: u64, month: u64) -> u64
+{
+	d_before_mon(year, month + 1) - d_before_mon(year, month)
+}
+
+
 // This function will determine if a given year is a leap-year.
 fn leapyr(year: u64) -> bool
 {
@@ -0,0 +1,432 @@
+// clocksource.rs
+//
+// Clock sources (free-running counters, used to keep time) and clock events (timers, used to generate the
+// periodic tick). Every driver registers what it provides, and the best-rated device of each kind is used.
+
+
+/*
+	IMPORTS
+*/
+
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::serprintln;
+
+
+/*
+	CONSTANTS
+*/
+
+// Maximum number of devices of each kind
+const MAX_DEVICES: usize = 8;
+
+// Nanoseconds per second
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+// Time over which the TSC is calibrated (in nanoseconds of the reference source)
+const TSC_CALIB_NS: u64 = 50_000_000;
+
+// Ratings (the highest-rated device of each kind is used)
+const RATING_PIT: u32 = 100;
+const RATING_TSC_UNSTABLE: u32 = 150;
+const RATING_TSC: u32 = 400;
+
+// Frequency of the TSC (in Hz, zero until it has been calibrated)
+static TSC_FREQ: AtomicU64 = AtomicU64::new(0);
+
+
+// ClockSource trait
+pub trait ClockSource: Sync
+{
+	fn name(&self) -> &'static str;
+	fn rating(&self) -> u32;
+
+	// Frequency of the counter (in Hz)
+	fn frequency(&self) -> u64;
+
+	// Mask of the bits implemented by the counter (it wraps around past this value)
+	fn mask(&self) -> u64;
+
+	fn read(&self) -> u64;
+}
+
+
+// ClockEvent trait
+pub trait ClockEvent: Sync
+{
+	fn name(&self) -> &'static str;
+	fn rating(&self) -> u32;
+
+	// Start firing periodically on the vector of IRQ 0 (the tick handler)
+	fn start(&self, period: u64) -> Result<(), ()>;
+
+	fn stop(&self);
+}
+
+
+// Clock struct (the clock source in use, and the cycles it has counted so far)
+struct Clock
+{
+	source: &'static dyn ClockSource,
+	last: u64,
+	cycles: u64,
+
+	// Nanoseconds counted by the previous sources
+	base: u64,
+}
+
+
+lazy_static!
+{
+	// These are fixed-size, as the PIT and TSC are registered before the heap is initialized
+	static ref SOURCES: Mutex<ArrayVec<&'static dyn ClockSource, MAX_DEVICES>> = Mutex::new(ArrayVec::new());
+	static ref EVENTS: Mutex<ArrayVec<&'static dyn ClockEvent, MAX_DEVICES>> = Mutex::new(ArrayVec::new());
+	static ref CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+	static ref EVENT: Mutex<Option<&'static dyn ClockEvent>> = Mutex::new(None);
+}
+
+
+// Implementation of the Clock struct
+impl Clock
+{
+	// Nanoseconds since the clock was started
+	fn ns(&mut self) -> u64
+	{
+		let mask = self.source.mask();
+		let now = self.source.read() & mask;
+
+		// A full-width counter never wraps around, so going backwards means that the read raced with the
+		// counter (e.g. the PIT reloading before its tick was counted)
+		if mask != u64::MAX || now >= self.last
+		{
+			self.cycles += now.wrapping_sub(self.last) & mask;
+			self.last = now;
+		}
+
+		self.base + ((self.cycles as u128 * NSEC_PER_SEC as u128) / self.source.frequency() as u128) as u64
+	}
+}
+
+
+// Whether or not two references point to the same device
+fn same<T: ?Sized>(a: &T, b: &T) -> bool
+{
+	core::ptr::eq(a as *const T as *const u8, b as *const T as *const u8)
+}
+
+
+// Register a clock source
+pub fn register_source(source: &'static dyn ClockSource)
+{
+	serprintln!("[INFO] CLOCKSOURCE: {} ({} HZ, RATING {})", source.name(), source.frequency(), source.rating());
+	if SOURCES.lock().try_push(source).is_err()
+	{
+		serprintln!("[ERR] CLOCKSOURCE: TOO MANY SOURCES, IGNORING {}", source.name());
+		return;
+	}
+	select_source();
+}
+
+
+// Register a clock event device
+pub fn register_event(event: &'static dyn ClockEvent)
+{
+	if EVENTS.lock().try_push(event).is_err()
+	{
+		serprintln!("[ERR] CLOCKEVENT: TOO MANY DEVICES, IGNORING {}", event.name());
+		return;
+	}
+	select_event();
+}
+
+
+// Select the best clock source (the time counted so far is carried over)
+pub fn select_source()
+{
+	let best = match SOURCES.lock().iter().max_by_key(|source| source.rating())
+	{
+		Some(&best) => best,
+		None => return,
+	};
+
+	interrupts::without_interrupts(||
+	{
+		let mut clock = CLOCK.lock();
+		let base = match clock.as_mut()
+		{
+			Some(clock) if same(clock.source, best) => return,
+			Some(clock) => clock.ns(),
+			None => 0,
+		};
+
+		*clock = Some(Clock
+		{
+			source: best,
+			last: best.read() & best.mask(),
+			cycles: 0,
+			base,
+		});
+	});
+
+	serprintln!("[INFO] CLOCKSOURCE: USING {}", best.name());
+}
+
+
+// Select the best clock event device that can be started
+pub fn select_event()
+{
+	let mut events = EVENTS.lock().clone();
+	events.sort_unstable_by_key(|event| core::cmp::Reverse(event.rating()));
+
+	let mut current = EVENT.lock();
+	for event in events
+	{
+		if let Some(old) = *current
+		{
+			if same(old, event)
+			{
+				return;
+			}
+			old.stop();
+		}
+
+		if event.start(crate::time::TICK_NS).is_ok()
+		{
+			serprintln!("[INFO] CLOCKEVENT: USING {}", event.name());
+			*current = Some(event);
+			return;
+		}
+
+		serprintln!("[ERR] CLOCKEVENT: UNABLE TO START {}", event.name());
+
+		// Fall back on the previous device, in case no other one can be started
+		if let Some(old) = *current
+		{
+			let _ = old.start(crate::time::TICK_NS);
+		}
+	}
+}
+
+
+// Name of the clock source in use
+pub fn source() -> Option<&'static str>
+{
+	CLOCK.lock().as_ref().map(|clock| clock.source.name())
+}
+
+
+// Name of the clock event device in use
+pub fn event() -> Option<&'static str>
+{
+	EVENT.lock().map(|event| event.name())
+}
+
+
+// Monotonic time (in nanoseconds)
+pub fn monotonic() -> u64
+{
+	interrupts::without_interrupts(||
+	{
+		CLOCK.lock().as_mut().map_or(0, |clock| clock.ns())
+	})
+}
+
+
+// Calibrate the TSC
+//
+// The TSC is measured against the best clock source (HPET or PIT), and then registered (replacing the
+// previous calibration, if any).
+pub fn calibrate_tsc()
+{
+	let reference = match SOURCES.lock().iter().filter(|source| source.name() != TSC.name()).max_by_key(|source| source.rating())
+	{
+		Some(&reference) => reference,
+		None => return,
+	};
+
+	let target = (reference.frequency() as u128 * TSC_CALIB_NS as u128 / NSEC_PER_SEC as u128) as u64;
+	let mask = reference.mask();
+
+	let start = reference.read() & mask;
+	let tsc_start = rdtsc();
+	let mut elapsed = 0;
+
+	while elapsed < target
+	{
+		let now = reference.read() & mask;
+
+		// See Clock::ns (a full-width counter going backwards is a read race)
+		if mask != u64::MAX || now >= start
+		{
+			elapsed = elapsed.max(now.wrapping_sub(start) & mask);
+		}
+		core::hint::spin_loop();
+	}
+
+	let tsc_end = rdtsc();
+	let freq = ((tsc_end - tsc_start) as u128 * reference.frequency() as u128 / elapsed as u128) as u64;
+
+	let first = TSC_FREQ.swap(freq, Ordering::SeqCst) == 0;
+	serprintln!("[INFO] TSC: {} HZ (CALIBRATED AGAINST {})", freq, reference.name());
+
+	if first
+	{
+		register_source(&TSC);
+	}
+	else
+	{
+		select_source();
+	}
+}
+
+
+// Frequency of the TSC (in Hz, zero until calibrated)
+pub fn tsc_freq() -> u64
+{
+	TSC_FREQ.load(Ordering::Relaxed)
+}
+
+
+// Read the TSC
+pub fn rdtsc() -> u64
+{
+	unsafe
+	{
+		core::arch::x86_64::_mm_lfence();
+		core::arch::x86_64::_rdtsc()
+	}
+}
+
+
+// Pit struct
+pub struct Pit;
+
+pub static PIT: Pit = Pit;
+
+
+// Implementation of the ClockSource trait for the Pit struct
+//
+// The counter of channel 0 is combined with the number of ticks, so this is only valid while the PIT drives
+// the tick (it is only meant to be used until better sources are found).
+impl ClockSource for Pit
+{
+	fn name(&self) -> &'static str
+	{
+		"PIT"
+	}
+
+	fn rating(&self) -> u32
+	{
+		RATING_PIT
+	}
+
+	fn frequency(&self) -> u64
+	{
+		crate::time::PITFREQ as u64
+	}
+
+	fn mask(&self) -> u64
+	{
+		u64::MAX
+	}
+
+	fn read(&self) -> u64
+	{
+		interrupts::without_interrupts(||
+		{
+			let mut cmd: Port<u8> = Port::new(0x43);
+			let mut data: Port<u8> = Port::new(0x40);
+
+			// Latch the counter of channel 0
+			let count = unsafe
+			{
+				cmd.write(0);
+				u16::from_le_bytes([data.read(), data.read()])
+			};
+
+			let div = crate::time::PITDIV as u64;
+			crate::time::tick() as u64 * div + div.saturating_sub(count as u64)
+		})
+	}
+}
+
+
+// Implementation of the ClockEvent trait for the Pit struct
+impl ClockEvent for Pit
+{
+	fn name(&self) -> &'static str
+	{
+		"PIT"
+	}
+
+	fn rating(&self) -> u32
+	{
+		RATING_PIT
+	}
+
+	fn start(&self, period: u64) -> Result<(), ()>
+	{
+		let div = (period as f64 * crate::time::PITFREQ / NSEC_PER_SEC as f64) as u64;
+		if div == 0 || div > 65535
+		{
+			return Err(());
+		}
+
+		crate::time::set_pitfreq_div(div as u16, 0);
+		crate::sys::idt::clr_irmask(0);
+		Ok(())
+	}
+
+	fn stop(&self)
+	{
+		crate::sys::idt::set_irmask(0);
+	}
+}
+
+
+// Tsc struct
+pub struct Tsc;
+
+pub static TSC: Tsc = Tsc;
+
+
+// Implementation of the ClockSource trait for the Tsc struct
+impl ClockSource for Tsc
+{
+	fn name(&self) -> &'static str
+	{
+		"TSC"
+	}
+
+	// The TSC only makes a good clock source if it runs at a constant rate in every power state
+	fn rating(&self) -> u32
+	{
+		if crate::sys::cpu::features().invariant_tsc
+		{
+			RATING_TSC
+		}
+		else
+		{
+			RATING_TSC_UNSTABLE
+		}
+	}
+
+	fn frequency(&self) -> u64
+	{
+		tsc_freq()
+	}
+
+	fn mask(&self) -> u64
+	{
+		u64::MAX
+	}
+
+	fn read(&self) -> u64
+	{
+		rdtsc()
+	}
+}
@@ -16,8 +16,11 @@ use x86_64::instructions::{interrupts, port::Port};
 enum Reg
 {
 	Second = 0x00,
+	AlarmSecond = 0x01,
 	Minute = 0x02,
+	AlarmMinute = 0x03,
 	Hour = 0x04,
+	AlarmHour = 0x05,
 	Day = 0x07,
 	Month = 0x08,
 	Year = 0x09,
@@ -27,9 +30,19 @@ enum Reg
 }
 
 
+// Register B bits
+const B_24H: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+const B_SET: u8 = 1 << 7;
+
+// PM bit of the hour register (in 12-hour mode)
+const HOUR_PM: u8 = 0x80;
+
+
 // Intr enumeration, creates interrupts for time-keeping/alarms
 #[repr(u8)]
-enum Intr
+#[derive(Clone, Copy)]
+pub enum Intr
 {
 	Periodic = 1 << 6,
 	Alarm = 1 << 5,
@@ -102,19 +115,30 @@ impl CMOS
 		}
 
 		let b = self.readreg(Reg::B);
-		if b & 0x04 == 0
+		let pm = rtc.hour & HOUR_PM != 0;
+		rtc.hour &= !HOUR_PM;
+
+		if b & B_BINARY == 0
 		{
-			rtc.day = (rtc.day & 0x0F) + ((rtc.day / 16) * 10);
-			rtc.hour = ((rtc.hour & 0x0F) + (((rtc.hour & 0x70) / 16) * 10)) | (rtc.hour & 0x80);
-			rtc.minute = (rtc.minute & 0x0F) + ((rtc.minute / 16) * 10);
-			rtc.month = (rtc.month & 0x0F) + ((rtc.month / 16) * 10);
-			rtc.second = (rtc.second & 0x0F) + ((rtc.second / 16) * 10);
-			rtc.year = (rtc.year & 0x0F) + ((rtc.year / 16) * 10);
+			rtc.day = frombcd(rtc.day);
+			rtc.hour = frombcd(rtc.hour);
+			rtc.minute = frombcd(rtc.minute);
+			rtc.month = frombcd(rtc.month);
+			rtc.second = frombcd(rtc.second);
+			rtc.year = frombcd(rtc.year as u8) as u16;
 		}
 
-		if (b & 0x02 == 0) && (rtc.hour & 0x80 == 0)
+		// In 12-hour mode, midnight is 12 AM
+		if b & B_24H == 0
 		{
-			rtc.hour = ((rtc.hour & 0x7F) + 12) % 24;
+			rtc.hour = (rtc.hour % 12) + if pm
+			{
+				12
+			}
+			else
+			{
+				0
+			};
 		}
 
 		rtc.year += 2000;
@@ -122,6 +146,110 @@ impl CMOS
 	}
 
 
+	// Set real-time (RTC)
+	//
+	// The values are written in the format that the RTC uses (BCD or binary, 12-hour or 24-hour), while updates
+	// are halted. Only the years 2000 to 2099 can be stored.
+	pub fn set_rtc(&mut self, rtc: &RTC) -> Result<(), ()>
+	{
+		if !(2000..2100).contains(&rtc.year)
+		{
+			return Err(());
+		}
+
+		interrupts::without_interrupts(||
+		{
+			let b = self.readreg(Reg::B);
+			self.writereg(Reg::B, b | B_SET);
+
+			let hour = self.tohour(rtc.hour, b);
+			let conv = |value: u8| if b & B_BINARY == 0
+			{
+				tobcd(value)
+			}
+			else
+			{
+				value
+			};
+
+			self.writereg(Reg::Second, conv(rtc.second));
+			self.writereg(Reg::Minute, conv(rtc.minute));
+			self.writereg(Reg::Hour, hour);
+			self.writereg(Reg::Day, conv(rtc.day));
+			self.writereg(Reg::Month, conv(rtc.month));
+			self.writereg(Reg::Year, conv((rtc.year - 2000) as u8));
+
+			self.writereg(Reg::B, b & !B_SET);
+		});
+
+		Ok(())
+	}
+
+
+	// Set alarm (the alarm interrupt fires every day, at the given time)
+	pub fn set_alarm(&mut self, hour: u8, minute: u8, second: u8)
+	{
+		interrupts::without_interrupts(||
+		{
+			let b = self.readreg(Reg::B);
+			let conv = |value: u8| if b & B_BINARY == 0
+			{
+				tobcd(value)
+			}
+			else
+			{
+				value
+			};
+
+			let hour = self.tohour(hour, b);
+			self.writereg(Reg::AlarmSecond, conv(second));
+			self.writereg(Reg::AlarmMinute, conv(minute));
+			self.writereg(Reg::AlarmHour, hour);
+		});
+
+		self.enable_alarmintr();
+	}
+
+
+	// Convert an hour (0 to 23) to the format of the hour registers
+	fn tohour(&self, hour: u8, b: u8) -> u8
+	{
+		let (value, pm) = if b & B_24H == 0
+		{
+			(if hour % 12 == 0
+			{
+				12
+			}
+			else
+			{
+				hour % 12
+			}, hour >= 12)
+		}
+		else
+		{
+			(hour, false)
+		};
+
+		let value = if b & B_BINARY == 0
+		{
+			tobcd(value)
+		}
+		else
+		{
+			value
+		};
+
+		if pm
+		{
+			value | HOUR_PM
+		}
+		else
+		{
+			value
+		}
+	}
+
+
 	// Unchecked real-time (RTC)
 	fn nocheck_rtc(&mut self) -> RTC
 	{
@@ -137,6 +265,17 @@ impl CMOS
 	}
 
 
+	// Disable interrupts
+	fn disable_intr(&mut self, intr: Intr)
+	{
+		interrupts::without_interrupts(||
+		{
+			let prev = self.readreg(Reg::B);
+			self.writereg(Reg::B, prev & !(intr as u8));
+		});
+	}
+
+
 	// Enable interrupts
 	fn enable_intr(&mut self, intr: Intr)
 	{
@@ -214,6 +353,17 @@ impl CMOS
 	}
 
 
+	// Write registers
+	fn writereg(&mut self, reg: Reg, data: u8)
+	{
+		unsafe
+		{
+			self.address.write(reg as u8);
+			self.data.write(data);
+		}
+	}
+
+
 	// Enable alarm interrupt
 	pub fn enable_alarmintr(&mut self)
 	{
@@ -221,6 +371,13 @@ impl CMOS
 	}
 
 
+	// Disable alarm interrupt
+	pub fn disable_alarmintr(&mut self)
+	{
+		self.disable_intr(Intr::Alarm);
+	}
+
+
 	// Enable update interrupt
 	pub fn enable_updateintr(&mut self)
 	{
@@ -231,11 +388,21 @@ impl CMOS
 	// Notify when interrupt has ended
 	pub fn notify_intrend(&mut self)
 	{
-		unsafe
-		{
-			self.address.write(Reg::C as u8);
-			self.data.read();
-		}
+		self.intr_flags();
+	}
+
+
+	// Interrupt flags (reading register C acknowledges the interrupt)
+	pub fn intr_flags(&mut self) -> u8
+	{
+		self.readreg(Reg::C)
+	}
+
+
+	// Whether or not the given interrupt is signalled by a value of register C
+	pub fn flagged(flags: u8, intr: Intr) -> bool
+	{
+		flags & intr as u8 != 0
 	}
 
 
@@ -261,3 +428,16 @@ impl CMOS
 	}
 }
 
+
+// Convert from BCD
+fn frombcd(value: u8) -> u8
+{
+	(value & 0x0F) + ((value / 16) * 10)
+}
+
+
+// Convert to BCD
+fn tobcd(value: u8) -> u8
+{
+	((value / 10) << 4) | (value % 10)
+}
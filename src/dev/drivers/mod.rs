@@ -5,3 +5,4 @@
 //pub mod mm_uart;
 pub mod pic8259;
 pub mod uart;
+pub mod virtio;
@@ -0,0 +1,580 @@
+// src/dev/drivers/virtio.rs
+//
+// Virtio devices on the PCI bus (legacy and modern transports), along with split virtqueues.
+
+/*
+	IMPORTS
+*/
+
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+use crate::{allocator::PhysicalBuffer, mem::vma::{ioremap, CacheMode}, sys::pci::{self, Bar, DevConfig}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Vendor ID of every virtio device
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+// Device status bits
+pub const STATUS_ACK: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+// Feature bit required by modern (virtio 1.0) devices
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// Descriptor flags
+pub const DESC_NEXT: u16 = 1;
+pub const DESC_WRITE: u16 = 2;
+
+// MSI-X vector register value for no interrupt
+const NO_VECTOR: u16 = 0xFFFF;
+
+// Largest queue this driver will set up (modern devices accept smaller queues)
+const MAX_QUEUE_SIZE: u16 = 256;
+
+// Virtio PCI capability types
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+
+// Transport enumeration
+#[derive(Debug, Clone, Copy)]
+pub enum Transport
+{
+	// Legacy (virtio 0.9.5) I/O port registers in BAR0
+	Legacy
+	{
+		iobase: u16,
+	},
+
+	// Modern (virtio 1.0) memory-mapped structures, located through PCI capabilities
+	Modern
+	{
+		common: u64,
+		notify: u64,
+		notify_mult: u32,
+		isr: u64,
+		device: u64,
+	},
+}
+
+
+// VirtioDev struct
+#[derive(Debug, Clone, Copy)]
+pub struct VirtioDev
+{
+	pub pci: DevConfig,
+	pub transport: Transport,
+}
+
+
+// Virtqueue struct
+//
+// A split virtqueue: a descriptor table, an available ring and a used ring, laid out in one
+// physically contiguous buffer.
+#[derive(Debug, Clone)]
+pub struct Virtqueue
+{
+	// Index of the queue on its device
+	pub idx: u16,
+
+	// Number of descriptors
+	pub size: u16,
+
+	mem: PhysicalBuffer,
+	avail_offset: usize,
+	used_offset: usize,
+
+	// Next free descriptor
+	next_desc: u16,
+
+	// Index of the next entry of the used ring to be consumed
+	last_used: u16,
+
+	// Where to write, in order to notify the device about this queue
+	notify: Notify,
+}
+
+
+// Notify enumeration
+#[derive(Debug, Clone, Copy)]
+enum Notify
+{
+	Port(u16),
+	Mmio(u64),
+}
+
+
+// Implementation of the VirtioDev struct
+impl VirtioDev
+{
+	// New
+	//
+	// Uses the modern transport when the device exposes the virtio PCI capabilities, and the
+	// legacy transport otherwise.
+	pub fn new(pci: DevConfig) -> Option<Self>
+	{
+		if pci.vid != VENDOR_ID
+		{
+			return None;
+		}
+
+		let transport = Self::modern(&pci).or_else(|| match pci.bar(0)
+		{
+			Some(Bar::Io { port, .. }) => Some(Transport::Legacy
+			{
+				iobase: port,
+			}),
+			_ => None,
+		})?;
+
+		Some(Self
+		{
+			pci,
+			transport,
+		})
+	}
+
+
+	// Look for the capabilities of the modern transport
+	//
+	// The first structure of each type is used, and they are only mapped once all of them have been found.
+	fn modern(pci: &DevConfig) -> Option<Transport>
+	{
+		let mut structs = [None; 4];
+		let mut notify_mult = 0;
+
+		for cap in pci.capabilities().filter(|cap| !cap.extended && cap.id == pci::CAP_VENDOR)
+		{
+			let ptr = cap.offset;
+			let cfgtype = pci.read_u8(ptr + 3);
+			if !(CAP_COMMON_CFG..=CAP_DEVICE_CFG).contains(&cfgtype) || structs[cfgtype as usize - 1].is_some()
+			{
+				continue;
+			}
+
+			if let Some(Bar::Mem { address, .. }) = pci.bar(pci.read_u8(ptr + 4) as usize)
+			{
+				let offset = pci.read(ptr + 8) as u64;
+				let length = pci.read(ptr + 12) as u64;
+				structs[cfgtype as usize - 1] = Some((address + offset, length));
+
+				if cfgtype == CAP_NOTIFY_CFG
+				{
+					notify_mult = pci.read(ptr + 16);
+				}
+			}
+		}
+
+		let [common, notify, isr, device] = structs;
+		let (common, notify, isr, device) = (common?, notify?, isr?, device?);
+		let map = |(address, length): (u64, u64)| ioremap(PhysAddr::new(address), length, CacheMode::Uncached).map(|address| address.as_u64());
+
+		Some(Transport::Modern
+		{
+			common: map(common)?,
+			notify: map(notify)?,
+			notify_mult,
+			isr: map(isr)?,
+			device: map(device)?,
+		})
+	}
+
+
+	// Checks whether or not the legacy transport is in use
+	pub fn legacy(&self) -> bool
+	{
+		matches!(self.transport, Transport::Legacy { .. })
+	}
+
+
+	// Status
+	pub fn status(&self) -> u8
+	{
+		match self.transport
+		{
+			Transport::Legacy { iobase } => unsafe
+			{
+				Port::<u8>::new(iobase + 0x12).read()
+			},
+
+			Transport::Modern { common, .. } => unsafe
+			{
+				core::ptr::read_volatile((common + 0x14) as *const u8)
+			},
+		}
+	}
+
+
+	// Set status
+	pub fn setstatus(&self, status: u8)
+	{
+		match self.transport
+		{
+			Transport::Legacy { iobase } => unsafe
+			{
+				Port::<u8>::new(iobase + 0x12).write(status)
+			},
+
+			Transport::Modern { common, .. } => unsafe
+			{
+				core::ptr::write_volatile((common + 0x14) as *mut u8, status)
+			},
+		}
+	}
+
+
+	// Add status bits
+	pub fn addstatus(&self, bits: u8)
+	{
+		self.setstatus(self.status() | bits);
+	}
+
+
+	// Reset
+	pub fn reset(&self)
+	{
+		self.setstatus(0);
+		while self.status() != 0
+		{
+			core::hint::spin_loop();
+		}
+	}
+
+
+	// Acknowledge interrupt (reading the ISR status register clears it)
+	pub fn isr(&self) -> u8
+	{
+		match self.transport
+		{
+			Transport::Legacy { iobase } => unsafe
+			{
+				Port::<u8>::new(iobase + 0x13).read()
+			},
+
+			Transport::Modern { isr, .. } => unsafe
+			{
+				core::ptr::read_volatile(isr as *const u8)
+			},
+		}
+	}
+
+
+	// Negotiate features
+	//
+	// Accepts the wanted features that the device offers, and returns them. Modern devices also
+	// require F_VERSION_1.
+	pub fn negotiate(&self, wanted: u64) -> Result<u64, ()>
+	{
+		match self.transport
+		{
+			Transport::Legacy { iobase } =>
+			{
+				let offered = unsafe
+				{
+					Port::<u32>::new(iobase).read()
+				} as u64;
+
+				let accepted = offered & wanted & 0xFFFF_FFFF;
+				unsafe
+				{
+					Port::<u32>::new(iobase + 0x04).write(accepted as u32);
+				}
+				Ok(accepted)
+			},
+
+			Transport::Modern { common, .. } =>
+			{
+				let mut offered = 0;
+				for select in 0..2
+				{
+					unsafe
+					{
+						core::ptr::write_volatile(common as *mut u32, select);
+						offered |= (core::ptr::read_volatile((common + 0x04) as *const u32) as u64) << (32 * select);
+					}
+				}
+
+				let accepted = offered & (wanted | F_VERSION_1);
+				if accepted & F_VERSION_1 == 0
+				{
+					return Err(());
+				}
+
+				for select in 0..2
+				{
+					unsafe
+					{
+						core::ptr::write_volatile((common + 0x08) as *mut u32, select);
+						core::ptr::write_volatile((common + 0x0C) as *mut u32, (accepted >> (32 * select)) as u32);
+					}
+				}
+
+				self.addstatus(STATUS_FEATURES_OK);
+				if self.status() & STATUS_FEATURES_OK == 0
+				{
+					return Err(());
+				}
+				Ok(accepted)
+			},
+		}
+	}
+
+
+	// Read a 32-bit value from the device-specific configuration
+	pub fn config_u32(&self, offset: u16) -> u32
+	{
+		match self.transport
+		{
+			// The device-specific configuration starts at 0x14, or after the two MSI-X vector registers (at 0x18) once
+			// MSI-X has been enabled
+			Transport::Legacy { iobase } =>
+			{
+				let base = if self.pci.msix_enabled()
+				{
+					0x18
+				}
+				else
+				{
+					0x14
+				};
+
+				unsafe
+				{
+					Port::<u32>::new(iobase + base + offset).read()
+				}
+			},
+
+			Transport::Modern { device, .. } => unsafe
+			{
+				core::ptr::read_volatile((device + offset as u64) as *const u32)
+			},
+		}
+	}
+
+
+	// Read a 64-bit value from the device-specific configuration
+	pub fn config_u64(&self, offset: u16) -> u64
+	{
+		let low = self.config_u32(offset) as u64;
+		let high = self.config_u32(offset + 4) as u64;
+		(high << 32) | low
+	}
+
+
+	// Set up a virtqueue
+	//
+	// With MSI-X, the queue signals the first entry of the table (and configuration changes signal nothing).
+	pub fn queue(&self, idx: u16) -> Result<Virtqueue, ()>
+	{
+		match self.transport
+		{
+			Transport::Legacy { iobase } =>
+			{
+				let size = unsafe
+				{
+					Port::<u16>::new(iobase + 0x0E).write(idx);
+					Port::<u16>::new(iobase + 0x0C).read()
+				};
+
+				if size == 0
+				{
+					return Err(());
+				}
+
+				// Legacy devices dictate the queue size, and expect the queue to be page-aligned
+				let queue = Virtqueue::new(idx, size, Notify::Port(iobase + 0x10));
+				let pfn = queue.mem.address() >> 12;
+				if pfn > u32::MAX as u64
+				{
+					return Err(());
+				}
+
+				unsafe
+				{
+					Port::<u32>::new(iobase + 0x08).write(pfn as u32);
+					if self.pci.msix_enabled()
+					{
+						Port::<u16>::new(iobase + 0x14).write(NO_VECTOR);
+						Port::<u16>::new(iobase + 0x16).write(0);
+					}
+				}
+				Ok(queue)
+			},
+
+			Transport::Modern { common, notify, notify_mult, .. } => unsafe
+			{
+				core::ptr::write_volatile((common + 0x16) as *mut u16, idx);
+				let max = core::ptr::read_volatile((common + 0x18) as *const u16);
+				if max == 0
+				{
+					return Err(());
+				}
+
+				let size = max.min(MAX_QUEUE_SIZE);
+				let offset = core::ptr::read_volatile((common + 0x1E) as *const u16) as u64;
+				let queue = Virtqueue::new(idx, size, Notify::Mmio(notify + offset * notify_mult as u64));
+
+				let desc = queue.mem.address();
+				core::ptr::write_volatile((common + 0x18) as *mut u16, size);
+				core::ptr::write_volatile((common + 0x20) as *mut u64, desc);
+				core::ptr::write_volatile((common + 0x28) as *mut u64, desc + queue.avail_offset as u64);
+				core::ptr::write_volatile((common + 0x30) as *mut u64, desc + queue.used_offset as u64);
+				if self.pci.msix_enabled()
+				{
+					core::ptr::write_volatile((common + 0x10) as *mut u16, NO_VECTOR);
+					core::ptr::write_volatile((common + 0x1A) as *mut u16, 0);
+				}
+				core::ptr::write_volatile((common + 0x1C) as *mut u16, 1);
+				Ok(queue)
+			},
+		}
+	}
+}
+
+
+// Implementation of the Virtqueue struct
+impl Virtqueue
+{
+	// New
+	fn new(idx: u16, size: u16, notify: Notify) -> Self
+	{
+		let n = size as usize;
+		let avail_offset = 16 * n;
+		let used_offset = align(avail_offset + 6 + 2 * n, 4096);
+		let len = used_offset + align(6 + 8 * n, 4096);
+
+		let mut mem = PhysicalBuffer::aligned(len, 4096);
+		mem.fill(0);
+
+		Self
+		{
+			idx,
+			size,
+			mem,
+			avail_offset,
+			used_offset,
+			next_desc: 0,
+			last_used: 0,
+			notify,
+		}
+	}
+
+
+	// Pointer to an offset within the queue's memory
+	fn ptr<T>(&mut self, offset: usize) -> *mut T
+	{
+		self.mem[offset..].as_mut_ptr() as *mut T
+	}
+
+
+	// Submit
+	//
+	// Places a chain of buffers (physical address, length, written by the device) on the available
+	// ring and notifies the device. Returns the ID of the head descriptor.
+	pub fn submit(&mut self, chain: &[(u64, u32, bool)]) -> u16
+	{
+		let head = self.next_desc;
+
+		for (i, &(address, len, write)) in chain.iter().enumerate()
+		{
+			let id = (head as usize + i) % self.size as usize;
+			let next = ((id + 1) % self.size as usize) as u16;
+
+			let mut flags = 0;
+			if write
+			{
+				flags |= DESC_WRITE;
+			}
+			if i < chain.len() - 1
+			{
+				flags |= DESC_NEXT;
+			}
+
+			unsafe
+			{
+				let desc = self.ptr::<u8>(16 * id);
+				core::ptr::write_volatile(desc as *mut u64, address);
+				core::ptr::write_volatile(desc.add(8) as *mut u32, len);
+				core::ptr::write_volatile(desc.add(12) as *mut u16, flags);
+				core::ptr::write_volatile(desc.add(14) as *mut u16, next);
+			}
+		}
+
+		self.next_desc = ((head as usize + chain.len()) % self.size as usize) as u16;
+
+		unsafe
+		{
+			let avail = self.avail_offset;
+			let idx = core::ptr::read_volatile(self.ptr::<u16>(avail + 2));
+			let slot = avail + 4 + 2 * (idx % self.size) as usize;
+			core::ptr::write_volatile(self.ptr::<u16>(slot), head);
+
+			// The ring entry must be visible before the index that publishes it
+			fence(Ordering::SeqCst);
+			core::ptr::write_volatile(self.ptr::<u16>(avail + 2), idx.wrapping_add(1));
+			fence(Ordering::SeqCst);
+		}
+
+		match self.notify
+		{
+			Notify::Port(port) => unsafe
+			{
+				Port::<u16>::new(port).write(self.idx)
+			},
+
+			Notify::Mmio(address) => unsafe
+			{
+				core::ptr::write_volatile(address as *mut u16, self.idx)
+			},
+		}
+
+		head
+	}
+
+
+	// Used
+	//
+	// Returns the next element of the used ring (head descriptor ID, bytes written), if the device
+	// has completed another request.
+	pub fn used(&mut self) -> Option<(u16, u32)>
+	{
+		fence(Ordering::SeqCst);
+
+		let used = self.used_offset;
+		let idx = unsafe
+		{
+			core::ptr::read_volatile(self.ptr::<u16>(used + 2))
+		};
+
+		if idx == self.last_used
+		{
+			return None;
+		}
+
+		let slot = used + 4 + 8 * (self.last_used % self.size) as usize;
+		let (id, len) = unsafe
+		{
+			(core::ptr::read_volatile(self.ptr::<u32>(slot)), core::ptr::read_volatile(self.ptr::<u32>(slot + 4)))
+		};
+
+		self.last_used = self.last_used.wrapping_add(1);
+		Some((id as u16, len))
+	}
+}
+
+
+// Align upwards to a power of two
+fn align(n: usize, align: usize) -> usize
+{
+	(n + align - 1) & !(align - 1)
+}
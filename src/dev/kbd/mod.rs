@@ -108,6 +108,7 @@ fn intrh()
 	if let Some(ref mut keyboard) = *KBD.lock()
 	{
 		let scancode = read_scancode();
+		crate::sys::rand::mix_input(scancode as u64);
 
 		if let Ok(Some(key_event)) = keyboard.addbyte(scancode)
 		{
@@ -160,6 +160,8 @@ impl Mouse
 
 	pub fn proc_packet(&mut self, packet: u8)
 	{
+		crate::sys::rand::mix_input((self.currentpacket as u64) << 8 | packet as u64);
+
 		match self.currentpacket
 		{
 			0 =>
@@ -0,0 +1,613 @@
+// src/fs/ahci.rs
+//
+// Used to work with SATA drives, through an AHCI controller.
+
+/*
+	IMPORTS
+*/
+
+use alloc::{string::String, vec::Vec};
+use bit_field::BitField;
+use core::{convert::TryInto, fmt, hint::spin_loop};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::{allocator::PhysicalBuffer, fs::ata::BLKSIZE, mem::vma::{ioremap, CacheMode}, serprintln, sys::pci::{Bar, DevConfig, Driver}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of the buffer used for transfers (16 blocks)
+pub const AHCI_BUFSIZE: usize = 16 * BLKSIZE;
+
+// Number of ports an HBA can have
+const MAX_PORTS: usize = 32;
+
+// Signature of a SATA drive
+const SIG_SATA: u32 = 0x0000_0101;
+
+// Offset of the first port's registers
+const PORT_OFFSET: u64 = 0x100;
+
+// Size of the registers of each port
+const PORT_SIZE: u64 = 0x80;
+
+// Offsets within the per-port command structures buffer
+const CMDLIST_OFFSET: usize = 0;
+const FIS_OFFSET: usize = 1024;
+const CMDTAB_OFFSET: usize = 1280;
+
+// Size of the per-port command structures
+const CMDBUF_SIZE: usize = 2048;
+
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+enum Command
+{
+	ID = 0xEC,
+	FlushExt = 0xEA,
+	ReadDMAExt = 0x25,
+	WriteDMAExt = 0x35,
+}
+
+
+// HBA register offsets
+#[repr(u64)]
+#[derive(Debug, Clone, Copy)]
+enum HbaReg
+{
+	// Host capabilities
+	CAP = 0x00,
+
+	// Global host control
+	GHC = 0x04,
+
+	// Interrupt status
+	IS = 0x08,
+
+	// Ports implemented
+	PI = 0x0C,
+
+	// Version
+	VS = 0x10,
+}
+
+
+// Port register offsets
+#[repr(u64)]
+#[derive(Debug, Clone, Copy)]
+enum PortReg
+{
+	// Command list base address
+	CLB = 0x00,
+	CLBU = 0x04,
+
+	// FIS base address
+	FB = 0x08,
+	FBU = 0x0C,
+
+	// Interrupt status
+	IS = 0x10,
+
+	// Interrupt enable
+	IE = 0x14,
+
+	// Command and status
+	CMD = 0x18,
+
+	// Task file data
+	TFD = 0x20,
+
+	// Signature
+	SIG = 0x24,
+
+	// SATA status
+	SSTS = 0x28,
+
+	// SATA error
+	SERR = 0x30,
+
+	// Command issue
+	CI = 0x38,
+}
+
+
+// Hba struct
+//
+// The memory-mapped registers of an AHCI host bus adapter.
+#[derive(Debug, Clone, Copy)]
+struct Hba
+{
+	base: u64,
+}
+
+
+// Implementation of the Hba struct
+impl Hba
+{
+	// Read
+	fn read(&self, offset: u64) -> u32
+	{
+		unsafe
+		{
+			core::ptr::read_volatile((self.base + offset) as *const u32)
+		}
+	}
+
+
+	// Write
+	fn write(&self, offset: u64, data: u32)
+	{
+		unsafe
+		{
+			core::ptr::write_volatile((self.base + offset) as *mut u32, data)
+		}
+	}
+}
+
+
+// AhciPort struct
+#[derive(Debug, Clone)]
+pub struct AhciPort
+{
+	hba: Hba,
+	id: u8,
+
+	// Command list, received FIS and command table
+	cmdbuf: PhysicalBuffer,
+
+	// Data buffer
+	buffer: PhysicalBuffer,
+}
+
+
+// Implementation of the AhciPort struct
+impl AhciPort
+{
+	// New
+	fn new(hba: Hba, id: u8) -> Self
+	{
+		// The command list must be aligned to 1 KiB (which also aligns the received FIS area and
+		// the command table), and data buffers to a word
+		let cmdbuf = PhysicalBuffer::aligned(CMDBUF_SIZE, 1024);
+		let buffer = PhysicalBuffer::aligned(AHCI_BUFSIZE, 2);
+
+		Self
+		{
+			hba,
+			id,
+			cmdbuf,
+			buffer,
+		}
+	}
+
+
+	// READ REGISTER
+	fn read_reg(&self, reg: PortReg) -> u32
+	{
+		self.hba.read(PORT_OFFSET + PORT_SIZE * self.id as u64 + reg as u64)
+	}
+
+
+	// WRITE REGISTER
+	fn write_reg(&self, reg: PortReg, data: u32)
+	{
+		self.hba.write(PORT_OFFSET + PORT_SIZE * self.id as u64 + reg as u64, data)
+	}
+
+
+	// Physical address of an offset within the command structures
+	fn cmdaddr(&self, offset: usize) -> u64
+	{
+		self.cmdbuf.address() + offset as u64
+	}
+
+
+	// Checks whether or not a SATA drive is attached to the port
+	fn present(&self) -> bool
+	{
+		let ssts = self.read_reg(PortReg::SSTS);
+
+		// Device detection (3 = present, with communication established)
+		let det = ssts.get_bits(0..4);
+
+		// Interface power management (1 = active)
+		let ipm = ssts.get_bits(8..12);
+
+		det == 3 && ipm == 1 && self.read_reg(PortReg::SIG) == SIG_SATA
+	}
+
+
+	// POLL (waits for a register to change, for up to a second)
+	fn poll(&self, reg: PortReg, mask: u32, val: bool) -> Result<(), ()>
+	{
+		let start = crate::clock::uptime();
+		while (self.read_reg(reg) & mask != 0) != val
+		{
+			if crate::clock::uptime() - start > 1.0
+			{
+				serprintln!("[INFO] AHCI PORT {} HUNG DURING POLLING OF {:?}", self.id, reg);
+				return Err(());
+			}
+			spin_loop();
+		}
+		Ok(())
+	}
+
+
+	// STOP (stops command processing and FIS reception)
+	fn stop(&self) -> Result<(), ()>
+	{
+		let mut cmd = self.read_reg(PortReg::CMD);
+		cmd.set_bit(0, false);
+		cmd.set_bit(4, false);
+		self.write_reg(PortReg::CMD, cmd);
+
+		// Wait for command list running (bit 15) and FIS receive running (bit 14) to clear
+		self.poll(PortReg::CMD, (1 << 15) | (1 << 14), false)
+	}
+
+
+	// START (starts command processing and FIS reception)
+	fn start(&self) -> Result<(), ()>
+	{
+		self.poll(PortReg::CMD, 1 << 15, false)?;
+
+		let mut cmd = self.read_reg(PortReg::CMD);
+		cmd.set_bit(4, true);
+		cmd.set_bit(0, true);
+		self.write_reg(PortReg::CMD, cmd);
+		Ok(())
+	}
+
+
+	// SETUP (points the port at its command list and received FIS area)
+	fn setup(&mut self) -> Result<(), ()>
+	{
+		self.stop()?;
+
+		self.cmdbuf.fill(0);
+
+		let clb = self.cmdaddr(CMDLIST_OFFSET);
+		let fb = self.cmdaddr(FIS_OFFSET);
+		self.write_reg(PortReg::CLB, clb as u32);
+		self.write_reg(PortReg::CLBU, (clb >> 32) as u32);
+		self.write_reg(PortReg::FB, fb as u32);
+		self.write_reg(PortReg::FBU, (fb >> 32) as u32);
+
+		// Clear any pending errors and interrupts
+		self.write_reg(PortReg::SERR, u32::MAX);
+		self.write_reg(PortReg::IS, u32::MAX);
+		self.write_reg(PortReg::IE, 0);
+
+		self.start()
+	}
+
+
+	// ISSUE (issues a command on slot 0, and waits for it to complete)
+	fn issue(&mut self, cmd: Command, lba: u64, count: u16, len: usize, write: bool) -> Result<(), ()>
+	{
+		if len > AHCI_BUFSIZE
+		{
+			return Err(());
+		}
+
+		let cmdtab = self.cmdaddr(CMDTAB_OFFSET);
+		let data = self.buffer.address();
+
+		// Command header (slot 0)
+		{
+			let header = &mut self.cmdbuf[CMDLIST_OFFSET..(CMDLIST_OFFSET + 32)];
+			header.fill(0);
+
+			// FIS length (in dwords), and the write bit
+			let mut flags: u16 = 5;
+			flags.set_bit(6, write);
+			header[0..2].copy_from_slice(&flags.to_le_bytes());
+
+			// Number of PRD entries
+			let prdtl: u16 = if len > 0 { 1 } else { 0 };
+			header[2..4].copy_from_slice(&prdtl.to_le_bytes());
+
+			header[8..12].copy_from_slice(&(cmdtab as u32).to_le_bytes());
+			header[12..16].copy_from_slice(&((cmdtab >> 32) as u32).to_le_bytes());
+		}
+
+		// Command table
+		{
+			let table = &mut self.cmdbuf[CMDTAB_OFFSET..(CMDTAB_OFFSET + 0x90)];
+			table.fill(0);
+
+			// Host-to-device register FIS
+			let lba = lba.to_le_bytes();
+			let count = count.to_le_bytes();
+			table[0] = 0x27;
+			table[1] = 0x80;
+			table[2] = cmd as u8;
+			table[4] = lba[0];
+			table[5] = lba[1];
+			table[6] = lba[2];
+			table[7] = 1 << 6;
+			table[8] = lba[3];
+			table[9] = lba[4];
+			table[10] = lba[5];
+			table[12] = count[0];
+			table[13] = count[1];
+
+			// Physical region descriptor
+			if len > 0
+			{
+				let prd = &mut table[0x80..0x90];
+				prd[0..4].copy_from_slice(&(data as u32).to_le_bytes());
+				prd[4..8].copy_from_slice(&((data >> 32) as u32).to_le_bytes());
+				prd[12..16].copy_from_slice(&((len - 1) as u32).to_le_bytes());
+			}
+		}
+
+		// Wait for the drive to stop being busy (BSY and DRQ)
+		self.poll(PortReg::TFD, 0x88, false)?;
+
+		self.write_reg(PortReg::IS, u32::MAX);
+		self.write_reg(PortReg::CI, 1);
+
+		let start = crate::clock::uptime();
+		while self.read_reg(PortReg::CI).get_bit(0)
+		{
+			// Task file error
+			if self.read_reg(PortReg::IS).get_bit(30)
+			{
+				serprintln!("[ERR] AHCI PORT {} TASK FILE ERROR (TFD: {:#X})", self.id, self.read_reg(PortReg::TFD));
+				return Err(());
+			}
+
+			if crate::clock::uptime() - start > 1.0
+			{
+				serprintln!("[INFO] AHCI PORT {} COMMAND TIMED OUT", self.id);
+				return Err(());
+			}
+			spin_loop();
+		}
+
+		if self.read_reg(PortReg::IS).get_bit(30) || self.read_reg(PortReg::TFD).get_bit(0)
+		{
+			serprintln!("[ERR] AHCI PORT {} TASK FILE ERROR (TFD: {:#X})", self.id, self.read_reg(PortReg::TFD));
+			return Err(());
+		}
+
+		Ok(())
+	}
+
+
+	// FLUSH
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		self.issue(Command::FlushExt, 0, 0, 0, false)
+	}
+
+
+	// IDENTIFY DRIVE
+	fn id_drive(&mut self) -> Result<[u8; 512], ()>
+	{
+		self.issue(Command::ID, 0, 0, 512, false)?;
+		Ok(self.buffer[0..512].try_into().unwrap())
+	}
+
+
+	// READ
+	fn read(&mut self, blk: u64, buffer: &mut [u8]) -> Result<(), ()>
+	{
+		if buffer.len() % BLKSIZE != 0
+		{
+			return Err(());
+		}
+
+		let count = (buffer.len() / BLKSIZE) as u16;
+		self.issue(Command::ReadDMAExt, blk, count, buffer.len(), false)?;
+		buffer.copy_from_slice(&self.buffer[0..buffer.len()]);
+		Ok(())
+	}
+
+
+	// WRITE
+	fn write(&mut self, blk: u64, buffer: &[u8]) -> Result<(), ()>
+	{
+		if buffer.len() % BLKSIZE != 0 || buffer.len() > AHCI_BUFSIZE
+		{
+			return Err(());
+		}
+
+		let count = (buffer.len() / BLKSIZE) as u16;
+		self.buffer[0..buffer.len()].copy_from_slice(buffer);
+		self.issue(Command::WriteDMAExt, blk, count, buffer.len(), true)
+	}
+}
+
+
+lazy_static!
+{
+	pub static ref PORTS: Mutex<Vec<AhciPort>> = Mutex::new(Vec::new());
+}
+
+
+// Initialization
+pub fn init()
+{
+	crate::sys::pci::register(Driver::class("ahci", 0x01, 0x06, probe));
+
+	for drive in ls()
+	{
+		serprintln!("[INFO] AHCI {} {}\n", drive.port, drive);
+	}
+}
+
+
+// PCI probe
+fn probe(mut dev: DevConfig) -> Result<(), ()>
+{
+	// Only a single controller is supported
+	if !PORTS.lock().is_empty()
+	{
+		return Err(());
+	}
+
+	dev.enable_busmast();
+
+	// BAR5 holds the physical address of the HBA's registers (ABAR)
+	let abar = match dev.bar(5)
+	{
+		Some(Bar::Mem { address, size, .. }) => ioremap(PhysAddr::new(address), size, CacheMode::Uncached).ok_or(())?,
+		_ => return Err(()),
+	};
+	let hba = Hba
+	{
+		base: abar.as_u64(),
+	};
+
+	// Enable AHCI mode
+	let mut ghc = hba.read(HbaReg::GHC as u64);
+	ghc.set_bit(31, true);
+	hba.write(HbaReg::GHC as u64, ghc);
+
+	let vs = hba.read(HbaReg::VS as u64);
+	let pi = hba.read(HbaReg::PI as u64);
+	serprintln!("[INFO] AHCI {}.{} CONTROLLER AT {:#X}", vs >> 16, vs & 0xFFFF, abar);
+
+	{
+		let mut ports = PORTS.lock();
+		for id in 0..MAX_PORTS
+		{
+			if !pi.get_bit(id)
+			{
+				continue;
+			}
+
+			let mut port = AhciPort::new(hba, id as u8);
+			if port.present() && port.setup().is_ok()
+			{
+				ports.push(port);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+
+// Drive struct
+#[derive(Clone)]
+pub struct Drive
+{
+	blk: u64,
+	pub port: u8,
+	model: String,
+	ser: String,
+}
+
+
+// Implementation of the Drive struct
+impl Drive
+{
+	// Block count
+	pub fn blkcount(&self) -> u64
+	{
+		self.blk
+	}
+
+
+	// Block size
+	pub const fn blksize(&self) -> u32
+	{
+		BLKSIZE as u32
+	}
+
+
+	// Open
+	pub fn open(port: u8) -> Option<Self>
+	{
+		let mut ports = PORTS.lock();
+		let p = ports.iter_mut().find(|p| p.id == port)?;
+		let buf = p.id_drive().ok()?;
+
+		// ATA strings are stored as big-endian words
+		let swap = |bytes: &[u8]| -> String
+		{
+			let swapped: Vec<u8> = bytes.chunks(2).flat_map(|c| [c[1], c[0]]).collect();
+			String::from_utf8_lossy(&swapped).trim().into()
+		};
+
+		let model = swap(&buf[54..94]);
+		let ser = swap(&buf[20..40]);
+
+		// Words 100-103 hold the number of 48-bit addressable blocks, words 60-61 the 28-bit count
+		let blk = match u64::from_le_bytes(buf[200..208].try_into().unwrap())
+		{
+			0 => u32::from_le_bytes(buf[120..124].try_into().unwrap()) as u64,
+			n => n,
+		};
+
+		Some(Self { port, model, ser, blk })
+	}
+
+
+	// Formatted size
+	fn formatted_size(&self) -> (u64, String)
+	{
+		let bytes = self.blkcount() * self.blksize() as u64;
+
+		if bytes >> 20 < 1000
+		{
+			(bytes >> 20, String::from("MB"))
+		}
+		else
+		{
+			(bytes >> 30, String::from("GB"))
+		}
+	}
+}
+
+
+// List
+pub fn ls() -> Vec<Drive>
+{
+	let ids: Vec<u8> = PORTS.lock().iter().map(|p| p.id).collect();
+	ids.into_iter().filter_map(Drive::open).collect()
+}
+
+
+// Read
+pub fn read(port: u8, blk: u64, buffer: &mut [u8]) -> Result<(), ()>
+{
+	let mut ports = PORTS.lock();
+	ports.iter_mut().find(|p| p.id == port).ok_or(())?.read(blk, buffer)
+}
+
+
+// Flush
+pub fn flush(port: u8) -> Result<(), ()>
+{
+	let mut ports = PORTS.lock();
+	ports.iter_mut().find(|p| p.id == port).ok_or(())?.flush()
+}
+
+
+// Write
+pub fn write(port: u8, blk: u64, buffer: &[u8]) -> Result<(), ()>
+{
+	let mut ports = PORTS.lock();
+	ports.iter_mut().find(|p| p.id == port).ok_or(())?.write(blk, buffer)
+}
+
+
+// Implementation of fmt::Display for the Drive struct
+impl fmt::Display for Drive
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		let (size, unit) = self.formatted_size();
+		write!(f, "{} {} ({} {})", self.model, self.ser, size, unit)
+	}
+}
@@ -8,24 +8,33 @@
 
 use alloc::{string::String, vec::Vec};
 use bit_field::BitField;
-use core::{convert::TryInto, fmt, hint::spin_loop};
+use core::{convert::TryInto, fmt, hint::spin_loop, sync::atomic::{AtomicBool, Ordering}};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
-use crate::{println, serprint, serprintln};
+use crate::{allocator::PhysicalBuffer, println, serprint, serprintln, sys::pci::{DevConfig, Driver}};
 
 
 pub const BLKSIZE: usize = 512;
 
+// Size of the buffer used for DMA transfers (8 blocks)
+pub const DMA_BUFSIZE: usize = 8 * BLKSIZE;
+
+// Set by the IRQ handler of each bus, once a DMA transfer has completed
+static DMA_DONE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
 
 #[repr(u16)]
 #[derive(Debug, Clone, Copy)]
 enum Command
 {
 	ID = 0xEC,
+	Flush = 0xE7,
 	Read = 0x20,
+	ReadDMA = 0xC8,
 	Write = 0x30,
+	WriteDMA = 0xCA,
 }
 
 
@@ -68,12 +77,32 @@ enum Status
 }
 
 
+// BusMaster struct
+//
+// The bus-master IDE registers of a bus, along with the buffers used for DMA transfers.
+#[derive(Debug, Clone)]
+struct BusMaster
+{
+	cmd_reg: Port<u8>,
+	prdt_reg: Port<u32>,
+	status_reg: Port<u8>,
+
+	// Physical region descriptor table (room for two entries)
+	prdt: PhysicalBuffer,
+
+	// Data buffer
+	buffer: PhysicalBuffer,
+}
+
+
 #[derive(Debug, Clone)]
 pub struct Bus
 {
 	id: u8,
 	irq: u8,
 
+	bm: Option<BusMaster>,
+
 	alt_status_reg: PortReadOnly<u8>,
 	cmd_reg: PortWriteOnly<u8>,
 	ctl_reg: PortWriteOnly<u8>,
@@ -100,6 +129,8 @@ impl Bus
 		{
 			id, irq,
 
+			bm: None,
+
 			alt_status_reg: PortReadOnly::new(ctlbase + 0),
 			cmd_reg: PortWriteOnly::new(iobase + 7),
 			ctl_reg: PortWriteOnly::new(ctlbase + 0),
@@ -205,7 +236,7 @@ impl Bus
 		}
 
 		self.drivesel(drive)?;
-		self.write_cmd_params(drive, 0)?;
+		self.write_cmd_params(drive, 0, 1)?;
 
 		if self.writecmd(Command::ID).is_err()
 		{
@@ -272,8 +303,67 @@ impl Bus
 	}
 
 
+	// FLUSH (writes the drive's cache out to the disk; the command transfers no data)
+	fn flush(&mut self, drive: u8) -> Result<(), ()>
+	{
+		self.drivesel(drive)?;
+
+		unsafe
+		{
+			self.cmd_reg.write(Command::Flush as u8)
+		}
+
+		// Wait for 400 nanoseconds
+		self.wait(400);
+		self.poll(Status::BUSY, false)?;
+
+		if self.error()
+		{
+			serprintln!("[ERR] ATA CACHE FLUSH FAILED ON BUS {}", self.id);
+			self.debug();
+			Err(())
+		}
+		else
+		{
+			Ok(())
+		}
+	}
+
+
 	// READ
 	fn read(&mut self, drive: u8, blk: u32, buffer: &mut [u8]) -> Result<(), ()>
+	{
+		if self.bm.is_some()
+		{
+			if self.read_dma(drive, blk, buffer).is_ok()
+			{
+				return Ok(());
+			}
+
+			serprintln!("[ERR] ATA DMA READ FAILED ON BUS {}, FALLING BACK TO PIO", self.id);
+			self.bm = None;
+		}
+
+		self.read_pio(drive, blk, buffer)
+	}
+
+
+	// READ (DMA)
+	fn read_dma(&mut self, drive: u8, blk: u32, buffer: &mut [u8]) -> Result<(), ()>
+	{
+		self.transfer_dma(drive, blk, buffer.len(), true)?;
+
+		if let Some(bm) = &self.bm
+		{
+			buffer.copy_from_slice(&bm.buffer[0..buffer.len()]);
+		}
+
+		Ok(())
+	}
+
+
+	// READ (PIO)
+	fn read_pio(&mut self, drive: u8, blk: u32, buffer: &mut [u8]) -> Result<(), ()>
 	{
 	//	serprint!("{}", buffer.len() == BLKSIZE);
 		self.setup_pio(drive, blk)?;
@@ -321,7 +411,7 @@ impl Bus
 	fn setup_pio(&mut self, drive: u8, blk: u32) -> Result<(), ()>
 	{
 		self.drivesel(drive)?;
-		self.write_cmd_params(drive, blk)?;
+		self.write_cmd_params(drive, blk, 1)?;
 		Ok(())
 	}
 
@@ -342,8 +432,179 @@ impl Bus
 	}
 
 
+	// TRANSFER (DMA)
+	//
+	// Transfers `len` bytes between the drive and the DMA buffer of the bus, then waits for the
+	// IRQ of the bus to signal completion.
+	fn transfer_dma(&mut self, drive: u8, blk: u32, len: usize, read: bool) -> Result<(), ()>
+	{
+		if len == 0 || len % BLKSIZE != 0 || len > DMA_BUFSIZE
+		{
+			return Err(());
+		}
+
+		let bm = self.bm.as_mut().ok_or(())?;
+
+		// Build the PRD table. An entry may not cross a 64 KiB boundary, so the buffer is split
+		// in two if it happens to straddle one.
+		let start = bm.buffer.address();
+		let end = start + len as u64;
+		let boundary = (start & !0xFFFF) + 0x10000;
+		let regions = if end > boundary
+		{
+			[(start, boundary - start), (boundary, end - boundary)]
+		}
+		else
+		{
+			[(start, len as u64), (0, 0)]
+		};
+
+		let count = if regions[1].1 == 0
+		{
+			1
+		}
+		else
+		{
+			2
+		};
+
+		for (i, &(address, size)) in regions[..count].iter().enumerate()
+		{
+			let mut flags: u16 = 0;
+			flags.set_bit(15, i == count - 1);
+
+			let entry = &mut bm.prdt[(i * 8)..(i * 8 + 8)];
+			entry[0..4].copy_from_slice(&(address as u32).to_le_bytes());
+			entry[4..6].copy_from_slice(&(size as u16).to_le_bytes());
+			entry[6..8].copy_from_slice(&flags.to_le_bytes());
+		}
+
+		let prdt = bm.prdt.address() as u32;
+
+		unsafe
+		{
+			// Stop any previous transfer, and load the PRD table
+			bm.cmd_reg.write(0);
+			bm.prdt_reg.write(prdt);
+
+			// Clear the interrupt and error bits (by writing ones to them)
+			let status = bm.status_reg.read();
+			bm.status_reg.write(status | 0b110);
+
+			// Set the direction (bit 3 is set when the drive writes to memory)
+			let mut cmd: u8 = 0;
+			cmd.set_bit(3, read);
+			bm.cmd_reg.write(cmd);
+		}
+
+		DMA_DONE[self.id as usize].store(false, Ordering::SeqCst);
+
+		self.drivesel(drive)?;
+		self.write_cmd_params(drive, blk, (len / BLKSIZE) as u8)?;
+
+		let cmd = if read
+		{
+			Command::ReadDMA
+		}
+		else
+		{
+			Command::WriteDMA
+		};
+
+		unsafe
+		{
+			self.cmd_reg.write(cmd as u8);
+		}
+
+		let bm = self.bm.as_mut().ok_or(())?;
+
+		unsafe
+		{
+			let cmd = bm.cmd_reg.read();
+			bm.cmd_reg.write(cmd | 1);
+		}
+
+		// Wait for the IRQ of the bus
+		let start = crate::clock::uptime();
+		let mut timeout = false;
+		while !DMA_DONE[self.id as usize].load(Ordering::SeqCst)
+		{
+			if crate::clock::uptime() - start > 1.0
+			{
+				timeout = true;
+				break;
+			}
+			crate::time::halt();
+		}
+
+		let bmstatus = unsafe
+		{
+			let cmd = bm.cmd_reg.read();
+			bm.cmd_reg.write(cmd & !1);
+
+			let status = bm.status_reg.read();
+			bm.status_reg.write(status | 0b110);
+			status
+		};
+
+		// Reading the status register acknowledges the interrupt
+		self.clrintr();
+
+		if timeout
+		{
+			serprintln!("[INFO] ATA DMA TRANSFER TIMED OUT ON BUS {}", self.id);
+			self.debug();
+			return Err(());
+		}
+
+		if bmstatus.get_bit(1) || self.error()
+		{
+			serprintln!("[ERR] ATA DMA TRANSFER ERROR");
+			self.debug();
+			return Err(());
+		}
+
+		Ok(())
+	}
+
+
 	// WRITE
 	fn write(&mut self, drive: u8, blk: u32, buffer: &[u8]) -> Result<(), ()>
+	{
+		if self.bm.is_some()
+		{
+			if self.write_dma(drive, blk, buffer).is_ok()
+			{
+				return Ok(());
+			}
+
+			serprintln!("[ERR] ATA DMA WRITE FAILED ON BUS {}, FALLING BACK TO PIO", self.id);
+			self.bm = None;
+		}
+
+		self.write_pio(drive, blk, buffer)
+	}
+
+
+	// WRITE (DMA)
+	fn write_dma(&mut self, drive: u8, blk: u32, buffer: &[u8]) -> Result<(), ()>
+	{
+		if buffer.len() > DMA_BUFSIZE
+		{
+			return Err(());
+		}
+
+		if let Some(bm) = &mut self.bm
+		{
+			bm.buffer[0..buffer.len()].copy_from_slice(buffer);
+		}
+
+		self.transfer_dma(drive, blk, buffer.len(), false)
+	}
+
+
+	// WRITE (PIO)
+	fn write_pio(&mut self, drive: u8, blk: u32, buffer: &[u8]) -> Result<(), ()>
 	{
 		serprint!("{}", buffer.len() == BLKSIZE);
 		self.setup_pio(drive, blk)?;
@@ -395,7 +656,7 @@ impl Bus
 
 
 	// WRITE COMMAND PARAMETERS
-	fn write_cmd_params(&mut self, drive: u8, blk: u32) -> Result<(), ()>
+	fn write_cmd_params(&mut self, drive: u8, blk: u32, count: u8) -> Result<(), ()>
 	{
 		let lba = true;
 		let mut bytes = blk.to_le_bytes();
@@ -407,7 +668,7 @@ impl Bus
 
 		unsafe
 		{
-			self.sectcount_reg.write(1);
+			self.sectcount_reg.write(count);
 			self.lba0_reg.write(bytes[0]);
 			self.lba1_reg.write(bytes[1]);
 			self.lba2_reg.write(bytes[2]);
@@ -434,6 +695,9 @@ pub fn init()
 		buses.push(Bus::new(1, 0x170, 0x376, 15));
 	}
 
+	// The IDE controller (if there is one) is set up by the PCI driver registry
+	crate::sys::pci::register(Driver::class("ata", 0x01, 0x01, probe));
+
 	for drive in ls()
 	{
 		serprintln!("[INFO] ATA {}:{} {}\n", drive.bus, drive.disk, drive);
@@ -441,6 +705,106 @@ pub fn init()
 }
 
 
+// PCI probe
+//
+// Switches the channels of the IDE controller that run in native mode (and can be switched) to
+// compatibility mode, so that the legacy ports and IRQs are used, then sets up DMA.
+fn probe(dev: DevConfig) -> Result<(), ()>
+{
+	let mut data = dev.read(0x08);
+	let prog_offset = 8;
+
+	if dev.prog.get_bit(0) && dev.prog.get_bit(1)
+	{
+		data.set_bit(prog_offset, false);
+		dev.write(0x08, data);
+	}
+
+	if dev.prog.get_bit(2) && dev.prog.get_bit(3)
+	{
+		data.set_bit(prog_offset + 2, false);
+		dev.write(0x08, data);
+	}
+
+	dma_init(dev);
+	Ok(())
+}
+
+
+// DMA initialization
+//
+// If the IDE controller supports bus-mastering, each bus is given a PRD table and a DMA buffer,
+// and completion is signalled through IRQ 14/15. Buses without DMA support keep using PIO.
+fn dma_init(mut dev: DevConfig)
+{
+	if !dev.prog.get_bit(7)
+	{
+		serprintln!("[INFO] ATA: THE IDE CONTROLLER DOES NOT SUPPORT BUS-MASTERING, USING PIO");
+		return;
+	}
+
+	// BAR4 holds the I/O base of the bus-master registers
+	let bar = dev.base_addresses[4];
+	if !bar.get_bit(0)
+	{
+		return;
+	}
+	let bmbase = (bar & 0xFFFC) as u16;
+
+	dev.enable_busmast();
+
+	let mut buses = BUSES.lock();
+	for bus in buses.iter_mut()
+	{
+		let base = bmbase + 8 * bus.id as u16;
+		let prdt = PhysicalBuffer::aligned(16, 4);
+		let buffer = PhysicalBuffer::aligned(DMA_BUFSIZE, 2);
+
+		// The controller only understands 32-bit physical addresses, and the PRD table may not
+		// cross a 64 KiB boundary
+		let prdt_address = prdt.address();
+		if buffer.address() + DMA_BUFSIZE as u64 > u32::MAX as u64 || (prdt_address & 0xFFFF) > 0xFFF0
+		{
+			continue;
+		}
+
+		bus.bm = Some(BusMaster
+		{
+			cmd_reg: Port::new(base),
+			status_reg: Port::new(base + 2),
+			prdt_reg: Port::new(base + 4),
+			prdt,
+			buffer,
+		});
+
+		crate::sys::idt::set_irh(bus.irq, if bus.id == 0
+		{
+			primary_intrh
+		}
+		else
+		{
+			secondary_intrh
+		});
+
+		serprintln!("[INFO] ATA: DMA ENABLED ON BUS {} (BMIDE {:#X})", bus.id, base);
+	}
+}
+
+
+// Primary bus interrupt handler (IRQ 14)
+fn primary_intrh()
+{
+	DMA_DONE[0].store(true, Ordering::SeqCst);
+}
+
+
+// Secondary bus interrupt handler (IRQ 15)
+fn secondary_intrh()
+{
+	DMA_DONE[1].store(true, Ordering::SeqCst);
+}
+
+
 // Drive struct
 #[derive(Clone)]
 pub struct Drive
@@ -535,6 +899,14 @@ pub fn read(bus: u8, drive: u8, blk: u32, buffer: &mut [u8]) -> Result<(), ()>
 }
 
 
+// Flush
+pub fn flush(bus: u8, drive: u8) -> Result<(), ()>
+{
+	let mut buses = BUSES.lock();
+	buses[bus as usize].flush(drive)
+}
+
+
 // Write
 pub fn write(bus: u8, drive: u8, blk: u32, buffer: &[u8]) -> Result<(), ()>
 {
@@ -11,7 +11,8 @@ use core::convert::TryInto;
 use crate::{serprintln, fs::{blkdev::BlkDevIO, bmapblk::BMapBlk}};
 
 
-const DATAOFFSET: usize = 4;
+// Offset of the data in a linked block (the address of the next block comes first)
+pub const DATAOFFSET: usize = 4;
 
 
 // Block struct
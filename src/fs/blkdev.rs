@@ -24,6 +24,8 @@ pub enum BlkDev
 {
 	MEM(MemBlkDev),
 	ATA(AtaBlkDev),
+	AHCI(AhciBlkDev),
+	VIRTIO(VirtioBlkDev),
 }
 
 
@@ -32,6 +34,7 @@ pub trait BlkDevIO
 {
 	fn read(&self, address: u32, buffer: &mut [u8]) -> Result<(), ()>;
 	fn write(&mut self, address: u32, buffer: &[u8]) -> Result<(), ()>;
+	fn flush(&mut self) -> Result<(), ()>;
 	fn blksize(&self) -> usize;
 	fn blkcount(&self) -> usize;
 }
@@ -47,6 +50,8 @@ impl BlkDevIO for BlkDev
 		{
 			BlkDev::MEM(dev) => dev.blkcount() as usize,
 			BlkDev::ATA(dev) => dev.blkcount() as usize,
+			BlkDev::AHCI(dev) => dev.blkcount() as usize,
+			BlkDev::VIRTIO(dev) => dev.blkcount() as usize,
 		}
 	}
 
@@ -57,6 +62,8 @@ impl BlkDevIO for BlkDev
 		{
 			BlkDev::MEM(dev) => dev.blksize() as usize,
 			BlkDev::ATA(dev) => dev.blksize() as usize,
+			BlkDev::AHCI(dev) => dev.blksize() as usize,
+			BlkDev::VIRTIO(dev) => dev.blksize() as usize,
 		}
 	}
 
@@ -67,6 +74,8 @@ impl BlkDevIO for BlkDev
 		{
 			BlkDev::MEM(dev) => dev.read(address, buffer),
 			BlkDev::ATA(dev) => dev.read(address, buffer),
+			BlkDev::AHCI(dev) => dev.read(address, buffer),
+			BlkDev::VIRTIO(dev) => dev.read(address, buffer),
 		}
 	}
 
@@ -78,6 +87,21 @@ impl BlkDevIO for BlkDev
 		{
 			BlkDev::MEM(dev) => dev.write(address, buffer),
 			BlkDev::ATA(dev) => dev.write(address, buffer),
+			BlkDev::AHCI(dev) => dev.write(address, buffer),
+			BlkDev::VIRTIO(dev) => dev.write(address, buffer),
+		}
+	}
+
+
+	// Flush
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		match self
+		{
+			BlkDev::MEM(dev) => dev.flush(),
+			BlkDev::ATA(dev) => dev.flush(),
+			BlkDev::AHCI(dev) => dev.flush(),
+			BlkDev::VIRTIO(dev) => dev.flush(),
 		}
 	}
 }
@@ -132,6 +156,12 @@ impl BlkDevIO for MemBlkDev
 		self.device[blkidx as usize][..].clone_from_slice(buffer);
 		Ok(())
 	}
+
+	// Flush
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		Ok(())
+	}
 }
 
 
@@ -185,6 +215,132 @@ impl BlkDevIO for AtaBlkDev
 	{
 		crate::fs::ata::write(self.device.bus, self.device.disk, blkaddr, buffer)
 	}
+
+	// Flush
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		crate::fs::ata::flush(self.device.bus, self.device.disk)
+	}
+}
+
+
+// AhciBlkDev struct
+#[derive(Clone)]
+pub struct AhciBlkDev
+{
+	device: crate::fs::ahci::Drive
+}
+
+
+// Implementation of the AhciBlkDev struct
+impl AhciBlkDev
+{
+	pub fn new(port: u8) -> Option<Self>
+	{
+		crate::fs::ahci::Drive::open(port).map(|device|
+		{
+			Self
+			{
+				device
+			}
+		})
+	}
+}
+
+
+// Implementation of the BlkDevIO trait for the AhciBlkDev struct
+impl BlkDevIO for AhciBlkDev
+{
+	// Block count
+	fn blkcount(&self) -> usize
+	{
+		self.device.blkcount() as usize
+	}
+
+	// Block size
+	fn blksize(&self) -> usize
+	{
+		self.device.blksize() as usize
+	}
+
+	// Read
+	fn read(&self, blkaddr: u32, buffer: &mut [u8]) -> Result<(), ()>
+	{
+		crate::fs::ahci::read(self.device.port, blkaddr as u64, buffer)
+	}
+
+	// Write
+	fn write(&mut self, blkaddr: u32, buffer: &[u8]) -> Result<(), ()>
+	{
+		crate::fs::ahci::write(self.device.port, blkaddr as u64, buffer)
+	}
+
+	// Flush
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		crate::fs::ahci::flush(self.device.port)
+	}
+}
+
+
+// VirtioBlkDev struct
+#[derive(Clone)]
+pub struct VirtioBlkDev
+{
+	idx: usize,
+	blkcount: u64,
+}
+
+
+// Implementation of the VirtioBlkDev struct
+impl VirtioBlkDev
+{
+	pub fn new(idx: usize) -> Option<Self>
+	{
+		crate::fs::virtio_blk::capacity(idx).map(|blkcount|
+		{
+			Self
+			{
+				idx,
+				blkcount
+			}
+		})
+	}
+}
+
+
+// Implementation of the BlkDevIO trait for the VirtioBlkDev struct
+impl BlkDevIO for VirtioBlkDev
+{
+	// Block count
+	fn blkcount(&self) -> usize
+	{
+		self.blkcount as usize
+	}
+
+	// Block size
+	fn blksize(&self) -> usize
+	{
+		crate::fs::ata::BLKSIZE
+	}
+
+	// Read
+	fn read(&self, blkaddr: u32, buffer: &mut [u8]) -> Result<(), ()>
+	{
+		crate::fs::virtio_blk::read(self.idx, blkaddr as u64, buffer)
+	}
+
+	// Write
+	fn write(&mut self, blkaddr: u32, buffer: &[u8]) -> Result<(), ()>
+	{
+		crate::fs::virtio_blk::write(self.idx, blkaddr as u64, buffer)
+	}
+
+	// Flush
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		crate::fs::virtio_blk::flush(self.idx)
+	}
 }
 
 
@@ -195,7 +351,34 @@ pub fn dismount()
 }
 
 
+// Flush (writes the caches of the mounted drive out to the disk)
+pub fn flush() -> Result<(), ()>
+{
+	match BLKDEV.lock().as_mut()
+	{
+		Some(dev) => dev.flush(),
+		None => Ok(()),
+	}
+}
+
+
+// Format AHCI (mounts the drive on the given port, and formats it)
+pub fn fmtahci(port: u8) -> Result<(), ()>
+{
+	mntahci(port);
+	if !mounted()
+	{
+		return Err(());
+	}
+
+	fmtata();
+	Ok(())
+}
+
+
 // Format ATA
+//
+// Formats the mounted drive, which may be attached to any of the disk controllers.
 pub fn fmtata()
 {
 	if let Some(sb) = SBlk::new()
@@ -227,6 +410,27 @@ pub fn fmtmem()
 }
 
 
+// Format virtio (mounts the given virtio block device, and formats it)
+pub fn fmtvirtio(idx: usize) -> Result<(), ()>
+{
+	mntvirtio(idx);
+	if !mounted()
+	{
+		return Err(());
+	}
+
+	fmtata();
+	Ok(())
+}
+
+
+// Mount AHCI
+pub fn mntahci(port: u8)
+{
+	*BLKDEV.lock() = AhciBlkDev::new(port).map(BlkDev::AHCI);
+}
+
+
 // Mount ATA
 pub fn mntata(bus: u8, disk: u8)
 {
@@ -237,8 +441,8 @@ pub fn mntata(bus: u8, disk: u8)
 // Mount memory
 pub fn mntmem()
 {
-	// Allocate half of available memory
-	let memory = crate::allocator::memsize() / 2;
+	// Allocate half of available memory (the heap grows to make room for it)
+	let memory = crate::allocator::memfree() / 2;
 	let len = memory / crate::fs::ata::BLKSIZE;
 	let device = MemBlkDev::new(len);
 
@@ -246,6 +450,13 @@ pub fn mntmem()
 }
 
 
+// Mount virtio
+pub fn mntvirtio(idx: usize)
+{
+	*BLKDEV.lock() = VirtioBlkDev::new(idx).map(BlkDev::VIRTIO);
+}
+
+
 // Whether or not drive has been mounted
 pub fn mounted() -> bool
 {
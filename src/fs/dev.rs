@@ -6,7 +6,7 @@
 	IMPORTS
 */
 
-use crate::{fs::directory::Directory, fs::blk::LinkBlk, fs::file::File, fs::FileIO, fs::fname, fs::rpath, sys::{console::Console, rand::Random}};
+use crate::{fs::directory::Directory, fs::blk::LinkBlk, fs::file::File, fs::FileIO, fs::IO, fs::fname, fs::rpath, sys::{console::Console, rand::Random}};
 
 // Device enumeration
 #[derive(Debug, Clone)]
@@ -106,4 +106,48 @@ impl FileIO for Device
 			Device::Random(io) => io.write(buffer),
 		}
 	}
+
+	// Poll
+	fn poll(&mut self, event: IO) -> bool
+	{
+		match self
+		{
+			Device::File(io) => io.poll(event),
+			Device::Console(io) => io.poll(event),
+			Device::Random(io) => io.poll(event),
+		}
+	}
+
+	// Poll a read
+	fn poll_read(&mut self, size: usize) -> bool
+	{
+		match self
+		{
+			Device::File(io) => io.poll_read(size),
+			Device::Console(io) => io.poll_read(size),
+			Device::Random(io) => io.poll_read(size),
+		}
+	}
+
+	// Read, without blocking
+	fn try_read(&mut self, buffer: &mut [u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		match self
+		{
+			Device::File(io) => io.try_read(buffer),
+			Device::Console(io) => io.try_read(buffer),
+			Device::Random(io) => io.try_read(buffer),
+		}
+	}
+
+	// Write, without blocking
+	fn try_write(&mut self, buffer: &[u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		match self
+		{
+			Device::File(io) => io.try_write(buffer),
+			Device::Console(io) => io.try_write(buffer),
+			Device::Random(io) => io.try_write(buffer),
+		}
+	}
 }
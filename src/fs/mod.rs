@@ -10,10 +10,12 @@ pub use crate::fs::bmapblk::BMAPSIZE;
 pub use crate::fs::dev::{Device, DevType};
 pub use crate::fs::directory::Directory;
 pub use crate::fs::file::{File, SeekFrom};
-pub use crate::fs::blkdev::{fmtata, fmtmem, mounted, mntata, mntmem, dismount};
+pub use crate::fs::pipe::{PipeReader, PipeWriter};
+pub use crate::fs::blkdev::{fmtahci, fmtata, fmtmem, fmtvirtio, flush, mounted, mntahci, mntata, mntmem, mntvirtio, dismount};
 pub use crate::fs::directory_entry::{DirectoryEntry, FileInfo};
 
 
+pub mod ahci;
 pub mod ata;
 pub mod blk;
 pub mod blkdev;
@@ -23,7 +25,9 @@ pub mod directory;
 pub mod directory_entry;
 pub mod directory_read;
 pub mod file;
+pub mod pipe;
 pub mod sblk;
+pub mod virtio_blk;
 
 
 pub const VERSION: u8 = 1;
@@ -44,6 +48,128 @@ pub trait FileIO
 {
 	fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()>;
 	fn write(&mut self, buffer: &[u8]) -> Result<usize, ()>;
+
+	// Poll
+	//
+	// Checks whether or not the given event can complete without blocking.
+	fn poll(&mut self, _event: IO) -> bool
+	{
+		true
+	}
+
+	// Poll a read
+	//
+	// Checks whether or not a read into a buffer of the given size can complete without blocking. Devices on which
+	// the amount of input a read waits for depends on the size of the buffer (the console) override this.
+	fn poll_read(&mut self, _size: usize) -> bool
+	{
+		self.poll(IO::Read)
+	}
+
+	// Read, without blocking
+	fn try_read(&mut self, buffer: &mut [u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		if self.poll_read(buffer.len())
+		{
+			self.read(buffer).map_err(crate::noblkio::Err::Other)
+		}
+		else
+		{
+			Err(crate::noblkio::Err::WouldBlk)
+		}
+	}
+
+	// Write, without blocking
+	fn try_write(&mut self, buffer: &[u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		if self.poll(IO::Write)
+		{
+			self.write(buffer).map_err(crate::noblkio::Err::Other)
+		}
+		else
+		{
+			Err(crate::noblkio::Err::WouldBlk)
+		}
+	}
+}
+
+
+// IO enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IO
+{
+	Read = 1,
+	Write = 2,
+}
+
+
+// Implementation of the IO enumeration
+impl IO
+{
+	// From the raw value of an event (None if it is not a valid event)
+	pub fn from(event: u8) -> Option<Self>
+	{
+		match event
+		{
+			1 => Some(IO::Read),
+			2 => Some(IO::Write),
+			_ => None,
+		}
+	}
+}
+
+
+// PollHandle struct
+//
+// An entry in the list passed to the POLL system-call.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct PollHandle
+{
+	pub handle: usize,
+
+	// Event polled for (the raw value of an IO, since the list is read from user memory)
+	pub event: u8,
+
+	// Whether or not the handle is ready (1 or 0, as a u8 rather than a bool for the same reason)
+	pub ready: u8,
+
+	// Size of the buffer that the handle will be read into (0 polls for a read of any size)
+	pub size: usize,
+}
+
+
+// Implementation of the PollHandle struct
+impl PollHandle
+{
+	// New
+	pub fn new(handle: usize, event: IO) -> Self
+	{
+		Self
+		{
+			handle,
+			event: event as u8,
+			ready: 0,
+			size: 0,
+		}
+	}
+
+	// Read of a given size
+	pub fn read(handle: usize, size: usize) -> Self
+	{
+		Self
+		{
+			size,
+			..Self::new(handle, IO::Read)
+		}
+	}
+
+	// Is ready
+	pub fn is_ready(&self) -> bool
+	{
+		self.ready != 0
+	}
 }
 
 
@@ -57,6 +183,7 @@ pub enum OpenFlag
 	CREATE = 4,
 	DIRECTORY = 8,
 	DEVICE = 16,
+	NONBLOCK = 32,
 }
 
 
@@ -67,6 +194,8 @@ pub enum Resource
 	Device(Device),
 	Directory(Directory),
 	File(File),
+	PipeReader(PipeReader),
+	PipeWriter(PipeWriter),
 }
 
 
@@ -81,6 +210,8 @@ impl FileIO for Resource
 			Resource::Directory(io) => io.read(buffer),
 			Resource::File(io) => io.read(buffer),
 			Resource::Device(io) => io.read(buffer),
+			Resource::PipeReader(io) => io.read(buffer),
+			Resource::PipeWriter(io) => io.read(buffer),
 		}
 	}
 
@@ -92,6 +223,60 @@ impl FileIO for Resource
 			Resource::Directory(io) => io.write(buffer),
 			Resource::File(io) => io.write(buffer),
 			Resource::Device(io) => io.write(buffer),
+			Resource::PipeReader(io) => io.write(buffer),
+			Resource::PipeWriter(io) => io.write(buffer),
+		}
+	}
+
+	// Poll
+	fn poll(&mut self, event: IO) -> bool
+	{
+		match self
+		{
+			Resource::Directory(io) => io.poll(event),
+			Resource::File(io) => io.poll(event),
+			Resource::Device(io) => io.poll(event),
+			Resource::PipeReader(io) => io.poll(event),
+			Resource::PipeWriter(io) => io.poll(event),
+		}
+	}
+
+	// Poll a read
+	fn poll_read(&mut self, size: usize) -> bool
+	{
+		match self
+		{
+			Resource::Directory(io) => io.poll_read(size),
+			Resource::File(io) => io.poll_read(size),
+			Resource::Device(io) => io.poll_read(size),
+			Resource::PipeReader(io) => io.poll_read(size),
+			Resource::PipeWriter(io) => io.poll_read(size),
+		}
+	}
+
+	// Read, without blocking
+	fn try_read(&mut self, buffer: &mut [u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		match self
+		{
+			Resource::Directory(io) => io.try_read(buffer),
+			Resource::File(io) => io.try_read(buffer),
+			Resource::Device(io) => io.try_read(buffer),
+			Resource::PipeReader(io) => io.try_read(buffer),
+			Resource::PipeWriter(io) => io.try_read(buffer),
+		}
+	}
+
+	// Write, without blocking
+	fn try_write(&mut self, buffer: &[u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		match self
+		{
+			Resource::Directory(io) => io.try_write(buffer),
+			Resource::File(io) => io.try_write(buffer),
+			Resource::Device(io) => io.try_write(buffer),
+			Resource::PipeReader(io) => io.try_write(buffer),
+			Resource::PipeWriter(io) => io.try_write(buffer),
 		}
 	}
 }
@@ -99,7 +284,7 @@ impl FileIO for Resource
 // Implementation of the OpenFlag enumeration
 impl OpenFlag
 {
-	fn set(&self, flags: usize) -> bool
+	pub fn set(&self, flags: usize) -> bool
 	{
 		flags & (*self as usize) != 0
 	}
@@ -0,0 +1,347 @@
+// src/fs/pipe.rs
+//
+// In-kernel pipes, used to connect the output of one process to the input of another.
+
+/*
+	IMPORTS
+*/
+
+use alloc::{collections::VecDeque, sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::fs::{FileIO, IO};
+
+
+/*
+	CONSTANTS
+*/
+
+// Capacity of a pipe (in bytes)
+pub const PIPE_CAPACITY: usize = 4096;
+
+
+// PipeBuffer struct
+#[derive(Debug)]
+struct PipeBuffer
+{
+	// Bytes that have been written, but not yet read
+	data: VecDeque<u8>,
+
+	// Number of open read-ends
+	readers: usize,
+
+	// Number of open write-ends
+	writers: usize,
+}
+
+
+// PipeReader struct
+#[derive(Debug)]
+pub struct PipeReader
+{
+	buffer: Arc<Mutex<PipeBuffer>>,
+}
+
+
+// PipeWriter struct
+#[derive(Debug)]
+pub struct PipeWriter
+{
+	buffer: Arc<Mutex<PipeBuffer>>,
+}
+
+
+// Create a new pipe, returning both ends
+pub fn new() -> (PipeReader, PipeWriter)
+{
+	let buffer = Arc::new(Mutex::new(PipeBuffer
+	{
+		data: VecDeque::with_capacity(PIPE_CAPACITY),
+		readers: 1,
+		writers: 1,
+	}));
+
+	let reader = PipeReader
+	{
+		buffer: buffer.clone(),
+	};
+
+	let writer = PipeWriter
+	{
+		buffer,
+	};
+
+	(reader, writer)
+}
+
+
+// Implementation of the PipeReader struct
+impl PipeReader
+{
+	// Available (number of bytes that can be read without blocking)
+	pub fn available(&self) -> usize
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().data.len()
+		})
+	}
+
+
+	// Checks whether or not every write-end has been closed
+	pub fn hungup(&self) -> bool
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().writers == 0
+		})
+	}
+}
+
+
+// Implementation of the PipeWriter struct
+impl PipeWriter
+{
+	// Space (number of bytes that can be written without blocking)
+	pub fn space(&self) -> usize
+	{
+		interrupts::without_interrupts(||
+		{
+			PIPE_CAPACITY - self.buffer.lock().data.len()
+		})
+	}
+
+
+	// Checks whether or not every read-end has been closed
+	pub fn broken(&self) -> bool
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().readers == 0
+		})
+	}
+}
+
+
+// Implementation of the Clone trait for the PipeReader struct
+impl Clone for PipeReader
+{
+	fn clone(&self) -> Self
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().readers += 1;
+		});
+
+		Self
+		{
+			buffer: self.buffer.clone(),
+		}
+	}
+}
+
+
+// Implementation of the Clone trait for the PipeWriter struct
+impl Clone for PipeWriter
+{
+	fn clone(&self) -> Self
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().writers += 1;
+		});
+
+		Self
+		{
+			buffer: self.buffer.clone(),
+		}
+	}
+}
+
+
+// Implementation of the Drop trait for the PipeReader struct
+impl Drop for PipeReader
+{
+	fn drop(&mut self)
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().readers -= 1;
+		});
+	}
+}
+
+
+// Implementation of the Drop trait for the PipeWriter struct
+impl Drop for PipeWriter
+{
+	fn drop(&mut self)
+	{
+		interrupts::without_interrupts(||
+		{
+			self.buffer.lock().writers -= 1;
+		});
+	}
+}
+
+
+// Implementation of the FileIO trait for the PipeReader struct
+impl FileIO for PipeReader
+{
+	// Read
+	//
+	// Blocks until at least one byte is available. Once every write-end has been closed and the
+	// pipe has been drained, this returns 0 (end-of-file).
+	fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()>
+	{
+		if buffer.is_empty()
+		{
+			return Ok(0);
+		}
+
+		loop
+		{
+			let res = interrupts::without_interrupts(||
+			{
+				let mut pipe = self.buffer.lock();
+				if !pipe.data.is_empty()
+				{
+					let n = buffer.len().min(pipe.data.len());
+					for (i, byte) in pipe.data.drain(..n).enumerate()
+					{
+						buffer[i] = byte;
+					}
+					Some(n)
+				}
+				else if pipe.writers == 0
+				{
+					Some(0)
+				}
+				else
+				{
+					None
+				}
+			});
+
+			if let Some(n) = res
+			{
+				return Ok(n);
+			}
+
+			crate::sys::proc::block();
+		}
+	}
+
+	// Write
+	fn write(&mut self, _buffer: &[u8]) -> Result<usize, ()>
+	{
+		Err(())
+	}
+
+	// Poll
+	fn poll(&mut self, event: IO) -> bool
+	{
+		match event
+		{
+			IO::Read => self.available() > 0 || self.hungup(),
+			IO::Write => false,
+		}
+	}
+
+	// Write, without blocking
+	fn try_write(&mut self, _buffer: &[u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		Err(crate::noblkio::Err::Other(()))
+	}
+}
+
+
+// Implementation of the FileIO trait for the PipeWriter struct
+impl FileIO for PipeWriter
+{
+	// Read
+	fn read(&mut self, _buffer: &mut [u8]) -> Result<usize, ()>
+	{
+		Err(())
+	}
+
+	// Write
+	//
+	// Blocks while the pipe is full. Writing to a pipe whose read-ends have all been closed is an
+	// error (broken pipe), unless part of the buffer has already been written.
+	fn write(&mut self, buffer: &[u8]) -> Result<usize, ()>
+	{
+		let mut written = 0;
+
+		while written < buffer.len()
+		{
+			let res = interrupts::without_interrupts(||
+			{
+				let mut pipe = self.buffer.lock();
+				if pipe.readers == 0
+				{
+					return None;
+				}
+
+				let n = (PIPE_CAPACITY - pipe.data.len()).min(buffer.len() - written);
+				pipe.data.extend(&buffer[written..written + n]);
+				Some(n)
+			});
+
+			match res
+			{
+				Some(n) => written += n,
+				None if written > 0 => break,
+				None => return Err(()),
+			}
+
+			if written < buffer.len()
+			{
+				crate::sys::proc::block();
+			}
+		}
+
+		Ok(written)
+	}
+
+	// Poll
+	fn poll(&mut self, event: IO) -> bool
+	{
+		match event
+		{
+			IO::Read => false,
+			IO::Write => self.space() > 0 || self.broken(),
+		}
+	}
+
+	// Read, without blocking
+	fn try_read(&mut self, _buffer: &mut [u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		Err(crate::noblkio::Err::Other(()))
+	}
+
+	// Write, without blocking
+	//
+	// Writes as much of the buffer as currently fits in the pipe.
+	fn try_write(&mut self, buffer: &[u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		interrupts::without_interrupts(||
+		{
+			let mut pipe = self.buffer.lock();
+			if pipe.readers == 0
+			{
+				return Err(crate::noblkio::Err::Other(()));
+			}
+
+			let n = (PIPE_CAPACITY - pipe.data.len()).min(buffer.len());
+			if n == 0 && !buffer.is_empty()
+			{
+				return Err(crate::noblkio::Err::WouldBlk);
+			}
+
+			pipe.data.extend(&buffer[..n]);
+			Ok(n)
+		})
+	}
+}
@@ -0,0 +1,366 @@
+// src/fs/virtio_blk.rs
+//
+// Used to work with virtio block devices (as attached by QEMU with "-drive if=virtio").
+
+/*
+	IMPORTS
+*/
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::{allocator::PhysicalBuffer, dev::drivers::virtio::{self, VirtioDev, Virtqueue}, fs::ata::BLKSIZE, serprintln, sys::pci::{DevConfig, Driver}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of the buffer used for transfers (16 blocks)
+pub const VIRTIO_BUFSIZE: usize = 16 * BLKSIZE;
+
+// Device IDs of block devices (transitional and modern)
+const DEVICE_IDS: [u16; 2] = [0x1001, 0x1042];
+
+// Request types
+const REQ_IN: u32 = 0;
+const REQ_OUT: u32 = 1;
+const REQ_FLUSH: u32 = 4;
+
+// Status written by the device, once a request has completed
+const STATUS_OK: u8 = 0;
+
+// Feature bits
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// Set by the IRQ handler, once the device has signalled an interrupt
+static IRQ_FIRED: AtomicBool = AtomicBool::new(false);
+
+
+// VirtioBlk struct
+#[derive(Debug, Clone)]
+pub struct VirtioBlk
+{
+	dev: VirtioDev,
+	queue: Virtqueue,
+
+	// Number of 512-byte sectors
+	capacity: u64,
+
+	// Whether or not the device is read-only
+	ro: bool,
+
+	// Whether or not the device has a write cache that can be flushed
+	flush: bool,
+
+	// Request header (16 bytes) followed by the status byte
+	req: PhysicalBuffer,
+
+	// Data buffer
+	buffer: PhysicalBuffer,
+}
+
+
+// Implementation of the VirtioBlk struct
+impl VirtioBlk
+{
+	// New
+	//
+	// Follows the initialization sequence of the virtio specification: reset, acknowledge, pick
+	// features, set up the request queue, then mark the driver as ready.
+	fn new(dev: VirtioDev) -> Result<Self, ()>
+	{
+		dev.reset();
+		dev.addstatus(virtio::STATUS_ACK);
+		dev.addstatus(virtio::STATUS_DRIVER);
+
+		let features = match dev.negotiate(F_RO | F_FLUSH)
+		{
+			Ok(features) => features,
+			Err(()) =>
+			{
+				dev.addstatus(virtio::STATUS_FAILED);
+				return Err(());
+			}
+		};
+
+		let queue = match dev.queue(0)
+		{
+			Ok(queue) => queue,
+			Err(()) =>
+			{
+				dev.addstatus(virtio::STATUS_FAILED);
+				return Err(());
+			}
+		};
+
+		let capacity = dev.config_u64(0);
+		dev.addstatus(virtio::STATUS_DRIVER_OK);
+
+		Ok(Self
+		{
+			dev,
+			queue,
+			capacity,
+			ro: features & F_RO != 0,
+			flush: features & F_FLUSH != 0,
+			req: PhysicalBuffer::aligned(32, 16),
+			buffer: PhysicalBuffer::aligned(VIRTIO_BUFSIZE, BLKSIZE),
+		})
+	}
+
+
+	// REQUEST (sends a request on the queue, and waits for the device to complete it)
+	fn request(&mut self, reqtype: u32, sector: u64, len: usize) -> Result<(), ()>
+	{
+		// Only flush requests come without data
+		if (len == 0) != (reqtype == REQ_FLUSH) || len % BLKSIZE != 0 || len > VIRTIO_BUFSIZE
+		{
+			return Err(());
+		}
+
+		if sector + (len / BLKSIZE) as u64 > self.capacity
+		{
+			return Err(());
+		}
+
+		self.req[0..4].copy_from_slice(&reqtype.to_le_bytes());
+		self.req[4..8].fill(0);
+		self.req[8..16].copy_from_slice(&sector.to_le_bytes());
+		self.req[16] = 0xFF;
+
+		let req = self.req.address();
+		let data = self.buffer.address();
+
+		IRQ_FIRED.store(false, Ordering::SeqCst);
+		let head = if len == 0
+		{
+			self.queue.submit(&[
+				(req, 16, false),
+				(req + 16, 1, true),
+			])
+		}
+		else
+		{
+			self.queue.submit(&[
+				(req, 16, false),
+				(data, len as u32, reqtype == REQ_IN),
+				(req + 16, 1, true),
+			])
+		};
+
+		// Sleep until the device interrupts (the timer interrupt also wakes us up, in case the
+		// IRQ line was not routed)
+		let start = crate::clock::uptime();
+		loop
+		{
+			// Completions of any other chain are stale, and dropped
+			match self.queue.used()
+			{
+				Some((id, _)) if id == head => break,
+				Some(_) => continue,
+				None => {},
+			}
+
+			if crate::clock::uptime() - start > 1.0
+			{
+				serprintln!("[INFO] VIRTIO-BLK REQUEST TIMED OUT");
+				self.recover();
+				return Err(());
+			}
+
+			if !IRQ_FIRED.swap(false, Ordering::SeqCst)
+			{
+				crate::time::halt();
+			}
+		}
+
+		if self.req[16] == STATUS_OK
+		{
+			Ok(())
+		}
+		else
+		{
+			serprintln!("[ERR] VIRTIO-BLK REQUEST FAILED (STATUS: {})", self.req[16]);
+			Err(())
+		}
+	}
+
+
+	// Recover from a request that timed out
+	//
+	// The request is still in flight, so the device is reset (after which it no longer uses the queue or the
+	// buffers) and set up again with a new queue.
+	fn recover(&mut self)
+	{
+		match Self::new(self.dev)
+		{
+			Ok(blk) => *self = blk,
+			Err(()) =>
+			{
+				serprintln!("[ERR] UNABLE TO RESET VIRTIO-BLK DEVICE {:02X}:{:02X}", self.dev.pci.bus, self.dev.pci.dev);
+			},
+		}
+	}
+
+
+	// FLUSH
+	fn flush(&mut self) -> Result<(), ()>
+	{
+		if !self.flush
+		{
+			return Ok(());
+		}
+
+		self.request(REQ_FLUSH, 0, 0)
+	}
+
+
+	// READ
+	fn read(&mut self, blk: u64, buffer: &mut [u8]) -> Result<(), ()>
+	{
+		self.request(REQ_IN, blk, buffer.len())?;
+		buffer.copy_from_slice(&self.buffer[0..buffer.len()]);
+		Ok(())
+	}
+
+
+	// WRITE
+	fn write(&mut self, blk: u64, buffer: &[u8]) -> Result<(), ()>
+	{
+		if self.ro || buffer.len() > VIRTIO_BUFSIZE
+		{
+			return Err(());
+		}
+
+		self.buffer[0..buffer.len()].copy_from_slice(buffer);
+		self.request(REQ_OUT, blk, buffer.len())
+	}
+}
+
+
+lazy_static!
+{
+	pub static ref DEVICES: Mutex<Vec<VirtioBlk>> = Mutex::new(Vec::new());
+
+	// Transports of every device, used by the IRQ handler (DEVICES stays locked during requests)
+	static ref TRANSPORTS: Mutex<Vec<VirtioDev>> = Mutex::new(Vec::new());
+}
+
+
+// Initialization
+pub fn init()
+{
+	for did in DEVICE_IDS
+	{
+		crate::sys::pci::register(Driver::id("virtio-blk", virtio::VENDOR_ID, did, probe));
+	}
+}
+
+
+// PCI probe
+fn probe(mut pci: DevConfig) -> Result<(), ()>
+{
+	pci.enable_busmast();
+
+	let dev = VirtioDev::new(pci).ok_or(())?;
+
+	// Interrupts are message-signalled when possible, and otherwise come through the interrupt pin, as routed by
+	// the _PRT. They are set up before the device, as the legacy registers move once MSI-X has been enabled.
+	let vector = pci.enable_msintr(intrh).or_else(|| pci.enable_intx(intrh));
+	let blk = match VirtioBlk::new(dev)
+	{
+		Ok(blk) => blk,
+		Err(()) =>
+		{
+			if let Some(vector) = vector
+			{
+				crate::sys::idt::free_vector(vector);
+			}
+			serprintln!("[ERR] UNABLE TO INITIALIZE VIRTIO-BLK DEVICE {:02X}:{:02X}", pci.bus, pci.dev);
+			return Err(());
+		},
+	};
+
+	let mut devices = DEVICES.lock();
+	serprintln!("[INFO] VIRTIO-BLK {} ({} MB, {}){}", devices.len(), (blk.capacity * BLKSIZE as u64) >> 20, if dev.legacy()
+	{
+		"LEGACY"
+	}
+	else
+	{
+		"MODERN"
+	},
+	if blk.ro
+	{
+		" READ-ONLY"
+	}
+	else
+	{
+		""
+	});
+
+	interrupts::without_interrupts(||
+	{
+		TRANSPORTS.lock().push(dev);
+	});
+
+	// Without the APIC, the pin goes through the PIC, on the line that the firmware has assigned to it
+	if vector.is_none() && !crate::sys::apic::enabled() && pci.intr_ln < 16
+	{
+		crate::sys::idt::set_irh(pci.intr_ln, intrh);
+	}
+	devices.push(blk);
+
+	Ok(())
+}
+
+
+// Interrupt handler
+fn intrh()
+{
+	// The IRQ line may be shared, so the ISR status of every device is read (which acknowledges it)
+	if let Some(transports) = TRANSPORTS.try_lock()
+	{
+		for dev in transports.iter()
+		{
+			dev.isr();
+		}
+	}
+	IRQ_FIRED.store(true, Ordering::SeqCst);
+}
+
+
+// Capacity (in blocks)
+pub fn capacity(idx: usize) -> Option<u64>
+{
+	DEVICES.lock().get(idx).map(|blk| blk.capacity)
+}
+
+
+// Read
+pub fn read(idx: usize, blk: u64, buffer: &mut [u8]) -> Result<(), ()>
+{
+	let mut devices = DEVICES.lock();
+	devices.get_mut(idx).ok_or(())?.read(blk, buffer)
+}
+
+
+// Flush
+pub fn flush(idx: usize) -> Result<(), ()>
+{
+	let mut devices = DEVICES.lock();
+	devices.get_mut(idx).ok_or(())?.flush()
+}
+
+
+// Write
+pub fn write(idx: usize, blk: u64, buffer: &[u8]) -> Result<(), ()>
+{
+	let mut devices = DEVICES.lock();
+	devices.get_mut(idx).ok_or(())?.write(blk, buffer)
+}
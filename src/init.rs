@@ -59,6 +59,10 @@ pub fn start(bootinfo: &'static BootInfo)
 	println!("[INFO] INITIALIZING MEMORY MANAGEMENT");
 	crate::mem::init(bootinfo);
 
+	// Replace the static boot stacks of the interrupt stack table with guarded ones
+	println!("[INFO] INITIALIZING KERNEL STACKS");
+	crate::sys::gdt::init_stacks();
+
 
 	// Initialize logger
 //	println!("[INFO] INITIALIZING LOGGER");
@@ -69,6 +73,26 @@ pub fn start(bootinfo: &'static BootInfo)
 	println!("[INFO] INITIALIZING CPU MODULE");
 	crate::sys::cpu::init();
 
+	// Seed the random number generator (RDRAND and RDSEED are known once the CPU has been probed)
+	println!("[INFO] INITIALIZING RANDOM NUMBER GENERATION");
+	crate::sys::rand::init();
+
+	// Initialize ACPI (the tables are used by the APIC, SMP and PCI modules)
+	println!("[INFO] INITIALIZING ACPI");
+	crate::sys::acpi::init();
+
+	// Initialize the HPET (and recalibrate the TSC against it)
+	println!("[INFO] INITIALIZING HPET");
+	crate::sys::hpet::init();
+
+	// Initialize the APIC (replaces the PIC, once the ACPI tables can be read)
+	println!("[INFO] INITIALIZING APIC");
+	crate::sys::apic::init();
+
+	// Start the application processors
+	println!("[INFO] INITIALIZING SMP");
+	crate::sys::smp::init();
+
 	// Initialize PCI support
 	println!("[INFO] INITIALIZING PCI SUPPORT");
 	crate::sys::pci::init();
@@ -81,6 +105,14 @@ pub fn start(bootinfo: &'static BootInfo)
 	println!("[INFO] INITIALIZING ATA SUPPORT");
 	crate::fs::ata::init();
 
+	// Initialize AHCI support
+	println!("[INFO] INITIALIZING AHCI SUPPORT");
+	crate::fs::ahci::init();
+
+	// Initialize virtio block device support
+	println!("[INFO] INITIALIZING VIRTIO-BLK SUPPORT");
+	crate::fs::virtio_blk::init();
+
 /*
 	// Create LibertyOS installation
 	let csicolor = crate::libcore::sys::console::Style::color("Blue");
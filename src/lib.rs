@@ -35,6 +35,7 @@ pub mod user;
 
 
 pub mod clock;
+pub mod clocksource;
 pub mod cmos;
 pub mod ctypes;
 pub mod font;
@@ -47,6 +48,7 @@ pub mod pic;
 pub mod rgx;
 pub mod ser;
 pub mod time;
+pub mod timer;
 pub mod vol;
 
 // This is set to be 2MB.
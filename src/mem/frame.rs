@@ -0,0 +1,379 @@
+// src/mem/frame.rs
+//
+// Physical memory manager. Every frame below the end of usable memory has a bit in a bitmap (set while the frame
+// is in use), which is built once from the memory map of the bootloader, and kept in usable memory itself.
+// Frames are handed out from zones, so that memory below 1 MiB and 4 GiB is kept for the devices that need it.
+
+/*
+	IMPORTS
+*/
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, instructions::interrupts, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB}};
+
+use crate::serprintln;
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of a frame
+pub const FRAME_SIZE: u64 = 4096;
+
+// Limits of the zones (in bytes)
+const LOW_LIMIT: u64 = 1 << 20;
+const DMA32_LIMIT: u64 = 1 << 32;
+
+// Ranges of memory (below 1 MiB, below 4 GiB, and above), which the zones are made of
+const RANGES: [(u64, u64); 3] = [(0, LOW_LIMIT), (LOW_LIMIT, DMA32_LIMIT), (DMA32_LIMIT, u64::MAX)];
+
+
+// Zone enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone
+{
+	// Below 1 MiB (e.g. for the SMP trampoline)
+	Low,
+
+	// Below 4 GiB (for devices that can only address 32 bits)
+	Dma32,
+
+	// Anywhere
+	Normal,
+}
+
+
+// Implementation of the Zone enumeration
+impl Zone
+{
+	// Ranges to allocate from, in order of preference (the scarcer memory is used last)
+	fn ranges(&self) -> &'static [usize]
+	{
+		match self
+		{
+			Zone::Low => &[0],
+			Zone::Dma32 => &[1, 0],
+			Zone::Normal => &[2, 1, 0],
+		}
+	}
+}
+
+
+// FrameStats struct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats
+{
+	// Usable frames
+	pub total: usize,
+
+	// Free frames
+	pub free: usize,
+
+	// Free frames below 1 MiB
+	pub free_low: usize,
+
+	// Free frames below 4 GiB
+	pub free_dma32: usize,
+}
+
+
+// Bitmap struct
+struct Bitmap
+{
+	bits: &'static mut [u64],
+
+	// Number of frames covered by the bitmap
+	frames: usize,
+
+	// Free frames, in each range
+	free: [usize; 3],
+
+	// Lowest frame that may be free, in each range
+	hint: [usize; 3],
+
+	// Usable frames
+	total: usize,
+}
+
+
+lazy_static!
+{
+	static ref FRAMES: Mutex<Option<Bitmap>> = Mutex::new(None);
+}
+
+
+// Implementation of the Bitmap struct
+impl Bitmap
+{
+	// Range of a frame
+	fn range(idx: usize) -> usize
+	{
+		let address = idx as u64 * FRAME_SIZE;
+		RANGES.iter().position(|&(start, end)| address >= start && address < end).unwrap_or(RANGES.len() - 1)
+	}
+
+
+	// Bounds of a range (in frames)
+	fn bounds(&self, range: usize) -> (usize, usize)
+	{
+		let (start, end) = RANGES[range];
+		let start = ((start / FRAME_SIZE) as usize).min(self.frames);
+		let end = (end / FRAME_SIZE).min(self.frames as u64) as usize;
+		(start, end)
+	}
+
+
+	// Whether or not a frame is free
+	fn is_free(&self, idx: usize) -> bool
+	{
+		self.bits[idx / 64] & (1 << (idx % 64)) == 0
+	}
+
+
+	// Mark a frame as used
+	fn mark_used(&mut self, idx: usize)
+	{
+		if self.is_free(idx)
+		{
+			self.bits[idx / 64] |= 1 << (idx % 64);
+			self.free[Self::range(idx)] -= 1;
+		}
+	}
+
+
+	// Mark a frame as free
+	fn mark_free(&mut self, idx: usize)
+	{
+		let range = Self::range(idx);
+		self.bits[idx / 64] &= !(1 << (idx % 64));
+		self.free[range] += 1;
+		self.hint[range] = self.hint[range].min(idx);
+	}
+
+
+	// Find a run of free frames in a range (the first frame of which is a multiple of the alignment)
+	fn find(&self, range: usize, count: usize, align: usize) -> Option<usize>
+	{
+		let (start, end) = self.bounds(range);
+		let mut idx = alignup(self.hint[range].max(start), align);
+
+		while idx + count <= end
+		{
+			// Skip whole words of used frames
+			if idx % 64 == 0 && self.bits[idx / 64] == u64::MAX
+			{
+				idx = alignup(idx + 64, align);
+				continue;
+			}
+
+			match (idx..idx + count).find(|&i| !self.is_free(i))
+			{
+				Some(used) => idx = alignup(used + 1, align),
+				None => return Some(idx),
+			}
+		}
+		None
+	}
+
+
+	// Allocate
+	fn alloc(&mut self, count: usize, align: usize, zone: Zone) -> Option<usize>
+	{
+		for &range in zone.ranges()
+		{
+			if self.free[range] < count
+			{
+				continue;
+			}
+
+			if let Some(idx) = self.find(range, count, align)
+			{
+				for i in idx..idx + count
+				{
+					self.mark_used(i);
+				}
+
+				// Everything below a single frame taken from the hint is in use
+				if count == 1 && align == 1 && idx == self.hint[range]
+				{
+					self.hint[range] = idx + 1;
+				}
+				return Some(idx);
+			}
+		}
+		None
+	}
+
+
+	// Free
+	fn free(&mut self, idx: usize, count: usize)
+	{
+		for i in idx..idx + count
+		{
+			if i >= self.frames || self.is_free(i)
+			{
+				serprintln!("[ERR] FRAME: FREEING UNUSED FRAME {:#X}", i as u64 * FRAME_SIZE);
+				continue;
+			}
+			self.mark_free(i);
+		}
+	}
+}
+
+
+// This aligns the specified index, upwards, to "align" (which does not need to be a power of two).
+fn alignup(idx: usize, align: usize) -> usize
+{
+	(idx + align - 1) / align * align
+}
+
+
+// Initialization
+pub fn init(memmap: &'static MemoryMap)
+{
+	let usable = || memmap.iter().filter(|region| region.region_type == MemoryRegionType::Usable);
+
+	let frames = usable().map(|region| region.range.end_frame_number).max().unwrap_or(0) as usize;
+	let words = (frames + 63) / 64;
+	let size = (words as u64 * 8 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+	// The bitmap goes into the first usable region above 1 MiB that it fits in
+	let range = usable().map(|region| region.range).find(|range|
+	{
+		range.start_frame_number * FRAME_SIZE >= LOW_LIMIT && range.end_frame_number - range.start_frame_number >= size
+	});
+
+	let base = match range
+	{
+		Some(range) => range.start_frame_number,
+		None =>
+		{
+			serprintln!("[ERR] FRAME: NO ROOM FOR THE BITMAP");
+			return;
+		}
+	};
+
+	let bits = unsafe
+	{
+		core::slice::from_raw_parts_mut(super::ptov(PhysAddr::new(base * FRAME_SIZE)).as_mut_ptr::<u64>(), words)
+	};
+	bits.fill(u64::MAX);
+
+	let mut bitmap = Bitmap
+	{
+		bits,
+		frames,
+		free: [0; 3],
+		hint: [0; 3],
+		total: 0,
+	};
+
+	for region in usable()
+	{
+		for idx in region.range.start_frame_number..region.range.end_frame_number
+		{
+			bitmap.mark_free(idx as usize);
+		}
+	}
+
+	// Frame 0 holds the real-mode interrupt vectors, and is never handed out
+	bitmap.mark_used(0);
+	for idx in base..base + size
+	{
+		bitmap.mark_used(idx as usize);
+	}
+
+	bitmap.total = bitmap.free.iter().sum();
+	serprintln!("[INFO] FRAME: {} FRAMES FREE ({} KB FOR THE BITMAP)", bitmap.total, (size * FRAME_SIZE) >> 10);
+
+	interrupts::without_interrupts(||
+	{
+		*FRAMES.lock() = Some(bitmap);
+	});
+}
+
+
+// Allocate a frame
+pub fn alloc(zone: Zone) -> Option<PhysFrame>
+{
+	alloc_contiguous(1, 1, zone)
+}
+
+
+// Allocate a run of physically contiguous frames (the first of which is aligned to `align` frames)
+pub fn alloc_contiguous(count: usize, align: usize, zone: Zone) -> Option<PhysFrame>
+{
+	if count == 0 || align == 0
+	{
+		return None;
+	}
+
+	interrupts::without_interrupts(||
+	{
+		let idx = FRAMES.lock().as_mut()?.alloc(count, align, zone)?;
+		Some(PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE)))
+	})
+}
+
+
+// Free a frame
+pub fn free(frame: PhysFrame)
+{
+	free_contiguous(frame, 1);
+}
+
+
+// Free a run of physically contiguous frames
+pub fn free_contiguous(frame: PhysFrame, count: usize)
+{
+	interrupts::without_interrupts(||
+	{
+		if let Some(bitmap) = FRAMES.lock().as_mut()
+		{
+			bitmap.free((frame.start_address().as_u64() / FRAME_SIZE) as usize, count);
+		}
+	});
+}
+
+
+// Statistics
+pub fn stats() -> FrameStats
+{
+	interrupts::without_interrupts(||
+	{
+		FRAMES.lock().as_ref().map_or(FrameStats::default(), |bitmap| FrameStats
+		{
+			total: bitmap.total,
+			free: bitmap.free.iter().sum(),
+			free_low: bitmap.free[0],
+			free_dma32: bitmap.free[0] + bitmap.free[1],
+		})
+	})
+}
+
+
+// GlobalFrameAllocator struct (hands out frames of the normal zone, for use with the page-table mapper)
+pub struct GlobalFrameAllocator;
+
+
+// Implementation of the FrameAllocator trait for the GlobalFrameAllocator struct
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator
+{
+	fn allocate_frame(&mut self) -> Option<PhysFrame>
+	{
+		alloc(Zone::Normal)
+	}
+}
+
+
+// Implementation of the FrameDeallocator trait for the GlobalFrameAllocator struct
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator
+{
+	unsafe fn deallocate_frame(&mut self, frame: PhysFrame)
+	{
+		free(frame);
+	}
+}
@@ -0,0 +1,79 @@
+// src/mem/kaslr.rs
+//
+// Address-space layout randomization. The heap (and the region of the slab-allocator after it), the region in which
+// processes are placed, and the region of kernel virtual memory areas start at random offsets from their usual
+// bases, and every process gets a random code address, stack top and start of its anonymous memory. The offsets
+// come from the kernel's CSPRNG.
+//
+// Building with LIBERTYOS_KASLR=off keeps every address at its base, for reproducible debugging. This is a build-time
+// switch (like LIBERTYOS_CMD), since the bootloader passes no command line: the variable has to be set when the
+// kernel is compiled, and setting it when QEMU is started has no effect.
+
+/*
+	IMPORTS
+*/
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use rand_core::RngCore;
+
+use crate::{allocator::{self, HEAP_BASE, HEAP_REGION, SLAB_REGION}, serprintln, sys::{proc, rand::KernelRng}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Largest offset of the heap and of the region of processes
+const REGION_SLIDE: u64 = 1 << 40; // 1 TB
+
+// Alignment of the offsets of regions (so that they keep to whole page tables)
+pub const REGION_ALIGN: u64 = 2 << 20; // 2 MB
+
+
+// Whether or not the layout is randomized
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+
+// Whether or not the layout is randomized
+pub fn enabled() -> bool
+{
+	ENABLED.load(Ordering::SeqCst)
+}
+
+
+// Random offset below the given range, and a multiple of the given alignment (zero without KASLR)
+pub fn slide(range: u64, align: u64) -> u64
+{
+	if !enabled() || range <= align
+	{
+		return 0;
+	}
+	KernelRng.next_u64() % (range / align) * align
+}
+
+
+// Initialization (places the heap and the region of processes, so it has to come before the heap is initialized)
+pub fn init()
+{
+	// Read at build time
+	if matches!(option_env!("LIBERTYOS_KASLR"), Some("off" | "0" | "no"))
+	{
+		ENABLED.store(false, Ordering::SeqCst);
+	}
+
+	let heap = HEAP_BASE as u64 + slide(REGION_SLIDE, REGION_ALIGN);
+	allocator::set_heap_start(heap as usize);
+
+	// Processes are placed above the region of the slab-allocator
+	let procs = heap + (HEAP_REGION + SLAB_REGION) as u64 + slide(REGION_SLIDE, REGION_ALIGN);
+	proc::CODEADDRESS.store(procs, Ordering::SeqCst);
+
+	if enabled()
+	{
+		serprintln!("[INFO] KASLR: HEAP AT {:#X}, PROCESSES FROM {:#X}", heap, procs);
+	}
+	else
+	{
+		serprintln!("[INFO] KASLR: DISABLED");
+	}
+}
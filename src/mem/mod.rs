@@ -1,4 +1,4 @@
-// src/mem.rs
+// src/mem/mod.rs
 //
 // Basic memory management functions.
 
@@ -12,15 +12,28 @@
 */
 
 use core::sync::atomic::{AtomicU64, Ordering};
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::bootinfo::MemoryMap;
 use bootloader::BootInfo;
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, page::PageRangeInclusive, PageTable, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate, page_table::PageTableEntry};
 
 use crate::serprint;
 
 
+// Physical memory manager
+pub mod frame;
+
+// Address-space layout randomization
+pub mod kaslr;
+
+// Swapping of the pages of processes to a block device
+pub mod swap;
+
+// Kernel virtual memory areas
+pub mod vma;
+
+
 // Physical memory offset
 pub static mut PMEM_OFFSET: u64 = 0;
 
@@ -66,12 +79,10 @@ pub fn init(bootinfo: &'static BootInfo)
 			mapper(VirtAddr::new(PMEM_OFFSET))
 		};
 
-		let mut framealloc = unsafe
-		{
-			BootInfoFrameAllocator::init(&bootinfo.memory_map)
-		};
+		frame::init(&bootinfo.memory_map);
+		kaslr::init();
 
-		crate::allocator::init_heap(&mut mapper, &mut framealloc)
+		crate::allocator::init_heap(&mut mapper, &mut frame::GlobalFrameAllocator)
 			.expect("[ERR] FAILED TO INITALIZE HEAP");
 	});
 }
@@ -88,32 +99,36 @@ unsafe fn active_lvl4_tab(physmem_offset: VirtAddr) -> &'static mut PageTable
 }
 
 
-// Deallocate pages
+// Deallocate pages (their frames are returned to the physical memory manager)
 pub fn p_dealloc(address: u64, size: u64)
 {
-	let mut mapper = unsafe
-	{
-			crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET))
-	};
+	crate::allocator::pdealloc(address, size);
+}
+
 
-	let pages: PageRangeInclusive<Size4KiB> =
+// Page-table entry of a page (None if the tables leading to it are missing, or if one of them maps a huge page)
+pub fn pte(address: VirtAddr) -> Option<&'static mut PageTableEntry>
+{
+	let mut table = unsafe
 	{
-		let page_start = Page::containing_address(VirtAddr::new(address));
-		let page_end = Page::containing_address(VirtAddr::new(address + size));
-		Page::range_inclusive(page_start, page_end)
+		active_lvl4_tab(VirtAddr::new(PMEM_OFFSET))
 	};
 
-	for page in pages
+	for idx in [address.p4_index(), address.p3_index(), address.p2_index()]
 	{
-		if let Ok((_frame, mapping)) = mapper.unmap(page)
+		let flags = table[idx].flags();
+		if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
 		{
-			mapping.flush();
+			return None;
 		}
-		else
+
+		table = unsafe
 		{
-			unimplemented!();
-		}
+			&mut *ptov(table[idx].addr()).as_mut_ptr::<PageTable>()
+		};
 	}
+
+	Some(&mut table[address.p1_index()])
 }
 
 
@@ -139,68 +154,33 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator
 }
 
 
-// This is a FrameAllocator that will return any usable frames from the memory map of the bootloader.
-pub struct BootInfoFrameAllocator
-{
-	memmap: &'static MemoryMap,
-	next: usize,
-}
-
-
-impl BootInfoFrameAllocator
+// Mapper
+pub unsafe fn mapper(pmem_offset: VirtAddr) -> OffsetPageTable<'static>
 {
-	// This creates a FrameAllocator from a memory map.
-	pub unsafe fn init(memmap: &'static MemoryMap) -> Self
-	{
-		BootInfoFrameAllocator
-		{
-			memmap,
-			next: 0,
-		}
-	}
-
-	// This returns an iterator over usable frames, as specified by the memory map.
-	fn usableframes(&self) -> impl Iterator<Item = PhysFrame>
-	{
-		// This figures out which regions, from the memory map, are usable.
-		let regions = self.memmap.iter();
-		let usableregions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-
-		// This maps each region to its respective address range.
-		let addr_ranges = usableregions.map(|r| r.range.start_addr()..r.range.end_addr());
-
-		// This transforms to an iterator of the frame start addresses.
-		let frameaddr = addr_ranges.flat_map(|r| r.step_by(4096));
-
-		// This creates a PhysFrame type from each start address.
-		frameaddr.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-	}
+	let lvl4_tab = active_lvl4_tab(pmem_offset);
+	OffsetPageTable::new(lvl4_tab, pmem_offset)
 }
 
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator
+// Free memory (physical, in bytes)
+pub fn memfree() -> u64
 {
-	fn allocate_frame(&mut self) -> Option<PhysFrame>
-	{
-		let frame = self.usableframes().nth(self.next);
-		self.next += 1;
-		frame
-	}
+	frame::stats().free as u64 * frame::FRAME_SIZE
 }
 
 
-// Mapper
-pub unsafe fn mapper(pmem_offset: VirtAddr) -> OffsetPageTable<'static>
+// Memory size
+pub fn memsize() -> u64
 {
-	let lvl4_tab = active_lvl4_tab(pmem_offset);
-	OffsetPageTable::new(lvl4_tab, pmem_offset)
+	MEMSIZE.load(Ordering::Relaxed)
 }
 
 
-// Memory size
-pub fn memsize() -> u64
+// Used memory (physical, in bytes, out of the usable memory)
+pub fn memused() -> u64
 {
-	MEMSIZE.load(Ordering::Relaxed)
+	let stats = frame::stats();
+	(stats.total - stats.free) as u64 * frame::FRAME_SIZE
 }
 
 
@@ -0,0 +1,501 @@
+// src/mem/swap.rs
+//
+// Swapping. When physical memory runs out, pages of processes are written to a swap area (a file of the filesystem,
+// or a range of blocks of a block device), and the entries that mapped them are left non-present, holding the slot
+// of the page instead. Pages are chosen with the clock algorithm: those that were accessed since the clock hand
+// last passed them get a second chance. Swapped pages are brought back in by the page-fault handler.
+
+/*
+	IMPORTS
+*/
+
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::cmp;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB}};
+
+use crate::{fs::{ata::BLKSIZE, blk::{DATAOFFSET, LinkBlk}, blkdev::{BlkDev, BlkDevIO, BLKDEV}, file::{File, SeekFrom}, FileIO}, mem::frame::{self, Zone, FRAME_SIZE}, serprintln, sys::{proc, smp}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of a page
+const PAGE_SIZE: usize = FRAME_SIZE as usize;
+
+// Marks a non-present page-table entry as a swap entry (its address holds the slot, and its other flags are those
+// of the page, to be restored when it is swapped back in)
+const SWAP_ENTRY: PageTableFlags = PageTableFlags::BIT_9;
+
+// Number of pages reclaimed at once, when a frame is needed
+const RECLAIM_BATCH: usize = 16;
+
+
+// SwapDev enumeration
+enum SwapDev
+{
+	// A file of the filesystem (its blocks are linked, so the address of each of them is kept)
+	File
+	{
+		blocks: Vec<u32>,
+	},
+
+	// A range of blocks of a block device, which the filesystem does not use
+	Partition
+	{
+		dev: BlkDev,
+		start: u32,
+	},
+}
+
+
+// SwapStats struct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwapStats
+{
+	// Slots of the swap area
+	pub total: usize,
+
+	// Slots in use
+	pub used: usize,
+
+	// Pages of processes that are in memory (and may be swapped out)
+	pub resident: usize,
+}
+
+
+// Swap struct
+struct Swap
+{
+	dev: SwapDev,
+
+	// Slots in use (one bit each)
+	bits: Vec<u64>,
+
+	// Number of slots
+	slots: usize,
+
+	// Slots in use
+	used: usize,
+}
+
+
+lazy_static!
+{
+	// The swap area (held across TLB shootdowns, so it is always taken with smp::lock)
+	static ref SWAP: Mutex<Option<Swap>> = Mutex::new(None);
+
+	// Pages that may be swapped out, in the order the clock hand passes them (the hand is at the front)
+	//
+	// Its capacity is reserved up front, so that tracking a page never allocates with the queue locked.
+	static ref RESIDENT: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::with_capacity(proc::MAX_PAGES));
+}
+
+
+// Implementation of the SwapDev enumeration
+impl SwapDev
+{
+	// Transfer a page to or from a slot
+	//
+	// Unless told to wait, this gives up when the filesystem's device is busy, rather than spin on it.
+	fn transfer(&mut self, slot: usize, page: &mut [u8], write: bool, wait: bool) -> Result<(), ()>
+	{
+		match self
+		{
+			SwapDev::Partition { dev, start } =>
+			{
+				let blksize = dev.blksize();
+				for (i, chunk) in page.chunks_mut(blksize).enumerate()
+				{
+					let address = *start + (slot * PAGE_SIZE / blksize + i) as u32;
+					if write
+					{
+						dev.write(address, chunk)?;
+					}
+					else
+					{
+						dev.read(address, chunk)?;
+					}
+				}
+				Ok(())
+			},
+
+			SwapDev::File { blocks } =>
+			{
+				let mut blkdev = if wait
+				{
+					Some(BLKDEV.lock())
+				}
+				else
+				{
+					BLKDEV.try_lock()
+				}.ok_or(())?;
+				let dev = blkdev.as_mut().ok_or(())?;
+
+				// The page is spread over the data of consecutive blocks
+				let datalen = BLKSIZE - DATAOFFSET;
+				let mut buffer = [0; BLKSIZE];
+				let mut pos = slot * PAGE_SIZE;
+				let mut done = 0;
+
+				while done < PAGE_SIZE
+				{
+					let address = *blocks.get(pos / datalen).ok_or(())?;
+					let offset = DATAOFFSET + pos % datalen;
+					let len = cmp::min(BLKSIZE - offset, PAGE_SIZE - done);
+
+					dev.read(address, &mut buffer)?;
+					if write
+					{
+						buffer[offset..offset + len].copy_from_slice(&page[done..done + len]);
+						dev.write(address, &buffer)?;
+					}
+					else
+					{
+						page[done..done + len].copy_from_slice(&buffer[offset..offset + len]);
+					}
+
+					done += len;
+					pos += len;
+				}
+				Ok(())
+			},
+		}
+	}
+}
+
+
+// Implementation of the Swap struct
+impl Swap
+{
+	// New
+	fn new(dev: SwapDev, slots: usize) -> Self
+	{
+		Self
+		{
+			dev,
+			bits: vec![0; (slots + 63) / 64],
+			slots,
+			used: 0,
+		}
+	}
+
+
+	// Allocate a slot
+	fn alloc(&mut self) -> Option<usize>
+	{
+		let slot = (0..self.slots).find(|&slot| self.bits[slot / 64] & (1 << (slot % 64)) == 0)?;
+		self.bits[slot / 64] |= 1 << (slot % 64);
+		self.used += 1;
+		Some(slot)
+	}
+
+
+	// Free a slot
+	fn free(&mut self, slot: usize)
+	{
+		if slot < self.slots && self.bits[slot / 64] & (1 << (slot % 64)) != 0
+		{
+			self.bits[slot / 64] &= !(1 << (slot % 64));
+			self.used -= 1;
+		}
+	}
+}
+
+
+// Contents of a frame
+fn contents(frame: PhysFrame) -> &'static mut [u8]
+{
+	unsafe
+	{
+		core::slice::from_raw_parts_mut(super::ptov(frame.start_address()).as_mut_ptr::<u8>(), PAGE_SIZE)
+	}
+}
+
+
+// Slot of a swap entry
+fn slot(flags: PageTableFlags, address: PhysAddr) -> Option<usize>
+{
+	if !flags.contains(PageTableFlags::PRESENT) && flags.contains(SWAP_ENTRY)
+	{
+		Some((address.as_u64() / FRAME_SIZE) as usize)
+	}
+	else
+	{
+		None
+	}
+}
+
+
+// Use a file of the filesystem as the swap area (it is created or extended to the given size, in bytes)
+pub fn swapon_file(path: &str, size: usize) -> Result<(), ()>
+{
+	let slots = size / PAGE_SIZE;
+	let len = slots * PAGE_SIZE;
+	if slots == 0 || smp::lock(&SWAP).is_some()
+	{
+		return Err(());
+	}
+
+	let mut file = File::open(path).or_else(|| File::create(path)).ok_or(())?;
+
+	// Every block of the file has to be allocated up front
+	if file.size() < len
+	{
+		file.seek(SeekFrom::Start(file.size() as u32))?;
+		if file.write(&vec![0; len - file.size()])? == 0
+		{
+			return Err(());
+		}
+	}
+
+	let mut blocks = Vec::new();
+	let mut blk = Some(LinkBlk::read(file.address()));
+	while let Some(link) = blk
+	{
+		blocks.push(link.address());
+		blk = link.next();
+	}
+
+	serprintln!("[INFO] SWAP: {} KB IN {}", len >> 10, path);
+	*smp::lock(&SWAP) = Some(Swap::new(SwapDev::File
+	{
+		blocks,
+	}, slots));
+	Ok(())
+}
+
+
+// Use a range of blocks of a block device as the swap area
+pub fn swapon_dev(dev: BlkDev, start: u32, count: u32) -> Result<(), ()>
+{
+	let blksize = dev.blksize();
+	if blksize == 0 || PAGE_SIZE % blksize != 0 || start as usize + count as usize > dev.blkcount()
+	{
+		return Err(());
+	}
+
+	let slots = count as usize * blksize / PAGE_SIZE;
+	let mut swap = smp::lock(&SWAP);
+	if slots == 0 || swap.is_some()
+	{
+		return Err(());
+	}
+
+	serprintln!("[INFO] SWAP: {} KB AT BLOCK {}", (slots * PAGE_SIZE) >> 10, start);
+	*swap = Some(Swap::new(SwapDev::Partition
+	{
+		dev,
+		start,
+	}, slots));
+	Ok(())
+}
+
+
+// Stop swapping (only once no page is left in the swap area)
+pub fn swapoff() -> Result<(), ()>
+{
+	let mut swap = smp::lock(&SWAP);
+	match swap.as_ref()
+	{
+		Some(area) if area.used == 0 =>
+		{
+			*swap = None;
+			Ok(())
+		},
+		_ => Err(()),
+	}
+}
+
+
+// Statistics
+pub fn stats() -> SwapStats
+{
+	let resident = interrupts::without_interrupts(|| RESIDENT.lock().len());
+	smp::lock(&SWAP).as_ref().map_or(SwapStats
+	{
+		resident,
+		..SwapStats::default()
+	}, |swap| SwapStats
+	{
+		total: swap.slots,
+		used: swap.used,
+		resident,
+	})
+}
+
+
+// Track a page of a process (which becomes a candidate for swapping out)
+//
+// Every page of a process is tracked at most once, so the queue never holds more than proc::MAX_PAGES of them; it is
+// checked all the same, so that the queue cannot grow.
+pub fn track(page: Page<Size4KiB>)
+{
+	interrupts::without_interrupts(||
+	{
+		let mut resident = RESIDENT.lock();
+		if resident.len() < proc::MAX_PAGES
+		{
+			resident.push_back(page.start_address().as_u64());
+		}
+	});
+}
+
+
+// Reclaim frames, by swapping out up to the given number of pages
+//
+// Returns the number of pages swapped out (nothing is, while another processor is using the swap area). This does
+// block I/O and TLB shootdowns, so it is only called for the frames of processes (see alloc_frame), when a process
+// is created or from the page-fault handler, and never from the allocator of the kernel heap.
+fn reclaim(count: usize) -> usize
+{
+	let mut swap = match SWAP.try_lock()
+	{
+		Some(swap) if swap.is_some() => swap,
+		_ => return 0,
+	};
+	let swap = swap.as_mut().unwrap();
+
+	let mut reclaimed = 0;
+
+	// Every page gets at most one second chance
+	let mut budget = interrupts::without_interrupts(|| RESIDENT.lock().len()) * 2;
+
+	while reclaimed < count && budget > 0
+	{
+		budget -= 1;
+		let address = match interrupts::without_interrupts(|| RESIDENT.lock().pop_front())
+		{
+			Some(address) => address,
+			None => break,
+		};
+
+		// Pages that have been unmapped since are dropped
+		let entry = match super::pte(VirtAddr::new(address))
+		{
+			Some(entry) if entry.flags().contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) => entry,
+			_ => continue,
+		};
+
+		let flags = entry.flags();
+		if flags.contains(PageTableFlags::ACCESSED)
+		{
+			entry.set_flags(flags - PageTableFlags::ACCESSED);
+			smp::tlb_shootdown(VirtAddr::new(address), 1);
+			track(Page::containing_address(VirtAddr::new(address)));
+			continue;
+		}
+
+		let slot = match swap.alloc()
+		{
+			Some(slot) => slot,
+			None =>
+			{
+				track(Page::containing_address(VirtAddr::new(address)));
+				break;
+			}
+		};
+
+		// The page is unmapped before it is written, so that it cannot change in the meantime
+		let frame = PhysFrame::containing_address(entry.addr());
+		let kept = flags - PageTableFlags::PRESENT - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+		entry.set_addr(PhysAddr::new(slot as u64 * FRAME_SIZE), kept | SWAP_ENTRY);
+		smp::tlb_shootdown(VirtAddr::new(address), 1);
+
+		if swap.dev.transfer(slot, contents(frame), true, false).is_err()
+		{
+			// The page stays in memory
+			swap.free(slot);
+			entry.set_frame(frame, flags);
+			track(Page::containing_address(VirtAddr::new(address)));
+			break;
+		}
+
+		frame::free(frame);
+		reclaimed += 1;
+	}
+
+	reclaimed
+}
+
+
+// Allocate a frame for a process (pages are swapped out, should there be no frame left)
+pub fn alloc_frame() -> Option<PhysFrame>
+{
+	frame::alloc(Zone::Normal).or_else(||
+	{
+		reclaim(RECLAIM_BATCH);
+		frame::alloc(Zone::Normal)
+	})
+}
+
+
+// Whether or not a page has been swapped out
+pub fn swapped(address: VirtAddr) -> bool
+{
+	super::pte(address).map_or(false, |entry| slot(entry.flags(), entry.addr()).is_some())
+}
+
+
+// Swap a page back in (it is mapped again, with the flags it had when it was swapped out)
+pub fn swapin(address: VirtAddr) -> Result<(), ()>
+{
+	let frame = alloc_frame().ok_or(())?;
+
+	let mut swap = smp::lock(&SWAP);
+	let res = (||
+	{
+		let swap = swap.as_mut().ok_or(())?;
+		let entry = super::pte(address).ok_or(())?;
+		let slot = slot(entry.flags(), entry.addr()).ok_or(())?;
+
+		swap.dev.transfer(slot, contents(frame), false, true)?;
+		swap.free(slot);
+
+		entry.set_frame(frame, (entry.flags() - SWAP_ENTRY) | PageTableFlags::PRESENT);
+		Ok(())
+	})();
+	drop(swap);
+
+	match res
+	{
+		Ok(()) => track(Page::containing_address(address)),
+		Err(()) =>
+		{
+			serprintln!("[ERR] SWAP: UNABLE TO SWAP IN {:#X}", address.as_u64());
+			frame::free(frame);
+		},
+	}
+	res
+}
+
+
+// Release the pages of a range, that are swapped out or tracked (when a process exits)
+pub fn release(start: u64, size: u64)
+{
+	interrupts::without_interrupts(||
+	{
+		RESIDENT.lock().retain(|&address| address < start || address >= start + size);
+	});
+
+	let mut swap = smp::lock(&SWAP);
+	let swap = match swap.as_mut()
+	{
+		Some(swap) if swap.used > 0 => swap,
+		_ => return,
+	};
+
+	for address in (start..start + size).step_by(PAGE_SIZE)
+	{
+		if let Some(entry) = super::pte(VirtAddr::new(address))
+		{
+			if let Some(slot) = slot(entry.flags(), entry.addr())
+			{
+				swap.free(slot);
+				entry.set_unused();
+			}
+		}
+	}
+}
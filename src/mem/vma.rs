@@ -0,0 +1,386 @@
+// src/mem/vma.rs
+//
+// Kernel virtual memory areas. A region of the higher half is handed out in areas, which are either backed by
+// frames of their own (vmalloc: virtually contiguous, physically scattered) or mapped onto device memory (ioremap,
+// with the caching that the device needs). Every area is followed by an unmapped guard page.
+
+/*
+	IMPORTS
+*/
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, instructions::interrupts, structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB}};
+
+use crate::{mem::frame::{self, GlobalFrameAllocator, Zone, FRAME_SIZE}, serprintln, sys::{cpu, smp}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Lowest start of the region
+pub const VMA_START: u64 = 0xFFFF_8000_0000_0000;
+
+// Size of the region
+pub const VMA_REGION: u64 = 64 << 30; // 64 GB
+
+// Largest offset of the start of the region
+const VMA_SLIDE: u64 = 1 << 40; // 1 TB
+
+// PAT bit of a 4 KiB page (the same bit as HUGE_PAGE, which only means that in the upper levels)
+const PAT_4K: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+
+// CacheMode enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode
+{
+	// Normal memory
+	WriteBack,
+
+	// Writes go straight to memory, reads are cached
+	WriteThrough,
+
+	// Writes are buffered and combined, reads are not cached (for framebuffers)
+	WriteCombining,
+
+	// Nothing is cached, and accesses are not reordered (for registers)
+	Uncached,
+}
+
+
+// Implementation of the CacheMode enumeration
+impl CacheMode
+{
+	// Page-table flags
+	//
+	// PWT, PCD and the PAT bit select an entry of the page attribute table. The first four entries are left as
+	// they are after reset (WB, WT, UC-, UC), and entry 4 is programmed as write-combining, see cpu::enable.
+	fn flags(&self) -> PageTableFlags
+	{
+		match self
+		{
+			CacheMode::WriteBack => PageTableFlags::empty(),
+			CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+			CacheMode::WriteCombining if cpu::features().pat => PAT_4K,
+			CacheMode::WriteCombining | CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+		}
+	}
+}
+
+
+// Kind enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind
+{
+	// Backed by frames of its own (freed with the area)
+	Vmalloc,
+
+	// Mapped onto device memory
+	Ioremap,
+}
+
+
+// Area struct
+#[derive(Debug, Clone, Copy)]
+struct Area
+{
+	// Mapped pages (the guard page is not counted)
+	pages: u64,
+
+	kind: Kind,
+}
+
+
+// Vma struct
+struct Vma
+{
+	// Areas, by start address
+	areas: BTreeMap<u64, Area>,
+
+	// Free ranges, by start address (with their length in pages)
+	free: BTreeMap<u64, u64>,
+}
+
+
+lazy_static!
+{
+	static ref VMA: Mutex<Vma> =
+	{
+		// The region starts at a random offset (see mem::kaslr)
+		let mut free = BTreeMap::new();
+		free.insert(VMA_START + super::kaslr::slide(VMA_SLIDE, super::kaslr::REGION_ALIGN), VMA_REGION / FRAME_SIZE);
+
+		Mutex::new(Vma
+		{
+			areas: BTreeMap::new(),
+			free,
+		})
+	};
+}
+
+
+// Implementation of the Vma struct
+impl Vma
+{
+	// Reserve an area (and the guard page after it), the first free range that is large enough is used
+	fn reserve(&mut self, pages: u64, kind: Kind) -> Option<u64>
+	{
+		let len = pages + 1;
+		let (&start, &free) = self.free.iter().find(|(_, &free)| free >= len)?;
+
+		self.free.remove(&start);
+		if free > len
+		{
+			self.free.insert(start + len * FRAME_SIZE, free - len);
+		}
+
+		self.areas.insert(start, Area
+		{
+			pages,
+			kind,
+		});
+		Some(start)
+	}
+
+
+	// Release the range of an area (it is merged with the free ranges around it)
+	fn release(&mut self, start: u64, pages: u64)
+	{
+		let mut start = start;
+		let mut len = pages + 1;
+
+		if let Some(next) = self.free.remove(&(start + len * FRAME_SIZE))
+		{
+			len += next;
+		}
+
+		if let Some((&prev, &prevlen)) = self.free.range(..start).next_back()
+		{
+			if prev + prevlen * FRAME_SIZE == start
+			{
+				self.free.remove(&prev);
+				start = prev;
+				len += prevlen;
+			}
+		}
+
+		self.free.insert(start, len);
+	}
+}
+
+
+// Number of pages needed for a size
+fn pagecount(size: u64) -> u64
+{
+	(size + FRAME_SIZE - 1) / FRAME_SIZE
+}
+
+
+// Map a page
+fn map(page: Page<Size4KiB>, frame: PhysFrame, flags: PageTableFlags) -> Result<(), ()>
+{
+	let mut mapper = unsafe
+	{
+		super::mapper(VirtAddr::new(super::PMEM_OFFSET))
+	};
+
+	unsafe
+	{
+		mapper.map_to(page, frame, flags - PAT_4K, &mut GlobalFrameAllocator).map_err(|_| ())?.flush();
+
+		// The mapper refuses the PAT bit, as it takes it for a huge page
+		if flags.contains(PAT_4K)
+		{
+			mapper.update_flags(page, flags).map_err(|_| ())?.flush();
+		}
+	}
+	Ok(())
+}
+
+
+// Unmap the pages of a range (the TLBs are not flushed), returning the frames of those that were mapped
+fn unmap(start: u64, pages: u64) -> Vec<PhysFrame>
+{
+	let mut mapper = unsafe
+	{
+		super::mapper(VirtAddr::new(super::PMEM_OFFSET))
+	};
+
+	let mut frames = Vec::with_capacity(pages as usize);
+	for i in 0..pages
+	{
+		let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(start + i * FRAME_SIZE));
+		unsafe
+		{
+			// The PAT bit has to go first, or the mapper takes the entry for a huge page
+			if let Ok(flush) = mapper.update_flags(page, PageTableFlags::PRESENT)
+			{
+				flush.ignore();
+			}
+		}
+
+		if let Ok((frame, flush)) = mapper.unmap(page)
+		{
+			flush.ignore();
+			frames.push(frame);
+		}
+	}
+	frames
+}
+
+
+// Base flags of a kernel mapping
+fn kernflags() -> PageTableFlags
+{
+	let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+	if cpu::features().nx
+	{
+		flags |= PageTableFlags::NO_EXECUTE;
+	}
+	flags
+}
+
+
+// Remove an area of the given kind, returning its length in pages
+fn remove(start: u64, kind: Kind) -> Option<u64>
+{
+	interrupts::without_interrupts(||
+	{
+		let mut vma = VMA.lock();
+		match vma.areas.get(&start)
+		{
+			Some(area) if area.kind == kind =>
+			{
+				let pages = area.pages;
+				vma.areas.remove(&start);
+				Some(pages)
+			},
+			_ => None,
+		}
+	})
+}
+
+
+// Unmap an area and release its range
+//
+// The shootdown only returns once every processor has flushed the range from its TLB, so its frames are freed, and
+// the range handed out again, only after no stale translation of it is left.
+fn teardown(start: u64, pages: u64, kind: Kind)
+{
+	let frames = unmap(start, pages);
+	smp::tlb_shootdown(VirtAddr::new(start), pages as usize);
+
+	if kind == Kind::Vmalloc
+	{
+		for frame in frames
+		{
+			frame::free(frame);
+		}
+	}
+
+	interrupts::without_interrupts(||
+	{
+		VMA.lock().release(start, pages);
+	});
+}
+
+
+// Allocate virtually contiguous memory (zeroed, and physically scattered)
+pub fn vmalloc(size: usize) -> Option<VirtAddr>
+{
+	let pages = pagecount(size as u64);
+	if pages == 0
+	{
+		return None;
+	}
+
+	let start = interrupts::without_interrupts(|| VMA.lock().reserve(pages, Kind::Vmalloc))?;
+
+	for i in 0..pages
+	{
+		let page = Page::containing_address(VirtAddr::new(start + i * FRAME_SIZE));
+		let mapped = frame::alloc(Zone::Normal).map_or(false, |frame|
+		{
+			map(page, frame, kernflags()).map_err(|_| frame::free(frame)).is_ok()
+		});
+
+		if !mapped
+		{
+			serprintln!("[ERR] VMA: OUT OF MEMORY, UNABLE TO ALLOCATE {} KB", (pages * FRAME_SIZE) >> 10);
+			remove(start, Kind::Vmalloc);
+			teardown(start, pages, Kind::Vmalloc);
+			return None;
+		}
+	}
+
+	unsafe
+	{
+		core::ptr::write_bytes(start as *mut u8, 0, (pages * FRAME_SIZE) as usize);
+	}
+	Some(VirtAddr::new(start))
+}
+
+
+// Free memory allocated with vmalloc
+pub fn vfree(address: VirtAddr)
+{
+	match remove(address.as_u64(), Kind::Vmalloc)
+	{
+		Some(pages) => teardown(address.as_u64(), pages, Kind::Vmalloc),
+		None =>
+		{
+			serprintln!("[ERR] VMA: VFREE OF AN UNKNOWN AREA {:#X}", address.as_u64());
+		},
+	}
+}
+
+
+// Map device memory (e.g. an MMIO BAR) into the kernel's address space
+//
+// The address does not need to be page-aligned; the returned address points to the same offset within the page.
+pub fn ioremap(address: PhysAddr, size: u64, mode: CacheMode) -> Option<VirtAddr>
+{
+	let offset = address.as_u64() % FRAME_SIZE;
+	let base = address.as_u64() - offset;
+	let pages = pagecount(offset + size);
+	if pages == 0
+	{
+		return None;
+	}
+
+	let start = interrupts::without_interrupts(|| VMA.lock().reserve(pages, Kind::Ioremap))?;
+
+	for i in 0..pages
+	{
+		let page = Page::containing_address(VirtAddr::new(start + i * FRAME_SIZE));
+		let frame = PhysFrame::containing_address(PhysAddr::new(base + i * FRAME_SIZE));
+
+		if map(page, frame, kernflags() | mode.flags()).is_err()
+		{
+			serprintln!("[ERR] VMA: UNABLE TO MAP {:#X}", base + i * FRAME_SIZE);
+			remove(start, Kind::Ioremap);
+			teardown(start, pages, Kind::Ioremap);
+			return None;
+		}
+	}
+
+	Some(VirtAddr::new(start + offset))
+}
+
+
+// Unmap device memory mapped with ioremap
+pub fn iounmap(address: VirtAddr)
+{
+	let start = address.align_down(FRAME_SIZE).as_u64();
+	match remove(start, Kind::Ioremap)
+	{
+		Some(pages) => teardown(start, pages, Kind::Ioremap),
+		None =>
+		{
+			serprintln!("[ERR] VMA: IOUNMAP OF AN UNKNOWN AREA {:#X}", address.as_u64());
+		},
+	}
+}
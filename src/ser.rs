@@ -94,6 +94,9 @@ pub fn init()
 
 
 // Interrupt handler
+//
+// Received bytes are fed to the console, so serial input is read (and polled) through the console device; there is
+// no separate serial device.
 fn intrh()
 {
 	let b = SER.lock().port.receive();
@@ -1,131 +1,644 @@
-// It would be good to change the name of items within the FADT enum, but for
-// now the "non camel case types" check must be disabled.
-#![allow(non_camel_case_types)]
+// src/sys/acpi.rs
+//
+// ACPI support: the tables are found once at boot, the DSDT and SSDTs are parsed into a persistent AML
+// namespace, and methods can then be evaluated by drivers and the power-management code.
 
-use acpi::{AcpiHandler, PhysicalMapping, AcpiTables};
-use alloc::boxed::Box;
-use aml::{AmlContext, AmlName, DebugVerbosity, Handler, value::AmlValue};
-use core::ptr::NonNull;
-use x86_64::{PhysAddr, instructions::port::Port};
+/*
+	IMPORTS
+*/
 
-use crate::sys;
+use acpi::{AcpiHandler, PhysicalMapping, AcpiTables, PlatformInfo, fadt::Fadt, platform::address::{AddressSpace, GenericAddress}, sdt::Signature};
+use alloc::{boxed::Box, vec::Vec};
+use aml::{AmlContext, AmlName, DebugVerbosity, Handler, pci_routing::{PciRoutingTable, Pin}, resource::{InterruptPolarity, InterruptTrigger}, value::{AmlValue, Args}};
+use core::{ptr::NonNull, sync::atomic::{AtomicBool, Ordering}};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, instructions::{interrupts, port::Port}};
 
+use crate::{serprintln, sys::pci::ConfigReg};
 
 
-#[allow(dead_code)]
-#[repr(u64)]
-enum FADT
+/*
+	CONSTANTS
+*/
+
+// PM1 event register bits
+const PWRBTN: u16 = 1 << 8;
+const WAK_STS: u16 = 1 << 15;
+
+// PM1 control register bits
+const SCI_EN: u16 = 1;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// Offsets of the firmware waking vectors in the FACS
+const FACS_WAKING_VECTOR: usize = 12;
+const FACS_X_WAKING_VECTOR: usize = 24;
+
+// Set by the SCI handler, once the power button has been pressed
+static POWER_BUTTON: AtomicBool = AtomicBool::new(false);
+
+
+// Power-management registers, as described by the FADT
+#[derive(Debug, Clone, Copy)]
+pub struct PmRegs
 {
-	SCI_INTR		= 46,
-	SMI_CMD_PORT		= 48,
-	ACPI_ENABLE		= 52,
-	ACPI_DISABLE		= 53,
-	S4_BIOS_REQ		= 54,
-	PSTATE_CTRL		= 55,
-	PM1A_EV_BLK		= 56,
-	PM1B_EV_BLK		= 60,
-	PM1A_CTRL_BLK		= 64,
-	PM1B_CTRL_BLK		= 68,
+	pub sci: u16,
+	pub smi_cmd: u32,
+	pub acpi_enable: u8,
+	pub pm1a_evt: Option<GenericAddress>,
+	pub pm1b_evt: Option<GenericAddress>,
+	pub pm1a_ctrl: Option<GenericAddress>,
+	pub pm1b_ctrl: Option<GenericAddress>,
+	pub reset: Option<GenericAddress>,
+	pub reset_value: u8,
+	pub reset_supported: bool,
+	pub facs: Option<usize>,
 }
 
 
+lazy_static!
+{
+	static ref TABLES: Mutex<Option<AcpiTables<KURIOS_ACPI_HANDLER>>> = Mutex::new(None);
+	static ref AML: Mutex<Option<AmlContext>> = Mutex::new(None);
+	static ref PMREGS: Mutex<Option<PmRegs>> = Mutex::new(None);
+}
+
+
+// Read address
 fn read_address<T>(physical_addr: usize) -> T where T: Copy
 {
 	let virtaddr = crate::mem::ptov(PhysAddr::new(physical_addr as u64));
 
 	unsafe
 	{
-		*virtaddr.as_ptr::<T>()
+		core::ptr::read_volatile(virtaddr.as_ptr::<T>())
 	}
 }
 
 
-fn read_fadt<T>(addr: usize, offset: FADT) -> T where T: Copy
+// Write address
+fn write_address<T>(physical_addr: usize, value: T) where T: Copy
 {
-	read_address::<T>(addr + offset as usize)
+	let virtaddr = crate::mem::ptov(PhysAddr::new(physical_addr as u64));
+
+	unsafe
+	{
+		core::ptr::write_volatile(virtaddr.as_mut_ptr::<T>(), value);
+	}
 }
 
 
-pub fn shutdown()
+// Read PCI configuration space (the register containing the offset is read, then the value is extracted)
+fn read_pci(bus: u8, device: u8, function: u8, offset: u16) -> u32
+{
+	ConfigReg::new(bus, device, function, offset).read() >> ((offset & 3) * 8)
+}
+
+
+// Write PCI configuration space (only the bytes covered by the mask are replaced)
+fn write_pci(bus: u8, device: u8, function: u8, offset: u16, mask: u32, value: u32)
 {
-	let mut pm1a_ctrl_blk = 0;
-	let mut slp_typa = 0;
-	let slp_len = 1 << 13;
+	let shift = (offset & 3) * 8;
+	let mut reg = ConfigReg::new(bus, device, function, offset);
+	let data = reg.read() & !(mask << shift);
+	reg.write(data | ((value & mask) << shift));
+}
 
 
-	// This needs to use println!
-	crate::sys::log::debug!("[LOG] ACPI SHUTDOWN\n");
+// Read a register described by a generic address structure
+pub fn read_gas(gas: &GenericAddress) -> u64
+{
+	match (gas.address_space, gas.bit_width)
+	{
+		(AddressSpace::SystemIo, 8) => KURIOS_ACPI_HANDLER.read_io_u8(gas.address as u16) as u64,
+		(AddressSpace::SystemIo, 16) => KURIOS_ACPI_HANDLER.read_io_u16(gas.address as u16) as u64,
+		(AddressSpace::SystemIo, _) => KURIOS_ACPI_HANDLER.read_io_u32(gas.address as u16) as u64,
+		(AddressSpace::SystemMemory, 8) => read_address::<u8>(gas.address as usize) as u64,
+		(AddressSpace::SystemMemory, 16) => read_address::<u16>(gas.address as usize) as u64,
+		(AddressSpace::SystemMemory, 32) => read_address::<u32>(gas.address as usize) as u64,
+		(AddressSpace::SystemMemory, _) => read_address::<u64>(gas.address as usize),
+		_ => 0,
+	}
+}
 
-	let mut aml = AmlContext::new(Box::new(KURIOS_ACPI_HANDLER), DebugVerbosity::None);
 
-	let res = unsafe
+// Write a register described by a generic address structure
+pub fn write_gas(gas: &GenericAddress, value: u64)
+{
+	match (gas.address_space, gas.bit_width)
+	{
+		(AddressSpace::SystemIo, 8) => KURIOS_ACPI_HANDLER.write_io_u8(gas.address as u16, value as u8),
+		(AddressSpace::SystemIo, 16) => KURIOS_ACPI_HANDLER.write_io_u16(gas.address as u16, value as u16),
+		(AddressSpace::SystemIo, _) => KURIOS_ACPI_HANDLER.write_io_u32(gas.address as u16, value as u32),
+		(AddressSpace::SystemMemory, 8) => write_address::<u8>(gas.address as usize, value as u8),
+		(AddressSpace::SystemMemory, 16) => write_address::<u16>(gas.address as usize, value as u16),
+		(AddressSpace::SystemMemory, 32) => write_address::<u32>(gas.address as usize, value as u32),
+		(AddressSpace::SystemMemory, _) => write_address::<u64>(gas.address as usize, value),
+
+		// The reset register may live in the configuration space of a device on bus 0
+		(AddressSpace::PciConfigSpace, _) =>
+		{
+			let device = (gas.address >> 32) as u8;
+			let function = (gas.address >> 16) as u8;
+			write_pci(0, device, function, gas.address as u16, 0xFF, value as u32);
+		},
+
+		_ => {},
+	}
+}
+
+
+// Read the power-management registers out of the FADT
+fn read_pmregs(tables: &AcpiTables<KURIOS_ACPI_HANDLER>) -> Option<PmRegs>
+{
+	let sdt = tables.sdts.get(&Signature::FADT)?;
+	let fadt = unsafe
+	{
+		KURIOS_ACPI_HANDLER.map_physical_region::<Fadt>(sdt.physical_address, core::mem::size_of::<Fadt>())
+	};
+
+	Some(PmRegs
+	{
+		sci: fadt.sci_interrupt,
+		smi_cmd: fadt.smi_cmd_port,
+		acpi_enable: fadt.acpi_enable,
+		pm1a_evt: fadt.pm1a_event_block().ok(),
+		pm1b_evt: fadt.pm1b_event_block().ok().flatten(),
+		pm1a_ctrl: fadt.pm1a_control_block().ok(),
+		pm1b_ctrl: fadt.pm1b_control_block().ok().flatten(),
+		reset: fadt.reset_register().ok(),
+		reset_value: fadt.reset_value,
+		reset_supported: { fadt.flags }.supports_system_reset_via_fadt(),
+		facs: fadt.facs_address().ok().filter(|&address| address != 0),
+	})
+}
+
+
+// PM1 event register (the status register is the first half of the block, the enable register the second)
+fn pm1_evt(block: &GenericAddress, enable: bool) -> GenericAddress
+{
+	let width = block.bit_width / 2;
+	GenericAddress
+	{
+		address: block.address + if enable
+		{
+			(width / 8) as u64
+		}
+		else
+		{
+			0
+		},
+
+		bit_width: width,
+		..*block
+	}
+}
+
+
+// Read a PM1 event register (the A and B blocks are combined)
+fn read_pm1_evt(regs: &PmRegs, enable: bool) -> u16
+{
+	[regs.pm1a_evt, regs.pm1b_evt].iter().flatten().fold(0, |value, block| value | read_gas(&pm1_evt(block, enable)) as u16)
+}
+
+
+// Write a PM1 event register (to both the A and B blocks)
+fn write_pm1_evt(regs: &PmRegs, enable: bool, value: u16)
+{
+	for block in [regs.pm1a_evt, regs.pm1b_evt].iter().flatten()
+	{
+		write_gas(&pm1_evt(block, enable), value as u64);
+	}
+}
+
+
+// Enable ACPI mode
+//
+// Asks the firmware (through the SMI command port) to hand the ACPI registers over, and waits for SCI_EN to be set.
+fn enable(regs: &PmRegs) -> Result<(), ()>
+{
+	let ctrl = regs.pm1a_ctrl.ok_or(())?;
+	if read_gas(&ctrl) as u16 & SCI_EN != 0
+	{
+		return Ok(());
+	}
+
+	// Hardware-reduced systems (and some others) are always in ACPI mode
+	if regs.smi_cmd == 0 || regs.acpi_enable == 0
+	{
+		return Ok(());
+	}
+
+	KURIOS_ACPI_HANDLER.write_io_u8(regs.smi_cmd as u16, regs.acpi_enable);
+
+	// Wait for up to 3 seconds
+	for _ in 0..300
+	{
+		if read_gas(&ctrl) as u16 & SCI_EN != 0
+		{
+			return Ok(());
+		}
+		crate::time::nwait(10_000_000);
+	}
+
+	Err(())
+}
+
+
+// SCI initialization (only fixed power-button events are enabled)
+fn sci_init(regs: &PmRegs)
+{
+	if regs.pm1a_evt.is_none() || regs.sci >= 16
+	{
+		serprintln!("[ERR] ACPI: SCI NOT SUPPORTED (IRQ {})", regs.sci);
+		return;
+	}
+
+	// Clear pending events before unmasking the line, as the SCI is level-triggered
+	write_pm1_evt(regs, false, read_pm1_evt(regs, false));
+	write_pm1_evt(regs, true, PWRBTN);
+
+	crate::sys::idt::set_irh(regs.sci as u8, scih);
+}
+
+
+// SCI handler
+fn scih()
+{
+	// PMREGS is only written during initialization, so the lock is never contended here
+	let regs = match PMREGS.try_lock().and_then(|regs| *regs)
+	{
+		Some(regs) => regs,
+		None => return,
+	};
+
+	// Status bits are cleared by writing them back
+	let status = read_pm1_evt(&regs, false);
+	write_pm1_evt(&regs, false, status);
+
+	if status & PWRBTN != 0
+	{
+		POWER_BUTTON.store(true, Ordering::SeqCst);
+	}
+}
+
+
+// Poll
+//
+// Handles the events recorded by the SCI handler. This is called from the places where the kernel waits for input,
+// as an orderly shutdown cannot be done from within an interrupt handler.
+pub fn poll()
+{
+	if POWER_BUTTON.swap(false, Ordering::SeqCst)
+	{
+		crate::println!();
+		crate::println!("[INFO] POWER BUTTON PRESSED");
+		crate::sys::power::poweroff();
+	}
+}
+
+
+// Parse an AML table (DSDT or SSDT)
+fn parse_table(aml: &mut AmlContext, address: usize, length: u32) -> Result<(), ()>
+{
+	let addr = crate::mem::ptov(PhysAddr::new(address as u64));
+	let table = unsafe
+	{
+		core::slice::from_raw_parts(addr.as_ptr::<u8>(), length as usize)
+	};
+
+	aml.parse_table(table).map_err(|_| ())
+}
+
+
+// Initialization
+pub fn init()
+{
+	let tables = match unsafe
 	{
 		AcpiTables::search_for_rsdp_bios(KURIOS_ACPI_HANDLER)
+	}
+	{
+		Ok(tables) => tables,
+		Err(_) =>
+		{
+			serprintln!("[ERR] ACPI: COULD NOT FIND RSDP IN BIOS");
+			return;
+		}
 	};
 
-	match res
+	let regs = read_pmregs(&tables);
+	*PMREGS.lock() = regs;
+
+	if let Some(regs) = &regs
 	{
-		Ok(acpi) =>
+		if enable(regs).is_err()
 		{
-			for (sign, sdt) in acpi.sdts
+			serprintln!("[ERR] ACPI: FIRMWARE DID NOT ENABLE ACPI MODE");
+		}
+	}
+
+	let mut aml = AmlContext::new(Box::new(KURIOS_ACPI_HANDLER), DebugVerbosity::None);
+	let mut count = 0;
+
+	if let Some(dsdt) = &tables.dsdt
+	{
+		match parse_table(&mut aml, dsdt.address, dsdt.length)
+		{
+			Ok(()) => count += 1,
+			Err(()) =>
 			{
-				if sign.as_str() == "FACP"
-				{
-					pm1a_ctrl_blk = read_fadt::<u32>(sdt.physical_address, FADT::PM1A_CTRL_BLK);
-				}
-			}
+				serprintln!("[ERR] ACPI: FAILED TO PARSE AML IN DSDT");
+			},
+		}
+	}
 
-			match &acpi.dsdt
+	for (idx, ssdt) in tables.ssdts.iter().enumerate()
+	{
+		match parse_table(&mut aml, ssdt.address, ssdt.length)
+		{
+			Ok(()) => count += 1,
+			Err(()) =>
 			{
-				Some(dsdt) =>
-				{
-					let addr = crate::mem::ptov(PhysAddr::new(dsdt.address as u64));
-					let table = unsafe
-					{
-						core::slice::from_raw_parts(addr.as_ptr(), dsdt.length as usize)
-					};
-
-					if aml.parse_table(table).is_ok()
-					{
-						let name = AmlName::from_str("\\_85").unwrap();
-						if let Ok(AmlValue::Package(s5)) = aml.namespace.get_by_path(&name)
-						{
-							if let AmlValue::Integer(value) = s5[0]
-							{
-								slp_typa = value as u16;
-							}
-						}
-					}
-					else
-					{
-						crate::sys::log::debug!("[ERR] ACPI FAILED TO PARSE AML IN DSDT");
-						slp_typa = (5 & 7) << 10;
-					}
-				},
-
-				None => {},
+				serprintln!("[ERR] ACPI: FAILED TO PARSE AML IN SSDT {}", idx);
+			},
+		}
+	}
+
+	if aml.initialize_objects().is_err()
+	{
+		serprintln!("[ERR] ACPI: FAILED TO INITIALIZE DEVICE OBJECTS");
+	}
+
+	serprintln!("[INFO] ACPI: REVISION {}, {} TABLES, {} AML TABLES PARSED", tables.revision, tables.sdts.len(), count);
+
+	*TABLES.lock() = Some(tables);
+	*AML.lock() = Some(aml);
+
+	if let Some(regs) = &regs
+	{
+		sci_init(regs);
+	}
+}
+
+
+// Whether or not the ACPI tables were found
+pub fn available() -> bool
+{
+	TABLES.lock().is_some()
+}
+
+
+// Run a closure on the ACPI tables
+pub fn with_tables<T>(f: impl FnOnce(&AcpiTables<KURIOS_ACPI_HANDLER>) -> T) -> Option<T>
+{
+	TABLES.lock().as_ref().map(f)
+}
+
+
+// Platform information (interrupt model, processors, PM timer)
+pub fn platform_info() -> Option<PlatformInfo>
+{
+	with_tables(|tables| tables.platform_info().ok()).flatten()
+}
+
+
+// Power-management registers
+pub fn pmregs() -> Option<PmRegs>
+{
+	*PMREGS.lock()
+}
+
+
+// Evaluate
+//
+// Evaluates the AML object at the given path (e.g. "\_SB.PCI0._STA"). Methods are invoked with the given
+// arguments, while plain objects are returned as they are.
+pub fn eval(path: &str, args: Vec<AmlValue>) -> Result<AmlValue, ()>
+{
+	let name = AmlName::from_str(path).map_err(|_| ())?;
+	let args = Args::from_list(args).map_err(|_| ())?;
+
+	interrupts::without_interrupts(||
+	{
+		AML.lock().as_mut().ok_or(())?.invoke_method(&name, args).map_err(|_| ())
+	})
+}
+
+
+// Evaluate an object, which is expected to be an integer
+pub fn eval_int(path: &str, args: Vec<AmlValue>) -> Option<u64>
+{
+	match eval(path, args)
+	{
+		Ok(AmlValue::Integer(value)) => Some(value),
+		Ok(AmlValue::Boolean(value)) => Some(value as u64),
+		_ => None,
+	}
+}
+
+
+// Whether or not an object exists in the namespace
+pub fn exists(path: &str) -> bool
+{
+	let name = match AmlName::from_str(path)
+	{
+		Ok(name) => name,
+		Err(_) => return false,
+	};
+
+	AML.lock().as_ref().map_or(false, |aml| aml.namespace.get_by_path(&name).is_ok())
+}
+
+
+// Device status (_STA), which defaults to "present and enabled" when the device has no _STA object
+pub fn status(device: &str) -> u64
+{
+	let path = alloc::format!("{}._STA", device);
+	if !exists(&path)
+	{
+		return 0x0F;
+	}
+
+	eval_int(&path, Vec::new()).unwrap_or(0)
+}
+
+
+// Put a device in the D0 power state (_PS0)
+pub fn power_on(device: &str) -> Result<(), ()>
+{
+	let path = alloc::format!("{}._PS0", device);
+	if !exists(&path)
+	{
+		return Ok(());
+	}
+
+	eval(&path, Vec::new()).map(|_| ())
+}
+
+
+// PCI routing
+//
+// Uses the _PRT of a host bridge (e.g. "\_SB.PCI0") to find the GSI that a device's interrupt pin is wired to.
+// The pin is numbered like the interrupt pin register of the configuration space (1 = INTA). Returns the GSI, and
+// whether it is level-triggered and active-low (as interrupts wired straight to a GSI are).
+pub fn pci_route(bridge: &str, dev: u8, func: u8, pin: u8) -> Option<(u32, bool, bool)>
+{
+	let pin = match pin
+	{
+		1 => Pin::IntA,
+		2 => Pin::IntB,
+		3 => Pin::IntC,
+		4 => Pin::IntD,
+		_ => return None,
+	};
+
+	let path = AmlName::from_str(&alloc::format!("{}._PRT", bridge)).ok()?;
+
+	interrupts::without_interrupts(||
+	{
+		let mut aml = AML.lock();
+		let aml = aml.as_mut()?;
+		let prt = PciRoutingTable::from_prt_path(&path, aml).ok()?;
+		prt.route(dev as u16, func as u16, pin, aml).ok().map(|irq| (irq.irq, matches!(irq.trigger, InterruptTrigger::Level), matches!(irq.polarity, InterruptPolarity::ActiveLow)))
+	})
+}
+
+
+// Sleep type values (SLP_TYPa, SLP_TYPb) of a sleep state, as given by the \_Sx package
+pub fn sleep_type(state: u8) -> Option<(u16, u16)>
+{
+	match eval(&alloc::format!("\\_S{}", state), Vec::new())
+	{
+		Ok(AmlValue::Package(values)) =>
+		{
+			let value = |idx: usize| match values.get(idx)
+			{
+				Some(AmlValue::Integer(value)) => Some(*value as u16),
+				_ => None,
+			};
+
+			let slp_typa = value(0)?;
+			Some((slp_typa, value(1).unwrap_or(slp_typa)))
+		},
+
+		_ => None,
+	}
+}
+
+
+// Enter a sleep state
+//
+// Returns once the state was either not entered, or has been left.
+fn enter(regs: &PmRegs, state: u8, (slp_typa, slp_typb): (u16, u16))
+{
+	// Tell the firmware that the system is about to enter the state (the method is optional)
+	if exists("\\_PTS")
+	{
+		let _ = eval("\\_PTS", alloc::vec![AmlValue::Integer(state as u64)]);
+	}
+
+	interrupts::without_interrupts(||
+	{
+		write_pm1_evt(regs, false, WAK_STS);
+
+		if let Some(pm1a) = regs.pm1a_ctrl
+		{
+			let data = read_gas(&pm1a) as u16 & !(7 << SLP_TYP_SHIFT);
+			write_gas(&pm1a, (data | ((slp_typa & 7) << SLP_TYP_SHIFT) | SLP_EN) as u64);
+		}
+
+		if let Some(pm1b) = regs.pm1b_ctrl
+		{
+			let data = read_gas(&pm1b) as u16 & !(7 << SLP_TYP_SHIFT);
+			write_gas(&pm1b, (data | ((slp_typb & 7) << SLP_TYP_SHIFT) | SLP_EN) as u64);
+		}
+
+		// Give the chipset some time to act on the request
+		for _ in 0..100
+		{
+			if read_pm1_evt(regs, false) & WAK_STS != 0
+			{
+				break;
 			}
+			crate::time::nwait(10_000_000);
 		}
+	});
+
+	if exists("\\_WAK")
+	{
+		let _ = eval("\\_WAK", alloc::vec![AmlValue::Integer(state as u64)]);
+	}
+}
+
+
+// Shutdown (enters S5)
+pub fn shutdown()
+{
+	// This needs to use println!
+	crate::sys::log::debug!("[LOG] ACPI SHUTDOWN\n");
 
-		Err(_e) =>
+	let regs = match pmregs()
+	{
+		Some(regs) => regs,
+		None =>
 		{
-			crate::sys::log::debug!("[ERR] ACPI: COULD NOT FIND RDSP IN BIOS\n");
+			crate::sys::log::debug!("[ERR] ACPI: NO FADT, UNABLE TO SHUT DOWN\n");
+			return;
 		}
 	};
 
-	let mut port: Port<u16> = Port::new(pm1a_ctrl_blk as u16);
+	// Fall back on the S5 value that most firmware (including QEMU's) uses
+	let slp_typ = sleep_type(5).unwrap_or_else(||
+	{
+		crate::sys::log::debug!("[ERR] ACPI: COULD NOT FIND \\_S5\n");
+		(5, 5)
+	});
 
-	unsafe
+	enter(&regs, 5, slp_typ);
+}
+
+
+// Suspend (enters S3)
+//
+// Resuming is not supported: the firmware waking vector is cleared, so waking up boots the machine again. An error
+// is returned if the system does not support S3, or did not enter it.
+pub fn suspend() -> Result<(), ()>
+{
+	let regs = pmregs().ok_or(())?;
+	let slp_typ = sleep_type(3).ok_or(())?;
+
+	if let Some(facs) = regs.facs
 	{
-		port.write(slp_typa | slp_len);
+		write_address::<u32>(facs + FACS_WAKING_VECTOR, 0);
+		write_address::<u64>(facs + FACS_X_WAKING_VECTOR, 0);
 	}
 
+	enter(&regs, 3, slp_typ);
+	Err(())
+}
 
 
+// Reset (through the reset register of the FADT)
+pub fn reset()
+{
+	if let Some(regs) = pmregs()
+	{
+		if let (true, Some(reset)) = (regs.reset_supported, regs.reset)
+		{
+			write_gas(&reset, regs.reset_value as u64);
+		}
+	}
+}
 
+
+#[allow(non_camel_case_types)]
 #[derive(Clone)]
 pub struct KURIOS_ACPI_HANDLER;
 
+
+// Implementation of the AcpiHandler trait for the KURIOS_ACPI_HANDLER struct
 impl AcpiHandler for KURIOS_ACPI_HANDLER
 {
 	unsafe fn map_physical_region<T>(&self, physical_addr: usize, size: usize) -> PhysicalMapping<Self, T>
@@ -138,8 +651,7 @@ impl AcpiHandler for KURIOS_ACPI_HANDLER
 }
 
 
-struct KURIOS_AML_HANDLER;
-
+// Implementation of the Handler trait (used by the AML interpreter) for the KURIOS_ACPI_HANDLER struct
 impl Handler for KURIOS_ACPI_HANDLER
 {
 	fn read_u8(&self, addr: usize) -> u8
@@ -162,84 +674,102 @@ impl Handler for KURIOS_ACPI_HANDLER
 		read_address::<u64>(addr)
 	}
 
-	fn write_u8(&mut self, _addr: usize, _val: u8)
+	fn write_u8(&mut self, addr: usize, val: u8)
 	{
-		unimplemented!()
+		write_address::<u8>(addr, val);
 	}
 
-	fn write_u16(&mut self, _addr: usize, _val: u16)
+	fn write_u16(&mut self, addr: usize, val: u16)
 	{
-		unimplemented!()
+		write_address::<u16>(addr, val);
 	}
 
-	fn write_u32(&mut self, _addr: usize, _val: u32)
+	fn write_u32(&mut self, addr: usize, val: u32)
 	{
-		unimplemented!()
+		write_address::<u32>(addr, val);
 	}
 
-	fn write_u64(&mut self, _addr: usize, _val: u64)
+	fn write_u64(&mut self, addr: usize, val: u64)
 	{
-		unimplemented!()
+		write_address::<u64>(addr, val);
 	}
 
-	fn read_io_u8(&self, _port: u16) -> u8
+	fn read_io_u8(&self, port: u16) -> u8
 	{
-		unimplemented!()
+		unsafe
+		{
+			Port::<u8>::new(port).read()
+		}
 	}
 
-	fn read_io_u16(&self, _port: u16) -> u16
+	fn read_io_u16(&self, port: u16) -> u16
 	{
-		unimplemented!()
+		unsafe
+		{
+			Port::<u16>::new(port).read()
+		}
 	}
 
-	fn read_io_u32(&self, _port: u16) -> u32
+	fn read_io_u32(&self, port: u16) -> u32
 	{
-		unimplemented!()
+		unsafe
+		{
+			Port::<u32>::new(port).read()
+		}
 	}
 
-	fn write_io_u8(&self, _port: u16, _val: u8)
+	fn write_io_u8(&self, port: u16, val: u8)
 	{
-		unimplemented!()
+		unsafe
+		{
+			Port::<u8>::new(port).write(val);
+		}
 	}
 
-	fn write_io_u16(&self, _port: u16, _val: u16)
+	fn write_io_u16(&self, port: u16, val: u16)
 	{
-		unimplemented!()
+		unsafe
+		{
+			Port::<u16>::new(port).write(val);
+		}
 	}
 
-	fn write_io_u32(&self, _port: u16, _val: u32)
+	fn write_io_u32(&self, port: u16, val: u32)
 	{
-		unimplemented!()
+		unsafe
+		{
+			Port::<u32>::new(port).write(val);
+		}
 	}
 
-	fn read_pci_u8(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16) -> u8
+	// Only segment 0 is supported by the PCI module
+	fn read_pci_u8(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8
 	{
-		unimplemented!()
+		read_pci(bus, device, function, offset) as u8
 	}
 
-	fn read_pci_u16(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16) -> u16
+	fn read_pci_u16(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16
 	{
-		unimplemented!()
+		read_pci(bus, device, function, offset) as u16
 	}
 
-	fn read_pci_u32(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16) -> u32
+	fn read_pci_u32(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32
 	{
-		unimplemented!()
+		read_pci(bus, device, function, offset)
 	}
 
-	fn write_pci_u8(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16, _value: u8)
+	fn write_pci_u8(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u8)
 	{
-		unimplemented!()
+		write_pci(bus, device, function, offset, 0xFF, value as u32);
 	}
 
-	fn write_pci_u16(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16, _value: u16)
+	fn write_pci_u16(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u16)
 	{
-		unimplemented!()
+		write_pci(bus, device, function, offset, 0xFFFF, value as u32);
 	}
 
-	fn write_pci_u32(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16, _value: u32)
+	fn write_pci_u32(&self, _segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: u32)
 	{
-		unimplemented!()
+		write_pci(bus, device, function, offset, 0xFFFF_FFFF, value);
 	}
 }
-}
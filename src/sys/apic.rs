@@ -0,0 +1,611 @@
+// src/sys/apic.rs
+//
+// Local APIC and I/O APIC support. Once the ACPI tables are available, interrupts are routed through
+// the I/O APIC(s) described by the MADT, and the 8259 PICs are disabled.
+
+/*
+	IMPORTS
+*/
+
+use acpi::{InterruptModel, platform::interrupt::{Polarity, TriggerMode}};
+use alloc::vec::Vec;
+use core::{hint::spin_loop, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{PhysAddr, instructions::interrupts, registers::model_specific::Msr};
+
+use crate::{clocksource::{self, ClockEvent, NSEC_PER_SEC}, mem::vma::{ioremap, CacheMode}, serprintln};
+
+
+/*
+	CONSTANTS
+*/
+
+// Model-specific register holding the base address of the local APIC
+const IA32_APIC_BASE: u32 = 0x1B;
+
+// Global enable bit of the IA32_APIC_BASE register
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Vector used for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Software-enable bit of the spurious interrupt vector register
+const SVR_ENABLE: u32 = 1 << 8;
+
+// Mask bit of LVT and redirection entries
+const MASKED: u32 = 1 << 16;
+
+// Periodic mode bit of the LVT timer entry
+const TIMER_PERIODIC: u32 = 1 << 17;
+
+// Delivery mode: NMI
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+// Delivery status bit of the ICR
+const ICR_PENDING: u32 = 1 << 12;
+
+// Redirection entry bits
+const REDIR_LOWACTIVE: u32 = 1 << 13;
+const REDIR_LEVEL: u32 = 1 << 15;
+
+// Size of the register blocks of the local APIC and the I/O APICs
+const LAPIC_SIZE: u64 = 0x1000;
+const IOAPIC_SIZE: u64 = 0x20;
+
+// I/O APIC registers
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+// Time over which the local APIC timer is calibrated (in nanoseconds)
+const CALIB_NS: u64 = 10_000_000;
+
+// Rating of the local APIC timer, as a clock event device
+const TIMER_RATING: u32 = 300;
+
+// Number of legacy (ISA) interrupt lines
+const ISA_IRQS: usize = 16;
+
+// GSI of a legacy IRQ that is not connected
+const UNROUTED: u32 = u32::MAX;
+
+
+// Virtual address of the local APIC registers (zero, while the APIC is not in use)
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// Whether or not the local APIC timer has replaced the PIT
+static TIMER: AtomicBool = AtomicBool::new(false);
+
+// Frequency of the local APIC timer (in Hz, with a divider of 16)
+static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+
+
+// Local APIC registers
+#[derive(Clone, Copy)]
+#[repr(usize)]
+pub enum LapicReg
+{
+	Id = 0x20,
+	Tpr = 0x80,
+	Eoi = 0xB0,
+	Svr = 0xF0,
+	Esr = 0x280,
+	IcrLow = 0x300,
+	IcrHigh = 0x310,
+	LvtTimer = 0x320,
+	LvtLint0 = 0x350,
+	LvtLint1 = 0x360,
+	LvtError = 0x370,
+	TimerInit = 0x380,
+	TimerCurrent = 0x390,
+	TimerDivide = 0x3E0,
+}
+
+
+// Route struct (where a legacy IRQ ends up on the I/O APIC)
+#[derive(Debug, Clone, Copy)]
+struct Route
+{
+	gsi: u32,
+	lowactive: bool,
+	level: bool,
+}
+
+
+// IoApic struct
+#[derive(Debug, Clone, Copy)]
+struct IoApic
+{
+	id: u8,
+	base: u64,
+
+	// First global system interrupt handled by this I/O APIC
+	gsibase: u32,
+
+	// Number of redirection entries
+	count: u32,
+}
+
+
+// Implementation of the IoApic struct
+impl IoApic
+{
+	// New
+	fn new(id: u8, address: u32, gsibase: u32) -> Option<Self>
+	{
+		let base = match ioremap(PhysAddr::new(address as u64), IOAPIC_SIZE, CacheMode::Uncached)
+		{
+			Some(base) => base.as_u64(),
+			None =>
+			{
+				serprintln!("[ERR] APIC: UNABLE TO MAP I/O APIC {}", id);
+				return None;
+			}
+		};
+
+		let mut ioapic = Self
+		{
+			id,
+			base,
+			gsibase,
+			count: 0,
+		};
+
+		ioapic.count = ((ioapic.read(IOAPIC_VER) >> 16) & 0xFF) + 1;
+		Some(ioapic)
+	}
+
+
+	// Read register
+	fn read(&self, reg: u32) -> u32
+	{
+		unsafe
+		{
+			core::ptr::write_volatile(self.base as *mut u32, reg);
+			core::ptr::read_volatile((self.base + 0x10) as *const u32)
+		}
+	}
+
+
+	// Write register
+	fn write(&self, reg: u32, val: u32)
+	{
+		unsafe
+		{
+			core::ptr::write_volatile(self.base as *mut u32, reg);
+			core::ptr::write_volatile((self.base + 0x10) as *mut u32, val);
+		}
+	}
+
+
+	// Whether or not a global system interrupt is handled by this I/O APIC
+	fn handles(&self, gsi: u32) -> bool
+	{
+		gsi >= self.gsibase && gsi < self.gsibase + self.count
+	}
+
+
+	// Set redirection entry
+	fn redirect(&self, gsi: u32, low: u32, dest: u8)
+	{
+		let reg = IOAPIC_REDTBL + 2 * (gsi - self.gsibase);
+		self.write(reg, MASKED);
+		self.write(reg + 1, (dest as u32) << 24);
+		self.write(reg, low);
+	}
+
+
+	// Mask (or unmask) a redirection entry
+	fn setmask(&self, gsi: u32, masked: bool)
+	{
+		let reg = IOAPIC_REDTBL + 2 * (gsi - self.gsibase);
+		let low = self.read(reg);
+		self.write(reg, if masked
+		{
+			low | MASKED
+		}
+		else
+		{
+			low & !MASKED
+		});
+	}
+}
+
+
+// Apic struct
+struct Apic
+{
+	ioapics: Vec<IoApic>,
+	routes: [Route; ISA_IRQS],
+}
+
+
+// Implementation of the Apic struct
+impl Apic
+{
+	// I/O APIC handling a legacy IRQ
+	fn ioapic(&self, ir: u8) -> Option<(&IoApic, u32)>
+	{
+		let gsi = self.routes.get(ir as usize)?.gsi;
+		self.ioapics.iter().find(|ioapic| ioapic.handles(gsi)).map(|ioapic| (ioapic, gsi))
+	}
+}
+
+
+lazy_static!
+{
+	static ref APIC: Mutex<Option<Apic>> = Mutex::new(None);
+}
+
+
+// Read local APIC register
+pub fn lapic_read(reg: LapicReg) -> u32
+{
+	let base = LAPIC_BASE.load(Ordering::Relaxed);
+	unsafe
+	{
+		core::ptr::read_volatile((base + reg as u64) as *const u32)
+	}
+}
+
+
+// Write local APIC register
+pub fn lapic_write(reg: LapicReg, val: u32)
+{
+	let base = LAPIC_BASE.load(Ordering::Relaxed);
+	unsafe
+	{
+		core::ptr::write_volatile((base + reg as u64) as *mut u32, val);
+	}
+}
+
+
+// Whether or not interrupts are routed through the APIC
+pub fn enabled() -> bool
+{
+	LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+
+// ID of the local APIC of the current CPU
+pub fn id() -> u32
+{
+	lapic_read(LapicReg::Id) >> 24
+}
+
+
+// End of interrupt
+pub fn eoi()
+{
+	lapic_write(LapicReg::Eoi, 0);
+}
+
+
+// Send an inter-processor interrupt
+pub fn send_ipi(apic_id: u32, icr: u32)
+{
+	interrupts::without_interrupts(||
+	{
+		lapic_write(LapicReg::IcrHigh, apic_id << 24);
+		lapic_write(LapicReg::IcrLow, icr);
+
+		// Wait for the IPI to be delivered
+		while lapic_read(LapicReg::IcrLow) & ICR_PENDING != 0
+		{
+			core::hint::spin_loop();
+		}
+	});
+}
+
+
+// Enable the local APIC of the current CPU
+pub fn lapic_init()
+{
+	unsafe
+	{
+		let mut msr = Msr::new(IA32_APIC_BASE);
+		let val = msr.read();
+		msr.write(val | APIC_BASE_ENABLE);
+	}
+
+	// Accept every priority
+	lapic_write(LapicReg::Tpr, 0);
+
+	// LINT0 (ExtINT from the 8259) is not used; LINT1 delivers NMIs
+	lapic_write(LapicReg::LvtLint0, MASKED);
+	lapic_write(LapicReg::LvtLint1, DELIVERY_NMI);
+	lapic_write(LapicReg::LvtError, MASKED);
+
+	// Clear errors (the register must be written before it is read)
+	lapic_write(LapicReg::Esr, 0);
+	lapic_read(LapicReg::Esr);
+
+	lapic_write(LapicReg::Svr, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+	eoi();
+}
+
+
+// Initialization
+pub fn init()
+{
+	if !crate::sys::acpi::available()
+	{
+		serprintln!("[ERR] APIC: COULD NOT FIND ACPI TABLES, KEEPING THE PIC");
+		return;
+	}
+
+	let model = match crate::sys::acpi::platform_info()
+	{
+		Some(info) => info.interrupt_model,
+		None =>
+		{
+			serprintln!("[ERR] APIC: COULD NOT READ THE MADT, KEEPING THE PIC");
+			return;
+		}
+	};
+
+	let madt = match model
+	{
+		InterruptModel::Apic(apic) if !apic.io_apics.is_empty() => apic,
+		_ =>
+		{
+			serprintln!("[ERR] APIC: NO I/O APIC FOUND, KEEPING THE PIC");
+			return;
+		}
+	};
+
+	// Legacy IRQs are identity-mapped onto global system interrupts, unless overridden
+	let mut routes = [Route
+	{
+		gsi: 0,
+		lowactive: false,
+		level: false,
+	}; ISA_IRQS];
+
+	for (ir, route) in routes.iter_mut().enumerate()
+	{
+		route.gsi = ir as u32;
+	}
+
+	for iso in madt.interrupt_source_overrides.iter()
+	{
+		if let Some(route) = routes.get_mut(iso.isa_source as usize)
+		{
+			route.gsi = iso.global_system_interrupt;
+			route.lowactive = matches!(iso.polarity, Polarity::ActiveLow);
+			route.level = matches!(iso.trigger_mode, TriggerMode::Level);
+		}
+	}
+
+	// A line whose GSI has been taken over by another IRQ (usually IRQ 2, by the PIT) is not routed
+	for (ir, route) in routes.iter_mut().enumerate()
+	{
+		let overridden = madt.interrupt_source_overrides.iter().any(|iso| iso.isa_source as usize == ir);
+		let claimed = madt.interrupt_source_overrides.iter().any(|iso| iso.isa_source as usize != ir && iso.global_system_interrupt == ir as u32);
+
+		if claimed && !overridden
+		{
+			route.gsi = UNROUTED;
+		}
+	}
+
+	let ioapics: Vec<IoApic> = madt.io_apics.iter().filter_map(|ioapic| IoApic::new(ioapic.id, ioapic.address, ioapic.global_system_interrupt_base)).collect();
+	let lapic = match ioremap(PhysAddr::new(madt.local_apic_address), LAPIC_SIZE, CacheMode::Uncached)
+	{
+		Some(lapic) if !ioapics.is_empty() => lapic,
+		_ =>
+		{
+			serprintln!("[ERR] APIC: UNABLE TO MAP THE APIC REGISTERS, KEEPING THE PIC");
+			return;
+		}
+	};
+
+	for ioapic in ioapics.iter()
+	{
+		serprintln!("[INFO] I/O APIC {} AT {:#X} (GSI {}-{})", ioapic.id, ioapic.base, ioapic.gsibase, ioapic.gsibase + ioapic.count - 1);
+	}
+
+	let apic = Apic
+	{
+		ioapics,
+		routes,
+	};
+
+	interrupts::without_interrupts(||
+	{
+		LAPIC_BASE.store(lapic.as_u64(), Ordering::Relaxed);
+		lapic_init();
+
+		// Every line is masked, before the PICs are disabled
+		for ioapic in apic.ioapics.iter()
+		{
+			for i in 0..ioapic.count
+			{
+				ioapic.setmask(ioapic.gsibase + i, true);
+			}
+		}
+
+		// Carry over the lines that had been unmasked on the PICs
+		let picmask = unsafe
+		{
+			let mut pics = crate::pic::PICS.lock();
+			let mask = pics.mask_read();
+			pics.disable();
+			mask[0] as u16 | (mask[1] as u16) << 8
+		};
+
+		let dest = id() as u8;
+		for ir in 0..ISA_IRQS as u8
+		{
+			if let Some((ioapic, gsi)) = apic.ioapic(ir)
+			{
+				let route = apic.routes[ir as usize];
+				let mut low = crate::sys::idt::intridx(ir) as u32 | MASKED;
+
+				if route.lowactive
+				{
+					low |= REDIR_LOWACTIVE;
+				}
+
+				if route.level
+				{
+					low |= REDIR_LEVEL;
+				}
+
+				ioapic.redirect(gsi, low, dest);
+
+				if picmask & (1 << ir) == 0
+				{
+					ioapic.setmask(gsi, false);
+				}
+			}
+		}
+
+		*APIC.lock() = Some(apic);
+	});
+
+	serprintln!("[INFO] APIC: LOCAL APIC {} ENABLED, PIC DISABLED", id());
+
+	timer_init();
+}
+
+
+// Local APIC timer initialization
+//
+// The timer is calibrated against the clock source, then registered as a clock event device (it fires on the
+// vector of IRQ 0, so that the existing tick handler keeps working).
+fn timer_init()
+{
+	// Divide by 16
+	lapic_write(LapicReg::TimerDivide, 0b0011);
+	lapic_write(LapicReg::LvtTimer, MASKED);
+
+	let start = crate::time::monotonic();
+	lapic_write(LapicReg::TimerInit, u32::MAX);
+
+	while crate::time::monotonic() - start < CALIB_NS
+	{
+		spin_loop();
+	}
+
+	let elapsed = (u32::MAX - lapic_read(LapicReg::TimerCurrent)) as u64;
+	let ns = crate::time::monotonic() - start;
+	lapic_write(LapicReg::TimerInit, 0);
+
+	let freq = (elapsed as u128 * NSEC_PER_SEC as u128 / ns as u128) as u64;
+	if freq == 0
+	{
+		serprintln!("[ERR] APIC: TIMER CALIBRATION FAILED");
+
+		// Other clock event devices may depend on the APIC
+		clocksource::select_event();
+		return;
+	}
+
+	TIMER_FREQ.store(freq, Ordering::Relaxed);
+	serprintln!("[INFO] APIC: TIMER RUNNING AT {} HZ", freq);
+	clocksource::register_event(&LAPIC_TIMER);
+}
+
+
+// LapicTimer struct
+pub struct LapicTimer;
+
+static LAPIC_TIMER: LapicTimer = LapicTimer;
+
+
+// Implementation of the ClockEvent trait for the LapicTimer struct
+impl ClockEvent for LapicTimer
+{
+	fn name(&self) -> &'static str
+	{
+		"LAPIC"
+	}
+
+	fn rating(&self) -> u32
+	{
+		TIMER_RATING
+	}
+
+	fn start(&self, period: u64) -> Result<(), ()>
+	{
+		let count = (period as u128 * TIMER_FREQ.load(Ordering::Relaxed) as u128 / NSEC_PER_SEC as u128) as u64;
+		if count == 0 || count > u32::MAX as u64
+		{
+			return Err(());
+		}
+
+		interrupts::without_interrupts(||
+		{
+			TIMER.store(true, Ordering::Relaxed);
+			lapic_write(LapicReg::LvtTimer, crate::sys::idt::intridx(0) as u32 | TIMER_PERIODIC);
+			lapic_write(LapicReg::TimerInit, count as u32);
+		});
+
+		Ok(())
+	}
+
+	fn stop(&self)
+	{
+		interrupts::without_interrupts(||
+		{
+			lapic_write(LapicReg::TimerInit, 0);
+			lapic_write(LapicReg::LvtTimer, MASKED);
+			TIMER.store(false, Ordering::Relaxed);
+		});
+	}
+}
+
+
+// Route a global system interrupt (of a PCI interrupt pin, as found in the _PRT) to a vector, and unmask it
+pub fn route(gsi: u32, vector: u8, level: bool, lowactive: bool) -> Result<(), ()>
+{
+	interrupts::without_interrupts(||
+	{
+		let apic = APIC.lock();
+		let ioapic = apic.as_ref().ok_or(())?.ioapics.iter().find(|ioapic| ioapic.handles(gsi)).ok_or(())?;
+
+		let mut low = vector as u32;
+		if lowactive
+		{
+			low |= REDIR_LOWACTIVE;
+		}
+
+		if level
+		{
+			low |= REDIR_LEVEL;
+		}
+
+		ioapic.redirect(gsi, low, id() as u8);
+		Ok(())
+	})
+}
+
+
+// Mask (or unmask) a legacy IRQ
+//
+// While the local APIC timer is in use, IRQ 0 refers to the timer rather than to the PIT.
+pub fn setmask(ir: u8, masked: bool)
+{
+	if ir == 0 && TIMER.load(Ordering::Relaxed)
+	{
+		let lvt = lapic_read(LapicReg::LvtTimer);
+		lapic_write(LapicReg::LvtTimer, if masked
+		{
+			lvt | MASKED
+		}
+		else
+		{
+			lvt & !MASKED
+		});
+		return;
+	}
+
+	if let Some(apic) = APIC.lock().as_ref()
+	{
+		if let Some((ioapic, gsi)) = apic.ioapic(ir)
+		{
+			ioapic.setmask(gsi, masked);
+		}
+	}
+}
@@ -13,7 +13,7 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::interrupts;
 
-use crate::{print, fs::FileIO};
+use crate::{print, fs::{FileIO, IO}};
 
 
 /*
@@ -98,6 +98,57 @@ impl FileIO for Console
 
 		Ok(n)
 	}
+
+	// Poll
+	fn poll(&mut self, event: IO) -> bool
+	{
+		match event
+		{
+			IO::Read => lnready(),
+			IO::Write => true,
+		}
+	}
+
+	// Poll a read
+	//
+	// Same rule as the read itself: a 4-byte buffer is read as soon as there is a character, and any other buffer
+	// once a line has been entered.
+	fn poll_read(&mut self, size: usize) -> bool
+	{
+		if size == 4
+		{
+			chready()
+		}
+		else
+		{
+			lnready()
+		}
+	}
+
+	// Read, without blocking
+	fn try_read(&mut self, buffer: &mut [u8]) -> crate::noblkio::Result<usize, ()>
+	{
+		let res = if buffer.len() == 4
+		{
+			try_readchar().map(|c| c.to_string())
+		}
+		else
+		{
+			try_readln()
+		};
+
+		if let Some(mut s) = res
+		{
+			s.truncate(buffer.len());
+			let n = s.len();
+			buffer[0..n].copy_from_slice(s.as_bytes());
+			Ok(n)
+		}
+		else
+		{
+			Err(crate::noblkio::Err::WouldBlk)
+		}
+	}
 }
 
 
@@ -347,6 +398,26 @@ pub fn keyhandler(key: char)
 	}
 }
 
+// Line ready (checks whether or not a complete line is waiting in STDIN)
+pub fn lnready() -> bool
+{
+	interrupts::without_interrupts(||
+	{
+		STDIN.lock().ends_with('\n')
+	})
+}
+
+
+// Character ready (checks whether or not a character is waiting in STDIN)
+pub fn chready() -> bool
+{
+	interrupts::without_interrupts(||
+	{
+		!STDIN.lock().is_empty()
+	})
+}
+
+
 // Print formatting
 pub fn printfmt(args: fmt::Arguments)
 {
@@ -388,21 +459,10 @@ pub fn readchar() -> char
 
 	loop
 	{
-		crate::time::halt();
-		let res = interrupts::without_interrupts(||
-		{
-			let mut stdin = STDIN.lock();
-			if !stdin.is_empty()
-			{
-				Some(stdin.remove(0))
-			}
-			else
-			{
-				None
-			}
-		});
+		crate::sys::proc::block();
+		crate::sys::acpi::poll();
 
-		if let Some(c) = res
+		if let Some(c) = try_readchar()
 		{
 			// Enable echo
 			crate::sys::console::echo_on();
@@ -420,29 +480,54 @@ pub fn readln() -> String
 {
 	loop
 	{
-		crate::time::halt();
-		let res = interrupts::without_interrupts(||
-		{
-			let mut stdin = STDIN.lock();
-			match stdin.chars().next_back()
-			{
-				Some('\n') =>
-				{
-					let ln = stdin.clone();
-					stdin.clear();
-					Some(ln)
-				}
+		crate::sys::proc::block();
+		crate::sys::acpi::poll();
 
-				_ =>
-				{
-					None
-				}
-			}
-		});
-
-		if let Some(ln) = res
+		if let Some(ln) = try_readln()
 		{
 			return ln;
 		}
 	}
 }
+
+
+// Try to read character (returns None, rather than waiting, if STDIN is empty)
+pub fn try_readchar() -> Option<char>
+{
+	interrupts::without_interrupts(||
+	{
+		let mut stdin = STDIN.lock();
+		if !stdin.is_empty()
+		{
+			Some(stdin.remove(0))
+		}
+		else
+		{
+			None
+		}
+	})
+}
+
+
+// Try to read line (returns None, rather than waiting, if no complete line is available)
+pub fn try_readln() -> Option<String>
+{
+	interrupts::without_interrupts(||
+	{
+		let mut stdin = STDIN.lock();
+		match stdin.chars().next_back()
+		{
+			Some('\n') =>
+			{
+				let ln = stdin.clone();
+				stdin.clear();
+				Some(ln)
+			}
+
+			_ =>
+			{
+				None
+			}
+		}
+	})
+}
@@ -1,16 +1,340 @@
 // src/libcore/sys/cpu.rs
 //
-// Get information about CPU.
+// Get information about CPU, and enable the features that the kernel relies on (NX, SMEP/SMAP, write-protect,
+// the page attribute table, and the saving of FPU state with XSAVE).
 
 /*
 	IMPORTS
 */
 
+use core::{arch::asm, fmt};
+use lazy_static::lazy_static;
 use raw_cpuid::CpuId;
+use x86_64::registers::{control::{Cr0, Cr0Flags, Cr4, Cr4Flags}, model_specific::{Efer, EferFlags, Msr}, xcontrol::{XCr0, XCr0Flags}};
 
 use crate::serprintln;
 
 
+/*
+	CONSTANTS
+*/
+
+// Size of the area that holds the FPU state of a process
+const FPU_AREA_SIZE: usize = 1024;
+
+// Size of the area used by FXSAVE
+const FXSAVE_AREA_SIZE: usize = 512;
+
+// Page attribute table
+const IA32_PAT: u32 = 0x277;
+
+// Memory types of the page attribute table (entry 4 is write-combining, and the others keep their reset values of
+// WB, WT, UC- and UC)
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+
+// CpuFeatures struct
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CpuFeatures
+{
+	pub fxsr: bool,
+	pub sse: bool,
+	pub sse2: bool,
+	pub sse3: bool,
+	pub ssse3: bool,
+	pub sse4_1: bool,
+	pub sse4_2: bool,
+	pub avx: bool,
+	pub avx2: bool,
+	pub xsave: bool,
+
+	// No-execute pages
+	pub nx: bool,
+
+	// Supervisor-mode execution and access prevention
+	pub smep: bool,
+	pub smap: bool,
+
+	// Process-context identifiers
+	pub pcid: bool,
+
+	// 1 GiB pages
+	pub page1gb: bool,
+
+	pub rdrand: bool,
+	pub rdseed: bool,
+	pub invariant_tsc: bool,
+	pub x2apic: bool,
+
+	// Page attribute table
+	pub pat: bool,
+}
+
+
+lazy_static!
+{
+	static ref FEATURES: CpuFeatures = CpuFeatures::detect();
+}
+
+
+// Implementation of the CpuFeatures struct
+impl CpuFeatures
+{
+	// Detect
+	fn detect() -> Self
+	{
+		let cpuid = CpuId::new();
+		let mut features = CpuFeatures::default();
+
+		if let Some(info) = cpuid.get_feature_info()
+		{
+			features.fxsr = info.has_fxsave_fxstor();
+			features.sse = info.has_sse();
+			features.sse2 = info.has_sse2();
+			features.sse3 = info.has_sse3();
+			features.ssse3 = info.has_ssse3();
+			features.sse4_1 = info.has_sse41();
+			features.sse4_2 = info.has_sse42();
+			features.avx = info.has_avx();
+			features.xsave = info.has_xsave();
+			features.pcid = info.has_pcid();
+			features.rdrand = info.has_rdrand();
+			features.x2apic = info.has_x2apic();
+			features.pat = info.has_pat();
+		}
+
+		if let Some(info) = cpuid.get_extended_feature_info()
+		{
+			features.avx2 = info.has_avx2();
+			features.smep = info.has_smep();
+			features.smap = info.has_smap();
+			features.rdseed = info.has_rdseed();
+		}
+
+		if let Some(info) = cpuid.get_extended_processor_and_feature_identifiers()
+		{
+			features.nx = info.has_execute_disable();
+			features.page1gb = info.has_1gib_pages();
+		}
+
+		features.invariant_tsc = cpuid.get_advanced_power_mgmt_info().map_or(false, |info| info.has_invariant_tsc());
+		features
+	}
+}
+
+
+// Implementation of the Display trait for the CpuFeatures struct (lists the features that are present)
+impl fmt::Display for CpuFeatures
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		let list = [
+			(self.sse, "SSE"),
+			(self.sse2, "SSE2"),
+			(self.sse3, "SSE3"),
+			(self.ssse3, "SSSE3"),
+			(self.sse4_1, "SSE4.1"),
+			(self.sse4_2, "SSE4.2"),
+			(self.avx, "AVX"),
+			(self.avx2, "AVX2"),
+			(self.xsave, "XSAVE"),
+			(self.nx, "NX"),
+			(self.smep, "SMEP"),
+			(self.smap, "SMAP"),
+			(self.pcid, "PCID"),
+			(self.page1gb, "1GB-PAGES"),
+			(self.rdrand, "RDRAND"),
+			(self.rdseed, "RDSEED"),
+			(self.invariant_tsc, "INVARIANT-TSC"),
+			(self.x2apic, "X2APIC"),
+			(self.pat, "PAT"),
+		];
+
+		let mut first = true;
+		for (_, name) in list.iter().filter(|(present, _)| *present)
+		{
+			if !first
+			{
+				write!(f, " ")?;
+			}
+			write!(f, "{}", name)?;
+			first = false;
+		}
+		Ok(())
+	}
+}
+
+
+// Features of the CPU
+pub fn features() -> &'static CpuFeatures
+{
+	&FEATURES
+}
+
+
+// FpuState struct (the x87, SSE and AVX state of a process, as saved by XSAVE or FXSAVE)
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct FpuState([u8; FPU_AREA_SIZE]);
+
+
+// Implementation of the FpuState struct
+impl FpuState
+{
+	// New
+	pub const fn new() -> Self
+	{
+		FpuState([0; FPU_AREA_SIZE])
+	}
+
+
+	// Save (the state of the current processor)
+	pub fn save(&mut self)
+	{
+		let ptr = self.0.as_mut_ptr();
+		unsafe
+		{
+			if xsave_enabled()
+			{
+				asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+			}
+			else if FEATURES.fxsr
+			{
+				asm!("fxsave64 [{}]", in(reg) ptr, options(nostack));
+			}
+		}
+	}
+
+
+	// Restore (onto the current processor)
+	pub fn restore(&self)
+	{
+		let ptr = self.0.as_ptr();
+		unsafe
+		{
+			if xsave_enabled()
+			{
+				asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+			}
+			else if FEATURES.fxsr
+			{
+				asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack));
+			}
+		}
+	}
+}
+
+
+// Implementation of the Debug trait for the FpuState struct
+impl fmt::Debug for FpuState
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		write!(f, "FpuState")
+	}
+}
+
+
+// Whether or not the FPU state is saved with XSAVE
+fn xsave_enabled() -> bool
+{
+	Cr4::read().contains(Cr4Flags::OSXSAVE)
+}
+
+
+// Enable (the features of the current processor, on every CPU)
+pub fn enable()
+{
+	let features = features();
+
+	unsafe
+	{
+		if features.nx
+		{
+			Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+		}
+
+		// Read-only pages are also read-only for the kernel
+		Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
+		// The kernel itself does not use the FPU, but processes do
+		if features.fxsr
+		{
+			Cr0::update(|flags|
+			{
+				flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+				flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+			});
+			Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+		}
+
+		let mut cr4 = Cr4::read();
+		cr4.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, features.smep);
+		cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, features.smap);
+		Cr4::write(cr4);
+
+		// Every processor needs the same memory types, or their caches would disagree
+		if features.pat
+		{
+			Msr::new(IA32_PAT).write(PAT_VALUE);
+		}
+	}
+
+	if features.xsave
+	{
+		let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+		if features.avx
+		{
+			xcr0 |= XCr0Flags::AVX;
+		}
+
+		unsafe
+		{
+			Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+			XCr0::write(xcr0);
+		}
+
+		// Fall back on FXSAVE, should the state not fit
+		let size = CpuId::new().get_extended_state_info().map_or(usize::MAX, |info| info.xsave_area_size_enabled_features() as usize);
+		if size > FPU_AREA_SIZE
+		{
+			unsafe
+			{
+				Cr4::update(|flags| flags.remove(Cr4Flags::OSXSAVE));
+			}
+		}
+	}
+}
+
+
+// User access
+//
+// With SMAP, the kernel can only reach the pages of processes while the AC flag is set, so this sets it for the
+// duration of the given function.
+pub fn user_access<R>(f: impl FnOnce() -> R) -> R
+{
+	let smap = FEATURES.smap;
+	if smap
+	{
+		unsafe
+		{
+			asm!("stac", options(nomem, nostack));
+		}
+	}
+
+	let res = f();
+
+	if smap
+	{
+		unsafe
+		{
+			asm!("clac", options(nomem, nostack));
+		}
+	}
+	res
+}
+
+
 // Initialization
 pub fn init()
 {
@@ -33,4 +357,16 @@ pub fn init()
 		let proc_basefreq = proc_freqinfo.processor_base_frequency();
 		serprintln!("[INFO] CPU: {} MHz\n", proc_basefreq);
 	}
+
+	serprintln!("[INFO] CPU: {}\n", features());
+
+	enable();
+	serprintln!("[INFO] CPU: FPU STATE SAVED WITH {}\n", if xsave_enabled()
+	{
+		"XSAVE"
+	}
+	else
+	{
+		"FXSAVE"
+	});
 }
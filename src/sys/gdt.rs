@@ -1,6 +1,8 @@
 // src/gdt.rs
 //
-// Global descriptor table (GDT) functionality for the LibertyOS kernel.
+// Global descriptor table (GDT) functionality for the LibertyOS kernel, along with the task state segments and the
+// stacks that the CPU switches to (those of the interrupt stack table, and the kernel stacks of processes). Stacks
+// come from the page allocator, with an unmapped guard page below each of them, so that overflows are caught.
 
 
 #![allow(deprecated)]
@@ -12,10 +14,17 @@
 
 use x86_64::{PrivilegeLevel, VirtAddr};
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, DescriptorFlags, SegmentSelector};
+use x86_64::structures::paging::PageTableFlags;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::instructions::segmentation::*;
 use x86_64::instructions::tables::load_tss;
+use x86_64::instructions::interrupts;
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt;
 use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::sys::smp::MAX_CPUS;
 
 
 
@@ -31,113 +40,193 @@ pub const GEN_PROT_FAULT_ISTIDX: u16 = 2;
 pub const PAGE_FAULT_ISTIDX: u16 = 1;
 
 
-// Privilege TSS stack
-pub static mut PRIV_TSS_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// Size of the stacks used until memory management is up
+const BOOT_STACK_SIZE: usize = 0x2000;
 
+// Sizes of the stacks of the interrupt stack table (by index; page faults may have to swap pages in)
+const IST_STACK_SIZES: [usize; 3] = [0x4000, 0x8000, 0x4000];
 
-// Stack
-pub static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+// Size of the kernel stack of a process (used by its system calls and interrupts)
+pub const TASK_STACK_SIZE: usize = 0x10000;
 
+// Page size
+const PAGE_SIZE: u64 = 4096;
 
-// Stack size
-const STACK_SIZE: usize = 0x2000;
 
+// Stacks used until memory management is up (on the BSP; they are replaced by init_stacks)
+static mut BOOT_STACKS: [[u8; BOOT_STACK_SIZE]; 3] = [[0; BOOT_STACK_SIZE]; 3];
 
 
-// Lazy static wrapper around the TSS reference
-lazy_static!
+// Task state segment of every processor (the CPU reads its stacks from it)
+const EMPTY_TSS: TaskStateSegment = TaskStateSegment::new();
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [EMPTY_TSS; MAX_CPUS];
+
+
+// StackKind enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind
 {
-	static ref TSS: TaskStateSegment = {
-		// Create new TSS
-		let mut tss = TaskStateSegment::new();
+	// Main kernel stack of a processor (for the BSP, the stack that the bootloader set up)
+	Kernel,
 
+	// Stack of the interrupt stack table (by index)
+	Ist(u16),
 
-		// Privilege stack table for the TSS
-		tss.privilege_stack_table[0] =
-		{
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-			VirtAddr::from_ptr(unsafe
-			{
-				&STACK
-			}) + STACK_SIZE
-		};
+	// Kernel stack of a process (by ID)
+	Task(usize),
+}
 
 
-		// Double-fault index for the interrupt stack table of the TSS
-		tss.interrupt_stack_table[DOUBLEFAULT_IST_IDX as usize] =
-		{
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-			VirtAddr::from_ptr(unsafe
-			{
-				&STACK
-			}) + STACK_SIZE
-		};
+// Stack struct
+#[derive(Debug, Clone, Copy)]
+pub struct Stack
+{
+	pub kind: StackKind,
+	pub cpu: usize,
+
+	// Lowest address of the stack (the page below it is unmapped)
+	pub bottom: u64,
+	pub top: u64,
+}
 
 
-		// Page-fault index for the interrupt stack table of the TSS
-		tss.interrupt_stack_table[PAGE_FAULT_ISTIDX as usize] =
+lazy_static!
+{
+	// Stacks allocated so far
+	static ref STACKS: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+}
+
+
+// Implementation of the Display trait for the Stack struct
+impl fmt::Display for Stack
+{
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+	{
+		match self.kind
 		{
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-			VirtAddr::from_ptr(unsafe
-			{
-				&STACK
-			}) + STACK_SIZE
-		};
+			StackKind::Kernel => write!(f, "KERNEL STACK OF CPU {}", self.cpu),
+			StackKind::Ist(DOUBLEFAULT_IST_IDX) => write!(f, "DOUBLE-FAULT STACK OF CPU {}", self.cpu),
+			StackKind::Ist(PAGE_FAULT_ISTIDX) => write!(f, "PAGE-FAULT STACK OF CPU {}", self.cpu),
+			StackKind::Ist(GEN_PROT_FAULT_ISTIDX) => write!(f, "GENERAL-PROTECTION-FAULT STACK OF CPU {}", self.cpu),
+			StackKind::Ist(idx) => write!(f, "IST STACK {} OF CPU {}", idx, self.cpu),
+			StackKind::Task(id) => write!(f, "KERNEL STACK OF PROCESS {}", id),
+		}
+	}
+}
 
 
-		// General protection fault index for the interrupt stack table of the TSS
-		tss.interrupt_stack_table[GEN_PROT_FAULT_ISTIDX as usize] =
+// Allocate a stack (from the page allocator, with an unmapped guard page below it), returning its top
+//
+// The areas of kernel virtual memory are separated by unmapped guard pages, so an overflow faults instead of
+// running into whatever lies below.
+pub fn alloc_stack(size: usize, kind: StackKind, cpu: usize) -> Option<VirtAddr>
+{
+	let bottom = crate::mem::vma::vmalloc(size)?.as_u64();
+	let top = bottom + size as u64;
+
+	interrupts::without_interrupts(||
+	{
+		STACKS.lock().push(Stack
 		{
-			static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-			VirtAddr::from_ptr(unsafe
-			{
-				&STACK
-			}) + STACK_SIZE
-		};
+			kind,
+			cpu,
+			bottom,
+			top,
+		});
+	});
+	Some(VirtAddr::new(top))
+}
 
 
-		// Return the fully-functional version of the TSS
-		tss
-	};
+// Stack that an address overflowed (if it lies in the guard page below a stack)
+//
+// This is used by fault handlers, so it gives up rather than wait for the list of stacks.
+pub fn overflowed(address: u64) -> Option<Stack>
+{
+	let stacks = STACKS.try_lock()?;
+	stacks.iter().find(|stack| address < stack.bottom && address >= stack.bottom - PAGE_SIZE).copied()
 }
 
 
-// Lazy static wrapper around the GDT static reference
-lazy_static!
+// Set the stack that the current processor switches to on interrupts from user mode
+pub fn set_kernel_stack(top: VirtAddr)
 {
-	pub static ref GDT: (GlobalDescriptorTable, Selectors) =
+	let cpu = crate::sys::smp::cpu().id();
+	unsafe
 	{
-		// Create new GDT
-		let mut gdt = GlobalDescriptorTable::new();
-
+		TSS[cpu].privilege_stack_table[0] = top;
+	}
+}
 
-		// Add TSS segment to the GDT
-		let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
 
-		// Add kernel code segment to the GDT
-		let code = gdt.add_entry(Descriptor::kernel_code_segment());
+// Task state segment of a processor
+fn tss(cpu: usize) -> &'static mut TaskStateSegment
+{
+	unsafe
+	{
+		&mut *core::ptr::addr_of_mut!(TSS[cpu])
+	}
+}
 
-		// Add kernel data segment to the GDT
-		let data = gdt.add_entry(Descriptor::kernel_data_segment());
 
-		// Add user code segment to the GDT
-		let usercode = gdt.add_entry(Descriptor::user_code_segment());
+// Allocate the stacks of the interrupt stack table of a processor
+fn ist_stacks(cpu: usize)
+{
+	for (idx, &size) in IST_STACK_SIZES.iter().enumerate()
+	{
+		let top = alloc_stack(size, StackKind::Ist(idx as u16), cpu).expect("[ERR] OUT OF MEMORY, UNABLE TO ALLOCATE AN IST STACK");
+		tss(cpu).interrupt_stack_table[idx] = top;
+	}
+}
 
-		// Add user data segment to the GDT
-		let userdata = gdt.add_entry(Descriptor::user_data_segment());
 
-		(gdt, Selectors
+// Lazy static wrapper around the GDT static reference
+lazy_static!
+{
+	pub static ref GDT: (GlobalDescriptorTable, Selectors) =
+	{
+		// The stacks of the BSP are static until memory management is up
+		let tss = tss(0);
+		for (idx, stack) in unsafe
 		{
-			tss,
-			code,
-			data,
-			usercode,
-			userdata
-		})
+			BOOT_STACKS.iter()
+		}.enumerate()
+		{
+			tss.interrupt_stack_table[idx] = VirtAddr::from_ptr(stack) + BOOT_STACK_SIZE;
+		}
+
+		// Interrupts from user mode only happen once a process has set its own kernel stack
+		tss.privilege_stack_table[0] = tss.interrupt_stack_table[DOUBLEFAULT_IST_IDX as usize];
+
+		// Create new GDT, with the TSS, kernel code/data and user code/data segments
+		new_gdt(tss)
 	};
 }
 
 
+// Build the GDT of a processor, around its own TSS
+//
+// Entries are added in the same order for every processor, so that the selectors are the same.
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors)
+{
+	let mut gdt = GlobalDescriptorTable::new();
+	let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+	let code = gdt.add_entry(Descriptor::kernel_code_segment());
+	let data = gdt.add_entry(Descriptor::kernel_data_segment());
+	let usercode = gdt.add_entry(Descriptor::user_code_segment());
+	let userdata = gdt.add_entry(Descriptor::user_data_segment());
+
+	(gdt, Selectors
+	{
+		tss,
+		code,
+		data,
+		usercode,
+		userdata
+	})
+}
+
+
 // Selectors struct
 // NOTE: This struct was originally private, but, in order for the kernel to compile with the load_tss line in the init function, this struct had to be public
 pub struct Selectors
@@ -162,3 +251,70 @@ pub fn init()
 		load_tss(GDT.1.tss);
 	}
 }
+
+
+// Stacks of the BSP (once memory management is up, they replace the static boot stacks)
+pub fn init_stacks()
+{
+	ist_stacks(0);
+	boot_stack();
+}
+
+
+// Register the stack that the bootloader set up (on which kernel_main and the shell run)
+//
+// The bootloader leaves the page below it unmapped, so its range is found by walking the page tables outwards from
+// the current stack pointer, up to the first unmapped page on either side.
+fn boot_stack()
+{
+	let mapped = |address: u64| crate::mem::pte(VirtAddr::new(address)).map_or(false, |pte| pte.flags().contains(PageTableFlags::PRESENT));
+
+	let rsp: u64;
+	unsafe
+	{
+		core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+	}
+
+	let mut bottom = rsp & !(PAGE_SIZE - 1);
+	while mapped(bottom - PAGE_SIZE)
+	{
+		bottom -= PAGE_SIZE;
+	}
+
+	let mut top = (rsp & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+	while mapped(top)
+	{
+		top += PAGE_SIZE;
+	}
+
+	interrupts::without_interrupts(||
+	{
+		STACKS.lock().push(Stack
+		{
+			kind: StackKind::Kernel,
+			cpu: 0,
+			bottom,
+			top,
+		});
+	});
+}
+
+
+// Initialization of an application processor (with its own GDT, TSS and IST stacks)
+pub fn init_cpu(cpu: usize)
+{
+	ist_stacks(cpu);
+
+	let tss: &'static TaskStateSegment = tss(cpu);
+	let gdt: &'static (GlobalDescriptorTable, Selectors) = Box::leak(Box::new(new_gdt(tss)));
+
+	gdt.0.load();
+
+	unsafe
+	{
+		CS::set_reg(gdt.1.code);
+		DS::set_reg(gdt.1.data);
+		SS::set_reg(gdt.1.data);
+		load_tss(gdt.1.tss);
+	}
+}
@@ -0,0 +1,245 @@
+// src/sys/hpet.rs
+//
+// High precision event timer (HPET), found through its ACPI table. The main counter is used as a clock source,
+// and timer 0 as a clock event device when it can deliver interrupts as messages (FSB).
+
+/*
+	IMPORTS
+*/
+
+use acpi::HpetInfo;
+use alloc::boxed::Box;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{clocksource::{self, ClockEvent, ClockSource, NSEC_PER_SEC}, mem::vma::{ioremap, iounmap, CacheMode}, serprintln};
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of the register block
+const HPET_SIZE: u64 = 0x400;
+
+// Registers
+const REG_CAP: u64 = 0x000;
+const REG_CONF: u64 = 0x010;
+const REG_COUNTER: u64 = 0x0F0;
+
+// Registers of timer 0
+const REG_TIMER_CONF: u64 = 0x100;
+const REG_TIMER_CMP: u64 = 0x108;
+const REG_TIMER_FSB: u64 = 0x110;
+
+// Capabilities register bits
+const CAP_COUNT_64: u64 = 1 << 13;
+
+// Configuration register bits
+const CONF_ENABLE: u64 = 1;
+
+// Timer configuration bits
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_32BIT: u64 = 1 << 8;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_FSB_CAP: u64 = 1 << 15;
+
+// Femtoseconds per second
+const FSEC_PER_SEC: u64 = 1_000_000_000_000_000;
+
+// Highest period allowed by the specification (100 ns, in femtoseconds)
+const MAX_PERIOD: u64 = 100_000_000;
+
+// Ratings
+const RATING_SOURCE: u32 = 300;
+const RATING_EVENT: u32 = 200;
+
+
+// Hpet struct
+pub struct Hpet
+{
+	base: u64,
+
+	// Frequency of the main counter (in Hz)
+	freq: u64,
+
+	// Whether or not the main counter is 64 bits wide
+	count64: bool,
+}
+
+
+// Implementation of the Hpet struct
+impl Hpet
+{
+	// Read register
+	fn read(&self, reg: u64) -> u64
+	{
+		unsafe
+		{
+			core::ptr::read_volatile((self.base + reg) as *const u64)
+		}
+	}
+
+
+	// Write register
+	fn write(&self, reg: u64, data: u64)
+	{
+		unsafe
+		{
+			core::ptr::write_volatile((self.base + reg) as *mut u64, data);
+		}
+	}
+}
+
+
+// Implementation of the ClockSource trait for the Hpet struct
+impl ClockSource for Hpet
+{
+	fn name(&self) -> &'static str
+	{
+		"HPET"
+	}
+
+	fn rating(&self) -> u32
+	{
+		RATING_SOURCE
+	}
+
+	fn frequency(&self) -> u64
+	{
+		self.freq
+	}
+
+	fn mask(&self) -> u64
+	{
+		if self.count64
+		{
+			u64::MAX
+		}
+		else
+		{
+			u32::MAX as u64
+		}
+	}
+
+	fn read(&self) -> u64
+	{
+		self.read(REG_COUNTER)
+	}
+}
+
+
+// Implementation of the ClockEvent trait for the Hpet struct
+impl ClockEvent for Hpet
+{
+	fn name(&self) -> &'static str
+	{
+		"HPET"
+	}
+
+	fn rating(&self) -> u32
+	{
+		RATING_EVENT
+	}
+
+	// Timer 0 sends the vector of IRQ 0 to the local APIC directly, so the legacy routing (which would also take
+	// over the RTC interrupt) is not needed
+	fn start(&self, period: u64) -> Result<(), ()>
+	{
+		let conf = self.read(REG_TIMER_CONF);
+		if conf & TIMER_FSB_CAP == 0 || conf & TIMER_PERIODIC_CAP == 0 || !crate::sys::apic::enabled()
+		{
+			return Err(());
+		}
+
+		let delta = (period as u128 * self.freq as u128 / NSEC_PER_SEC as u128) as u64;
+		if delta == 0 || (!self.count64 && delta > u32::MAX as u64)
+		{
+			return Err(());
+		}
+
+		let (address, data) = crate::sys::pci::msi_message(crate::sys::idt::intridx(0));
+		self.write(REG_TIMER_FSB, (address << 32) | data as u64);
+
+		// In periodic mode, the first write sets the comparator, and the second one the period
+		let conf = (conf & !TIMER_32BIT) | TIMER_FSB_ENABLE | TIMER_PERIODIC | TIMER_VAL_SET | TIMER_INT_ENABLE;
+		self.write(REG_TIMER_CONF, conf);
+		self.write(REG_TIMER_CMP, self.read(REG_COUNTER) + delta);
+		self.write(REG_TIMER_CMP, delta);
+
+		Ok(())
+	}
+
+	fn stop(&self)
+	{
+		let conf = self.read(REG_TIMER_CONF);
+		self.write(REG_TIMER_CONF, conf & !TIMER_INT_ENABLE);
+	}
+}
+
+
+// Initialization
+pub fn init()
+{
+	let info = match crate::sys::acpi::with_tables(|tables| HpetInfo::new(tables).ok()).flatten()
+	{
+		Some(info) => info,
+		None =>
+		{
+			serprintln!("[INFO] HPET: NOT FOUND");
+			return;
+		}
+	};
+
+	let base = match ioremap(PhysAddr::new(info.base_address as u64), HPET_SIZE, CacheMode::Uncached)
+	{
+		Some(base) => base.as_u64(),
+		None =>
+		{
+			serprintln!("[ERR] HPET: UNABLE TO MAP THE REGISTERS");
+			return;
+		}
+	};
+
+	let cap = unsafe
+	{
+		core::ptr::read_volatile((base + REG_CAP) as *const u64)
+	};
+
+	let period = cap >> 32;
+	if period == 0 || period > MAX_PERIOD
+	{
+		serprintln!("[ERR] HPET: INVALID PERIOD ({} FS)", period);
+		iounmap(VirtAddr::new(base));
+		return;
+	}
+
+	let hpet: &'static Hpet = Box::leak(Box::new(Hpet
+	{
+		base,
+		freq: FSEC_PER_SEC / period,
+		count64: cap & CAP_COUNT_64 != 0,
+	}));
+
+	// Timer 0 stays disabled until it is used as a clock event device
+	hpet.stop();
+	let conf = hpet.read(REG_CONF);
+	hpet.write(REG_CONF, conf | CONF_ENABLE);
+
+	serprintln!("[INFO] HPET: {} TIMERS, {}-BIT COUNTER", info.num_comparators(), if hpet.count64
+	{
+		64
+	}
+	else
+	{
+		32
+	});
+
+	clocksource::register_source(hpet);
+	clocksource::register_event(hpet);
+
+	// Now that a more accurate reference is available
+	clocksource::calibrate_tsc();
+}
@@ -10,9 +10,9 @@
 use core::arch::asm;
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::{structures::idt::{InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode}, instructions::{interrupts, port::Port}};
+use x86_64::{VirtAddr, registers::control::Cr2, structures::idt::{InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode}, instructions::{interrupts, port::Port}};
 
-use crate::{print, println, sys::proc::Reg};
+use crate::{print, println, serprintln, sys::proc::Reg};
 
 
 // P1 constant
@@ -21,6 +21,16 @@ pub const P1: u16 = 0x21;
 // P2 constant
 pub const P2: u16 = 0xA1;
 
+// First vector handed out for message-signalled interrupts
+pub const MSI_VECTOR_BASE: u8 = 0x30;
+
+// Number of vectors for message-signalled interrupts
+pub const MSI_VECTORS: usize = 16;
+
+
+// Handlers of the message-signalled interrupt vectors
+type VecHandlers = [Option<fn()>; MSI_VECTORS];
+
 
 // Lazy static wrapper around the IR_HANDLES reference
 lazy_static!
@@ -28,6 +38,9 @@ lazy_static!
 	// Establishes the IR_HANDLERS reference, which is based on the def_iq_handler function
 	pub static ref IR_HANDLERS: Mutex<[fn(); 16]> = Mutex::new([def_ir_handler; 16]);
 
+	// Handlers of the vectors used by message-signalled interrupts (None, while a vector is free)
+	pub static ref VEC_HANDLERS: Mutex<VecHandlers> = Mutex::new([None; MSI_VECTORS]);
+
 
 	// The IDT static reference
 	static ref IDT: InterruptDescriptorTable =
@@ -47,7 +60,7 @@ lazy_static!
 
 			// Page-faults
 			idt.page_fault.
-				set_handler_fn(page_fault_handler).
+				set_handler_fn(core::mem::transmute(wrapped_page_fault_handler as *mut fn())).
 				set_stack_index(crate::sys::gdt::PAGE_FAULT_ISTIDX);
 
 			// General protection faults
@@ -55,10 +68,9 @@ lazy_static!
 				set_handler_fn(gen_prot_fault_handler).
 				set_stack_index(crate::sys::gdt::GEN_PROT_FAULT_ISTIDX);
 
-			// [0x80]
+			// [0x80] (system calls of processes run on their own kernel stack, see gdt::set_kernel_stack)
 			idt[0x80].
 				set_handler_fn(core::mem::transmute(wrapped_sch as *mut fn())).
-				set_stack_index(crate::sys::gdt::DOUBLEFAULT_IST_IDX).
 				set_privilege_level(x86_64::PrivilegeLevel::Ring3);
 		}
 
@@ -111,6 +123,22 @@ lazy_static!
 		// Interrupt index: 15
 		idt[intridx(15) as usize].set_handler_fn(ir15h);
 
+		// Vectors for message-signalled interrupts
+		let vechs: [extern "x86-interrupt" fn(InterruptStackFrame); MSI_VECTORS] = [vec0h, vec1h, vec2h, vec3h, vec4h, vec5h, vec6h, vec7h, vec8h, vec9h, vec10h, vec11h, vec12h, vec13h, vec14h, vec15h];
+		for (i, handler) in vechs.iter().enumerate()
+		{
+			idt[MSI_VECTOR_BASE as usize + i].set_handler_fn(*handler);
+		}
+
+		// Spurious interrupts from the local APIC
+		idt[crate::sys::apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
+
+		// Wake-up IPIs, sent to idle processors
+		idt[crate::sys::smp::WAKEUP_VECTOR as usize].set_handler_fn(wakeup_handler);
+
+		// TLB shootdowns, sent by the processor that unmapped the pages
+		idt[crate::sys::smp::TLB_VECTOR as usize].set_handler_fn(tlb_handler);
+
 		// Set stack-segment fault-handler
 		idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
 
@@ -153,8 +181,16 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame)
 
 
 // Double fault
+//
+// Reports the stack that overflowed, if the fault (or the stack pointer) is in the guard page below one.
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+	let address = Cr2::read_raw();
+	if let Some(stack) = crate::sys::gdt::overflowed(address).or_else(|| crate::sys::gdt::overflowed(stack_frame.stack_pointer.as_u64()))
+	{
+		serprintln!("[ERR] STACK OVERFLOW: {} ({:#X}-{:#X}), FAULT AT {:#X}", stack, stack.bottom, stack.top, address);
+		panic!("[ERR] DOUBLE FAULT: {} OVERFLOWED\n{:#?}", stack, stack_frame);
+	}
 	panic!("[ERR] DOUBLE FAULT: \n{:#?}", stack_frame);
 }
 
@@ -166,24 +202,29 @@ extern "x86-interrupt" fn gen_prot_fault_handler(stack_frame: InterruptStackFram
 }
 
 
-// Page fault
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+// Segment not found
+extern "x86-interrupt" fn seg_not_found_handler(stack_frame: InterruptStackFrame, _error_code: u64)
 {
-	let ip = stack_frame.instruction_pointer.as_ptr();
-	let inst: [u8; 8] = unsafe
-	{
-		core::ptr::read(ip)
-	};
+	panic!("[ERR] SEGMENT NOT FOUND\n{:#?}", stack_frame);
+}
+
+
+// Spurious interrupt (these are not acknowledged)
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
 
-	println!("[INFO] CODE: {:?}", inst);
-	panic!("[ERR] PAGE FAULT: \n{:#?}\n{:#?}", stack_frame, error_code);
+
+// Wake-up IPI (the processor only needs to leave its halted state)
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame)
+{
+	crate::sys::apic::eoi();
 }
 
 
-// Segment not found
-extern "x86-interrupt" fn seg_not_found_handler(stack_frame: InterruptStackFrame, _error_code: u64)
+// TLB shootdown IPI
+extern "x86-interrupt" fn tlb_handler(_stack_frame: InterruptStackFrame)
 {
-	panic!("[ERR] SEGMENT NOT FOUND\n{:#?}", stack_frame);
+	crate::sys::smp::tlb_service();
+	crate::sys::apic::eoi();
 }
 
 
@@ -194,6 +235,23 @@ extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStac
 }
 
 
+// Signal the end of an interrupt-request to the active interrupt controller
+fn intrend(ir: u8)
+{
+	if crate::sys::apic::enabled()
+	{
+		crate::sys::apic::eoi();
+	}
+	else
+	{
+		unsafe
+		{
+			crate::pic::PICS.lock().notify_intrend(intridx(ir));
+		}
+	}
+}
+
+
 // Interrupt-request handler macro
 macro_rules! irh
 {
@@ -201,13 +259,11 @@ macro_rules! irh
 	{
 		pub extern "x86-interrupt" fn $handler(_stack_frame: InterruptStackFrame)
 		{
+			crate::sys::rand::mix_interrupt($ir);
 			let handlers = IR_HANDLERS.lock();
 			handlers[$ir]();
 
-			unsafe
-			{
-				crate::pic::PICS.lock().notify_intrend(intridx($ir));
-			}
+			intrend($ir);
 		}
 	};
 }
@@ -265,12 +321,76 @@ irh!(ir15h, 15);
 
 
 
+// Vector handler macro (message-signalled interrupts are always delivered by the local APIC)
+macro_rules! vech
+{
+	($handler: ident, $idx:expr) =>
+	{
+		pub extern "x86-interrupt" fn $handler(_stack_frame: InterruptStackFrame)
+		{
+			crate::sys::rand::mix_interrupt(0x10 + $idx);
+			if let Some(handler) = VEC_HANDLERS.lock()[$idx]
+			{
+				handler();
+			}
+
+			crate::sys::apic::eoi();
+		}
+	};
+}
+
+
+// Vector handlers
+vech!(vec0h, 0);
+vech!(vec1h, 1);
+vech!(vec2h, 2);
+vech!(vec3h, 3);
+vech!(vec4h, 4);
+vech!(vec5h, 5);
+vech!(vec6h, 6);
+vech!(vec7h, 7);
+vech!(vec8h, 8);
+vech!(vec9h, 9);
+vech!(vec10h, 10);
+vech!(vec11h, 11);
+vech!(vec12h, 12);
+vech!(vec13h, 13);
+vech!(vec14h, 14);
+vech!(vec15h, 15);
+
+
+// Allocate a vector for a message-signalled interrupt
+pub fn alloc_vector(handler: fn()) -> Option<u8>
+{
+	interrupts::without_interrupts(||
+	{
+		let mut handlers = VEC_HANDLERS.lock();
+		let idx = handlers.iter().position(|handler| handler.is_none())?;
+		handlers[idx] = Some(handler);
+		Some(MSI_VECTOR_BASE + idx as u8)
+	})
+}
+
+
+// Free a vector allocated with alloc_vector
+pub fn free_vector(vector: u8)
+{
+	interrupts::without_interrupts(||
+	{
+		if let Some(handler) = VEC_HANDLERS.lock().get_mut(vector.wrapping_sub(MSI_VECTOR_BASE) as usize)
+		{
+			*handler = None;
+		}
+	});
+}
+
+
 // Wrap macro
 macro_rules! wrap
 {
 	($fn: ident => $w:ident) =>
 	{
-//		#[naked]
+		#[naked]
 		pub unsafe extern "sysv64" fn $w()
 		{
 			asm!(
@@ -305,9 +425,166 @@ macro_rules! wrap
 }
 
 
+// Wrap macro for exceptions that push an error code (it is passed as the third argument, and dropped on return)
+macro_rules! wrap_err
+{
+	($fn: ident => $w:ident) =>
+	{
+		#[naked]
+		unsafe extern "sysv64" fn $w()
+		{
+			asm!(
+				"push rax",
+				"push rcx",
+				"push rdx",
+				"push rsi",
+				"push rdi",
+				"push r8",
+				"push r9",
+				"push r10",
+				"push r11",
+				"mov rsi, rsp",
+				"mov rdi, rsp",
+				"add rdi, 10 * 8",
+				"mov rdx, [rsp + 9 * 8]",
+				"sub rsp, 8",
+				"call {}",
+				"add rsp, 8",
+				"pop r11",
+				"pop r10",
+				"pop r9",
+				"pop r8",
+				"pop rdi",
+				"pop rsi",
+				"pop rdx",
+				"pop rcx",
+				"pop rax",
+				"add rsp, 8",
+				"iretq",
+				sym $fn,
+				options(noreturn)
+			);
+		}
+	}
+}
+
+
 // Wrap sch, convert into wrapped_sch
 wrap!(sch => wrapped_sch);
 
+// Wrap page_fault_handler, convert into wrapped_page_fault_handler
+wrap_err!(page_fault_handler => wrapped_page_fault_handler);
+
+
+// Resume the parent of a process that has exited (where it spawned the process)
+fn resume_parent(stack_frame: &mut InterruptStackFrame, reg: &mut Reg)
+{
+	let stackframe = crate::sys::proc::sf();
+
+	unsafe
+	{
+		core::ptr::write_volatile(stack_frame.as_mut().extract_inner() as *mut InterruptStackFrameValue, stackframe);
+		core::ptr::write_volatile(reg, crate::sys::proc::reg());
+	}
+	crate::sys::proc::restorefpu();
+}
+
+
+// Page fault
+//
+// Faults of a process on pages it has not touched yet are resolved. A process that makes an invalid access is
+// killed, and any other fault of the kernel is an oops.
+extern "sysv64" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, reg: &mut Reg, error_code: u64)
+{
+	let address = Cr2::read();
+	let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+	let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+	let sp = if user
+	{
+		Some(stack_frame.stack_pointer)
+	}
+	else
+	{
+		None
+	};
+
+	if crate::sys::proc::page_fault(address, sp, error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)).is_ok()
+	{
+		return;
+	}
+
+	if !user
+	{
+		oops(stack_frame, error_code, address);
+	}
+
+	let (access, page) = describe(error_code);
+	let pid = crate::sys::proc::id();
+	println!("[ERR] PROCESS {} KILLED: {} OF {:#X} ({} PAGE) AT {:#X}", pid, access, address.as_u64(), page, stack_frame.instruction_pointer.as_u64());
+
+	crate::sys::proc::exit();
+	crate::sys::proc::reap(pid);
+	resume_parent(stack_frame, reg);
+
+	// The parent's spawn system call fails
+	reg.rax = usize::MAX;
+}
+
+
+// Kernel oops (a page fault of the kernel itself)
+fn oops(stack_frame: &InterruptStackFrame, error_code: PageFaultErrorCode, address: VirtAddr) -> !
+{
+	let (access, page) = describe(error_code);
+	if let Some(stack) = crate::sys::gdt::overflowed(address.as_u64())
+	{
+		serprintln!("[ERR] STACK OVERFLOW: {} ({:#X}-{:#X})", stack, stack.bottom, stack.top);
+	}
+	serprintln!("[ERR] KERNEL OOPS: {} OF {:#X} ({} PAGE)", access, address.as_u64(), page);
+	serprintln!("[ERR] CPU {}, PROCESS {}, ERROR CODE {:?}", crate::sys::smp::cpu().id(), crate::sys::proc::id(), error_code);
+
+	// The code is only shown if it can be read
+	let ip = stack_frame.instruction_pointer;
+	if crate::mem::vtop(ip).is_some() && crate::mem::vtop(ip + 7u64).is_some()
+	{
+		let inst: [u8; 8] = unsafe
+		{
+			core::ptr::read(ip.as_ptr())
+		};
+		serprintln!("[ERR] CODE: {:02X?}", inst);
+	}
+
+	panic!("[ERR] KERNEL OOPS: PAGE FAULT AT {:#X}\n{:#?}", address.as_u64(), stack_frame);
+}
+
+
+// Describe a page fault (the kind of access, and the state of the page)
+fn describe(error_code: PageFaultErrorCode) -> (&'static str, &'static str)
+{
+	let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+	{
+		"EXECUTION"
+	}
+	else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+	{
+		"WRITE"
+	}
+	else
+	{
+		"READ"
+	};
+
+	let page = if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+	{
+		"PROTECTED"
+	}
+	else
+	{
+		"NOT-PRESENT"
+	};
+
+	(access, page)
+}
+
 
 // sch
 extern "sysv64" fn sch(stack_frame: &mut InterruptStackFrame, reg: &mut Reg)
@@ -324,19 +601,23 @@ extern "sysv64" fn sch(stack_frame: &mut InterruptStackFrame, reg: &mut Reg)
 		crate::sys::proc::setreg(*reg);
 	}
 
-	let res = crate::sys::sc::dispatch(n, a1, a2, a3);
+	// Save the FPU state of the parent, which the new process may clobber
+	if n == crate::sys::sc::SPAWN
+	{
+		crate::sys::proc::savefpu();
+	}
+
+	let pid = crate::sys::proc::id();
+
+	// The arguments of system calls point into the pages of the process
+	let res = crate::sys::cpu::user_access(|| crate::sys::sc::dispatch(n, a1, a2, a3));
 
 
 	// Restore from backup
 	if n == crate::sys::sc::EXIT
 	{
-		let stackframe = crate::sys::proc::sf();
-
-		unsafe
-		{
-			core::ptr::write_volatile(stack_frame.as_mut().extract_inner() as *mut InterruptStackFrameValue, stackframe);
-			core::ptr::write_volatile(reg, crate::sys::proc::reg());
-		}
+		crate::sys::proc::reap(pid);
+		resume_parent(stack_frame, reg);
 	}
 
 	reg.rax = res;
@@ -351,6 +632,12 @@ extern "sysv64" fn sch(stack_frame: &mut InterruptStackFrame, reg: &mut Reg)
 // Clear interrupt-request mask
 pub fn clr_irmask(ir: u8)
 {
+	if crate::sys::apic::enabled()
+	{
+		crate::sys::apic::setmask(ir, false);
+		return;
+	}
+
 	let mut port: Port<u8> = Port::new(if ir < 8
 	{
 		P1
@@ -393,6 +680,12 @@ pub fn set_irh(ir: u8, handler: fn())
 // Set interrupt-request mask
 pub fn set_irmask(ir: u8)
 {
+	if crate::sys::apic::enabled()
+	{
+		crate::sys::apic::setmask(ir, true);
+		return;
+	}
+
 	let mut port: Port<u8> = Port::new(if ir < 8
 	{
 		P1
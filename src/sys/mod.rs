@@ -6,6 +6,9 @@
 // ACPI stuff.
 pub mod acpi;
 
+// Local APIC and I/O APIC
+pub mod apic;
+
 // Booting the kernel, bootloader support, etc.
 pub mod boot;
 
@@ -18,6 +21,9 @@ pub mod cpu;
 // Global descriptor table (GDT)
 pub mod gdt;
 
+// High precision event timer (HPET)
+pub mod hpet;
+
 // Interrupt descriptor table (IDT)
 pub mod idt;
 
@@ -30,6 +36,9 @@ pub mod math;
 // PCI
 pub mod pci;
 
+// Reboot, halt and power-off
+pub mod power;
+
 // System processes
 pub mod proc;
 
@@ -45,6 +54,9 @@ pub mod rsdp;
 // Syscalls
 pub mod sc;
 
+// Symmetric multiprocessing
+pub mod smp;
+
 // Tasking
 pub mod task;
 
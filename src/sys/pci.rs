@@ -7,23 +7,129 @@
 	IMPORTS
 */
 
+use acpi::PciConfigRegions;
 use alloc::vec::Vec;
 use bit_field::BitField;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::port::Port;
+use spin::{Mutex, RwLock};
+use x86_64::{PhysAddr, instructions::port::Port};
 
-use crate::println;
+use crate::{mem::vma::{ioremap, iounmap, CacheMode}, println, serprintln};
+
+
+/*
+	CONSTANTS
+*/
+
+// Size of the configuration space of a function, through port I/O
+pub const CONFIG_SIZE: usize = 256;
+
+// Size of the configuration space of a function, through ECAM (PCIe)
+pub const EXT_CONFIG_SIZE: usize = 4096;
+
+// Capability IDs
+pub const CAP_PM: u16 = 0x01;
+pub const CAP_MSI: u16 = 0x05;
+pub const CAP_VENDOR: u16 = 0x09;
+pub const CAP_PCIE: u16 = 0x10;
+pub const CAP_MSIX: u16 = 0x11;
+
+// Largest number of capabilities followed in a list (protects against malformed, looping lists)
+const MAX_CAPS: usize = 64;
+
+// Base address of MSI messages (the local APIC ID goes in bits 12-19)
+const MSI_ADDRESS: u64 = 0xFEE0_0000;
+
+// ACPI path of the host bridge, whose _PRT routes the interrupt pins of the devices on the root bus
+const HOST_BRIDGE: &str = "\\_SB.PCI0";
 
 
 // PCIDEV
 lazy_static!
 {
 	pub static ref PCIDEV: Mutex<Vec<DevConfig>> = Mutex::new(Vec::new());
+
+	// Memory-mapped configuration regions (ECAM), as described by the MCFG table
+	static ref ECAM: RwLock<Option<PciConfigRegions>> = RwLock::new(None);
+
+	// Registered drivers
+	static ref DRIVERS: Mutex<Vec<Driver>> = Mutex::new(Vec::new());
+
+	// Functions that have been bound to a driver (bus, device, function)
+	static ref BOUND: Mutex<Vec<(u8, u8, u8)>> = Mutex::new(Vec::new());
+}
+
+
+// Bar enumeration (a decoded base address register)
+#[derive(Debug, Clone, Copy)]
+pub enum Bar
+{
+	// I/O space
+	Io
+	{
+		port: u16,
+		size: u32,
+	},
+
+	// Memory space (32-bit or 64-bit)
+	Mem
+	{
+		address: u64,
+		size: u64,
+		bits64: bool,
+		prefetchable: bool,
+	},
+}
+
+
+// Capability struct
+#[derive(Debug, Clone, Copy)]
+pub struct Capability
+{
+	// Capability ID
+	pub id: u16,
+
+	// Offset in the configuration space
+	pub offset: u16,
+
+	// Whether or not this is a PCIe extended capability (located past the first 256 bytes)
+	pub extended: bool,
+}
+
+
+// Capabilities struct (iterator over the capability list, followed by the extended capabilities)
+pub struct Capabilities
+{
+	dev: DevConfig,
+	next: u16,
+	extended: bool,
+	stdonly: bool,
+	count: usize,
+}
+
+
+// Driver struct
+//
+// A driver is bound to the functions matching its vendor/device IDs, or its class/subclass. Fields
+// set to None match anything.
+#[derive(Debug, Clone, Copy)]
+pub struct Driver
+{
+	pub name: &'static str,
+	pub vid: Option<u16>,
+	pub did: Option<u16>,
+	pub class: Option<(u8, u8)>,
+
+	// Called for every matching function; returns Ok if the function has been claimed
+	pub probe: fn(DevConfig) -> Result<(), ()>,
 }
 
 
 // ConfigReg struct
+//
+// A dword of the configuration space of a function. When the MCFG table describes the bus, the
+// register is accessed through ECAM (which reaches the full 4 KiB); otherwise, ports 0xCF8/0xCFC are
+// used, which only reach the first 256 bytes.
 pub struct ConfigReg
 {
 	// Address
@@ -34,6 +140,12 @@ pub struct ConfigReg
 
 	// Data port
 	dport: Port<u32>,
+
+	// Virtual address of the register (ECAM)
+	mmio: Option<u64>,
+
+	// Offset of the register
+	offset: u16,
 }
 
 
@@ -89,13 +201,21 @@ pub struct DevConfig
 impl ConfigReg
 {
 	// New
-	pub fn new(bus: u8, dev: u8, func: u8, offset: u8) -> Self
+	pub fn new(bus: u8, dev: u8, func: u8, offset: u16) -> Self
 	{
+		let offset = offset & 0xFFC;
+		let mmio = ECAM.read().as_ref().and_then(|regions| regions.physical_address(0, bus, dev, func)).map(|address|
+		{
+			crate::mem::ptov(PhysAddr::new(address)).as_u64() + offset as u64
+		});
+
 		Self
 		{
-			dport: Port::new(0xCDC),
+			dport: Port::new(0xCFC),
 			aport: Port::new(0xCF8),
 			address: 0x8000_0000 | ((bus as u32) << 16) | ((dev as u32) << 11) | ((func as u32) << 8) | ((offset as u32) & 0xFC),
+			mmio,
+			offset,
 		}
 	}
 
@@ -103,6 +223,20 @@ impl ConfigReg
 	// Read
 	pub fn read(&mut self) -> u32
 	{
+		if let Some(address) = self.mmio
+		{
+			return unsafe
+			{
+				core::ptr::read_volatile(address as *const u32)
+			};
+		}
+
+		// Extended registers cannot be reached through port I/O
+		if self.offset as usize >= CONFIG_SIZE
+		{
+			return 0xFFFF_FFFF;
+		}
+
 		unsafe
 		{
 			self.aport.write(self.address);
@@ -114,6 +248,20 @@ impl ConfigReg
 	// Write
 	pub fn write(&mut self, data: u32)
 	{
+		if let Some(address) = self.mmio
+		{
+			unsafe
+			{
+				core::ptr::write_volatile(address as *mut u32, data);
+			}
+			return;
+		}
+
+		if self.offset as usize >= CONFIG_SIZE
+		{
+			return;
+		}
+
 		unsafe
 		{
 			self.aport.write(self.address);
@@ -157,7 +305,7 @@ impl DevConfig
 
 		for i in 0..6
 		{
-			let offset = 0x10 + ((i as u8) << 2);
+			let offset = 0x10 + ((i as u16) << 2);
 			let mut reg = ConfigReg::new(bus, dev, func, offset);
 			base_addresses[i] = reg.read();
 		}
@@ -182,6 +330,310 @@ impl DevConfig
 		}
 	}
 
+	// Size of the configuration space (4 KiB if it can be reached through ECAM)
+	pub fn config_size(&self) -> usize
+	{
+		if ecam(self.bus)
+		{
+			EXT_CONFIG_SIZE
+		}
+		else
+		{
+			CONFIG_SIZE
+		}
+	}
+
+
+	// Read the whole configuration space
+	pub fn config_space(&self) -> Vec<u8>
+	{
+		let mut space = Vec::with_capacity(self.config_size());
+		for offset in (0..self.config_size()).step_by(4)
+		{
+			space.extend_from_slice(&self.read(offset as u16).to_le_bytes());
+		}
+		space
+	}
+
+
+	// Read a dword of the configuration space
+	pub fn read(&self, offset: u16) -> u32
+	{
+		ConfigReg::new(self.bus, self.dev, self.func, offset).read()
+	}
+
+
+	// Write a dword of the configuration space
+	pub fn write(&self, offset: u16, data: u32)
+	{
+		ConfigReg::new(self.bus, self.dev, self.func, offset).write(data);
+	}
+
+
+	// Read a byte of the configuration space
+	pub fn read_u8(&self, offset: u16) -> u8
+	{
+		self.read(offset & !3).get_bits(((offset & 3) as usize * 8)..((offset & 3) as usize * 8 + 8)) as u8
+	}
+
+
+	// Read a word of the configuration space
+	pub fn read_u16(&self, offset: u16) -> u16
+	{
+		self.read(offset & !3).get_bits(((offset & 2) as usize * 8)..((offset & 2) as usize * 8 + 16)) as u16
+	}
+
+
+	// Write a word of the configuration space
+	pub fn write_u16(&self, offset: u16, data: u16)
+	{
+		let mut dword = self.read(offset & !3);
+		dword.set_bits(((offset & 2) as usize * 8)..((offset & 2) as usize * 8 + 16), data as u32);
+		self.write(offset & !3, dword);
+	}
+
+
+	// Capabilities
+	pub fn capabilities(&self) -> Capabilities
+	{
+		// Bit 4 of the status register signals a capability list
+		let next = if self.read_u16(0x06).get_bit(4)
+		{
+			(self.read_u8(0x34) & 0xFC) as u16
+		}
+		else
+		{
+			0
+		};
+
+		Capabilities
+		{
+			dev: *self,
+			next,
+			extended: false,
+			stdonly: false,
+			count: 0,
+		}
+	}
+
+
+	// Offset of a capability (from the standard list)
+	pub fn capability(&self, id: u16) -> Option<u16>
+	{
+		let mut caps = self.capabilities();
+		caps.stdonly = true;
+		caps.find(|cap| cap.id == id).map(|cap| cap.offset)
+	}
+
+
+	// Offset of an extended capability
+	pub fn ext_capability(&self, id: u16) -> Option<u16>
+	{
+		self.capabilities().find(|cap| cap.extended && cap.id == id).map(|cap| cap.offset)
+	}
+
+
+	// Decode (and size) a base address register
+	//
+	// The size is found by writing all ones to the register, with decoding disabled in the command
+	// register meanwhile. The upper half of a 64-bit BAR is the following register.
+	pub fn bar(&self, idx: usize) -> Option<Bar>
+	{
+		if idx >= 6
+		{
+			return None;
+		}
+
+		let offset = 0x10 + 4 * idx as u16;
+		let low = self.read(offset);
+		let cmd = self.read_u16(0x04);
+
+		self.write_u16(0x04, cmd & !0b11);
+
+		let bar = if low.get_bit(0)
+		{
+			self.write(offset, 0xFFFF_FFFF);
+			let mask = self.read(offset) & 0xFFFF_FFFC;
+			self.write(offset, low);
+
+			(mask != 0).then(|| Bar::Io
+			{
+				port: (low & 0xFFFC) as u16,
+				size: (!mask | 0xFFFF_0000).wrapping_add(1),
+			})
+		}
+		else
+		{
+			let bits64 = low.get_bits(1..3) == 2;
+			let prefetchable = low.get_bit(3);
+
+			self.write(offset, 0xFFFF_FFFF);
+			let mut mask = (self.read(offset) & 0xFFFF_FFF0) as u64;
+			self.write(offset, low);
+
+			let mut address = (low & 0xFFFF_FFF0) as u64;
+
+			if bits64 && idx < 5
+			{
+				let high = self.read(offset + 4);
+				self.write(offset + 4, 0xFFFF_FFFF);
+				mask |= (self.read(offset + 4) as u64) << 32;
+				self.write(offset + 4, high);
+				address |= (high as u64) << 32;
+			}
+			else
+			{
+				mask |= 0xFFFF_FFFF_0000_0000;
+			}
+
+			(mask as u32 != 0).then(|| Bar::Mem
+			{
+				address,
+				size: (!mask).wrapping_add(1),
+				bits64,
+				prefetchable,
+			})
+		};
+
+		self.write_u16(0x04, cmd);
+		bar
+	}
+
+
+	// Enable MSI, delivering the given vector to the current CPU
+	pub fn enable_msi(&self, vector: u8) -> Result<(), ()>
+	{
+		let cap = self.capability(CAP_MSI).ok_or(())?;
+		let (address, data) = msi_message(vector);
+		let mut ctrl = self.read_u16(cap + 2);
+
+		self.write(cap + 4, address as u32);
+		let data_offset = if ctrl.get_bit(7)
+		{
+			self.write(cap + 8, (address >> 32) as u32);
+			cap + 12
+		}
+		else
+		{
+			cap + 8
+		};
+		self.write_u16(data_offset, data as u16);
+
+		// A single message, enabled
+		ctrl.set_bits(4..7, 0);
+		ctrl.set_bit(0, true);
+		self.write_u16(cap + 2, ctrl);
+
+		self.disable_intx();
+		Ok(())
+	}
+
+
+	// Enable MSI-X, delivering the given vector (through one entry of the table) to the current CPU
+	pub fn enable_msix(&self, entry: u16, vector: u8) -> Result<(), ()>
+	{
+		let cap = self.capability(CAP_MSIX).ok_or(())?;
+		let mut ctrl = self.read_u16(cap + 2);
+		if entry > ctrl.get_bits(0..11)
+		{
+			return Err(());
+		}
+
+		let table = self.read(cap + 4);
+		let base = match self.bar(table.get_bits(0..3) as usize)
+		{
+			Some(Bar::Mem { address, .. }) => address + (table & !7) as u64,
+			_ => return Err(()),
+		};
+
+		let (address, data) = msi_message(vector);
+		let entry = ioremap(PhysAddr::new(base + 16 * entry as u64), 16, CacheMode::Uncached).ok_or(())?;
+		let ptr = entry.as_mut_ptr::<u32>();
+
+		unsafe
+		{
+			core::ptr::write_volatile(ptr, address as u32);
+			core::ptr::write_volatile(ptr.add(1), (address >> 32) as u32);
+			core::ptr::write_volatile(ptr.add(2), data);
+
+			// Unmask the entry
+			core::ptr::write_volatile(ptr.add(3), 0);
+		}
+		iounmap(entry);
+
+		// Enabled, with the function unmasked
+		ctrl.set_bit(15, true);
+		ctrl.set_bit(14, false);
+		self.write_u16(cap + 2, ctrl);
+
+		self.disable_intx();
+		Ok(())
+	}
+
+
+	// Checks whether or not MSI-X has been enabled
+	pub fn msix_enabled(&self) -> bool
+	{
+		self.capability(CAP_MSIX).map_or(false, |cap| self.read_u16(cap + 2).get_bit(15))
+	}
+
+
+	// Set up a message-signalled interrupt (MSI-X, or else MSI), returning the vector it uses
+	pub fn enable_msintr(&self, handler: fn()) -> Option<u8>
+	{
+		if !crate::sys::apic::enabled()
+		{
+			return None;
+		}
+
+		let vector = crate::sys::idt::alloc_vector(handler)?;
+		if self.enable_msix(0, vector).is_ok() || self.enable_msi(vector).is_ok()
+		{
+			Some(vector)
+		}
+		else
+		{
+			crate::sys::idt::free_vector(vector);
+			None
+		}
+	}
+
+
+	// Set up the legacy (pin-based) interrupt through the I/O APIC, returning the vector it uses
+	//
+	// The pin is routed as the _PRT of the host bridge says (level-triggered and active-low, unless it goes through
+	// a link device configured otherwise), so that a line shared by several devices keeps firing until all of them
+	// have been serviced. Only devices on the root bus can be routed this way.
+	pub fn enable_intx(&self, handler: fn()) -> Option<u8>
+	{
+		if !crate::sys::apic::enabled() || self.bus != 0
+		{
+			return None;
+		}
+
+		let (gsi, level, lowactive) = crate::sys::acpi::pci_route(HOST_BRIDGE, self.dev, self.func, self.intr_pin)?;
+		let vector = crate::sys::idt::alloc_vector(handler)?;
+		if crate::sys::apic::route(gsi, vector, level, lowactive).is_ok()
+		{
+			Some(vector)
+		}
+		else
+		{
+			crate::sys::idt::free_vector(vector);
+			None
+		}
+	}
+
+
+	// Disable the legacy (pin-based) interrupt
+	pub fn disable_intx(&self)
+	{
+		let mut cmd = self.read_u16(0x04);
+		cmd.set_bit(10, true);
+		self.write_u16(0x04, cmd);
+	}
+
+
 	// Enable bus-mastering
 	pub fn enable_busmast(&mut self)
 	{
@@ -193,6 +645,109 @@ impl DevConfig
 }
 
 
+// Implementation of the Iterator trait for the Capabilities struct
+impl Iterator for Capabilities
+{
+	type Item = Capability;
+
+	fn next(&mut self) -> Option<Capability>
+	{
+		loop
+		{
+			if self.count >= MAX_CAPS
+			{
+				return None;
+			}
+
+			if self.next == 0
+			{
+				// Extended capabilities start at 0x100, for PCIe functions reachable through ECAM
+				if self.extended || self.stdonly || self.dev.config_size() != EXT_CONFIG_SIZE || self.dev.capability(CAP_PCIE).is_none()
+				{
+					return None;
+				}
+
+				self.extended = true;
+				self.count = 0;
+				self.next = CONFIG_SIZE as u16;
+				continue;
+			}
+
+			self.count += 1;
+			let offset = self.next;
+
+			if self.extended
+			{
+				let header = self.dev.read(offset);
+				if header == 0 || header == 0xFFFF_FFFF
+				{
+					self.next = 0;
+					self.count = MAX_CAPS;
+					return None;
+				}
+
+				self.next = (header.get_bits(20..32) as u16) & 0xFFC;
+				return Some(Capability
+				{
+					id: header.get_bits(0..16) as u16,
+					offset,
+					extended: true,
+				});
+			}
+
+			self.next = (self.dev.read_u8(offset + 1) & 0xFC) as u16;
+			return Some(Capability
+			{
+				id: self.dev.read_u8(offset) as u16,
+				offset,
+				extended: false,
+			});
+		}
+	}
+}
+
+
+// Implementation of the Driver struct
+impl Driver
+{
+	// Driver for a vendor/device ID
+	pub fn id(name: &'static str, vid: u16, did: u16, probe: fn(DevConfig) -> Result<(), ()>) -> Self
+	{
+		Self
+		{
+			name,
+			vid: Some(vid),
+			did: Some(did),
+			class: None,
+			probe,
+		}
+	}
+
+
+	// Driver for a class/subclass
+	pub fn class(name: &'static str, class: u8, subclass: u8, probe: fn(DevConfig) -> Result<(), ()>) -> Self
+	{
+		Self
+		{
+			name,
+			vid: None,
+			did: None,
+			class: Some((class, subclass)),
+			probe,
+		}
+	}
+
+
+	// Checks whether or not the driver handles a function
+	pub fn matches(&self, dev: &DevConfig) -> bool
+	{
+		self.vid.map_or(true, |vid| vid == dev.vid)
+			&& self.did.map_or(true, |did| did == dev.did)
+			&& self.class.map_or(true, |(class, subclass)| class == dev.class && subclass == dev.subclass)
+	}
+}
+
+
 // Check bus
 pub fn checkbus(bus: u8)
 {
@@ -234,6 +789,34 @@ pub fn checkdev(bus: u8, dev: u8)
 }
 
 
+// Whether or not a bus can be reached through ECAM
+pub fn ecam(bus: u8) -> bool
+{
+	ECAM.read().as_ref().map_or(false, |regions| regions.physical_address(0, bus, 0, 0).is_some())
+}
+
+
+// Enhanced configuration access mechanism (ECAM) initialization
+fn ecam_init()
+{
+	let regions = crate::sys::acpi::with_tables(|tables| PciConfigRegions::new(tables).ok()).flatten();
+
+	match regions
+	{
+		Some(regions) =>
+		{
+			*ECAM.write() = Some(regions);
+			serprintln!("[INFO] PCI: USING ECAM (MCFG)");
+		},
+
+		None =>
+		{
+			serprintln!("[INFO] PCI: NO MCFG TABLE, USING PORT I/O");
+		},
+	}
+}
+
+
 // Find device
 pub fn find_dev(vid: u16, did: u16) -> Option<DevConfig>
 {
@@ -275,41 +858,15 @@ pub fn get_vid(bus: u8, dev: u8, func: u8) -> u16
 // Initialization
 pub fn init()
 {
+	ecam_init();
+
 	for bus in 0..256
 	{
 		checkbus(bus as u8);
 	}
-
-	let devs = PCIDEV.lock();
-	for dev in devs.iter()
-	{
-		if dev.class == 0x01 && dev.subclass == 0x01
-		{
-			let mut reg = ConfigReg::new(dev.bus, dev.dev, dev.func, 0x08);
-			let mut data = reg.read();
-			let prog_offset = 8;
-
-			if dev.prog.get_bit(0)
-			{
-				if dev.prog.get_bit(1)
-				{
-					data.set_bit(prog_offset, false);
-					reg.write(data);
-				}
-			}
-
-			if dev.prog.get_bit(2)
-			{
-				if dev.prog.get_bit(3)
-				{
-					data.set_bit(prog_offset + 2, false);
-					reg.write(data);
-				}
-			}
-		}
-	}
 }
 
+
 // List
 pub fn ls() -> Vec<DevConfig>
 {
@@ -317,6 +874,35 @@ pub fn ls() -> Vec<DevConfig>
 }
 
 
+// Message (address and data) of an MSI delivering a vector to the current CPU
+pub fn msi_message(vector: u8) -> (u64, u32)
+{
+	(MSI_ADDRESS | (crate::sys::apic::id() as u64) << 12, vector as u32)
+}
+
+
+// Register a driver, and probe the functions that it matches (and that are not bound yet)
+pub fn register(driver: Driver)
+{
+	DRIVERS.lock().push(driver);
+
+	for dev in ls()
+	{
+		let id = (dev.bus, dev.dev, dev.func);
+		if !driver.matches(&dev) || BOUND.lock().contains(&id)
+		{
+			continue;
+		}
+
+		if (driver.probe)(dev).is_ok()
+		{
+			BOUND.lock().push(id);
+			serprintln!("[INFO] PCI {:02X}:{:02X}.{} BOUND TO {}", dev.bus, dev.dev, dev.func, driver.name);
+		}
+	}
+}
+
+
 // Create a new device
 pub fn new_dev(bus: u8, dev: u8, func: u8)
 {
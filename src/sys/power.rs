@@ -0,0 +1,115 @@
+// src/sys/power.rs
+//
+// Orderly reboot, halt, suspend and power-off of the system.
+
+/*
+	IMPORTS
+*/
+
+use x86_64::{VirtAddr, instructions::{self, interrupts, port::Port, tables::lidt}, structures::DescriptorTablePointer};
+
+use crate::println;
+
+
+/*
+	CONSTANTS
+*/
+
+// Keyboard controller
+const KBC_STATUS: u16 = 0x64;
+const KBC_RESET: u8 = 0xFE;
+
+
+// Prepare (flushes the filesystems, before the machine goes down)
+fn prepare()
+{
+	println!("[INFO] FLUSHING FILESYSTEMS");
+	if crate::fs::flush().is_err()
+	{
+		println!("[ERR] UNABLE TO FLUSH FILESYSTEMS");
+	}
+}
+
+
+// Stop (halts the processor for good)
+fn stop() -> !
+{
+	loop
+	{
+		interrupts::disable();
+		instructions::hlt();
+	}
+}
+
+
+// Halt
+pub fn halt() -> !
+{
+	prepare();
+	println!("[INFO] SYSTEM HALTED");
+	stop()
+}
+
+
+// Power off
+pub fn poweroff() -> !
+{
+	prepare();
+	println!("[INFO] POWERING OFF");
+	crate::sys::acpi::shutdown();
+
+	println!("[ERR] ACPI POWER-OFF FAILED, HALTING INSTEAD");
+	stop()
+}
+
+
+// Reboot
+//
+// Tries the ACPI reset register first, then the keyboard controller, and finally forces a triple fault.
+pub fn reboot() -> !
+{
+	prepare();
+	println!("[INFO] REBOOTING");
+	interrupts::disable();
+
+	crate::sys::acpi::reset();
+	crate::time::nwait(100_000_000);
+
+	// Pulse the CPU reset line through the keyboard controller, once its input buffer is empty
+	let mut status: Port<u8> = Port::new(KBC_STATUS);
+	unsafe
+	{
+		for _ in 0..10_000
+		{
+			if status.read() & 2 == 0
+			{
+				break;
+			}
+			crate::time::nwait(10_000);
+		}
+		status.write(KBC_RESET);
+	}
+	crate::time::nwait(100_000_000);
+
+	// Any exception now ends in a triple fault
+	unsafe
+	{
+		lidt(&DescriptorTablePointer
+		{
+			limit: 0,
+			base: VirtAddr::new(0),
+		});
+	}
+	instructions::interrupts::int3();
+
+	stop()
+}
+
+
+// Suspend (to RAM)
+pub fn suspend() -> Result<(), ()>
+{
+	prepare();
+	println!("[INFO] SUSPENDING");
+	crate::sys::acpi::suspend()
+}
@@ -1,6 +1,7 @@
 // src/sys/proc.rs
 //
-// Kernel processes.
+// Kernel processes. Only the image of a process is mapped when it is created; the rest of its address space is
+// made of regions whose pages are mapped when the process first touches them.
 
 /*
 	IMPORTS
@@ -8,42 +9,78 @@
 
 #![allow(unused_mut)]
 
-use alloc::{collections::BTreeMap, string::{String, ToString}};
-use core::{arch::asm, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+use alloc::{collections::BTreeMap, string::{String, ToString}, vec, vec::Vec};
+use core::{arch::asm, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
 use lazy_static::lazy_static;
 use object::{Object, ObjectSegment};
-use spin::RwLock;
-use x86_64::{structures::idt::InterruptStackFrameValue, VirtAddr};
+use spin::{Mutex, RwLock};
+use x86_64::{structures::{idt::InterruptStackFrameValue, paging::{Mapper, Page, PageTableFlags, Size4KiB}}, VirtAddr};
 
-use crate::{sys::console::Console, sys::gdt::GDT, fs::{dev::Device, Resource}};
+use crate::{mem::{frame::{self, GlobalFrameAllocator, Zone}, kaslr, swap}, sys::{console::Console, cpu::FpuState, gdt::{StackKind, GDT}}, fs::{dev::Device, Resource}};
 
 
 /*
 	CONSTANTS
 */
 
-// Code address
-// NOTE: Set to 16 MB
-pub static CODEADDRESS: AtomicU64 = AtomicU64::new((crate::allocator::HEAP_START as u64) + (16 << 20));
+// Code address (where the next process is placed)
+// NOTE: Set above the regions reserved for the heap and the slab-allocator, see mem::kaslr
+pub static CODEADDRESS: AtomicU64 = AtomicU64::new((crate::allocator::HEAP_BASE + crate::allocator::HEAP_REGION + crate::allocator::SLAB_REGION) as u64);
 
 // Magic number for ELF executables
 const ELFMAG: [u8; 4] = [0x74, b'E', b'L', b'F'];
 
 // Maximum number of filehandles
-const MAX_FILEHANDLE: usize = 16;
+pub const MAX_FILEHANDLE: usize = 16;
 
-// Maximum number of processes
-const MAX_PROC: usize = 2;
+// Maximum number of processes (including the shell, as process 0)
+const MAX_PROC: usize = 8;
 
 // Page size
 pub const PAGESIZE: u64 = 4 * 1024;
 
+// Size of the address space of a process
+const CODE_SIZE: u64 = 1024 * PAGESIZE;
+
+// Most pages that processes can have mapped at once
+pub const MAX_PAGES: usize = MAX_PROC * (CODE_SIZE / PAGESIZE) as usize;
+
+// Maximum size of the stack of a process
+const STACK_MAX: u64 = 256 * 1024;
+
+// How far below the stack pointer an access may be, for the stack to grow down to it
+const STACK_SLACK: u64 = 64 * 1024 + 256;
+
+// Largest random gap before the address space of a process
+const CODE_SLIDE: u64 = 64 << 20;
+
+// Largest random offsets of the stack top and of the start of the anonymous memory of a process
+const STACK_SLIDE: u64 = 64 * 1024;
+const ANON_SLIDE: u64 = 256 * 1024;
+
+// Alignment of the stack pointer
+const STACK_ALIGN: u64 = 16;
+
+
+// Whether or not spawned processes are held back, to be run together by run_group()
+static HOLD: AtomicBool = AtomicBool::new(false);
+
+// Saved kernel stack pointers of the processes of the group, by ID (the one of the shell, which runs the group, is
+// at index 0)
+// NOTE: Only the shell runs groups, so they are only touched by the processor that runs it
+static mut CONTEXTS: [u64; MAX_PROC] = [0; MAX_PROC];
+
 
 lazy_static!
 {
-	pub static ref PID: AtomicUsize = AtomicUsize::new(0);
-	pub static ref MAXPID: AtomicUsize = AtomicUsize::new(1);
 	pub static ref PROCTAB: RwLock<[Proc; MAX_PROC]> = RwLock::new([(); MAX_PROC].map(|_| Proc::new(0)));
+
+	// Tops of the kernel stacks of processes, by ID (each is allocated for the first process with its ID, and kept
+	// for the next ones, as an exiting process still runs on its stack)
+	static ref KSTACKS: Mutex<[u64; MAX_PROC]> = Mutex::new([0; MAX_PROC]);
+
+	// Processes that take turns to run, while the shell waits for them (by ID)
+	static ref GROUP: Mutex<Vec<usize>> = Mutex::new(Vec::new());
 }
 
 
@@ -55,9 +92,41 @@ pub struct Proc
 	code_size: u64,
 	data: ProcData,
 	entrypt: u64,
+	fpu: FpuState,
 	id: usize,
 	reg: Reg,
+	kstack: u64,
+	regions: Vec<Region>,
 	sf: InterruptStackFrameValue,
+	stack_top: u64,
+}
+
+
+// RegionKind enumeration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind
+{
+	// The binary, mapped when the process is created
+	Image,
+
+	// Zeroed pages, mapped on demand
+	Anonymous,
+
+	// Zeroed pages, mapped on demand as the stack grows down (to no further than the limit)
+	Stack
+	{
+		limit: u64,
+	},
+}
+
+
+// Region struct (a range of the address space of a process)
+#[derive(Clone, Copy, Debug)]
+pub struct Region
+{
+	pub start: u64,
+	pub end: u64,
+	pub kind: RegionKind,
 }
 
 
@@ -69,6 +138,7 @@ pub struct ProcData
 	directory: String,
 	user: Option<String>,
 	filehandle: [Option<Resource>; MAX_FILEHANDLE],
+	fhflags: [usize; MAX_FILEHANDLE],
 }
 
 
@@ -95,13 +165,44 @@ impl Proc
 	pub fn create(bin: &[u8]) -> Result<usize, ()>
 	{
 		// Code size
-		let code_size = 1024 * PAGESIZE;
+		let code_size = CODE_SIZE;
 
-		// Code address
-		let code_address = CODEADDRESS.fetch_add(code_size, Ordering::SeqCst);
+		let elf = if bin[0..4] == ELFMAG
+		{
+			object::File::parse(bin).ok()
+		}
+		else
+		{
+			None
+		};
+
+		// Size of the image (pages past it are mapped on demand)
+		let image_size = match &elf
+		{
+			Some(obj) => obj.segments().filter_map(|seg| Some(seg.address() + seg.data().ok()?.len() as u64)).max().unwrap_or(0),
+			None => bin.len() as u64,
+		};
+		let image_size = (image_size + PAGESIZE - 1) / PAGESIZE * PAGESIZE;
+
+		// The image, a guard page, and the stack (wherever its top ends up) have to fit
+		if image_size + PAGESIZE + STACK_MAX + STACK_SLIDE > code_size
+		{
+			return Err(());
+		}
+
+		// Code address (after a random gap)
+		let gap = kaslr::slide(CODE_SLIDE, PAGESIZE);
+		let code_address = CODEADDRESS.fetch_add(gap + code_size, Ordering::SeqCst) + gap;
 
-		// Allocate pages, using code address and code size
-		crate::allocator::palloc(code_address, code_size);
+		// Map the pages of the image
+		for offset in (0..image_size).step_by(PAGESIZE as usize)
+		{
+			if mapzeroed(Page::containing_address(VirtAddr::new(code_address + offset))).is_err()
+			{
+				crate::mem::p_dealloc(code_address, image_size);
+				return Err(());
+			}
+		}
 
 		// Entry point
 		let mut entrypt = 0;
@@ -110,23 +211,20 @@ impl Proc
 		let cptr = code_address as *mut u8;
 
 		// If binary is an ELF binary
-		if bin[0..4] == ELFMAG
+		if let Some(obj) = elf
 		{
-			if let Ok(obj) = object::File::parse(bin)
+			entrypt = obj.entry();
+			for seg in obj.segments()
 			{
-				entrypt = obj.entry();
-				for seg in obj.segments()
+				let address = seg.address() as usize;
+				if let Ok(data) = seg.data()
 				{
-					let address = seg.address() as usize;
-					if let Ok(data) = seg.data()
+					for (i, op) in data.iter().enumerate()
 					{
-						for (i, op) in data.iter().enumerate()
+						unsafe
 						{
-							unsafe
-							{
-								let ptr = cptr.add(address + i);
-								core::ptr::write(ptr, *op);
-							}
+							let ptr = cptr.add(address + i);
+							core::ptr::write(ptr, *op);
 						}
 					}
 				}
@@ -145,23 +243,72 @@ impl Proc
 			}
 		}
 
+		// Everything between the image and the stack is anonymous memory (from a random offset), but for a guard
+		// page below the stack
+		let stack_end = code_address + code_size - kaslr::slide(STACK_SLIDE, PAGESIZE);
+		let stack_top = stack_end - kaslr::slide(PAGESIZE, STACK_ALIGN);
+		let stack_limit = stack_end - STACK_MAX;
+		let anon_start = code_address + image_size + kaslr::slide(ANON_SLIDE.min(stack_limit - PAGESIZE - code_address - image_size), PAGESIZE);
+		let regions = vec![
+			Region
+			{
+				start: code_address,
+				end: code_address + image_size,
+				kind: RegionKind::Image,
+			},
+			Region
+			{
+				start: anon_start,
+				end: stack_limit - PAGESIZE,
+				kind: RegionKind::Anonymous,
+			},
+			Region
+			{
+				start: stack_end,
+				end: stack_end,
+				kind: RegionKind::Stack
+				{
+					limit: stack_limit,
+				},
+			},
+		];
+
 		let mut tab = PROCTAB.write();
 		let parent = &tab[id()];
 		let data = parent.data.clone();
 		let reg = parent.reg;
 		let sf = parent.sf.clone();
 
-		let id = MAXPID.fetch_add(1, Ordering::SeqCst);
+		// A process gets the first free slot of the table (ID 0 is the shell's)
+		let slot = (1..MAX_PROC).find(|&id| tab[id].id == 0);
+		let kstack = slot.and_then(kstack);
+		let (id, kstack) = match slot.zip(kstack)
+		{
+			Some(new) => new,
+			None =>
+			{
+				drop(tab);
+				swap::release(code_address, code_size);
+				crate::mem::p_dealloc(code_address, code_size);
+				return Err(());
+			}
+		};
+
 		let proc = Proc
 		{
 			id,
 			code_address,
 			code_size,
 			entrypt,
+			kstack,
+			fpu: FpuState::new(),
 			data,
 			sf,
-			reg
+			reg,
+			regions,
+			stack_top,
 		};
+		tab[id] = proc;
 
 		Ok(id)
 	}
@@ -171,6 +318,7 @@ impl Proc
 	pub fn exec(&self)
 	{
 		setid(self.id);
+		crate::sys::gdt::set_kernel_stack(VirtAddr::new(self.kstack));
 		unsafe
 		{
 			asm!(
@@ -182,7 +330,7 @@ impl Proc
 				"push rdi",
 				"iretq",
 				in("rax") GDT.1.userdata.0,
-				in("rsi") self.code_address + self.code_size,
+				in("rsi") self.stack_top,
 				in("rdx") GDT.1.usercode.0,
 				in("rdi") self.code_address + self.entrypt,
 			);
@@ -207,18 +355,30 @@ impl Proc
 			code_address: 0,
 			code_size: 0,
 			entrypt: 0,
+			fpu: FpuState::new(),
+			kstack: 0,
 			sf: isf,
 			reg: Reg::default(),
+			regions: Vec::new(),
+			stack_top: 0,
 			data: ProcData::new("/", None),
 		}
 	}
 
 
 	// Spawn
+	//
+	// While spawned processes are held back, the process joins the group instead of running right away.
 	pub fn spawn(bin: &[u8])
 	{
 		if let Ok(pid) = Self::create(bin)
 		{
+			if HOLD.load(Ordering::SeqCst)
+			{
+				enqueue(pid);
+				return;
+			}
+
 			let proc =
 			{
 				let tab = PROCTAB.read();
@@ -230,6 +390,28 @@ impl Proc
 }
 
 
+// Implementation of the Region struct
+impl Region
+{
+	// Whether or not a fault at an address can be resolved by mapping a page of the region
+	//
+	// The stack only grows down to accesses close enough to the stack pointer (which is only known for faults of
+	// the process itself, and not for those of the kernel on its behalf).
+	fn admits(&self, address: u64, sp: Option<u64>) -> bool
+	{
+		match self.kind
+		{
+			RegionKind::Image => false,
+			RegionKind::Anonymous => address >= self.start && address < self.end,
+			RegionKind::Stack { limit } =>
+			{
+				address >= limit && address < self.end && (address >= self.start || sp.map_or(false, |sp| address + STACK_SLACK >= sp))
+			},
+		}
+	}
+}
+
+
 // Implementation of the ProcData struct
 impl ProcData
 {
@@ -240,6 +422,7 @@ impl ProcData
 		let directory = directory.to_string();
 		let user = user.map(String::from);
 		let mut filehandle = [(); MAX_FILEHANDLE].map(|_| None);
+		let fhflags = [0; MAX_FILEHANDLE];
 
 		filehandle[0] = Some(Resource::Device(Device::Console(Console::new())));
 		filehandle[1] = Some(Resource::Device(Device::Console(Console::new())));
@@ -250,13 +433,34 @@ impl ProcData
 			env,
 			directory,
 			user,
-			filehandle
+			filehandle,
+			fhflags
 		}
 
 	}
 }
 
 
+// Block (until something that the current process waits for happens)
+//
+// A process of the group lets the others run meanwhile, as the one it waits for may be among them.
+pub fn block()
+{
+	let pid = id();
+	if pid == 0 || !GROUP.lock().contains(&pid)
+	{
+		crate::time::halt();
+		return;
+	}
+
+	savefpu();
+	unsafe
+	{
+		switch(core::ptr::addr_of_mut!(CONTEXTS[pid]), CONTEXTS[0]);
+	}
+}
+
+
 // Code address
 pub fn ca() -> u64
 {
@@ -275,6 +479,34 @@ pub fn directory() -> String
 }
 
 
+// Add a process to the group
+//
+// Its kernel stack is set up for switch() to continue at start(), and it starts with the FPU state of the shell.
+fn enqueue(pid: usize)
+{
+	let top =
+	{
+		let mut tab = PROCTAB.write();
+		tab[pid].fpu.save();
+		tab[pid].kstack
+	};
+
+	// Return address of start() (never used), start(), flags (interrupts disabled), and the callee-saved registers
+	let frame = [0, start as usize as u64, 0x2, 0, 0, 0, 0, 0, 0];
+	let rsp = top - (frame.len() as u64) * 8;
+	unsafe
+	{
+		for (i, word) in frame.iter().rev().enumerate()
+		{
+			core::ptr::write((rsp as *mut u64).add(i), *word);
+		}
+		CONTEXTS[pid] = rsp;
+	}
+
+	GROUP.lock().push(pid);
+}
+
+
 // Environment
 pub fn env(key: &str) -> Option<String>
 {
@@ -296,10 +528,17 @@ pub fn envs() -> BTreeMap<String, String>
 // Exit
 pub fn exit()
 {
-	let tab = PROCTAB.read();
-	let proc = &tab[id()];
-	crate::mem::p_dealloc(proc.code_address, proc.code_address);
-	MAXPID.fetch_sub(1, Ordering::SeqCst);
+	let mut tab = PROCTAB.write();
+	let proc = &mut tab[id()];
+	swap::release(proc.code_address, proc.code_size);
+	crate::mem::p_dealloc(proc.code_address, proc.code_size);
+
+	// Close every file handle, so that pipe-ends held by the process are released
+	proc.data.filehandle = [(); MAX_FILEHANDLE].map(|_| None);
+	proc.data.fhflags = [0; MAX_FILEHANDLE];
+
+	// Free the slot
+	proc.id = 0;
 	setid(0);
 }
 
@@ -319,6 +558,25 @@ pub fn fh_del(handle: usize)
 	let mut tab = PROCTAB.write();
 	let proc = &mut tab[id()];
 	proc.data.filehandle[handle] = None;
+	proc.data.fhflags[handle] = 0;
+}
+
+
+// File handle flags
+pub fn fh_flags(handle: usize) -> usize
+{
+	let tab = PROCTAB.read();
+	let proc = &tab[id()];
+	proc.data.fhflags[handle]
+}
+
+
+// Set file handle flags
+pub fn fh_setflags(handle: usize, flags: usize)
+{
+	let mut tab = PROCTAB.write();
+	let proc = &mut tab[id()];
+	proc.data.fhflags[handle] = flags;
 }
 
 
@@ -335,6 +593,7 @@ pub fn fh_new(file: Resource) -> Result<usize, ()>
 		if proc.data.filehandle[handle].is_none()
 		{
 			proc.data.filehandle[handle] = Some(file);
+			proc.data.fhflags[handle] = 0;
 			return Ok(handle);
 		}
 	}
@@ -351,10 +610,99 @@ pub fn fh_update(handle: usize, file: Resource)
 }
 
 
-// ID
+// Hold back spawned processes (to be run together by run_group()), or stop doing so
+pub fn hold(enable: bool)
+{
+	HOLD.store(enable, Ordering::SeqCst);
+}
+
+
+// Kernel stack of a process (allocated for the first process with its ID)
+fn kstack(id: usize) -> Option<u64>
+{
+	let mut kstacks = KSTACKS.lock();
+	if kstacks[id] == 0
+	{
+		kstacks[id] = crate::sys::gdt::alloc_stack(crate::sys::gdt::TASK_STACK_SIZE, StackKind::Task(id), crate::sys::smp::cpu().id())?.as_u64();
+	}
+	Some(kstacks[id])
+}
+
+
+// ID (of the process running on the current CPU)
 pub fn id() -> usize
 {
-	PID.load(Ordering::SeqCst)
+	crate::sys::smp::cpu().pid.load(Ordering::SeqCst)
+}
+
+
+// Map a zeroed page into the address space of processes (the page may be swapped out later)
+pub fn mapzeroed(page: Page<Size4KiB>) -> Result<(), ()>
+{
+	let frame = swap::alloc_frame().ok_or(())?;
+	unsafe
+	{
+		core::ptr::write_bytes(crate::mem::ptov(frame.start_address()).as_mut_ptr::<u8>(), 0, PAGESIZE as usize);
+	}
+
+	let mut mapper = unsafe
+	{
+		crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET))
+	};
+
+	let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+	match unsafe
+	{
+		mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)
+	}
+	{
+		Ok(flush) =>
+		{
+			flush.flush();
+			swap::track(page);
+			Ok(())
+		},
+		Err(_) =>
+		{
+			frame::free(frame);
+			Err(())
+		},
+	}
+}
+
+
+// Page fault
+//
+// Resolves a fault of the current process on a page that it has not touched yet, within one of its regions, or on a
+// page that has been swapped out (also when the kernel touches it). Faults on pages that are present (protection
+// violations) and outside of the regions cannot be resolved, nor can those that happen while the process table is
+// locked.
+pub fn page_fault(address: VirtAddr, sp: Option<VirtAddr>, present: bool) -> Result<(), ()>
+{
+	if !present && swap::swapped(address)
+	{
+		return swap::swapin(address);
+	}
+
+	if present || id() == 0
+	{
+		return Err(());
+	}
+
+	let mut tab = PROCTAB.try_write().ok_or(())?;
+	let proc = &mut tab[id()];
+
+	let address = address.as_u64();
+	let region = proc.regions.iter_mut().find(|region| region.admits(address, sp.map(|sp| sp.as_u64()))).ok_or(())?;
+
+	let page = Page::containing_address(VirtAddr::new(address));
+	mapzeroed(page)?;
+
+	if let RegionKind::Stack { .. } = region.kind
+	{
+		region.start = region.start.min(page.start_address().as_u64());
+	}
+	Ok(())
 }
 
 
@@ -365,6 +713,28 @@ pub fn ptr_from_address(address: u64) -> *mut u8
 }
 
 
+// Reap a process that has exited
+//
+// A process of the group leaves it, and the shell runs the others, so this does not return for them.
+pub fn reap(pid: usize)
+{
+	{
+		let mut group = GROUP.lock();
+		match group.iter().position(|&id| id == pid)
+		{
+			Some(i) => group.remove(i),
+			None => return,
+		};
+	}
+
+	let mut discard = 0;
+	unsafe
+	{
+		switch(&mut discard, CONTEXTS[0]);
+	}
+}
+
+
 // Registers
 pub fn reg() -> Reg
 {
@@ -374,6 +744,66 @@ pub fn reg() -> Reg
 }
 
 
+// Restore FPU state
+pub fn restorefpu()
+{
+	let tab = PROCTAB.read();
+	let proc = &tab[id()];
+	proc.fpu.restore();
+}
+
+
+// Run the group, until each of its processes has exited
+//
+// The processes take turns, each one running until it blocks or exits. The shell waits for an interrupt after each
+// round, as every process may be waiting for one.
+pub fn run_group()
+{
+	savefpu();
+
+	let mut next = 0;
+	loop
+	{
+		let pid =
+		{
+			let group = GROUP.lock();
+			if group.is_empty()
+			{
+				break;
+			}
+			next %= group.len();
+			group[next]
+		};
+
+		let kstack = PROCTAB.read()[pid].kstack;
+		setid(pid);
+		restorefpu();
+		crate::sys::gdt::set_kernel_stack(VirtAddr::new(kstack));
+		unsafe
+		{
+			switch(core::ptr::addr_of_mut!(CONTEXTS[0]), CONTEXTS[pid]);
+		}
+		setid(0);
+		restorefpu();
+
+		next += 1;
+		if next >= GROUP.lock().len()
+		{
+			crate::time::halt();
+		}
+	}
+}
+
+
+// Save FPU state
+pub fn savefpu()
+{
+	let mut tab = PROCTAB.write();
+	let proc = &mut tab[id()];
+	proc.fpu.save();
+}
+
+
 // Set code address
 pub fn set_ca(address: u64)
 {
@@ -404,7 +834,7 @@ pub fn setenv(key: &str, val: &str)
 // Set ID
 pub fn setid(id: usize)
 {
-	PID.store(id, Ordering::SeqCst)
+	crate::sys::smp::cpu().pid.store(id, Ordering::SeqCst)
 }
 
 
@@ -435,6 +865,15 @@ pub fn setuser(user: &str)
 }
 
 
+// Start a process of the group (when it first runs)
+extern "sysv64" fn start() -> !
+{
+	let proc = PROCTAB.read()[id()].clone();
+	proc.exec();
+	unreachable!();
+}
+
+
 // Stack-frame
 pub fn sf() -> InterruptStackFrameValue
 {
@@ -456,6 +895,36 @@ pub fn spawn(path: &str) -> Result<(), ()>
 }
 
 
+// Switch kernel stacks
+//
+// The callee-saved registers and the flags are saved on the current stack, and its pointer is stored into `from`,
+// before continuing on the stack that `to` points to (where the caller of an earlier switch() returns).
+#[naked]
+unsafe extern "sysv64" fn switch(from: *mut u64, to: u64)
+{
+	asm!(
+		"pushfq",
+		"push rbp",
+		"push rbx",
+		"push r12",
+		"push r13",
+		"push r14",
+		"push r15",
+		"mov [rdi], rsp",
+		"mov rsp, rsi",
+		"pop r15",
+		"pop r14",
+		"pop r13",
+		"pop r12",
+		"pop rbx",
+		"pop rbp",
+		"popfq",
+		"ret",
+		options(noreturn)
+	);
+}
+
+
 // User
 pub fn user() -> String
 {
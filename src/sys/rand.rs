@@ -1,17 +1,245 @@
 // src/sys/rand.rs
 //
-// Random number generation. This module may need to be expanded to support older CPUs.
+// Random number generation. Entropy is gathered into a pool (from RDSEED and RDRAND where the CPU has them, the
+// jitter of the TSC, the timing of interrupts, and keyboard and mouse events), which seeds a ChaCha20 generator.
+// The generator is reseeded from the pool whenever enough new entropy has come in, and its key is replaced after
+// every request, so that its earlier output cannot be recovered from its state. It backs /dev/random, the GETRANDOM
+// system call, and the kernel's own users (through KernelRng).
 
 /*
 	IMPORTS
 */
 
-use rand_core::{RngCore, SeedableRng};
-use x86_64::instructions::random::RdRand;
+use core::arch::asm;
+use lazy_static::lazy_static;
+use rand_core::{block::{BlockRng, BlockRngCore}, CryptoRng, RngCore, SeedableRng};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, random::RdRand};
 
-use crate::fs::FileIO;
+use crate::{clocksource::rdtsc, fs::FileIO, serprintln, sys::cpu};
 
 
+/*
+	CONSTANTS
+*/
+
+// Words of the entropy pool
+const POOL_WORDS: usize = 32;
+
+// Entropy (in bits) that the pool needs before it reseeds the generator
+const RESEED_BITS: usize = 256;
+
+// Most entropy (in bits) that the pool is credited with
+const MAX_BITS: usize = POOL_WORDS * 32;
+
+// Bytes generated with the lock held (larger requests release it in between)
+const CHUNK: usize = 256;
+
+// Attempts at RDSEED before giving up (it fails while the CPU's entropy source is drained)
+const RDSEED_RETRIES: usize = 16;
+
+// Samples of the TSC jitter taken when the pool is filled
+const JITTER_SAMPLES: usize = 256;
+
+// Samples of the TSC jitter credited with one bit of entropy
+const JITTER_PER_BIT: usize = 16;
+
+// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+
+// ChaCha20Core struct (the block function of ChaCha20, with a 64-bit counter and a 64-bit nonce)
+#[derive(Clone)]
+pub struct ChaCha20Core
+{
+	state: [u32; 16],
+}
+
+
+// The ChaCha20 generator
+pub type ChaCha20Rng = BlockRng<ChaCha20Core>;
+
+
+// Implementation of the ChaCha20Core struct
+impl ChaCha20Core
+{
+	// New (with the given key, counter and nonce)
+	pub fn new(key: [u8; 32], counter: u64, nonce: u64) -> Self
+	{
+		let mut core = Self::from_seed(key);
+		core.state[12] = counter as u32;
+		core.state[13] = (counter >> 32) as u32;
+		core.state[14] = nonce as u32;
+		core.state[15] = (nonce >> 32) as u32;
+		core
+	}
+
+
+	// Quarter round
+	fn qr(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize)
+	{
+		x[a] = x[a].wrapping_add(x[b]);
+		x[d] = (x[d] ^ x[a]).rotate_left(16);
+		x[c] = x[c].wrapping_add(x[d]);
+		x[b] = (x[b] ^ x[c]).rotate_left(12);
+		x[a] = x[a].wrapping_add(x[b]);
+		x[d] = (x[d] ^ x[a]).rotate_left(8);
+		x[c] = x[c].wrapping_add(x[d]);
+		x[b] = (x[b] ^ x[c]).rotate_left(7);
+	}
+
+
+	// Block (of the current counter)
+	fn block(&self) -> [u32; 16]
+	{
+		let mut x = self.state;
+		for _ in 0..10
+		{
+			Self::qr(&mut x, 0, 4, 8, 12);
+			Self::qr(&mut x, 1, 5, 9, 13);
+			Self::qr(&mut x, 2, 6, 10, 14);
+			Self::qr(&mut x, 3, 7, 11, 15);
+			Self::qr(&mut x, 0, 5, 10, 15);
+			Self::qr(&mut x, 1, 6, 11, 12);
+			Self::qr(&mut x, 2, 7, 8, 13);
+			Self::qr(&mut x, 3, 4, 9, 14);
+		}
+
+		for (word, init) in x.iter_mut().zip(self.state.iter())
+		{
+			*word = word.wrapping_add(*init);
+		}
+		x
+	}
+}
+
+
+// Implementation of the BlockRngCore trait for the ChaCha20Core struct
+impl BlockRngCore for ChaCha20Core
+{
+	type Item = u32;
+	type Results = [u32; 16];
+
+	fn generate(&mut self, results: &mut Self::Results)
+	{
+		*results = self.block();
+
+		let counter = ((self.state[13] as u64) << 32 | self.state[12] as u64).wrapping_add(1);
+		self.state[12] = counter as u32;
+		self.state[13] = (counter >> 32) as u32;
+	}
+}
+
+
+// Implementation of the SeedableRng trait for the ChaCha20Core struct (the seed is the key)
+impl SeedableRng for ChaCha20Core
+{
+	type Seed = [u8; 32];
+
+	fn from_seed(seed: Self::Seed) -> Self
+	{
+		let mut state = [0; 16];
+		state[..4].copy_from_slice(&SIGMA);
+		for (word, bytes) in state[4..12].iter_mut().zip(seed.chunks_exact(4))
+		{
+			*word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+		}
+		Self
+		{
+			state,
+		}
+	}
+}
+
+
+// ChaCha20 is a stream cipher, so its output is fit for cryptography
+impl CryptoRng for ChaCha20Core {}
+
+
+// Pool struct (the entropy pool)
+//
+// Inputs are only stirred in, which is cheap enough for interrupt handlers; it is the extraction, through ChaCha20,
+// that makes the seeds unpredictable.
+struct Pool
+{
+	words: [u32; POOL_WORDS],
+	pos: usize,
+
+	// Entropy credited since the last reseed (in bits)
+	bits: usize,
+}
+
+
+// Implementation of the Pool struct
+impl Pool
+{
+	// Mix in a value, credited with the given entropy (in bits)
+	fn mix(&mut self, value: u64, bits: usize)
+	{
+		for half in [value as u32, (value >> 32) as u32]
+		{
+			let i = self.pos;
+			let w = self.words[i] ^ half ^ self.words[(i + 7) % POOL_WORDS].rotate_left(7) ^ self.words[(i + 19) % POOL_WORDS].rotate_right(11);
+			self.words[i] = w.wrapping_mul(0x9E37_79B9).rotate_left(13);
+			self.pos = (i + 1) % POOL_WORDS;
+		}
+		self.bits = (self.bits + bits).min(MAX_BITS);
+	}
+
+
+	// Mix in an event (with the time at which it happened)
+	fn event(&mut self, value: u64, bits: usize)
+	{
+		self.mix(rdtsc() ^ value.rotate_left(32), bits);
+	}
+
+
+	// Extract a seed (mixed with the output of the generator, so that a pool with little entropy cannot make it
+	// worse)
+	fn extract(&mut self, rng: &mut ChaCha20Rng) -> [u8; 32]
+	{
+		let mut key = [0u8; 32];
+		rng.fill_bytes(&mut key);
+		for (i, word) in self.words.iter().enumerate()
+		{
+			let at = (i % 8) * 4;
+			let folded = u32::from_le_bytes([key[at], key[at + 1], key[at + 2], key[at + 3]]) ^ word.rotate_left((i / 8) as u32 * 8);
+			key[at..at + 4].copy_from_slice(&folded.to_le_bytes());
+		}
+
+		let mut seed = [0u8; 32];
+		ChaCha20Rng::from_seed(key).fill_bytes(&mut seed);
+
+		// The pool keeps its contents, but has to earn its credit again
+		self.bits = 0;
+		self.mix(rdtsc(), 0);
+		seed
+	}
+}
+
+
+lazy_static!
+{
+	static ref POOL: Mutex<Pool> = Mutex::new(Pool
+	{
+		words: [0; POOL_WORDS],
+		pos: 0,
+		bits: 0,
+	});
+
+	static ref RNG: Mutex<ChaCha20Rng> =
+	{
+		let mut rng = ChaCha20Rng::from_seed([0; 32]);
+		let seed = interrupts::without_interrupts(||
+		{
+			let mut pool = POOL.lock();
+			gather(&mut pool);
+			pool.extract(&mut rng)
+		});
+		Mutex::new(ChaCha20Rng::from_seed(seed))
+	};
+}
+
 
 // Random struct
 #[derive(Debug, Clone)]
@@ -24,18 +252,24 @@ impl FileIO for Random
 	// Read
 	fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ()>
 	{
-		let n = buffer.len();
-		for i in 0..n
-		{
-			buffer[i] = ret_u64() as u8;
-		}
-		Ok(n)
+		fill(buffer);
+		Ok(buffer.len())
 	}
 
-	// Write
-	fn write(&mut self, _buffer: &[u8]) -> Result<usize, ()>
+	// Write (the data is mixed into the pool, without being credited with any entropy)
+	fn write(&mut self, buffer: &[u8]) -> Result<usize, ()>
 	{
-		unimplemented!();
+		interrupts::without_interrupts(||
+		{
+			let mut pool = POOL.lock();
+			for chunk in buffer.chunks(8)
+			{
+				let mut bytes = [0; 8];
+				bytes[..chunk.len()].copy_from_slice(chunk);
+				pool.mix(u64::from_le_bytes(bytes), 0);
+			}
+		});
+		Ok(buffer.len())
 	}
 }
 
@@ -51,29 +285,223 @@ impl Random
 }
 
 
-// Returns a random integer (using ret_u64) as an unsigned, 16-bit integer
+// KernelRng struct (a handle on the kernel's generator)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KernelRng;
+
+
+// Implementation of the RngCore trait for the KernelRng struct
+impl RngCore for KernelRng
+{
+	fn next_u32(&mut self) -> u32
+	{
+		let mut bytes = [0; 4];
+		fill(&mut bytes);
+		u32::from_le_bytes(bytes)
+	}
+
+	fn next_u64(&mut self) -> u64
+	{
+		let mut bytes = [0; 8];
+		fill(&mut bytes);
+		u64::from_le_bytes(bytes)
+	}
+
+	fn fill_bytes(&mut self, dest: &mut [u8])
+	{
+		fill(dest);
+	}
+
+	fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error>
+	{
+		fill(dest);
+		Ok(())
+	}
+}
+
+
+// The kernel's generator is a CSPRNG
+impl CryptoRng for KernelRng {}
+
+
+// RDSEED
+fn rdseed() -> Option<u64>
+{
+	if !cpu::features().rdseed
+	{
+		return None;
+	}
+
+	for _ in 0..RDSEED_RETRIES
+	{
+		let value: u64;
+		let ok: u8;
+		unsafe
+		{
+			asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+		}
+
+		if ok == 1
+		{
+			return Some(value);
+		}
+	}
+	None
+}
+
+
+// RDRAND
+fn rdrand() -> Option<u64>
+{
+	RdRand::new().and_then(|rdrand| rdrand.get_u64())
+}
+
+
+// Gather entropy from the CPU (RDSEED, RDRAND, and the jitter of the TSC)
+fn gather(pool: &mut Pool)
+{
+	for _ in 0..4
+	{
+		if let Some(seed) = rdseed()
+		{
+			pool.mix(seed, 64);
+		}
+
+		// RDRAND is the output of a generator, which the CPU reseeds; it is only given partial credit
+		if let Some(rand) = rdrand()
+		{
+			pool.mix(rand, 16);
+		}
+	}
+
+	// The time that the same work takes varies with caches, pipelines and interrupts, but successive samples are far
+	// from independent; only one bit is credited for every JITTER_PER_BIT of them, so that the jitter alone cannot
+	// reach RESEED_BITS
+	let mut last = rdtsc();
+	for i in 0..JITTER_SAMPLES
+	{
+		let mut x = last ^ i as u64;
+		for _ in 0..(last & 0xF) + 8
+		{
+			x = x.rotate_left(5).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+		}
+
+		let now = rdtsc();
+		pool.mix(now.wrapping_sub(last) ^ (x & 0xFF), (i % JITTER_PER_BIT == JITTER_PER_BIT - 1) as usize);
+		last = now;
+	}
+}
+
+
+// Fill a buffer with random bytes
+pub fn fill(buffer: &mut [u8])
+{
+	for chunk in buffer.chunks_mut(CHUNK)
+	{
+		interrupts::without_interrupts(||
+		{
+			let mut rng = RNG.lock();
+
+			{
+				let mut pool = POOL.lock();
+				if pool.bits >= RESEED_BITS
+				{
+					if let Some(rand) = rdrand()
+					{
+						pool.mix(rand, 0);
+					}
+					let seed = pool.extract(&mut rng);
+					*rng = ChaCha20Rng::from_seed(seed);
+				}
+			}
+
+			rng.fill_bytes(chunk);
+
+			// Fast key erasure
+			let mut key = [0; 32];
+			rng.fill_bytes(&mut key);
+			*rng = ChaCha20Rng::from_seed(key);
+		});
+	}
+}
+
+
+// Mix in the timing of an interrupt (from interrupt handlers, which skip it rather than wait for the pool)
+pub fn mix_interrupt(irq: u8)
+{
+	if let Some(mut pool) = POOL.try_lock()
+	{
+		pool.event(irq as u64, 1);
+	}
+}
+
+
+// Mix in an input event (a scancode or a mouse packet, with its timing)
+pub fn mix_input(value: u64)
+{
+	interrupts::without_interrupts(||
+	{
+		if let Some(mut pool) = POOL.try_lock()
+		{
+			pool.event(value, 2);
+		}
+	});
+}
+
+
+// Returns a random, unsigned, 16-bit integer
 pub fn ret_u16() -> u16
 {
-	ret_u64 as u16
+	KernelRng.next_u32() as u16
 }
 
 
-// Returns a random integer (using ret_u64) as an unsigned, 32-bit integer
+// Returns a random, unsigned, 32-bit integer
 pub fn ret_u32() -> u32
 {
-	ret_u64() as u32
+	KernelRng.next_u32()
 }
 
 
 // Returns a random, unsigned, 64-bit integer
 pub fn ret_u64() -> u64
 {
-	if let Some(rdrand) = RdRand::new()
+	KernelRng.next_u64()
+}
+
+
+// Initialization (seeds the generator)
+pub fn init()
+{
+	lazy_static::initialize(&RNG);
+	serprintln!("[INFO] RAND: SEEDED FROM{}{} TSC JITTER", if cpu::features().rdseed
 	{
-		if let Some(rand) = rdrand.get_u64()
-		{
-			return rand;
-		}
+		" RDSEED,"
+	}
+	else
+	{
+		""
+	}, if cpu::features().rdrand
+	{
+		" RDRAND,"
 	}
-	0
+	else
+	{
+		""
+	});
+}
+
+
+#[test_case]
+fn test_chacha20_block()
+{
+	// RFC 8439, section 2.3.2 (its 32-bit counter and 96-bit nonce are laid out as the 64-bit counter and nonce here)
+	let key = core::array::from_fn(|i| i as u8);
+	let core = ChaCha20Core::new(key, 0x0900_0000_0000_0001, 0x4A00_0000);
+	assert_eq!(core.block(), [
+		0xE4E7_F110, 0x1559_3BD1, 0x1FDD_0F50, 0xC471_20A3,
+		0xC7F4_D1C7, 0x0368_C033, 0x9AAA_2204, 0x4E6C_D4C3,
+		0x4664_82D2, 0x09AA_9F07, 0x05D7_C214, 0xA202_8BD9,
+		0xD19C_12B5, 0xB94E_16DE, 0xE883_D0CB, 0x4E3C_50A2,
+	]);
 }
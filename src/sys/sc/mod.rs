@@ -6,9 +6,10 @@
 /*
 	IMPORTS
 */
+use alloc::string::String;
 use core::arch::asm;
 
-use crate::{sc, fs::directory_entry::FileInfo, sys::sc};
+use crate::{sc, fs::{directory_entry::FileInfo, PollHandle}, noblkio, sys::sc};
 
 
 // Services
@@ -52,9 +53,24 @@ pub const UT: usize = 0xA;
 // Real-time
 pub const RT: usize = 0xB;
 
+// Pipe
+pub const PIPE: usize = 0xC;
+
+// Poll
+pub const POLL: usize = 0xD;
+
+// Set time
+pub const SETTIME: usize = 0xE;
+
+// Get random bytes
+pub const GETRANDOM: usize = 0xF;
+
 // Unknown system call
 pub const UNKNOWN: usize = 0x26;
 
+// Returned by READ and WRITE when a non-blocking handle is not ready
+pub const WOULDBLK: isize = -2;
+
 
 
 // Dispatcher for system-calls
@@ -66,7 +82,7 @@ pub fn dispatch(n: usize, a1: usize, a2: usize, a3: usize) -> usize
 		CLOSE =>
 		{
 			let handle = a1;
-			close(handle);
+			crate::sys::sc::svc::cl(handle);
 			0
 		}
 
@@ -80,6 +96,70 @@ pub fn dispatch(n: usize, a1: usize, a2: usize, a3: usize) -> usize
 		}
 
 
+		// Exit
+		EXIT =>
+		{
+			crate::sys::proc::exit();
+			0
+		}
+
+
+		// Get random bytes
+		GETRANDOM =>
+		{
+			let ptr = crate::sys::proc::ptr_from_address(a1 as u64);
+			let len = a2;
+			let buffer = unsafe
+			{
+				core::slice::from_raw_parts_mut(ptr, len)
+			};
+
+			crate::sys::sc::svc::gr(buffer) as usize
+		}
+
+
+		// Open
+		OPEN =>
+		{
+			let ptr = crate::sys::proc::ptr_from_address(a1 as u64);
+			let len = a2;
+			let flags = a3;
+			let path = unsafe
+			{
+				core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
+			};
+
+			crate::sys::sc::svc::op(path, flags) as usize
+		}
+
+
+		// Pipe
+		PIPE =>
+		{
+			let ptr = crate::sys::proc::ptr_from_address(a1 as u64) as *mut usize;
+			let handles = unsafe
+			{
+				&mut *(ptr as *mut [usize; 2])
+			};
+
+			crate::sys::sc::svc::pp(handles) as usize
+		}
+
+
+		// Poll
+		POLL =>
+		{
+			let ptr = crate::sys::proc::ptr_from_address(a1 as u64) as *mut PollHandle;
+			let len = a2;
+			let list = unsafe
+			{
+				core::slice::from_raw_parts_mut(ptr, len)
+			};
+
+			crate::sys::sc::svc::pl(list, f64::from_bits(a3 as u64)) as usize
+		}
+
+
 		// Read
 		READ =>
 		{
@@ -97,15 +177,21 @@ pub fn dispatch(n: usize, a1: usize, a2: usize, a3: usize) -> usize
 		// Real-time
 		RT =>
 		{
-			// TODO: Convert time, using FL32's conv_to_bits
-			crate::sys::sc::svc::rt() as usize
+			crate::sys::sc::svc::rt().to_bits() as usize
+		}
+
+
+		// Set time
+		SETTIME =>
+		{
+			crate::sys::sc::svc::st(f64::from_bits(a1 as u64)) as usize
 		}
 
 
 		// Sleep
 		SLEEP =>
 		{
-			crate::sys::sc::svc::sl(a1 as f64);
+			crate::sys::sc::svc::sl(f64::from_bits(a1 as u64));
 			0
 		}
 
@@ -121,16 +207,38 @@ pub fn dispatch(n: usize, a1: usize, a2: usize, a3: usize) -> usize
 				core::str::from_utf8_unchecked(core::slice::from_raw_parts(ptr, len))
 			};
 
-			spawn(path);
-			0
+			// The binary is read (through system calls of its own) before the process is created
+			match crate::fs::read_to_bytes(&String::from(path))
+			{
+				Ok(bin) =>
+				{
+					crate::sys::proc::Proc::spawn(&bin);
+					0
+				},
+				Err(_) => usize::MAX,
+			}
 		}
 
 
 		// Up-time
 		UT =>
 		{
-			// TODO: Convert time, using FL32's conv_to_bits
-			crate::sys::sc::svc::ut as usize
+			crate::sys::sc::svc::ut().to_bits() as usize
+		}
+
+
+		// Write
+		WRITE =>
+		{
+			let handle = a1;
+			let ptr = crate::sys::proc::ptr_from_address(a2 as u64);
+			let len = a3;
+			let buffer = unsafe
+			{
+				core::slice::from_raw_parts_mut(ptr, len)
+			};
+
+			crate::sys::sc::svc::wr(handle, buffer) as usize
 		}
 
 
@@ -185,6 +293,27 @@ pub fn exit(code: usize) -> usize
 }
 
 
+// Get random bytes (from the kernel's CSPRNG)
+pub fn getrandom(buffer: &mut [u8]) -> Option<usize>
+{
+	let ptr = buffer.as_mut_ptr() as usize;
+	let len = buffer.len();
+	let res = unsafe
+	{
+		sc!(GETRANDOM, ptr, len)
+	} as isize;
+
+	if res.is_negative()
+	{
+		None
+	}
+	else
+	{
+		Some(res as usize)
+	}
+}
+
+
 // Info
 pub fn info(path: &str) -> Option<FileInfo>
 {
@@ -229,6 +358,53 @@ pub fn open(path: &str, flags: usize) -> Option<usize>
 }
 
 
+// Pipe
+//
+// Returns the handles of the read-end and write-end of a new pipe, in that order.
+pub fn pipe() -> Option<(usize, usize)>
+{
+	let mut handles = [0usize; 2];
+	let ptr = handles.as_mut_ptr() as usize;
+	let res = unsafe
+	{
+		sc!(PIPE, ptr)
+	} as isize;
+
+	if res.is_negative()
+	{
+		None
+	}
+	else
+	{
+		Some(handles.into())
+	}
+}
+
+
+// Poll
+//
+// Waits for at least one handle in the list to become ready, for up to `timeout` seconds (or
+// indefinitely, if the timeout is negative). Returns the number of ready handles.
+pub fn poll(list: &mut [PollHandle], timeout: f64) -> Option<usize>
+{
+	let ptr = list.as_mut_ptr() as usize;
+	let len = list.len();
+	let res = unsafe
+	{
+		sc!(POLL, ptr, len, timeout.to_bits())
+	} as isize;
+
+	if res.is_negative()
+	{
+		None
+	}
+	else
+	{
+		Some(res as usize)
+	}
+}
+
+
 // Read
 pub fn read(handle: usize, buffer: &mut [u8]) -> Option<usize>
 {
@@ -261,6 +437,25 @@ pub fn rt() -> f64
 }
 
 
+// Set time (to a UTC timestamp, in seconds)
+pub fn settime(timestamp: f64) -> Result<(), ()>
+{
+	let res = unsafe
+	{
+		sc!(SETTIME, timestamp.to_bits())
+	} as isize;
+
+	if res.is_negative()
+	{
+		Err(())
+	}
+	else
+	{
+		Ok(())
+	}
+}
+
+
 // Sleep
 pub fn sleep(sec: f64)
 {
@@ -284,6 +479,44 @@ pub fn spawn(path: &str)
 }
 
 
+// Try to read (for handles opened with OpenFlag::NONBLOCK)
+pub fn try_read(handle: usize, buffer: &mut [u8]) -> noblkio::Result<usize, ()>
+{
+	let ptr = buffer.as_ptr() as usize;
+	let len = buffer.len();
+	let res = unsafe
+	{
+		sc!(READ, handle, ptr, len)
+	} as isize;
+
+	match res
+	{
+		WOULDBLK => Err(noblkio::Err::WouldBlk),
+		res if res.is_negative() => Err(noblkio::Err::Other(())),
+		res => Ok(res as usize),
+	}
+}
+
+
+// Try to write (for handles opened with OpenFlag::NONBLOCK)
+pub fn try_write(handle: usize, buffer: &[u8]) -> noblkio::Result<usize, ()>
+{
+	let ptr = buffer.as_ptr() as usize;
+	let len = buffer.len();
+	let res = unsafe
+	{
+		sc!(WRITE, handle, ptr, len)
+	} as isize;
+
+	match res
+	{
+		WOULDBLK => Err(noblkio::Err::WouldBlk),
+		res if res.is_negative() => Err(noblkio::Err::Other(())),
+		res => Ok(res as usize),
+	}
+}
+
+
 // Uptime
 pub fn uptime() -> f64
 {
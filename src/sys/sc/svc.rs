@@ -8,7 +8,14 @@
 
 use alloc::vec;
 
-use crate::{fs::FileIO, sys::sc::FileInfo};
+use crate::{fs::{FileIO, IO, OpenFlag, PollHandle, Resource}, noblkio, sys::sc::FileInfo};
+
+
+// Close
+pub fn cl(handle: usize)
+{
+	crate::sys::proc::fh_del(handle);
+}
 
 
 // Duplicate
@@ -17,11 +24,20 @@ pub fn dp(original: usize, new: usize) -> isize
 	if let Some(file) = crate::sys::proc::fh(original)
 	{
 		crate::sys::proc::fh_update(new, file);
+		crate::sys::proc::fh_setflags(new, crate::sys::proc::fh_flags(original));
 		return new as isize;
 	}
 	-1
 }
 
+
+// Get random bytes
+pub fn gr(buffer: &mut [u8]) -> isize
+{
+	crate::sys::rand::fill(buffer);
+	buffer.len() as isize
+}
+
 // Info
 pub fn info(path: &str, info: &mut FileInfo) -> isize
 {
@@ -37,15 +53,121 @@ pub fn info(path: &str, info: &mut FileInfo) -> isize
 }
 
 
+// Open
+pub fn op(path: &str, flags: usize) -> isize
+{
+	if let Some(res) = crate::fs::open(path, flags)
+	{
+		if let Ok(handle) = crate::sys::proc::fh_new(res)
+		{
+			crate::sys::proc::fh_setflags(handle, flags);
+			return handle as isize;
+		}
+	}
+	-1
+}
+
+
+// Poll
+//
+// Waits until at least one of the listed handles is ready, or until the timeout (in seconds) has
+// passed. A negative timeout waits indefinitely. Returns the number of ready handles.
+pub fn pl(list: &mut [PollHandle], timeout: f64) -> isize
+{
+	let start = crate::clock::uptime();
+
+	loop
+	{
+		let mut n = 0;
+		for item in list.iter_mut()
+		{
+			if item.handle >= crate::sys::proc::MAX_FILEHANDLE
+			{
+				return -1;
+			}
+
+			let event = match IO::from(item.event)
+			{
+				Some(event) => event,
+				None => return -1,
+			};
+
+			if let Some(mut file) = crate::sys::proc::fh(item.handle)
+			{
+				let ready = match event
+				{
+					IO::Read if item.size > 0 => file.poll_read(item.size),
+					event => file.poll(event),
+				};
+				item.ready = ready as u8;
+				if ready
+				{
+					n += 1;
+				}
+			}
+			else
+			{
+				return -1;
+			}
+		}
+
+		if n > 0 || (timeout >= 0.0 && crate::clock::uptime() - start >= timeout)
+		{
+			return n;
+		}
+
+		crate::time::sleep(crate::time::time_between_ticks());
+	}
+}
+
+
+// Pipe
+pub fn pp(handles: &mut [usize; 2]) -> isize
+{
+	let (reader, writer) = crate::fs::pipe::new();
+
+	if let Ok(rh) = crate::sys::proc::fh_new(Resource::PipeReader(reader))
+	{
+		if let Ok(wh) = crate::sys::proc::fh_new(Resource::PipeWriter(writer))
+		{
+			handles[0] = rh;
+			handles[1] = wh;
+			return 0;
+		}
+		crate::sys::proc::fh_del(rh);
+	}
+	-1
+}
+
+
 // Read
 pub fn rd(handle: usize, buffer: &mut [u8]) -> isize
 {
 	if let Some(mut file) = crate::sys::proc::fh(handle)
 	{
-		if let Ok(bytes) = file.read(buffer)
+		let res = if OpenFlag::NONBLOCK.set(crate::sys::proc::fh_flags(handle))
+		{
+			file.try_read(buffer)
+		}
+		else
+		{
+			file.read(buffer).map_err(noblkio::Err::Other)
+		};
+
+		match res
 		{
-			crate::sys::proc::fh_update(handle, file);
-			return bytes as isize;
+			Ok(bytes) =>
+			{
+				crate::sys::proc::fh_update(handle, file);
+				return bytes as isize;
+			},
+
+			Err(noblkio::Err::WouldBlk) =>
+			{
+				return crate::sys::sc::WOULDBLK;
+			},
+
+			Err(noblkio::Err::Other(_)) => {},
 		}
 	}
 	-1
@@ -59,6 +181,20 @@ pub fn rt() -> f64
 }
 
 
+// Set time
+pub fn st(timestamp: f64) -> isize
+{
+	if crate::clock::set_realtime(timestamp).is_ok()
+	{
+		0
+	}
+	else
+	{
+		-1
+	}
+}
+
+
 // Sleep
 pub fn sl(sec: f64)
 {
@@ -78,10 +214,29 @@ pub fn wr(handle: usize, buffer: &mut [u8]) -> isize
 {
 	if let Some(mut file) = crate::sys::proc::fh(handle)
 	{
-		if let Ok(bytes) = file.write(buffer)
+		let res = if OpenFlag::NONBLOCK.set(crate::sys::proc::fh_flags(handle))
+		{
+			file.try_write(buffer)
+		}
+		else
+		{
+			file.write(buffer).map_err(noblkio::Err::Other)
+		};
+
+		match res
 		{
-			crate::sys::proc::fh_update(handle, file);
-			return bytes as isize;
+			Ok(bytes) =>
+			{
+				crate::sys::proc::fh_update(handle, file);
+				return bytes as isize;
+			},
+
+			Err(noblkio::Err::WouldBlk) =>
+			{
+				return crate::sys::sc::WOULDBLK;
+			},
+
+			Err(noblkio::Err::Other(_)) => {},
 		}
 	}
 	-1
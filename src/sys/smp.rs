@@ -0,0 +1,564 @@
+// src/sys/smp.rs
+//
+// Symmetric multiprocessing: starts the application processors listed in the MADT, and keeps the
+// per-CPU data of every processor.
+
+/*
+	IMPORTS
+*/
+
+use acpi::platform::ProcessorState;
+use alloc::collections::VecDeque;
+use core::{arch::global_asm, sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering}};
+use spin::{Mutex, MutexGuard};
+use x86_64::{VirtAddr, instructions::{interrupts, tlb}, registers::{control::{Cr3, Cr4, Cr4Flags}, model_specific::{Efer, EferFlags, GsBase}}, structures::paging::{Mapper, Page, PageTableFlags, Size4KiB}};
+
+use crate::{mem::frame::{self, GlobalFrameAllocator, Zone}, serprintln, sys::{apic, gdt::StackKind}};
+
+
+/*
+	CONSTANTS
+*/
+
+// Maximum number of CPUs
+pub const MAX_CPUS: usize = 8;
+
+// Size of the kernel stack of an application processor
+const AP_STACK_SIZE: usize = 0x4000;
+
+// Vector of the IPI used to wake up an idle processor
+pub const WAKEUP_VECTOR: u8 = 0xF0;
+
+// Vector of the IPI used to flush the TLBs of other processors
+pub const TLB_VECTOR: u8 = 0xF1;
+
+// Number of pages above which the whole TLB is flushed, instead of each page
+const TLB_FLUSH_ALL: usize = 32;
+
+// ICR values (INIT and STARTUP IPIs)
+const ICR_INIT: u32 = 0x4500;
+const ICR_STARTUP: u32 = 0x4600;
+
+// ICR value for a fixed IPI
+const ICR_FIXED: u32 = 0x4000;
+
+
+// Control register 4 of the BSP (copied by the application processors)
+static BSP_CR4: AtomicU64 = AtomicU64::new(0);
+
+// Number of processors that are online
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+// TLB shootdown in progress (only one at a time), with the range to flush and the processors that have yet to do so
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+static TLB_START: AtomicU64 = AtomicU64::new(0);
+static TLB_PAGES: AtomicUsize = AtomicUsize::new(0);
+static TLB_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+
+// PerCpu struct
+pub struct PerCpu
+{
+	// Index of the CPU (the BSP is 0)
+	id: AtomicUsize,
+
+	// ID of the local APIC
+	apic_id: AtomicU32,
+
+	// ID of the process that is running on the CPU
+	pub pid: AtomicUsize,
+
+	// Whether or not the CPU has been started
+	online: AtomicBool,
+
+	// Jobs waiting to be run by the CPU
+	runq: Mutex<VecDeque<fn()>>,
+}
+
+
+// Implementation of the PerCpu struct
+impl PerCpu
+{
+	// New
+	const fn new() -> Self
+	{
+		Self
+		{
+			id: AtomicUsize::new(0),
+			apic_id: AtomicU32::new(0),
+			pid: AtomicUsize::new(0),
+			online: AtomicBool::new(false),
+			runq: Mutex::new(VecDeque::new()),
+		}
+	}
+
+
+	// ID
+	pub fn id(&self) -> usize
+	{
+		self.id.load(Ordering::Relaxed)
+	}
+
+
+	// Local APIC ID
+	pub fn apic_id(&self) -> u32
+	{
+		self.apic_id.load(Ordering::Relaxed)
+	}
+}
+
+
+// Per-CPU data of every processor (the constant is only used to initialize the array)
+#[allow(clippy::declare_interior_mutable_const)]
+const PERCPU: PerCpu = PerCpu::new();
+static CPUS: [PerCpu; MAX_CPUS] = [PERCPU; MAX_CPUS];
+
+
+
+// Trampoline, started in real mode by the STARTUP IPI
+//
+// The code is copied below 1 MB, and switches to long mode with the page tables of the BSP, before
+// jumping to ap_main. Everything is addressed relative to the start of the trampoline (kept in ebx).
+global_asm!(
+	".section .text",
+	".global ap_trampoline_start",
+	".global ap_trampoline_end",
+	".global ap_data",
+	".code16",
+	"ap_trampoline_start:",
+	"	cli",
+	"	cld",
+	"	xorl %ebx, %ebx",
+	"	movw %cs, %bx",
+	"	movw %bx, %ds",
+	"	shll $4, %ebx",
+	"	leal (ap_gdt - ap_trampoline_start)(%ebx), %eax",
+	"	movl %eax, (ap_gdtr - ap_trampoline_start + 2)",
+	"	lgdtl (ap_gdtr - ap_trampoline_start)",
+	"	movl %cr0, %eax",
+	"	orl $1, %eax",
+	"	movl %eax, %cr0",
+	"	leal (ap_pm32 - ap_trampoline_start)(%ebx), %eax",
+	"	pushl $0x08",
+	"	pushl %eax",
+	"	lretl",
+	".code32",
+	"ap_pm32:",
+	"	movw $0x10, %ax",
+	"	movw %ax, %ds",
+	"	movw %ax, %es",
+	"	movw %ax, %ss",
+	"	movl %cr4, %eax",
+	"	orl $0x20, %eax",
+	"	movl %eax, %cr4",
+	"	movl (ap_cr3 - ap_trampoline_start)(%ebx), %eax",
+	"	movl %eax, %cr3",
+	"	movl $0xC0000080, %ecx",
+	"	rdmsr",
+	"	orl (ap_efer - ap_trampoline_start)(%ebx), %eax",
+	"	wrmsr",
+	"	movl %cr0, %eax",
+	"	orl $0x80000000, %eax",
+	"	movl %eax, %cr0",
+	"	leal (ap_lm64 - ap_trampoline_start)(%ebx), %eax",
+	"	pushl $0x18",
+	"	pushl %eax",
+	"	lretl",
+	".code64",
+	"ap_lm64:",
+	"	movw $0x10, %ax",
+	"	movw %ax, %ds",
+	"	movw %ax, %es",
+	"	movw %ax, %ss",
+	"	movl %ebx, %ebx",
+	"	movq (ap_stack - ap_trampoline_start)(%rbx), %rsp",
+	"	movq (ap_cpu - ap_trampoline_start)(%rbx), %rdi",
+	"	movq (ap_entry - ap_trampoline_start)(%rbx), %rax",
+	"	callq *%rax",
+	"	ud2",
+	".balign 8",
+	"ap_gdt:",
+	"	.quad 0",
+	"	.quad 0x00CF9A000000FFFF",
+	"	.quad 0x00CF92000000FFFF",
+	"	.quad 0x00AF9A000000FFFF",
+	"ap_gdtr:",
+	"	.word 4 * 8 - 1",
+	"	.long 0",
+	".balign 8",
+	"ap_data:",
+	"ap_cr3:",
+	"	.quad 0",
+	"ap_stack:",
+	"	.quad 0",
+	"ap_entry:",
+	"	.quad 0",
+	"ap_cpu:",
+	"	.quad 0",
+	"ap_efer:",
+	"	.quad 0",
+	"ap_trampoline_end:",
+	options(att_syntax)
+);
+
+
+extern "C"
+{
+	static ap_trampoline_start: u8;
+	static ap_trampoline_end: u8;
+	static ap_data: u8;
+}
+
+
+// TrampolineData struct (layout of ap_data)
+#[repr(C)]
+struct TrampolineData
+{
+	cr3: u64,
+	stack: u64,
+	entry: u64,
+	cpu: u64,
+	efer: u64,
+}
+
+
+// Per-CPU data of the current processor
+pub fn cpu() -> &'static PerCpu
+{
+	let gs = GsBase::read();
+	if gs.is_null()
+	{
+		&CPUS[0]
+	}
+	else
+	{
+		unsafe
+		{
+			&*gs.as_ptr::<PerCpu>()
+		}
+	}
+}
+
+
+// Number of processors that are online
+pub fn count() -> usize
+{
+	ONLINE.load(Ordering::SeqCst)
+}
+
+
+// Point the GS base of the current processor to its per-CPU data
+fn setcpu(id: usize)
+{
+	let cpu = &CPUS[id];
+	cpu.id.store(id, Ordering::Relaxed);
+	cpu.online.store(true, Ordering::SeqCst);
+	GsBase::write(VirtAddr::from_ptr(cpu));
+}
+
+
+// Initialization
+pub fn init()
+{
+	setcpu(0);
+
+	if !apic::enabled()
+	{
+		serprintln!("[INFO] SMP: THE APIC IS NOT IN USE, ONLY USING THE BSP");
+		return;
+	}
+
+	CPUS[0].apic_id.store(apic::id(), Ordering::Relaxed);
+
+	let processors = match crate::sys::acpi::platform_info().and_then(|info| info.processor_info)
+	{
+		Some(processors) => processors,
+		None =>
+		{
+			serprintln!("[ERR] SMP: COULD NOT READ THE PROCESSOR LIST");
+			return;
+		}
+	};
+
+	// The startup IPI can only point below 1 MB
+	let trampoline = match frame::alloc(Zone::Low)
+	{
+		Some(frame) => frame,
+		None =>
+		{
+			serprintln!("[ERR] SMP: NO MEMORY BELOW 1 MB FOR THE TRAMPOLINE");
+			return;
+		}
+	};
+
+	// The trampoline enables paging while running from its physical address, so it is identity-mapped
+	let mut mapper = unsafe
+	{
+		crate::mem::mapper(VirtAddr::new(crate::mem::PMEM_OFFSET))
+	};
+
+	let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(trampoline.start_address().as_u64()));
+	match unsafe
+	{
+		mapper.identity_map(trampoline, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, &mut GlobalFrameAllocator)
+	}
+	{
+		Ok(flush) => flush.flush(),
+		Err(_) =>
+		{
+			serprintln!("[ERR] SMP: UNABLE TO MAP THE TRAMPOLINE");
+			frame::free(trampoline);
+			return;
+		}
+	}
+
+	let (start, len, offset) = unsafe
+	{
+		let start = &ap_trampoline_start as *const u8;
+		let len = (&ap_trampoline_end as *const u8).offset_from(start) as usize;
+		let offset = (&ap_data as *const u8).offset_from(start) as usize;
+		(start, len, offset)
+	};
+
+	let base = crate::mem::ptov(trampoline.start_address()).as_mut_ptr::<u8>();
+	unsafe
+	{
+		core::ptr::copy_nonoverlapping(start, base, len);
+	}
+
+	let data = unsafe
+	{
+		&mut *(base.add(offset) as *mut TrampolineData)
+	};
+
+	BSP_CR4.store(Cr4::read().bits(), Ordering::SeqCst);
+
+	let efer = Efer::read() & (EferFlags::LONG_MODE_ENABLE | EferFlags::NO_EXECUTE_ENABLE | EferFlags::SYSTEM_CALL_EXTENSIONS);
+	data.cr3 = Cr3::read().0.start_address().as_u64();
+	data.efer = efer.bits();
+	data.entry = ap_main as usize as u64;
+
+	let vector = (trampoline.start_address().as_u64() >> 12) as u32;
+
+	for ap in processors.application_processors.iter().filter(|ap| ap.state != ProcessorState::Disabled)
+	{
+		let id = count();
+		if id >= MAX_CPUS
+		{
+			serprintln!("[INFO] SMP: IGNORING CPUS BEYOND {}", MAX_CPUS);
+			break;
+		}
+
+		CPUS[id].apic_id.store(ap.local_apic_id, Ordering::Relaxed);
+
+		let stack = match crate::sys::gdt::alloc_stack(AP_STACK_SIZE, StackKind::Kernel, id)
+		{
+			Some(stack) => stack,
+			None =>
+			{
+				serprintln!("[ERR] SMP: OUT OF MEMORY, UNABLE TO ALLOCATE THE STACK OF CPU {}", id);
+				break;
+			}
+		};
+
+		unsafe
+		{
+			core::ptr::write_volatile(&mut data.stack, stack.as_u64());
+			core::ptr::write_volatile(&mut data.cpu, id as u64);
+		}
+
+		// INIT, then two STARTUP IPIs
+		apic::send_ipi(ap.local_apic_id, ICR_INIT);
+		crate::time::sleep(0.01);
+		apic::send_ipi(ap.local_apic_id, ICR_STARTUP | vector);
+		crate::time::sleep(0.001);
+
+		if !CPUS[id].online.load(Ordering::SeqCst)
+		{
+			apic::send_ipi(ap.local_apic_id, ICR_STARTUP | vector);
+		}
+
+		let start = crate::clock::uptime();
+		while !CPUS[id].online.load(Ordering::SeqCst) && crate::clock::uptime() - start < 1.0
+		{
+			crate::time::halt();
+		}
+
+		if CPUS[id].online.load(Ordering::SeqCst)
+		{
+			ONLINE.fetch_add(1, Ordering::SeqCst);
+			serprintln!("[INFO] SMP: CPU {} (APIC {}) ONLINE", id, ap.local_apic_id);
+		}
+		else
+		{
+			serprintln!("[ERR] SMP: CPU WITH APIC {} DID NOT START", ap.local_apic_id);
+		}
+	}
+
+	if let Ok((_, flush)) = mapper.unmap(page)
+	{
+		flush.flush();
+	}
+
+	serprintln!("[INFO] SMP: {} CPU(S) ONLINE", count());
+}
+
+
+// Entry point of the application processors (called by the trampoline)
+extern "C" fn ap_main(id: usize) -> !
+{
+	unsafe
+	{
+		Cr4::write(Cr4Flags::from_bits_truncate(BSP_CR4.load(Ordering::SeqCst)));
+	}
+	crate::sys::cpu::enable();
+
+	crate::sys::gdt::init_cpu(id);
+	crate::sys::idt::init();
+	apic::lapic_init();
+	setcpu(id);
+
+	idle()
+}
+
+
+// Idle loop of an application processor (runs the jobs of its queue)
+fn idle() -> !
+{
+	let cpu = cpu();
+	loop
+	{
+		interrupts::disable();
+		let job = cpu.runq.lock().pop_front();
+
+		match job
+		{
+			Some(job) =>
+			{
+				interrupts::enable();
+				job();
+			},
+
+			// Interrupts are enabled and the CPU halted atomically, so a wake-up IPI cannot be missed
+			None => interrupts::enable_and_hlt(),
+		}
+	}
+}
+
+
+// Wake up another processor (it returns from HLT, and checks whatever it was waiting for)
+pub fn wake(id: usize)
+{
+	if id == cpu().id()
+	{
+		return;
+	}
+
+	if let Some(cpu) = CPUS.get(id).filter(|cpu| cpu.online.load(Ordering::SeqCst))
+	{
+		apic::send_ipi(cpu.apic_id(), ICR_FIXED | WAKEUP_VECTOR as u32);
+	}
+}
+
+
+// Run a job on another processor
+pub fn run(id: usize, job: fn()) -> Result<(), ()>
+{
+	let cpu = CPUS.get(id).ok_or(())?;
+	if id == 0 || !cpu.online.load(Ordering::SeqCst)
+	{
+		return Err(());
+	}
+
+	interrupts::without_interrupts(||
+	{
+		cpu.runq.lock().push_back(job);
+	});
+
+	apic::send_ipi(cpu.apic_id(), ICR_FIXED | WAKEUP_VECTOR as u32);
+	Ok(())
+}
+
+
+// Flush a range of pages from the TLB of the current processor
+fn tlb_flush(start: VirtAddr, pages: usize)
+{
+	if pages > TLB_FLUSH_ALL
+	{
+		tlb::flush_all();
+		return;
+	}
+
+	for i in 0..pages as u64
+	{
+		tlb::flush(start + i * Page::<Size4KiB>::SIZE);
+	}
+}
+
+
+// Flush the range of the shootdown in progress, should the current processor still have to (called by the IPI)
+pub fn tlb_service()
+{
+	let bit = 1 << cpu().id();
+	if TLB_PENDING.load(Ordering::SeqCst) & bit != 0
+	{
+		tlb_flush(VirtAddr::new(TLB_START.load(Ordering::SeqCst)), TLB_PAGES.load(Ordering::SeqCst));
+		TLB_PENDING.fetch_and(!bit, Ordering::SeqCst);
+	}
+}
+
+
+// TLB shootdown
+//
+// Flushes a range of pages (which has just been unmapped) from the TLBs of every processor, and returns once all
+// of them have done so. A processor that waits for a lock with interrupts disabled cannot take the IPI, so locks
+// that may be held across a shootdown are taken with lock(), which services it meanwhile.
+pub fn tlb_shootdown(start: VirtAddr, pages: usize)
+{
+	tlb_flush(start, pages);
+	if count() == 1
+	{
+		return;
+	}
+
+	// Another processor may be waiting for this one to flush, while this one waits for the lock
+	let _shootdown = lock(&SHOOTDOWN);
+
+	let id = cpu().id();
+	let targets = CPUS.iter()
+		.enumerate()
+		.filter(|(i, cpu)| *i != id && cpu.online.load(Ordering::SeqCst))
+		.fold(0, |mask, (i, _)| mask | 1 << i);
+
+	TLB_START.store(start.as_u64(), Ordering::SeqCst);
+	TLB_PAGES.store(pages, Ordering::SeqCst);
+	TLB_PENDING.store(targets, Ordering::SeqCst);
+
+	for (_, cpu) in CPUS.iter().enumerate().filter(|(i, _)| targets & 1 << i != 0)
+	{
+		apic::send_ipi(cpu.apic_id(), ICR_FIXED | TLB_VECTOR as u32);
+	}
+
+	while TLB_PENDING.load(Ordering::SeqCst) != 0
+	{
+		core::hint::spin_loop();
+	}
+}
+
+
+// Lock a mutex, flushing the TLB of the current processor whenever a shootdown asks for it while it waits
+//
+// The holder of the lock may be waiting for this processor to acknowledge a shootdown, which it could not do with
+// interrupts disabled.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T>
+{
+	loop
+	{
+		if let Some(guard) = mutex.try_lock()
+		{
+			return guard;
+		}
+		tlb_service();
+		core::hint::spin_loop();
+	}
+}
@@ -8,14 +8,14 @@
 */
 
 use core::hint::spin_loop;
-use core::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::instructions::{interrupts, port::Port};
 
-use crate::cmos::CMOS;
+use crate::{clocksource::{self, NSEC_PER_SEC}, cmos::{CMOS, Intr}};
 
 
 // PID divider
-const PITDIV: usize = 1193;
+pub const PITDIV: usize = 1193;
 
 // PIT frequency
 pub const PITFREQ: f64 = 3_579_545.0 / 3.0;
@@ -23,16 +23,15 @@ pub const PITFREQ: f64 = 3_579_545.0 / 3.0;
 // PIT interval
 const PITINTV: f64 = (PITDIV as f64) / PITFREQ;
 
+// Tick period (in nanoseconds), which every clock event device is programmed with
+pub const TICK_NS: u64 = (PITINTV * NSEC_PER_SEC as f64) as u64;
+
 // PIT tick
 static PIT_TICK: AtomicUsize = AtomicUsize::new(0);
 
 // Last RTC update
 static LAST_RTCUPDATE: AtomicUsize = AtomicUsize::new(0);
 
-// Clock per nanoseconds
-static CLOCK_PER_NS: AtomicU64 = AtomicU64::new(0);
-
-
 
 // Tick function
 pub fn tick() -> usize
@@ -84,6 +83,8 @@ pub fn init()
 
 	set_pitfreq_div(div as u16, channel);
 	crate::sys::idt::set_irh(0, pit_intrh);
+	clocksource::register_source(&clocksource::PIT);
+	clocksource::register_event(&clocksource::PIT);
 
 
 	// RTC
@@ -91,40 +92,24 @@ pub fn init()
 	crate::cmos::CMOS::new().enable_updateintr();
 
 
-	// TSC
-
-	// Set calibration time to around 0.25 seconds
-	let calib = 250_000;
-	let a = rdtsc();
-
-	// Sleep for ~0.25 seconds
-	sleep(calib as f64 / 1e6);
-
-	let b = rdtsc();
-
-	CLOCK_PER_NS.store((b - a) / calib, Ordering::Relaxed);
+	// TSC (calibrated against the PIT for now, and again once the HPET has been found)
+	clocksource::calibrate_tsc();
 }
 
 
-
-fn rdtsc() -> u64
+// Monotonic time (in nanoseconds since boot), as counted by the best clock source
+pub fn monotonic() -> u64
 {
-	unsafe
-	{
-		core::arch::x86_64::_mm_lfence();
-		core::arch::x86_64::_rdtsc()
-	}
+	clocksource::monotonic()
 }
 
 
-// Sleep
+// Sleep (blocks until a timer wakes the processor up)
 pub fn sleep(sec: f64)
 {
-	let start = crate::clock::uptime();
-
-	while crate::clock::uptime() - start < sec
+	if sec > 0.0
 	{
-		halt();
+		crate::timer::block((sec * NSEC_PER_SEC as f64) as u64);
 	}
 }
 
@@ -132,9 +117,9 @@ pub fn sleep(sec: f64)
 // Wait (in nanoseconds)
 pub fn nwait(nsec: u64)
 {
-	let start = rdtsc();
-	let delta = nsec * CLOCK_PER_NS.load(Ordering::Relaxed);
-	while rdtsc() - start < delta
+	let start = clocksource::rdtsc();
+	let delta = (nsec as u128 * clocksource::tsc_freq() as u128 / NSEC_PER_SEC as u128) as u64;
+	while clocksource::rdtsc() - start < delta
 	{
 		spin_loop();
 	}
@@ -149,7 +134,8 @@ pub fn set_pitfreq_div(divider: u16, channel: u8)
 		let bytes = divider.to_le_bytes();
 		let mut cmd: Port<u8> = Port::new(0x43);
 		let mut data: Port<u8> = Port::new(0x40 + channel as u16);
-		let opmode = 6;
+		// Mode 2 (rate generator), so that the counter can be read back
+		let opmode = 4;
 		let accmode = 3;
 		unsafe
 		{
@@ -165,12 +151,21 @@ pub fn set_pitfreq_div(divider: u16, channel: u8)
 pub fn pit_intrh()
 {
 	PIT_TICK.fetch_add(1, Ordering::Relaxed);
+	crate::timer::tick();
 }
 
 
 // RTC interrupt handler
 pub fn rtc_intrh()
 {
-	LAST_RTCUPDATE.store(tick(), Ordering::Relaxed);
-	CMOS::new().notify_intrend();
+	// Reading the flags also ends the interrupt
+	let flags = CMOS::new().intr_flags();
+	if CMOS::flagged(flags, Intr::Update)
+	{
+		LAST_RTCUPDATE.store(tick(), Ordering::Relaxed);
+	}
+	if CMOS::flagged(flags, Intr::Alarm)
+	{
+		crate::clock::alarm_intrh();
+	}
 }
@@ -0,0 +1,296 @@
+// timer.rs
+//
+// Kernel timers. One-shot and periodic timers are kept in a timer wheel (one slot per tick), which is advanced
+// by the tick handler. Callbacks run in interrupt context, so they should only wake something up.
+
+
+/*
+	IMPORTS
+*/
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{future::Future, pin::Pin, sync::atomic::{AtomicBool, AtomicU64, Ordering}, task::{Context, Poll}};
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time::TICK_NS;
+
+
+/*
+	CONSTANTS
+*/
+
+// Number of slots of the wheel (timers further away than this many ticks stay in their slot for several rounds)
+const WHEEL_SLOTS: usize = 256;
+
+// ID of the next timer
+static NEXTID: AtomicU64 = AtomicU64::new(1);
+
+
+// TimerID struct
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerID(u64);
+
+
+// Timer struct
+struct Timer
+{
+	id: TimerID,
+
+	// Expiry (in nanoseconds of monotonic time)
+	deadline: u64,
+
+	// Period (zero for one-shot timers)
+	period: u64,
+
+	callback: Box<dyn FnMut() + Send>,
+}
+
+
+// Wheel struct
+struct Wheel
+{
+	slots: [Vec<Timer>; WHEEL_SLOTS],
+
+	// Slot of every pending timer (used to cancel timers)
+	index: BTreeMap<TimerID, usize>,
+
+	// Last tick that has been processed
+	tick: u64,
+}
+
+
+lazy_static!
+{
+	static ref WHEEL: Mutex<Wheel> = Mutex::new(Wheel
+	{
+		slots: [(); WHEEL_SLOTS].map(|_| Vec::new()),
+		index: BTreeMap::new(),
+		tick: crate::time::monotonic() / TICK_NS,
+	});
+}
+
+
+// Implementation of the Wheel struct
+impl Wheel
+{
+	// Insert
+	//
+	// A timer goes in the slot of the first tick that starts after its deadline (or of the next tick, if it is
+	// already due), so that it has expired whenever its slot is visited.
+	fn insert(&mut self, timer: Timer)
+	{
+		let tick = ((timer.deadline + TICK_NS - 1) / TICK_NS).max(self.tick + 1);
+		let slot = tick as usize % WHEEL_SLOTS;
+
+		self.index.insert(timer.id, slot);
+		self.slots[slot].push(timer);
+	}
+
+
+	// Remove
+	fn remove(&mut self, id: TimerID) -> Option<Timer>
+	{
+		let slot = self.index.remove(&id)?;
+		let idx = self.slots[slot].iter().position(|timer| timer.id == id)?;
+		Some(self.slots[slot].swap_remove(idx))
+	}
+
+
+	// Advance (takes the timers that expired up to the given time out of the wheel)
+	fn advance(&mut self, now: u64) -> Vec<Timer>
+	{
+		let mut expired = Vec::new();
+		let target = now / TICK_NS;
+
+		// Every slot is visited at most once, however many ticks were missed
+		let last = target.min(self.tick + WHEEL_SLOTS as u64);
+		while self.tick < last
+		{
+			self.tick += 1;
+			let slot = self.tick as usize % WHEEL_SLOTS;
+
+			let mut idx = 0;
+			while idx < self.slots[slot].len()
+			{
+				if self.slots[slot][idx].deadline <= now
+				{
+					let timer = self.slots[slot].swap_remove(idx);
+					self.index.remove(&timer.id);
+					expired.push(timer);
+				}
+				else
+				{
+					idx += 1;
+				}
+			}
+		}
+		self.tick = self.tick.max(target);
+
+		expired
+	}
+}
+
+
+// Add a timer
+fn add(delay: u64, period: u64, callback: Box<dyn FnMut() + Send>) -> TimerID
+{
+	let id = TimerID(NEXTID.fetch_add(1, Ordering::Relaxed));
+	let timer = Timer
+	{
+		id,
+		deadline: crate::time::monotonic() + delay,
+		period,
+		callback,
+	};
+
+	interrupts::without_interrupts(||
+	{
+		WHEEL.lock().insert(timer);
+	});
+
+	id
+}
+
+
+// One-shot timer (the callback runs once, after the delay, in nanoseconds)
+pub fn oneshot(delay: u64, callback: impl FnMut() + Send + 'static) -> TimerID
+{
+	add(delay, 0, Box::new(callback))
+}
+
+
+// Periodic timer (the callback runs every period, in nanoseconds, until the timer is cancelled)
+pub fn periodic(period: u64, callback: impl FnMut() + Send + 'static) -> TimerID
+{
+	add(period, period.max(1), Box::new(callback))
+}
+
+
+// Cancel a timer (returns false if it had already expired)
+pub fn cancel(id: TimerID) -> bool
+{
+	interrupts::without_interrupts(||
+	{
+		WHEEL.lock().remove(id).is_some()
+	})
+}
+
+
+// Tick (called by the tick handler)
+pub fn tick()
+{
+	let now = crate::time::monotonic();
+
+	// The callbacks run without the wheel being locked, so that they can add timers of their own
+	let expired = match WHEEL.try_lock()
+	{
+		Some(mut wheel) => wheel.advance(now),
+		None => return,
+	};
+
+	let mut rearmed = Vec::new();
+	for mut timer in expired
+	{
+		(timer.callback)();
+
+		if timer.period > 0
+		{
+			// Skip the periods that were missed, rather than running the callback in a burst
+			timer.deadline += timer.period * ((now - timer.deadline) / timer.period + 1);
+			rearmed.push(timer);
+		}
+	}
+
+	if !rearmed.is_empty()
+	{
+		let mut wheel = WHEEL.lock();
+		for timer in rearmed
+		{
+			wheel.insert(timer);
+		}
+	}
+}
+
+
+// Sleep struct (a future, that completes once its deadline has passed)
+pub struct Sleep
+{
+	deadline: u64,
+	waker: Arc<AtomicWaker>,
+	timer: Option<TimerID>,
+}
+
+
+// Implementation of the Future trait for the Sleep struct
+impl Future for Sleep
+{
+	type Output = ();
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+	{
+		let now = crate::time::monotonic();
+		if now >= self.deadline
+		{
+			return Poll::Ready(());
+		}
+
+		self.waker.register(cx.waker());
+		if self.timer.is_none()
+		{
+			let waker = self.waker.clone();
+			self.timer = Some(oneshot(self.deadline - now, move || waker.wake()));
+		}
+
+		Poll::Pending
+	}
+}
+
+
+// Implementation of the Drop trait for the Sleep struct
+impl Drop for Sleep
+{
+	fn drop(&mut self)
+	{
+		if let Some(id) = self.timer
+		{
+			cancel(id);
+		}
+	}
+}
+
+
+// Sleep (asynchronously, for the given number of nanoseconds)
+pub fn sleep(ns: u64) -> Sleep
+{
+	Sleep
+	{
+		deadline: crate::time::monotonic() + ns,
+		waker: Arc::new(AtomicWaker::new()),
+		timer: None,
+	}
+}
+
+
+// Block (halts the current processor until the given number of nanoseconds has passed)
+//
+// The processor that handles the tick wakes the sleeping one up, if they are not the same.
+pub fn block(ns: u64)
+{
+	let fired = Arc::new(AtomicBool::new(false));
+	let cpu = crate::sys::smp::cpu().id();
+
+	let flag = fired.clone();
+	oneshot(ns, move ||
+	{
+		flag.store(true, Ordering::SeqCst);
+		crate::sys::smp::wake(cpu);
+	});
+
+	while !fired.load(Ordering::SeqCst)
+	{
+		crate::time::halt();
+	}
+}
@@ -1,9 +1,64 @@
-// src/libcore/user/date.rs
+// src/user/date.rs
 //
-// The date command prints the current date.
+// The date command prints the current date and time (in ISO-8601), and sets the clock or the UTC offset.
 
 /*
 	IMPORTS
 */
 
-use crate::time::{validate_fmtstr};
+use crate::{clock::{self, DateTime, Offset}, println, sys::sc, user::shell::XCode};
+
+
+// Usage
+fn usage() -> XCode
+{
+	println!("USAGE: date [-u | -s <YYYY-MM-DDTHH:MM:SS[+HH:MM]> | -z [<+HH:MM>]]");
+	XCode::CMD_ERR
+}
+
+
+pub fn main(args: &[&str]) -> XCode
+{
+	let args: alloc::vec::Vec<&str> = args.iter().copied().filter(|arg| !arg.is_empty()).collect();
+
+	match args[1..]
+	{
+		[] => println!("{}", DateTime::from_timestamp(sc::rt(), clock::utc_offset())),
+		["-u" | "--utc"] => println!("{}", DateTime::from_timestamp(sc::rt(), 0)),
+
+		["-s" | "--set", datetime] =>
+		{
+			let dt = match DateTime::parse(datetime)
+			{
+				Ok(dt) => dt,
+				Err(_) =>
+				{
+					println!("[ERR] INVALID DATE: {}", datetime);
+					return XCode::CMD_ERR;
+				}
+			};
+
+			if sc::settime(dt.timestamp()).is_err()
+			{
+				println!("[ERR] UNABLE TO SET THE CLOCK");
+				return XCode::CMD_ERR;
+			}
+			println!("{}", dt);
+		},
+
+		["-z" | "--zone"] => println!("{}", Offset(clock::utc_offset())),
+
+		["-z" | "--zone", offset] =>
+		{
+			if clock::parse_offset(offset).and_then(clock::set_utc_offset).is_err()
+			{
+				println!("[ERR] INVALID OFFSET: {}", offset);
+				return XCode::CMD_ERR;
+			}
+		},
+
+		_ => return usage(),
+	}
+
+	XCode::CMD_SUCCESS
+}
@@ -0,0 +1,97 @@
+// src/user/disk.rs
+//
+// The mount and format commands, which mount a memory disk or a drive (on the ATA or AHCI controller, or a virtio
+// block device) as the filesystem, or format it first. Without arguments, mount shows whether or not a drive has been
+// mounted.
+
+/*
+	IMPORTS
+*/
+
+use crate::{fs, println, user::shell::XCode};
+
+
+// Usage
+fn usage(cmd: &str) -> XCode
+{
+	println!("USAGE: {} [mem | ata <BUS> <DISK> | ahci <PORT> | virtio <IDX>]", cmd);
+	XCode::CMD_ERR
+}
+
+
+pub fn main(args: &[&str]) -> XCode
+{
+	let args: alloc::vec::Vec<&str> = args.iter().copied().filter(|arg| !arg.is_empty()).collect();
+	let format = args[0] == "format";
+
+	let res = match args[1..]
+	{
+		[] if !format =>
+		{
+			println!("{}", if fs::mounted()
+			{
+				"MOUNTED"
+			}
+			else
+			{
+				"NOT MOUNTED"
+			});
+			return XCode::CMD_SUCCESS;
+		},
+
+		["mem"] =>
+		{
+			fs::mntmem();
+			if format
+			{
+				fs::fmtmem();
+			}
+			Ok(())
+		},
+
+		["ata", bus, disk] => match (bus.parse::<u8>(), disk.parse::<u8>())
+		{
+			(Ok(bus), Ok(disk)) =>
+			{
+				fs::mntata(bus, disk);
+				if format && fs::mounted()
+				{
+					fs::fmtata();
+				}
+				Ok(())
+			},
+			_ => return usage(args[0]),
+		},
+
+		["ahci", port] => match port.parse::<u8>()
+		{
+			Ok(port) if format => fs::fmtahci(port),
+			Ok(port) =>
+			{
+				fs::mntahci(port);
+				Ok(())
+			},
+			Err(_) => return usage(args[0]),
+		},
+
+		["virtio", idx] => match idx.parse::<usize>()
+		{
+			Ok(idx) if format => fs::fmtvirtio(idx),
+			Ok(idx) =>
+			{
+				fs::mntvirtio(idx);
+				Ok(())
+			},
+			Err(_) => return usage(args[0]),
+		},
+
+		_ => return usage(args[0]),
+	};
+
+	if res.is_err() || !fs::mounted()
+	{
+		println!("[ERR] UNABLE TO {} {}", args[0].to_uppercase(), args[1].to_uppercase());
+		return XCode::CMD_ERR;
+	}
+	XCode::CMD_SUCCESS
+}
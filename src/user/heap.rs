@@ -0,0 +1,51 @@
+// src/user/heap.rs
+//
+// The heap command shows how much of the kernel heap is in use, and sets the size up to which the heap may grow.
+
+/*
+	IMPORTS
+*/
+
+use crate::{allocator, println, user::shell::XCode};
+
+
+// Usage
+fn usage() -> XCode
+{
+	println!("USAGE: heap [max <KB>]");
+	XCode::CMD_ERR
+}
+
+
+pub fn main(args: &[&str]) -> XCode
+{
+	let args: alloc::vec::Vec<&str> = args.iter().copied().filter(|arg| !arg.is_empty()).collect();
+
+	match args[1..]
+	{
+		[] =>
+		{
+			println!("HEAP: {} KB USED OF {} KB, {} KB FREE, {} KB MAXIMUM", allocator::memused() >> 10, allocator::memsize() >> 10, allocator::memfree() >> 10, allocator::heapmax() >> 10);
+		},
+
+		["max", size] =>
+		{
+			let size = match size.parse::<usize>()
+			{
+				Ok(size) => size << 10,
+				Err(_) => return usage(),
+			};
+
+			// The heap does not shrink, so it cannot be limited to less than it has already mapped
+			if allocator::set_heapmax(size).is_err()
+			{
+				println!("[ERR] THE HEAP IS ALREADY LARGER THAN {} KB", size >> 10);
+				return XCode::CMD_ERR;
+			}
+		},
+
+		_ => return usage(),
+	}
+
+	XCode::CMD_SUCCESS
+}
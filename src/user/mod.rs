@@ -4,8 +4,23 @@
 // The libcore::user module contains the basic functionality required for having users, providing commands, and providing the user with a shell.
 
 
+// The date command, which prints or sets the date and time
+pub mod date;
+
+// The mount and format commands, which mount (or format) a disk as the filesystem
+pub mod disk;
+
+// The heap command, which shows the use of the kernel heap and sets its maximum size
+pub mod heap;
+
+// The reboot, halt and poweroff commands
+pub mod power;
+
 // The shell
 pub mod shell;
 
+// The swap command, which turns swapping on or off
+pub mod swap;
+
 // The ut command, which returns the time the system has gone without being restarted.
 pub mod ut;
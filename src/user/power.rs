@@ -0,0 +1,43 @@
+// src/user/power.rs
+//
+// The reboot, halt and poweroff commands. "halt --suspend" attempts to suspend the system to RAM instead.
+
+/*
+	IMPORTS
+*/
+
+use crate::{println, sys::power, user::shell::XCode};
+
+
+pub fn main(args: &[&str]) -> XCode
+{
+	match (args[0], args.get(1).copied().filter(|arg| !arg.is_empty()))
+	{
+		("reboot", None) => power::reboot(),
+		("poweroff", None) => power::poweroff(),
+		("halt", None) => power::halt(),
+
+		("halt", Some("-s" | "--suspend")) =>
+		{
+			if power::suspend().is_err()
+			{
+				println!("[ERR] UNABLE TO SUSPEND");
+				return XCode::CMD_ERR;
+			}
+			XCode::CMD_SUCCESS
+		},
+
+		(cmd, _) =>
+		{
+			println!("USAGE: {}{}", cmd, if cmd == "halt"
+			{
+				" [--suspend]"
+			}
+			else
+			{
+				""
+			});
+			XCode::CMD_ERR
+		},
+	}
+}
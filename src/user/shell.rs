@@ -15,8 +15,16 @@ use crate::{print, println, {sys::console::Style}};
 
 
 // Autocompletion commands
-pub const AUTOCMD: [&str; 1] = [
-	"help"
+pub const AUTOCMD: [&str; 9] = [
+	"date",
+	"format",
+	"halt",
+	"heap",
+	"help",
+	"mount",
+	"poweroff",
+	"reboot",
+	"swap",
 	];
 
 
@@ -41,6 +49,12 @@ pub enum XCode
 // Execute
 pub fn exec(cmd: &str) -> XCode
 {
+	let stages = splpipe(cmd);
+	if stages.len() > 1
+	{
+		return pipeline(&stages);
+	}
+
 	let cmd = cmd.to_string();
 
 	let mut args = splargs(&cmd);
@@ -116,6 +130,11 @@ pub fn exec(cmd: &str) -> XCode
 	let res = match args[0]
 	{
 		"help" => unimplemented!(),
+		"date" => crate::user::date::main(&args),
+		"format" | "mount" => crate::user::disk::main(&args),
+		"halt" | "poweroff" | "reboot" => crate::user::power::main(&args),
+		"heap" => crate::user::heap::main(&args),
+		"swap" => crate::user::swap::main(&args),
 		cmd =>
 		{
 			if crate::sys::proc::spawn(cmd).is_ok()
@@ -181,6 +200,65 @@ pub fn main(args: &[&str]) -> XCode
 	}
 }
 
+// Pipeline
+//
+// Each stage is spawned in turn, with its stdout connected to the stdin of the following stage. The processes are
+// held back until every stage has been spawned, and then run together (taking turns whenever one blocks on a pipe).
+pub fn pipeline(stages: &[&str]) -> XCode
+{
+	if stages.iter().any(|stage| stage.trim().is_empty())
+	{
+		println!("[ERR] UNABLE TO PARSE PIPE");
+		return XCode::CMD_ERR;
+	}
+
+	let n = stages.len();
+	let mut res = XCode::CMD_SUCCESS;
+
+	crate::sys::proc::hold(true);
+	for (i, stage) in stages.iter().enumerate()
+	{
+		let mut reader = None;
+
+		if i < n - 1
+		{
+			if let Some((rh, wh)) = crate::sys::sc::pipe()
+			{
+				crate::sys::sc::dup(wh, 1);
+				crate::sys::sc::close(wh);
+				reader = Some(rh);
+			}
+			else
+			{
+				println!("[ERR] UNABLE TO CREATE PIPE");
+				res = XCode::CMD_ERR;
+				break;
+			}
+		}
+
+		res = exec(stage);
+
+		// Closing the write-end lets the next stage see end-of-file
+		crate::fs::reopen("/dev/console", 1).ok();
+
+		if let Some(rh) = reader
+		{
+			crate::sys::sc::dup(rh, 0);
+			crate::sys::sc::close(rh);
+		}
+	}
+
+	crate::sys::proc::hold(false);
+
+	// The shell has closed its ends of the pipes, so that each stage sees end-of-file (or a broken pipe) once its
+	// neighbour exits
+	crate::fs::reopen("/dev/console", 0).ok();
+	crate::sys::proc::run_group();
+
+	res
+}
+
+
 // Prompt string function
 pub fn promptstr(success: bool) -> String
 {
@@ -304,6 +382,37 @@ pub fn shcomp(ln: &str) -> Vec<String>
 }
 
 
+// Split pipeline
+//
+// Splits a command on every '|' that is not inside of a quotation.
+pub fn splpipe(cmd: &str) -> Vec<&str>
+{
+	let mut stages: Vec<&str> = Vec::new();
+	let mut i = 0;
+	let mut quotation = false;
+
+	for (j, c) in cmd.char_indices()
+	{
+		if c == '#' && !quotation
+		{
+			break;
+		}
+		else if c == '|' && !quotation
+		{
+			stages.push(&cmd[i..j]);
+			i = j + 1;
+		}
+		else if c == '"'
+		{
+			quotation = !quotation;
+		}
+	}
+
+	stages.push(&cmd[i..]);
+	stages
+}
+
+
 // Split arguements
 pub fn splargs(cmd: &str) -> Vec<&str>
 {
@@ -0,0 +1,79 @@
+// src/user/swap.rs
+//
+// The swap command shows how much of the swap area is in use, and turns swapping on (onto a file, or a range of
+// blocks of a disk) or off.
+
+/*
+	IMPORTS
+*/
+
+use crate::{fs::blkdev::{AhciBlkDev, AtaBlkDev, BlkDev, VirtioBlkDev}, mem::swap, println, user::shell::XCode};
+
+
+// Usage
+fn usage() -> XCode
+{
+	println!("USAGE: swap [on <FILE> <KB> | on <ahci|virtio> <IDX> <START> <BLOCKS> | on ata <BUS> <DISK> <START> <BLOCKS> | off]");
+	XCode::CMD_ERR
+}
+
+
+pub fn main(args: &[&str]) -> XCode
+{
+	let args: alloc::vec::Vec<&str> = args.iter().copied().filter(|arg| !arg.is_empty()).collect();
+
+	let res = match args[1..]
+	{
+		[] =>
+		{
+			let stats = swap::stats();
+			println!("SWAP: {} KB, {} KB USED, {} PAGES RESIDENT", stats.total * 4, stats.used * 4, stats.resident);
+			return XCode::CMD_SUCCESS;
+		},
+
+		["off"] => swap::swapoff(),
+
+		["on", path, size] => match size.parse::<usize>()
+		{
+			Ok(size) => swap::swapon_file(&crate::fs::rpath(path), size << 10),
+			Err(_) => return usage(),
+		},
+
+		["on", kind @ ("ahci" | "virtio"), idx, start, count] =>
+		{
+			let (idx, start, count) = match (idx.parse::<u8>(), start.parse::<u32>(), count.parse::<u32>())
+			{
+				(Ok(idx), Ok(start), Ok(count)) => (idx, start, count),
+				_ => return usage(),
+			};
+
+			let dev = if kind == "ahci"
+			{
+				AhciBlkDev::new(idx).map(BlkDev::AHCI)
+			}
+			else
+			{
+				VirtioBlkDev::new(idx as usize).map(BlkDev::VIRTIO)
+			};
+			dev.ok_or(()).and_then(|dev| swap::swapon_dev(dev, start, count))
+		},
+
+		["on", "ata", bus, disk, start, count] =>
+		{
+			match (bus.parse::<u8>(), disk.parse::<u8>(), start.parse::<u32>(), count.parse::<u32>())
+			{
+				(Ok(bus), Ok(disk), Ok(start), Ok(count)) => AtaBlkDev::new(bus, disk).ok_or(()).and_then(|dev| swap::swapon_dev(BlkDev::ATA(dev), start, count)),
+				_ => return usage(),
+			}
+		},
+
+		_ => return usage(),
+	};
+
+	if res.is_err()
+	{
+		println!("[ERR] UNABLE TO TURN SWAPPING {}", args[1].to_uppercase());
+		return XCode::CMD_ERR;
+	}
+	XCode::CMD_SUCCESS
+}
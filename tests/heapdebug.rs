@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+// Misuses caught by the debug allocator. It is used directly (whichever allocator is the global one), and every
+// misuse has to be counted as an error; the reports are printed over serial.
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use libertyos_kernel::{allocator::{debug, DEBUG}, exitqemu, QEMUExitCode, serprint, serprintln};
+
+entry_point!(main);
+
+
+fn main(bootinfo: &'static BootInfo) -> !
+{
+	libertyos_kernel::init();
+	libertyos_kernel::mem::init(bootinfo);
+
+	serprint!("HEAPDEBUG::MISUSES...\t");
+	let layout = Layout::from_size_align(100, 16).unwrap();
+
+	unsafe
+	{
+		// Correct use
+		let ptr = DEBUG.alloc(layout);
+		assert!(!ptr.is_null() && ptr as usize % 16 == 0);
+		core::ptr::write_bytes(ptr, 1, layout.size());
+		DEBUG.dealloc(ptr, layout);
+		assert_eq!(debug::stats().errors, 0);
+
+		// Overrun
+		let ptr = DEBUG.alloc(layout);
+		*ptr.add(layout.size()) = 0;
+		DEBUG.dealloc(ptr, layout);
+		assert_eq!(debug::stats().errors, 1, "OVERRUN NOT CAUGHT");
+
+		// Double free
+		DEBUG.dealloc(ptr, layout);
+		assert_eq!(debug::stats().errors, 2, "DOUBLE FREE NOT CAUGHT");
+
+		// Write after free (caught when the quarantine is drained)
+		let ptr = DEBUG.alloc(layout);
+		DEBUG.dealloc(ptr, layout);
+		*ptr = 0;
+		debug::drain();
+		assert_eq!(debug::stats().errors, 3, "WRITE AFTER FREE NOT CAUGHT");
+
+		// Leak
+		let mark = debug::mark();
+		let leaked = DEBUG.alloc(layout);
+		debug::dump(mark);
+		assert_eq!(debug::stats().live, 1);
+		DEBUG.dealloc(leaked, layout);
+	}
+
+	serprintln!("[SUCCESS]");
+	exitqemu(QEMUExitCode::Success);
+	loop {}
+}
+
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+	libertyos_kernel::test_panic_handler(info)
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+// Pipes are bounded: once PIPE_CAPACITY bytes are waiting to be read, a writer that cannot block gets WouldBlk, and
+// writes only go through again as the reader makes room. Closing the other end gives end-of-file or a broken pipe.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use libertyos_kernel::{exitqemu, fs::{pipe::{self, PIPE_CAPACITY}, FileIO, IO}, noblkio, serprint, serprintln, QEMUExitCode};
+
+// Size of each read
+const CHUNK: usize = 1000;
+
+entry_point!(main);
+
+
+fn main(bootinfo: &'static BootInfo) -> !
+{
+	libertyos_kernel::init();
+	libertyos_kernel::mem::init(bootinfo);
+
+	serprint!("PIPE::BOUNDED...\t");
+
+	let (mut reader, mut writer) = pipe::new();
+	let data: Vec<u8> = (0..2 * PIPE_CAPACITY).map(|i| (i % 251) as u8).collect();
+
+	// Only the capacity fits, and then the writer would block
+	assert_eq!(writer.try_write(&data), Ok(PIPE_CAPACITY), "PIPE NOT BOUNDED");
+	assert_eq!(writer.space(), 0);
+	assert!(!writer.poll(IO::Write), "FULL PIPE WRITABLE");
+	assert!(matches!(writer.try_write(&data[..1]), Err(noblkio::Err::WouldBlk)), "FULL PIPE DID NOT BLOCK");
+
+	// Reading makes room for as much again, which a blocking write fills without waiting
+	let mut buffer = [0u8; CHUNK];
+	assert_eq!(reader.read(&mut buffer), Ok(CHUNK));
+	assert!(buffer[..] == data[..CHUNK], "DATA CORRUPTED");
+	assert!(writer.poll(IO::Write));
+	assert_eq!(writer.write(&data[PIPE_CAPACITY..PIPE_CAPACITY + CHUNK]), Ok(CHUNK));
+	assert!(matches!(writer.try_write(&data[..1]), Err(noblkio::Err::WouldBlk)), "FULL PIPE DID NOT BLOCK");
+
+	// Everything comes out in order, and end-of-file follows once the writers are gone
+	drop(writer);
+	let mut output = Vec::from(&buffer[..]);
+	loop
+	{
+		let n = reader.read(&mut buffer).expect("READ");
+		if n == 0
+		{
+			break;
+		}
+		output.extend_from_slice(&buffer[..n]);
+	}
+	assert!(output[..] == data[..PIPE_CAPACITY + CHUNK], "DATA CORRUPTED");
+
+	// Writing without readers is a broken pipe
+	let (reader, mut writer) = pipe::new();
+	drop(reader);
+	assert!(writer.broken());
+	assert_eq!(writer.write(&data[..1]), Err(()), "NO BROKEN PIPE");
+
+	serprintln!("[SUCCESS]");
+	exitqemu(QEMUExitCode::Success);
+	loop {}
+}
+
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+	libertyos_kernel::test_panic_handler(info)
+}
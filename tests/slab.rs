@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+
+// Benchmark of the slab-allocator, against the linked-list heap. Both allocators are used directly (whichever
+// of them is the global allocator), and the cycles taken by each pattern are printed over serial.
+
+extern crate alloc;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use libertyos_kernel::{allocator::{ALLOCATOR, SLAB}, clocksource::rdtsc, exitqemu, QEMUExitCode, serprint, serprintln};
+
+// Number of objects allocated by each round
+const OBJECTS: usize = 512;
+
+// Object sizes
+const SIZES: [usize; 6] = [16, 64, 256, 1024, 2048, 8192];
+
+entry_point!(main);
+
+
+fn main(bootinfo: &'static BootInfo) -> !
+{
+	libertyos_kernel::init();
+	libertyos_kernel::mem::init(bootinfo);
+
+	serprintln!("SLAB::BENCHMARK...");
+	for &size in SIZES.iter()
+	{
+		let layout = Layout::from_size_align(size, 8).unwrap();
+		let heap = unsafe
+		{
+			bench(&ALLOCATOR, layout)
+		};
+		let slab = unsafe
+		{
+			bench(&SLAB, layout)
+		};
+		serprintln!("SIZE {:5}: HEAP {:10} CYCLES, SLAB {:10} CYCLES", size, heap, slab);
+	}
+
+	let stats = libertyos_kernel::allocator::slab::stats();
+	for cache in stats.caches.iter()
+	{
+		serprintln!("CACHE {:5}: {} SLABS, {}/{} OBJECTS", cache.size, cache.slabs, cache.objects, cache.capacity);
+	}
+	serprintln!("FRAGMENTATION: {:.1}%", stats.fragmentation() * 100.0);
+
+	serprint!("SLAB::BENCHMARK...\t");
+	serprintln!("[SUCCESS]");
+	exitqemu(QEMUExitCode::Success);
+	loop {}
+}
+
+
+// Benchmark (allocates objects, frees every other one, allocates them again, and frees everything)
+//
+// Returns the number of cycles taken. Every object is filled with its own pattern, which is checked before it is
+// freed, so that overlapping objects are caught.
+unsafe fn bench(allocator: &dyn GlobalAlloc, layout: Layout) -> u64
+{
+	let mut ptrs = [core::ptr::null_mut::<u8>(); OBJECTS];
+	let start = rdtsc();
+
+	for (i, ptr) in ptrs.iter_mut().enumerate()
+	{
+		*ptr = allocator.alloc(layout);
+		assert!(!ptr.is_null(), "OUT OF MEMORY");
+		core::ptr::write_bytes(*ptr, i as u8, layout.size());
+	}
+
+	for (i, ptr) in ptrs.iter_mut().enumerate().step_by(2)
+	{
+		check(*ptr, layout, i as u8);
+		allocator.dealloc(*ptr, layout);
+		*ptr = allocator.alloc(layout);
+		assert!(!ptr.is_null(), "OUT OF MEMORY");
+		core::ptr::write_bytes(*ptr, i as u8, layout.size());
+	}
+
+	for (i, ptr) in ptrs.iter().enumerate()
+	{
+		check(*ptr, layout, i as u8);
+		allocator.dealloc(*ptr, layout);
+	}
+
+	rdtsc() - start
+}
+
+
+// Check the pattern of an object
+unsafe fn check(ptr: *mut u8, layout: Layout, value: u8)
+{
+	let object = core::slice::from_raw_parts(ptr, layout.size());
+	assert!(object.iter().all(|&byte| byte == value), "OBJECTS OVERLAP");
+}
+
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+	libertyos_kernel::test_panic_handler(info)
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+
+// Swapping under memory pressure. Almost every free frame is taken, and more pages of processes than there are
+// frames left are mapped onto a memory disk used as the swap area. Every page is then checked, which swaps those
+// that were evicted back in through the page-fault handler (read-only pages must stay read-only). Can also be run
+// with a small "-m" setting.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use libertyos_kernel::{exitqemu, fs::blkdev::{BlkDev, MemBlkDev}, mem::{frame::{self, Zone}, swap}, serprint, serprintln, sys::{cpu, proc}, QEMUExitCode};
+use x86_64::{instructions::tlb, structures::paging::{Page, PageTableFlags}, VirtAddr};
+
+// Pages mapped
+const PAGES: u64 = 64;
+
+// Frames left free
+const SPARE: usize = 8;
+
+// Size of a page
+const PAGE_SIZE: u64 = 4096;
+
+entry_point!(main);
+
+
+fn main(bootinfo: &'static BootInfo) -> !
+{
+	libertyos_kernel::init();
+	libertyos_kernel::sys::idt::init();
+	libertyos_kernel::mem::init(bootinfo);
+
+	serprint!("SWAP::PRESSURE...\t");
+
+	// Twice as many blocks as the pages need
+	swap::swapon_dev(BlkDev::MEM(MemBlkDev::new((PAGES * 16) as usize)), 0, (PAGES * 16) as u32).expect("SWAPON");
+
+	let mut taken = Vec::with_capacity(frame::stats().free);
+	while frame::stats().free > SPARE
+	{
+		match frame::alloc(Zone::Normal)
+		{
+			Some(frame) => taken.push(frame),
+			None => break,
+		}
+	}
+
+	let start = proc::CODEADDRESS.load(core::sync::atomic::Ordering::SeqCst);
+	for i in 0..PAGES
+	{
+		let address = start + i * PAGE_SIZE;
+		proc::mapzeroed(Page::containing_address(VirtAddr::new(address))).expect("OUT OF MEMORY");
+		cpu::user_access(|| unsafe
+		{
+			core::ptr::write_bytes(address as *mut u8, i as u8, PAGE_SIZE as usize);
+		});
+
+		if i % 2 == 1
+		{
+			let entry = libertyos_kernel::mem::pte(VirtAddr::new(address)).expect("NOT MAPPED");
+			entry.set_flags(entry.flags() - PageTableFlags::WRITABLE);
+			tlb::flush(VirtAddr::new(address));
+		}
+	}
+
+	let stats = swap::stats();
+	assert!(stats.used > 0, "NOTHING SWAPPED OUT");
+
+	for i in 0..PAGES
+	{
+		let address = start + i * PAGE_SIZE;
+		let page = cpu::user_access(|| unsafe
+		{
+			core::slice::from_raw_parts(address as *const u8, PAGE_SIZE as usize).iter().all(|&byte| byte == i as u8)
+		});
+		assert!(page, "PAGE {} CORRUPTED", i);
+
+		let flags = libertyos_kernel::mem::pte(VirtAddr::new(address)).expect("NOT MAPPED").flags();
+		assert_eq!(flags.contains(PageTableFlags::WRITABLE), i % 2 == 0, "PAGE {} FLAGS LOST", i);
+	}
+
+	swap::release(start, PAGES * PAGE_SIZE);
+	libertyos_kernel::mem::p_dealloc(start, PAGES * PAGE_SIZE);
+	for frame in taken
+	{
+		frame::free(frame);
+	}
+	assert_eq!(swap::stats().used, 0);
+
+	serprintln!("[SUCCESS]");
+	serprintln!("{} PAGES SWAPPED OUT AT MOST", stats.used);
+	exitqemu(QEMUExitCode::Success);
+	loop {}
+}
+
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+	libertyos_kernel::test_panic_handler(info)
+}